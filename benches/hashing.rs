@@ -0,0 +1,91 @@
+//! Ingestion throughput on a generated workload spread over the whole ClientId space, to compare the hashers of the client and
+//! transaction maps, the amount representations and the effect of Exchange::with_capacity():
+//!
+//! cargo bench --bench hashing
+//! cargo bench --bench hashing --features fast-hash
+//! cargo bench --bench hashing --features minor-units
+//!
+//! BENCH_TRANSACTIONS overrides the number of generated transactions (10M by default)
+use std::env;
+use std::fs::File;
+use std::io::BufWriter;
+use std::io::Write;
+use std::time::Instant;
+
+use payment_engine::exchange;
+use payment_engine::exchange::Exchange;
+
+const CLIENTS: u64 = u16::MAX as u64 + 1;
+
+/// xorshift, so every run processes the same input
+struct Generator(u64);
+
+impl Generator {
+    fn next(&mut self, bound: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % bound
+    }
+}
+
+fn generate(path: &std::path::Path, transactions: u64) -> std::io::Result<()> {
+    let mut generator = Generator(0x5eed);
+    let mut file = BufWriter::new(File::create(path)?);
+    writeln!(file, "type,client,tx,amount")?;
+    for tx in 1..=transactions {
+        let client = generator.next(CLIENTS);
+        match generator.next(10) {
+            0..=5 => writeln!(file, "deposit,{},{},{}.0", client, tx, generator.next(1000))?,
+            6..=8 => writeln!(
+                file,
+                "withdrawal,{},{},{}.0",
+                client,
+                tx,
+                generator.next(100)
+            )?,
+            _ => writeln!(file, "dispute,{},{},", client, tx - generator.next(tx))?,
+        }
+    }
+    file.flush()
+}
+
+fn run(name: &str, path: &str, mut exchange: Exchange) {
+    let start = Instant::now();
+    exchange::process_transactions_from_csv(path, &mut exchange).unwrap();
+    println!("{:<28} {:>8} ms", name, start.elapsed().as_millis());
+}
+
+fn main() {
+    let transactions = env::var("BENCH_TRANSACTIONS")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(10_000_000);
+    let path = env::temp_dir().join("payment_engine_bench_hashing.csv");
+    generate(&path, transactions).unwrap();
+
+    println!(
+        "{} transactions over {} clients, hasher: {}, amounts: {}",
+        transactions,
+        CLIENTS,
+        if cfg!(feature = "fast-hash") {
+            "FxHash"
+        } else {
+            "SipHash"
+        },
+        if cfg!(feature = "minor-units") {
+            "i64 minor units"
+        } else {
+            "rust_decimal"
+        }
+    );
+    let input = path.to_str().unwrap();
+    run("Exchange::new()", input, Exchange::new());
+    run(
+        "Exchange::with_capacity()",
+        input,
+        Exchange::with_capacity(CLIENTS as usize),
+    );
+
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,101 @@
+//! Scaling of Exchange::process_speculatively() against the serial engine, on a generated workload of deposits and
+//! withdrawals with rare disputes (one transaction in a thousand). Scaling is bound by the cores of the machine:
+//!
+//! cargo bench --bench speculative
+//!
+//! BENCH_TRANSACTIONS overrides the number of generated transactions (10M by default), BENCH_THREADS the thread counts
+//! compared (1,2,4,8,16 by default)
+use std::env;
+use std::fs::File;
+use std::io::BufWriter;
+use std::io::Write;
+use std::time::Instant;
+
+use payment_engine::exchange;
+use payment_engine::exchange::speculative;
+use payment_engine::exchange::Exchange;
+use payment_engine::exchange::IngestOptions;
+
+const CLIENTS: u64 = u16::MAX as u64 + 1;
+
+/// xorshift, so every run processes the same input
+struct Generator(u64);
+
+impl Generator {
+    fn next(&mut self, bound: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % bound
+    }
+}
+
+fn generate(path: &std::path::Path, transactions: u64) -> std::io::Result<()> {
+    let mut generator = Generator(0x5eed);
+    let mut file = BufWriter::new(File::create(path)?);
+    writeln!(file, "type,client,tx,amount")?;
+    for tx in 1..=transactions {
+        let client = generator.next(CLIENTS);
+        match generator.next(1000) {
+            0 => writeln!(file, "dispute,{},{},", client, tx - generator.next(tx))?,
+            1..=599 => writeln!(file, "deposit,{},{},{}.0", client, tx, generator.next(1000))?,
+            _ => writeln!(
+                file,
+                "withdrawal,{},{},{}.0",
+                client,
+                tx,
+                generator.next(100)
+            )?,
+        }
+    }
+    file.flush()
+}
+
+fn main() {
+    let transactions = env::var("BENCH_TRANSACTIONS")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(10_000_000);
+    let threads: Vec<usize> = env::var("BENCH_THREADS")
+        .unwrap_or_else(|_| "1,2,4,8,16".to_string())
+        .split(',')
+        .filter_map(|n| n.trim().parse().ok())
+        .collect();
+    let path = env::temp_dir().join("payment_engine_bench_speculative.csv");
+    generate(&path, transactions).unwrap();
+    let input = path.to_str().unwrap();
+    let options = IngestOptions {
+        quiet: true,
+        ..Default::default()
+    };
+
+    println!(
+        "{} transactions over {} clients, {} cores available",
+        transactions,
+        CLIENTS,
+        std::thread::available_parallelism().map_or(1, |n| n.get())
+    );
+    let start = Instant::now();
+    exchange::process_transactions_from_csv_with(input, &mut Exchange::new(), &options).unwrap();
+    let serial = start.elapsed();
+    println!("{:<28} {:>8} ms", "serial", serial.as_millis());
+    for threads in threads {
+        let start = Instant::now();
+        speculative::process_transactions_from_csv_speculatively(
+            input,
+            &mut Exchange::new(),
+            &options,
+            threads,
+        )
+        .unwrap();
+        let elapsed = start.elapsed();
+        println!(
+            "{:<28} {:>8} ms {:>6.2}x",
+            format!("speculative, {} threads", threads),
+            elapsed.as_millis(),
+            serial.as_secs_f64() / elapsed.as_secs_f64()
+        );
+    }
+
+    std::fs::remove_file(&path).unwrap();
+}
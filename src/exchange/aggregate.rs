@@ -0,0 +1,204 @@
+use std::error::Error;
+
+use crate::exchange::client_profile::plus;
+use crate::exchange::client_profile::positive;
+use crate::exchange::client_profile::ClientProfile;
+use crate::exchange::client_profile::ProcessingError;
+use crate::exchange::transaction::ClientId;
+use crate::exchange::transaction::Currency;
+use crate::exchange::transaction::IdMap;
+use crate::exchange::transaction::Money;
+use crate::exchange::transaction::Type;
+use crate::exchange::Exchange;
+use crate::exchange::IngestOptions;
+use crate::exchange::RecordReader;
+
+/// Process the input in aggregate-only mode, the fast path of sanity runs over inputs too large for anything but the final
+/// account summaries: records are read as process_transactions_from_csv_with() reads them, but only the available funds of
+/// each client are kept. No transaction is retained, so a repeated deposit id is not caught, no event is published, and of
+/// the account rules only the available funds of withdrawals are checked (no reserve, caps or freezes), besides the
+/// deposits overflowing the balances and the amounts that are not positive. The input fails on
+/// the first dispute, resolve, chargeback or custom transaction, which need the engine. The clients are added to bank, which
+/// must have none, once the input is exhausted; processed and rejected transactions are counted in its stats
+pub fn aggregate_transactions_from_csv(
+    path: &str,
+    bank: &mut Exchange,
+    options: &IngestOptions,
+) -> Result<(), Box<dyn Error>> {
+    if !bank.clients.is_empty() {
+        return Err(
+            "The aggregate-only mode can only start from an exchange without clients".into(),
+        );
+    }
+    let mut reader = csv::Reader::from_path(path)?;
+    let headers = reader.headers()?.clone();
+    let ingest = RecordReader::new(&headers, options);
+
+    let mut balances: IdMap<ClientId, Currency> = IdMap::default();
+    let mut record = csv::StringRecord::new();
+    while reader.read_record(&mut record)? {
+        let mut t = ingest.parse(bank, &record)?;
+        if let Some(amount) = &mut t.amount {
+            amount.rescale(bank.config.amount_scale);
+        }
+        let available = balances.entry(t.client).or_insert_with(Currency::zero);
+        let rejection = match (&t.tx_type, t.amount) {
+            (Type::Deposit | Type::Withdrawal, Some(amount)) if amount <= Currency::zero() => {
+                positive(amount, &t).err()
+            }
+            (Type::Deposit, Some(amount)) => match plus(*available, amount, &t) {
+                Ok(sum) => {
+                    *available = sum;
+                    None
+                }
+                Err(overflow) => Some(overflow),
+            },
+            (Type::Withdrawal, Some(amount)) if amount <= *available => {
+                *available -= amount;
+                None
+            }
+            (Type::Withdrawal, Some(amount)) => Some(ProcessingError::new(format!(
+                "{} amount exceeds available funds {}. Igoring transaction {}..",
+                amount,
+                available,
+                t.mention()
+            ))),
+            (Type::Deposit | Type::Withdrawal, None) => Some(ProcessingError::new(format!(
+                "Igoring malformed transaction {}..",
+                t.mention()
+            ))),
+            _ => {
+                return Err(ProcessingError::new(format!(
+                    "The aggregate-only mode only supports deposits and withdrawals, found transaction {}",
+                    t.mention()
+                ))
+                .into())
+            }
+        };
+        bank.submitted += 1;
+        match rejection {
+            Some(error) => {
+                bank.stats.rejected += 1;
+                ingest.log_rejection(bank, &error);
+            }
+            None => bank.stats.processed += 1,
+        }
+    }
+
+    for (client, available) in balances {
+        bank.clients.insert(
+            client,
+            ClientProfile::new(
+                client,
+                available,
+                Currency::zero(),
+                available,
+                false,
+                std::iter::empty(),
+            ),
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use crate::exchange::client_profile::AccountBalance;
+
+    #[test]
+    fn it_should_sum_up_the_accounts_without_retaining_transactions() {
+        let input = std::env::temp_dir().join("payment_engine_aggregate.csv");
+        let rows = "type,client,tx,amount\n\
+                    deposit,1,1,10.0\n\
+                    deposit,2,2,5.0\n\
+                    withdrawal,1,3,4.0\n\
+                    withdrawal,2,4,8.0\n\
+                    deposit,1,5,1.5\n";
+        std::fs::write(&input, rows).unwrap();
+
+        let mut full = Exchange::new();
+        crate::exchange::process_transactions_from_csv(input.to_str().unwrap(), &mut full).unwrap();
+        let mut aggregated = Exchange::new();
+        let options = IngestOptions {
+            quiet: true,
+            ..IngestOptions::default()
+        };
+        aggregate_transactions_from_csv(input.to_str().unwrap(), &mut aggregated, &options)
+            .unwrap();
+        for client in [1, 2] {
+            assert_eq!(
+                full.client(client).map(AccountBalance::from),
+                aggregated.client(client).map(AccountBalance::from)
+            );
+        }
+        assert_eq!(0, aggregated.client(1).unwrap().transaction_count());
+        assert_eq!(
+            (4, 1),
+            (
+                aggregated.stats().processed(),
+                aggregated.stats().rejected()
+            )
+        );
+        assert_eq!(
+            true,
+            aggregate_transactions_from_csv(input.to_str().unwrap(), &mut aggregated, &options)
+                .is_err()
+        );
+
+        std::fs::write(
+            &input,
+            "type,client,tx,amount\ndeposit,1,1,10.0\ndispute,1,1,\n",
+        )
+        .unwrap();
+        assert_eq!(
+            "The aggregate-only mode only supports deposits and withdrawals, found transaction Dispute,1,1,None,false",
+            aggregate_transactions_from_csv(input.to_str().unwrap(), &mut Exchange::new(), &options)
+                .unwrap_err()
+                .to_string()
+        );
+        std::fs::remove_file(&input).unwrap();
+    }
+
+    #[test]
+    fn it_should_reject_overflowing_zero_and_missing_amounts() {
+        let input = std::env::temp_dir().join("payment_engine_aggregate_rejections.csv");
+        let rows = format!(
+            "type,client,tx,amount\n\
+             deposit,1,1,{max}\n\
+             deposit,1,2,1.0\n\
+             deposit,2,3,\n\
+             withdrawal,2,4,\n\
+             deposit,2,5,2.00004\n\
+             deposit,2,6,0\n\
+             withdrawal,2,7,0.00001\n",
+            max = Currency::MAX
+        );
+        std::fs::write(&input, rows).unwrap();
+        let options = IngestOptions {
+            quiet: true,
+            ..IngestOptions::default()
+        };
+        let mut aggregated = Exchange::new();
+        aggregate_transactions_from_csv(input.to_str().unwrap(), &mut aggregated, &options)
+            .unwrap();
+
+        assert_eq!(Currency::MAX, aggregated.client(1).unwrap().available());
+        //rescaled to the 4 decimals of the amounts as the engine does
+        assert_eq!(
+            Currency::str("2.0000"),
+            aggregated.client(2).unwrap().available()
+        );
+        assert_eq!(
+            (7, 2, 5),
+            (
+                aggregated.submitted(),
+                aggregated.stats().processed(),
+                aggregated.stats().rejected()
+            )
+        );
+        std::fs::remove_file(&input).unwrap();
+    }
+}
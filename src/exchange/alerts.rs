@@ -0,0 +1,368 @@
+use serde::Serialize;
+use std::fmt;
+#[cfg(feature = "alert-webhook")]
+use std::io;
+#[cfg(feature = "alert-webhook")]
+use std::time::Duration;
+
+use crate::exchange::stats::Stats;
+
+/// The error budget is checked every that many transactions submitted, and once more when the run is over
+pub const CHECK_EVERY: u64 = 1000;
+
+/// Thresholds of a run beyond which the input is considered broken, see Exchange::check_error_budget(). Rates are percentages,
+/// every threshold left as None is not checked
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct ErrorBudget {
+    /// Rejected transactions among the submitted ones
+    pub max_reject_rate: Option<f64>,
+    /// Records that could not be read (quarantined) among all the records
+    pub max_parse_failure_rate: Option<f64>,
+    /// Accounts with negative available funds
+    pub max_negative_balances: Option<usize>,
+}
+
+/// A threshold of the error budget exceeded, with the value it was exceeded by
+#[derive(Debug, PartialEq, Clone, Copy, Serialize)]
+#[serde(tag = "threshold", rename_all = "snake_case")]
+pub enum BudgetBreach {
+    RejectRate { rate: f64, max: f64 },
+    ParseFailureRate { rate: f64, max: f64 },
+    NegativeBalances { count: usize, max: usize },
+}
+
+impl BudgetBreach {
+    /// Whether both are breaches of the same threshold
+    pub fn same_threshold(&self, other: &BudgetBreach) -> bool {
+        std::mem::discriminant(self) == std::mem::discriminant(other)
+    }
+}
+
+impl fmt::Display for BudgetBreach {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BudgetBreach::RejectRate { rate, max } => {
+                write!(f, "reject rate {:.2}% above {}%", rate, max)
+            }
+            BudgetBreach::ParseFailureRate { rate, max } => {
+                write!(f, "parse failure rate {:.2}% above {}%", rate, max)
+            }
+            BudgetBreach::NegativeBalances { count, max } => {
+                write!(
+                    f,
+                    "{} accounts with negative available funds, above {}",
+                    count, max
+                )
+            }
+        }
+    }
+}
+
+fn percent(part: u64, whole: u64) -> f64 {
+    if whole == 0 {
+        0.0
+    } else {
+        part as f64 * 100.0 / whole as f64
+    }
+}
+
+impl ErrorBudget {
+    pub fn is_empty(&self) -> bool {
+        *self == ErrorBudget::default()
+    }
+
+    /// The thresholds exceeded by the run so far, given the transactions submitted and the accounts with negative available funds
+    pub fn check(
+        &self,
+        stats: &Stats,
+        submitted: u64,
+        negative_balances: usize,
+    ) -> Vec<BudgetBreach> {
+        let mut breaches = Vec::new();
+        let rejected = percent(stats.rejected(), submitted);
+        if let Some(max) = self.max_reject_rate.filter(|max| rejected > *max) {
+            breaches.push(BudgetBreach::RejectRate {
+                rate: rejected,
+                max,
+            });
+        }
+        let malformed = percent(stats.malformed(), submitted + stats.malformed());
+        if let Some(max) = self.max_parse_failure_rate.filter(|max| malformed > *max) {
+            breaches.push(BudgetBreach::ParseFailureRate {
+                rate: malformed,
+                max,
+            });
+        }
+        if let Some(max) = self
+            .max_negative_balances
+            .filter(|max| negative_balances > *max)
+        {
+            breaches.push(BudgetBreach::NegativeBalances {
+                count: negative_balances,
+                max,
+            });
+        }
+        breaches
+    }
+}
+
+/// How long connecting to the webhook, sending it the alert and receiving its answer may each take, unless
+/// Webhook::with_timeout() says otherwise
+#[cfg(feature = "alert-webhook")]
+pub const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// http:// or https:// endpoint POSTed a JSON document for every breach of the error budget. https endpoints are verified
+/// against the webpki root certificates, and an endpoint that does not accept the connection or answer in time fails the post
+/// rather than holding up the run
+#[cfg(feature = "alert-webhook")]
+#[derive(Debug, PartialEq, Clone)]
+pub struct Webhook {
+    url: String,
+    timeout: Duration,
+}
+
+#[cfg(feature = "alert-webhook")]
+impl Webhook {
+    /// http[s]://host[:port][/path]
+    pub fn parse(url: &str) -> Option<Webhook> {
+        let uri: ureq::http::Uri = url.parse().ok()?;
+        let host = uri.host().filter(|host| !host.is_empty());
+        (matches!(uri.scheme_str(), Some("http" | "https")) && host.is_some()).then(|| Webhook {
+            url: url.to_string(),
+            timeout: WEBHOOK_TIMEOUT,
+        })
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Webhook {
+        self.timeout = timeout;
+        self
+    }
+
+    /// POST the breach, failing unless the endpoint answers with a 2xx status
+    pub fn post(&self, breach: &BudgetBreach) -> io::Result<()> {
+        let body = serde_json::json!({
+            "alert": "error_budget_exceeded",
+            "message": breach.to_string(),
+            "breach": breach,
+        })
+        .to_string();
+        let timeout = Some(self.timeout);
+        let agent: ureq::Agent = ureq::Agent::config_builder()
+            .timeout_connect(timeout)
+            .timeout_send_request(timeout)
+            .timeout_send_body(timeout)
+            .timeout_recv_response(timeout)
+            .timeout_recv_body(timeout)
+            .build()
+            .into();
+        match agent
+            .post(self.url.as_str())
+            .header("Content-Type", "application/json")
+            .send(body)
+        {
+            Ok(_) => Ok(()),
+            Err(ureq::Error::StatusCode(status)) => {
+                Err(io::Error::other(format!("Webhook answered {}", status)))
+            }
+            Err(e) => Err(e.into_io()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use std::collections::HashMap;
+    #[cfg(feature = "alert-webhook")]
+    use std::io::Read;
+    #[cfg(feature = "alert-webhook")]
+    use std::io::Write;
+    #[cfg(feature = "alert-webhook")]
+    use std::net::TcpListener;
+    #[cfg(feature = "alert-webhook")]
+    use std::time::Instant;
+
+    use crate::exchange::config::EngineConfig;
+    use crate::exchange::events::DomainEvent;
+    use crate::exchange::transaction::Currency;
+    use crate::exchange::transaction::Money;
+    use crate::exchange::transaction::Transaction;
+    use crate::exchange::transaction::Type;
+    use crate::exchange::Exchange;
+
+    fn budgeted() -> Exchange {
+        Exchange::new().with_config(EngineConfig {
+            error_budget: ErrorBudget {
+                max_reject_rate: Some(10.0),
+                max_negative_balances: Some(0),
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+    }
+
+    /// One withdrawal in four, all of them rejected
+    fn reject_one_in_four(exchange: &mut Exchange) {
+        for tx in 1..=2 * CHECK_EVERY as u32 {
+            let tx_type = if tx % 4 == 0 {
+                Type::Withdrawal
+            } else {
+                Type::Deposit
+            };
+            let _ = exchange.process_new_transaction(Transaction {
+                tx_type,
+                client: (tx % 4) as u16,
+                tx,
+                amount: Some(Currency::str("1.0")),
+                metadata: HashMap::new(),
+                under_dispute: false,
+                dispute_metadata: None,
+            });
+        }
+    }
+
+    #[test]
+    fn it_should_raise_each_exceeded_threshold_once() {
+        let mut exchange = budgeted();
+        let mut events = exchange.subscribe();
+        reject_one_in_four(&mut exchange);
+
+        assert_eq!(
+            vec![BudgetBreach::RejectRate {
+                rate: 25.0,
+                max: 10.0
+            }],
+            exchange.check_error_budget()
+        );
+        let mut breaches = 0;
+        while let Ok(event) = events.try_recv() {
+            breaches += matches!(event, DomainEvent::BudgetExceeded { .. }) as usize;
+        }
+        assert_eq!(1, breaches);
+    }
+
+    #[test]
+    fn it_should_only_check_the_thresholds_given() {
+        let stats = Stats::default();
+        assert_eq!(true, ErrorBudget::default().is_empty());
+        assert_eq!(
+            Vec::<BudgetBreach>::new(),
+            ErrorBudget::default().check(&stats, 0, 100)
+        );
+
+        let budget = ErrorBudget {
+            max_reject_rate: Some(0.0),
+            max_parse_failure_rate: Some(0.0),
+            max_negative_balances: Some(2),
+        };
+        //nothing submitted yet is no rate at all
+        assert_eq!(Vec::<BudgetBreach>::new(), budget.check(&stats, 0, 2));
+        let breaches = budget.check(&stats, 10, 3);
+        assert_eq!(
+            vec![BudgetBreach::NegativeBalances { count: 3, max: 2 }],
+            breaches
+        );
+        assert_eq!(
+            "3 accounts with negative available funds, above 2",
+            breaches[0].to_string()
+        );
+        assert_eq!(
+            true,
+            breaches[0].same_threshold(&BudgetBreach::NegativeBalances { count: 9, max: 2 })
+        );
+        assert_eq!(
+            false,
+            breaches[0].same_threshold(&BudgetBreach::RejectRate {
+                rate: 50.0,
+                max: 0.0
+            })
+        );
+    }
+
+    /// Accept one connection, read the request and answer it with the response. Returns the request
+    #[cfg(feature = "alert-webhook")]
+    fn serve(listener: TcpListener, response: &'static [u8]) -> std::thread::JoinHandle<String> {
+        std::thread::spawn(move || {
+            let (mut connection, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0; 1024];
+            while !request.ends_with(b"}") {
+                let read = connection.read(&mut buffer).unwrap();
+                if read == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buffer[..read]);
+            }
+            connection.write_all(response).unwrap();
+            String::from_utf8(request).unwrap()
+        })
+    }
+
+    #[cfg(feature = "alert-webhook")]
+    #[test]
+    fn it_should_post_each_exceeded_threshold_once() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/alerts", listener.local_addr().unwrap());
+        let server = serve(
+            listener,
+            b"HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n",
+        );
+        let mut exchange = budgeted().with_alert_webhook(Webhook::parse(&url).unwrap());
+        reject_one_in_four(&mut exchange);
+
+        let request = server.join().unwrap();
+        assert_eq!(true, request.starts_with("POST /alerts HTTP/1.1\r\n"));
+        assert_eq!(
+            true,
+            request
+                .to_lowercase()
+                .contains("content-type: application/json")
+        );
+        assert_eq!(true, request.contains("\"threshold\":\"reject_rate\""));
+    }
+
+    #[cfg(feature = "alert-webhook")]
+    #[test]
+    fn it_should_fail_the_post_unless_the_webhook_accepts_it_in_time() {
+        let breach = BudgetBreach::NegativeBalances { count: 1, max: 0 };
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let server = serve(
+            listener,
+            b"HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\n\r\n",
+        );
+        let error = Webhook::parse(&url).unwrap().post(&breach).unwrap_err();
+        assert_eq!("Webhook answered 500", error.to_string());
+        server.join().unwrap();
+
+        //accepts the connection but never answers
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let started = Instant::now();
+        let webhook = Webhook::parse(&url)
+            .unwrap()
+            .with_timeout(Duration::from_millis(200));
+        assert_eq!(true, webhook.post(&breach).is_err());
+        assert_eq!(true, started.elapsed() < WEBHOOK_TIMEOUT);
+        drop(listener);
+    }
+
+    #[cfg(feature = "alert-webhook")]
+    #[test]
+    fn it_should_parse_http_and_https_urls() {
+        assert_eq!(
+            Some(Webhook {
+                url: "https://alerts.example.com/payment-engine".to_string(),
+                timeout: WEBHOOK_TIMEOUT,
+            }),
+            Webhook::parse("https://alerts.example.com/payment-engine")
+        );
+        assert_eq!(true, Webhook::parse("http://relay:9000").is_some());
+        assert_eq!(None, Webhook::parse("ftp://relay/alerts"));
+        assert_eq!(None, Webhook::parse("relay/alerts"));
+        assert_eq!(None, Webhook::parse("http://"));
+    }
+}
@@ -0,0 +1,284 @@
+use serde::Deserialize;
+use serde::Serialize;
+use std::fmt;
+
+use crate::exchange::transaction::ClientId;
+use crate::exchange::transaction::Currency;
+use crate::exchange::transaction::IdMap;
+
+/// Deposits a client must have made before its next ones are scored, so the first few do not flag each other
+pub const WARMUP: u32 = 5;
+
+/// Settings of the anomaly detector: a deposit deviating from the client's moving averages by more than z_score standard
+/// deviations is flagged. Each new deposit weighs 2 / (window + 1) in the averages, so they mostly reflect the last window deposits
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct AnomalyDetection {
+    pub z_score: f64,
+    pub window: u32,
+}
+
+impl AnomalyDetection {
+    /// <z-score>:<window> e.g. 3:20, both positive
+    pub fn parse(rule: &str) -> Option<AnomalyDetection> {
+        let (z_score, window) = rule.split_once(':')?;
+        let z_score: f64 = z_score.parse().ok()?;
+        let window: u32 = window.parse().ok()?;
+        (z_score > 0.0 && window > 0).then_some(AnomalyDetection { z_score, window })
+    }
+
+    fn weight(&self) -> f64 {
+        2.0 / (self.window as f64 + 1.0)
+    }
+}
+
+/// What about a deposit deviated from the client's usual behavior
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum AnomalyMetric {
+    DepositAmount,
+    /// The number of transactions submitted (by any client) since the client's previous deposit. The input has no
+    /// timestamps, so input positions stand in for time: a burst shows as gaps far below the usual one
+    DepositGap,
+}
+
+impl fmt::Display for AnomalyMetric {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            AnomalyMetric::DepositAmount => "deposit_amount",
+            AnomalyMetric::DepositGap => "deposit_gap",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// A flagged observation, with the moving average it was compared to
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Anomaly {
+    pub metric: AnomalyMetric,
+    pub observed: f64,
+    pub expected: f64,
+    pub z_score: f64,
+}
+
+/// Exponentially weighted moving average and variance of one metric
+#[derive(Debug, Default, PartialEq, Clone, Copy, Serialize, Deserialize)]
+struct Ewma {
+    mean: f64,
+    variance: f64,
+}
+
+impl Ewma {
+    /// The z-score of the observation against the averages before it, which are then updated with it. The deviation is
+    /// floored at 1% of the mean, so a client whose deposits never varied is not flagged for a rounding difference
+    fn observe(&mut self, observed: f64, weight: f64, first: bool) -> f64 {
+        if first {
+            self.mean = observed;
+            return 0.0;
+        }
+        let difference = observed - self.mean;
+        let deviation = self.variance.sqrt().max(self.mean.abs() * 0.01).max(1e-4);
+        let increment = weight * difference;
+        self.mean += increment;
+        self.variance = (1.0 - weight) * (self.variance + difference * increment);
+        difference / deviation
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Clone, Copy, Serialize, Deserialize)]
+struct ClientBaseline {
+    deposits: u32,
+    last_deposit: u64,
+    amount: Ewma,
+    gap: Ewma,
+}
+
+/// Online per-client baselines of the deposits, fed by the exchange with every accepted deposit when
+/// EngineConfig::anomaly_detection is set. Scoring a deposit is a few float operations on the client's baseline, cheap enough
+/// to see every transaction. The baselines are kept in checkpoints, so a resumed run scores deposits as the uninterrupted one
+#[derive(Debug, Default, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct AnomalyDetector {
+    clients: IdMap<ClientId, ClientBaseline>,
+}
+
+impl AnomalyDetector {
+    pub fn new() -> AnomalyDetector {
+        AnomalyDetector::default()
+    }
+
+    /// Score a deposit of the client made when submitted transactions had been submitted so far, and learn from it
+    pub fn observe(
+        &mut self,
+        detection: &AnomalyDetection,
+        client: ClientId,
+        amount: &Currency,
+        submitted: u64,
+    ) -> Vec<Anomaly> {
+        let weight = detection.weight();
+        let baseline = self.clients.entry(client).or_default();
+        let first = baseline.deposits == 0;
+        let scored = baseline.deposits >= WARMUP;
+        let mut anomalies = Vec::new();
+
+        let amount = amount.to_f64();
+        let expected = baseline.amount.mean;
+        let z_score = baseline.amount.observe(amount, weight, first);
+        if scored && z_score.abs() > detection.z_score {
+            anomalies.push(Anomaly {
+                metric: AnomalyMetric::DepositAmount,
+                observed: amount,
+                expected,
+                z_score,
+            });
+        }
+
+        //the first deposit has no previous one to measure a gap from, the second one starts the averages
+        if !first {
+            let gap = (submitted - baseline.last_deposit) as f64;
+            let expected = baseline.gap.mean;
+            let z_score = baseline.gap.observe(gap, weight, baseline.deposits == 1);
+            if scored && z_score.abs() > detection.z_score {
+                anomalies.push(Anomaly {
+                    metric: AnomalyMetric::DepositGap,
+                    observed: gap,
+                    expected,
+                    z_score,
+                });
+            }
+        }
+        baseline.deposits = baseline.deposits.saturating_add(1);
+        baseline.last_deposit = submitted;
+        anomalies
+    }
+
+    /// Keep the baselines of another detector for the clients this one has not seen, see Exchange::merge()
+    pub(crate) fn merge(&mut self, other: AnomalyDetector) {
+        for (client, baseline) in other.clients {
+            self.clients.entry(client).or_insert(baseline);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use std::collections::HashMap;
+
+    use crate::exchange::config::EngineConfig;
+    use crate::exchange::events::DomainEvent;
+    use crate::exchange::transaction::Money;
+    use crate::exchange::transaction::Transaction;
+    use crate::exchange::transaction::Type;
+    use crate::exchange::Exchange;
+
+    #[test]
+    fn it_should_flag_deposits_deviating_from_the_client_baseline() {
+        let mut exchange = Exchange::new().with_config(EngineConfig {
+            anomaly_detection: AnomalyDetection::parse("3:10"),
+            freeze_on_anomaly: true,
+            ..Default::default()
+        });
+        let mut events = exchange.subscribe();
+        let mut submit = |client, tx, amount: &str| {
+            exchange
+                .process_new_transaction(Transaction {
+                    tx_type: Type::Deposit,
+                    client,
+                    tx,
+                    amount: Some(Currency::str(amount)),
+                    metadata: HashMap::new(),
+                    under_dispute: false,
+                    dispute_metadata: None,
+                })
+                .unwrap();
+        };
+        //client 1 deposits around 10 every other transaction, client 2 fills the gaps
+        for tx in 1..=20 {
+            let amount = if tx % 4 < 2 { "10.0" } else { "11.0" };
+            submit(1 + ((tx + 1) % 2) as ClientId, tx, amount);
+        }
+        submit(1, 21, "500.0");
+        submit(2, 22, "10.0");
+        //a burst from client 2
+        submit(2, 23, "10.0");
+
+        let (mut anomalies, mut frozen) = (Vec::new(), Vec::new());
+        while let Ok(event) = events.try_recv() {
+            match event {
+                DomainEvent::AnomalyDetected {
+                    client,
+                    tx,
+                    anomaly,
+                    ..
+                } => anomalies.push((client, tx, anomaly.metric)),
+                DomainEvent::AccountFrozen { client, tx } => frozen.push((client, tx)),
+                _ => {}
+            }
+        }
+        assert_eq!(
+            vec![
+                (1, 21, AnomalyMetric::DepositAmount),
+                (2, 23, AnomalyMetric::DepositGap)
+            ],
+            anomalies
+        );
+        assert_eq!(vec![(1, 21), (2, 23)], frozen);
+        assert_eq!(true, exchange.client(1).unwrap().frozen());
+        assert_eq!(
+            true,
+            exchange
+                .stats()
+                .to_string()
+                .contains("anomalies.deposit_amount: 1\nanomalies.deposit_gap: 1")
+        );
+
+        assert_eq!(None, AnomalyDetection::parse("3"));
+        assert_eq!(None, AnomalyDetection::parse("-1:10"));
+    }
+
+    #[test]
+    fn it_should_only_score_deposits_once_the_baseline_warmed_up() {
+        let detection = AnomalyDetection::parse("2:5").unwrap();
+        let mut detector = AnomalyDetector::new();
+        let deposits = ["10.0", "10.0", "900.0", "10.0", "10.0"];
+        for (submitted, amount) in deposits.iter().enumerate() {
+            let anomalies =
+                detector.observe(&detection, 1, &Currency::str(amount), submitted as u64);
+            assert_eq!(Vec::<Anomaly>::new(), anomalies, "deposit {}", submitted);
+        }
+        //past the warmup, in step with the client's spacing but far above its amounts
+        let anomalies = detector.observe(&detection, 1, &Currency::str("5000.0"), 5);
+        assert_eq!(
+            vec![AnomalyMetric::DepositAmount],
+            anomalies
+                .iter()
+                .map(|anomaly| anomaly.metric)
+                .collect::<Vec<AnomalyMetric>>()
+        );
+        assert_eq!(5000.0, anomalies[0].observed);
+        assert_eq!(true, anomalies[0].z_score > detection.z_score);
+    }
+
+    #[test]
+    fn it_should_keep_its_own_baselines_when_merging() {
+        let detection = AnomalyDetection::parse("3:20").unwrap();
+        let (mut left, mut right) = (AnomalyDetector::new(), AnomalyDetector::new());
+        left.observe(&detection, 1, &Currency::str("10.0"), 1);
+        right.observe(&detection, 1, &Currency::str("99.0"), 1);
+        right.observe(&detection, 2, &Currency::str("5.0"), 2);
+        let mut expected = left.clone();
+        expected.observe(&detection, 2, &Currency::str("5.0"), 2);
+
+        left.merge(right);
+        assert_eq!(expected, left);
+        assert_eq!(
+            Some(AnomalyDetection {
+                z_score: 2.5,
+                window: 30
+            }),
+            AnomalyDetection::parse("2.5:30")
+        );
+        assert_eq!(None, AnomalyDetection::parse("3:0"));
+    }
+}
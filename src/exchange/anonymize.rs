@@ -0,0 +1,199 @@
+use std::error::Error;
+use std::io;
+use std::str::FromStr;
+
+use crate::exchange::transaction::ClientId;
+use crate::exchange::transaction::Currency;
+use crate::exchange::transaction::TransactionId;
+
+/// Number of client ids, each one remapped to another
+const CLIENTS: usize = ClientId::MAX as usize + 1;
+
+/// Turns a production input into a fixture that can be shared: client ids are shuffled by a permutation of the whole id space,
+/// tx ids by a bijection of theirs, so disputes, resolves and chargebacks still refer to their transaction, and every amount
+/// of a client is scaled by that client's own factor, between 0.50 and 2.00. As all the amounts of a client are scaled alike,
+/// a withdrawal the funds covered is still covered (up to the rounding to 4 decimals) and the outcomes of the fixture are the
+/// ones of the input. The same seed always gives the same mapping, for fixtures to be regenerated reproducibly. The seed is
+/// the only secret: whoever has it can map the fixture back
+pub struct Anonymizer {
+    clients: Vec<ClientId>,
+    /// Percentages the amounts of each client are scaled by
+    factors: Vec<u8>,
+    tx_multiplier: u32,
+    tx_mask: u32,
+}
+
+/// Rows written by Anonymizer::anonymize_csv() and rows skipped because their client, tx or amount could not be read
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Anonymized {
+    pub rows: u64,
+    pub skipped: u64,
+}
+
+impl Anonymizer {
+    pub fn new(seed: u64) -> Anonymizer {
+        //xorshift never leaves zero
+        let mut state = seed.max(1);
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        //Fisher-Yates
+        let mut clients: Vec<ClientId> = (0..CLIENTS).map(|id| id as ClientId).collect();
+        for i in (1..CLIENTS).rev() {
+            clients.swap(i, (next() % (i as u64 + 1)) as usize);
+        }
+        let factors = (0..CLIENTS).map(|_| 50 + (next() % 151) as u8).collect();
+        Anonymizer {
+            clients,
+            factors,
+            //odd, so multiplying is a bijection of the u32 space
+            tx_multiplier: next() as u32 | 1,
+            tx_mask: next() as u32,
+        }
+    }
+
+    pub fn client(&self, client: ClientId) -> ClientId {
+        self.clients[client as usize]
+    }
+
+    pub fn tx(&self, tx: TransactionId) -> TransactionId {
+        tx.wrapping_mul(self.tx_multiplier) ^ self.tx_mask
+    }
+
+    /// The amount scaled by the factor of the client, rounded to 4 decimals
+    pub fn amount(&self, client: ClientId, amount: Currency) -> Currency {
+        (amount * Currency::new(self.factors[client as usize] as i64, 2)).round_dp(4)
+    }
+
+    /// Anonymize a CSV input (type, client, tx and amount columns) into output, row by row and in the same order, so the
+    /// transactions of every client keep their order. Only those four columns are written: metadata columns may hold
+    /// anything and are dropped, and so are the rows whose client, tx or amount can not be read
+    pub fn anonymize_csv<R: io::Read, W: io::Write>(
+        &self,
+        input: R,
+        output: W,
+    ) -> Result<Anonymized, Box<dyn Error>> {
+        let mut reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .flexible(true)
+            .from_reader(input);
+        let headers = reader.headers()?.clone();
+        let column = |name: &str| headers.iter().position(|header| header == name);
+        let (Some(tx_type), Some(client), Some(tx)) =
+            (column("type"), column("client"), column("tx"))
+        else {
+            return Err("The input needs type, client and tx columns".into());
+        };
+        let amount = column("amount");
+
+        let mut writer = csv::Writer::from_writer(output);
+        writer.write_record(["type", "client", "tx", "amount"])?;
+        let mut anonymized = Anonymized {
+            rows: 0,
+            skipped: 0,
+        };
+        let mut record = csv::StringRecord::new();
+        while reader.read_record(&mut record)? {
+            let row = || -> Option<[String; 4]> {
+                let client: ClientId = record.get(client)?.parse().ok()?;
+                let tx: TransactionId = record.get(tx)?.parse().ok()?;
+                let amount = match amount.and_then(|amount| record.get(amount)) {
+                    None | Some("") => String::new(),
+                    Some(amount) => self
+                        .amount(client, Currency::from_str(amount).ok()?)
+                        .to_string(),
+                };
+                Some([
+                    record.get(tx_type)?.to_string(),
+                    self.client(client).to_string(),
+                    self.tx(tx).to_string(),
+                    amount,
+                ])
+            };
+            match row() {
+                Some(row) => {
+                    writer.write_record(&row)?;
+                    anonymized.rows += 1;
+                }
+                None => anonymized.skipped += 1,
+            }
+        }
+        writer.flush()?;
+        Ok(anonymized)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use crate::exchange::process_transactions_from_reader_with;
+    use crate::exchange::Exchange;
+    use crate::exchange::IngestOptions;
+
+    #[test]
+    fn it_should_keep_the_outcomes_of_the_anonymized_input() {
+        let rows = "type,client,tx,amount,merchant\n\
+                    deposit,1,1,10.0,acme\n\
+                    deposit,2,2,5.0,acme\n\
+                    withdrawal,1,3,4.0,acme\n\
+                    withdrawal,2,4,6.0,acme\n\
+                    dispute,1,1,,\n\
+                    chargeback,1,1,,\n";
+        let anonymizer = Anonymizer::new(42);
+        let mut fixture = Vec::new();
+        assert_eq!(
+            Anonymized {
+                rows: 6,
+                skipped: 1
+            },
+            anonymizer
+                .anonymize_csv(
+                    format!("{}deposit,x,5,1.0,acme\n", rows).as_bytes(),
+                    &mut fixture
+                )
+                .unwrap()
+        );
+        assert_eq!(false, String::from_utf8_lossy(&fixture).contains("acme"));
+
+        let (mut original, mut anonymized) = (Exchange::new(), Exchange::new());
+        let options = IngestOptions::default();
+        process_transactions_from_reader_with(rows.as_bytes(), &mut original, &options).unwrap();
+        process_transactions_from_reader_with(fixture.as_slice(), &mut anonymized, &options)
+            .unwrap();
+        for client in [1, 2] {
+            let (before, after) = (
+                original.client(client).unwrap(),
+                anonymized.client(anonymizer.client(client)).unwrap(),
+            );
+            assert_eq!(
+                anonymizer.amount(client, before.available()),
+                after.available()
+            );
+            assert_eq!(before.locked(), after.locked());
+        }
+        assert_eq!(original.stats().rejected(), anonymized.stats().rejected());
+
+        //the same seed gives the same mapping
+        let mut again = Vec::new();
+        Anonymizer::new(42)
+            .anonymize_csv(rows.as_bytes(), &mut again)
+            .unwrap();
+        assert_eq!(fixture, again);
+        let mut reseeded = Vec::new();
+        Anonymizer::new(43)
+            .anonymize_csv(rows.as_bytes(), &mut reseeded)
+            .unwrap();
+        assert_ne!(fixture, reseeded);
+        let mut clients: Vec<ClientId> = (0..CLIENTS)
+            .map(|id| anonymizer.client(id as ClientId))
+            .collect();
+        clients.sort_unstable();
+        clients.dedup();
+        assert_eq!(CLIENTS, clients.len());
+    }
+}
@@ -0,0 +1,129 @@
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::exchange::transaction::ClientId;
+use crate::exchange::transaction::Currency;
+use crate::exchange::transaction::Transaction;
+use crate::exchange::transaction::TransactionId;
+
+/// A chargeback above the approval threshold, parked until a reviewer approves or denies it. The dispute it would close stays open meanwhile
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct PendingChargeback {
+    chargeback: Transaction,
+    amount: Currency,
+}
+
+impl PendingChargeback {
+    pub fn new(chargeback: Transaction, amount: Currency) -> PendingChargeback {
+        PendingChargeback { chargeback, amount }
+    }
+
+    pub fn client(&self) -> ClientId {
+        self.chargeback.client
+    }
+
+    pub fn tx(&self) -> TransactionId {
+        self.chargeback.tx
+    }
+
+    /// Amount of the disputed transaction that would be charged back
+    pub fn amount(&self) -> Currency {
+        self.amount
+    }
+
+    pub fn chargeback(&self) -> &Transaction {
+        &self.chargeback
+    }
+
+    pub fn into_chargeback(self) -> Transaction {
+        self.chargeback
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use std::collections::HashMap;
+
+    use crate::exchange::checkpoint::InputPosition;
+    use crate::exchange::config::EngineConfig;
+    use crate::exchange::transaction::Money;
+    use crate::exchange::transaction::Type;
+    use crate::exchange::Exchange;
+
+    fn transaction(tx_type: Type, tx: TransactionId, amount: Option<&str>) -> Transaction {
+        Transaction {
+            tx_type,
+            client: 7,
+            tx,
+            amount: amount.map(Currency::str),
+            metadata: HashMap::new(),
+            under_dispute: false,
+            dispute_metadata: None,
+        }
+    }
+
+    #[test]
+    fn it_should_describe_the_parked_chargeback() {
+        let chargeback = transaction(Type::Chargeback, 3, None);
+        let pending = PendingChargeback::new(chargeback.clone(), Currency::str("250.0"));
+
+        assert_eq!(7, pending.client());
+        assert_eq!(3, pending.tx());
+        assert_eq!(Currency::str("250.0"), pending.amount());
+        assert_eq!(&chargeback, pending.chargeback());
+        let json = serde_json::to_string(&pending).unwrap();
+        assert_eq!(
+            pending,
+            serde_json::from_str::<PendingChargeback>(&json).unwrap()
+        );
+        assert_eq!(chargeback, pending.into_chargeback());
+    }
+
+    #[test]
+    fn it_should_only_park_chargebacks_strictly_above_the_threshold_and_keep_them_in_checkpoints() {
+        let config = || EngineConfig {
+            chargeback_approval_threshold: Some(Currency::str("100.0")),
+            ..EngineConfig::default()
+        };
+        let mut exchange = Exchange::new().with_config(config());
+        for (tx, amount) in [(1, "100.0"), (2, "100.01")] {
+            exchange
+                .process_new_transaction(transaction(Type::Deposit, tx, Some(amount)))
+                .unwrap();
+            exchange
+                .process_new_transaction(transaction(Type::Dispute, tx, None))
+                .unwrap();
+        }
+        exchange
+            .process_new_transaction(transaction(Type::Chargeback, 2, None))
+            .unwrap();
+        assert_eq!(1, exchange.pending_chargebacks().len());
+        assert_eq!(false, exchange.client(7).unwrap().locked());
+
+        let path = std::env::temp_dir().join("payment_engine_approval.checkpoint");
+        let position = InputPosition {
+            byte: 0,
+            line: 1,
+            records: 1,
+        };
+        exchange.write_checkpoint(&path, position).unwrap();
+        let mut restored = Exchange::new().with_config(config());
+        restored.restore_checkpoint(&path).unwrap();
+        assert_eq!(
+            exchange.pending_chargebacks(),
+            restored.pending_chargebacks()
+        );
+        std::fs::remove_file(&path).unwrap();
+
+        //at the threshold, applied right away
+        exchange
+            .process_new_transaction(transaction(Type::Chargeback, 1, None))
+            .unwrap();
+        assert_eq!(1, exchange.pending_chargebacks().len());
+        assert_eq!(true, exchange.client(7).unwrap().locked());
+        assert_eq!(Currency::str("100.01"), exchange.client(7).unwrap().held());
+    }
+}
@@ -0,0 +1,553 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::BufRead;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::exchange::client_profile::AccountBalance;
+use crate::exchange::client_profile::ClientProfile;
+use crate::exchange::transaction::ClientId;
+
+/// Rows left behind by rehydrated clients are only compacted away once there are at least this many and they outnumber the
+/// archived ones
+pub const COMPACT_AFTER: usize = 1024;
+
+/// Version of the profile record archived with each row. Rows without one, written before the profiles were archived, are
+/// read back with their balances and locked flag only
+pub const ARCHIVE_VERSION: u32 = 1;
+
+/// The account summary header with the profile column of the archived rows
+pub const HEADER: &str = "client,available,held,total,locked,profile";
+
+/// The state of an archived client, transaction history included, as JSON in the profile column of its row
+#[derive(Debug, Serialize, Deserialize)]
+struct ArchivedProfile<P = ClientProfile> {
+    version: u32,
+    profile: P,
+}
+
+/// The profile column of a row: the versioned JSON record, quoted as a CSV field
+fn profile_field(client: &ClientProfile) -> io::Result<String> {
+    let record = serde_json::to_string(&ArchivedProfile {
+        version: ARCHIVE_VERSION,
+        profile: client,
+    })?;
+    Ok(format!("\"{}\"", record.replace('"', "\"\"")))
+}
+
+/// File holding the client profiles moved out of memory by Exchange::archive_inactive(). Rows are appended in the account
+/// summary layout followed by the versioned profile record, and only their byte offsets are kept in memory, so a client can be
+/// read back when a new transaction for it arrives. The whole profile survives archival (lock and freeze, cooling-off, tiers,
+/// lifetime counts, annotation) along with the transaction history, so the transactions of a client read back can still be
+/// disputed. Rows archived before the history was kept are read back without it.
+/// The offsets are also persisted in an index next to the archive (see index_path()), so an archive can be reopened without
+/// scanning it
+#[derive(Debug, Clone)]
+pub struct Archive {
+    path: PathBuf,
+    offsets: HashMap<ClientId, u64>,
+    /// Rows in the file, including the ones of rehydrated clients
+    rows: usize,
+    /// A read-only view of another exchange's archive, see detached()
+    detached: bool,
+}
+
+/// The index of an archive: its path with .idx appended. It is append-only, one line per change made to the archive:
+/// `+,client,offset,checksum` when a client's row is written and `-,client,offset,checksum` when it is read back. The
+/// checksum (FNV-1a of the rest of the line) tells a torn or corrupted line apart from a valid one
+pub fn index_path(path: &Path) -> PathBuf {
+    let mut index = path.as_os_str().to_owned();
+    index.push(".idx");
+    PathBuf::from(index)
+}
+
+fn checksum(entry: &str) -> u32 {
+    entry.bytes().fold(0x811c9dc5, |hash, byte| {
+        (hash ^ byte as u32).wrapping_mul(0x01000193)
+    })
+}
+
+fn index_entry(archived: bool, client: ClientId, offset: u64) -> String {
+    let entry = format!("{},{},{}", if archived { '+' } else { '-' }, client, offset);
+    format!("{},{:08x}\n", entry, checksum(&entry))
+}
+
+/// An index line as (archived, client, offset), None when it is malformed or its checksum does not match
+fn parse_index_entry(line: &str) -> Option<(bool, ClientId, u64)> {
+    let (entry, sum) = line.rsplit_once(',')?;
+    if u32::from_str_radix(sum, 16).ok()? != checksum(entry) {
+        return None;
+    }
+    let mut fields = entry.split(',');
+    let archived = match fields.next()? {
+        "+" => true,
+        "-" => false,
+        _ => return None,
+    };
+    let client = fields.next()?.parse().ok()?;
+    let offset = fields.next()?.parse().ok()?;
+    fields
+        .next()
+        .is_none()
+        .then_some((archived, client, offset))
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// An archived row as its balances and, unless it predates ARCHIVE_VERSION, the profile it was archived with
+fn parse_row(row: &str, offset: u64) -> io::Result<(AccountBalance, Option<ClientProfile>)> {
+    let invalid = |e: &dyn std::fmt::Display| {
+        invalid_data(format!("Invalid archive row at offset {}: {}", offset, e))
+    };
+    let record = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .from_reader(row.as_bytes())
+        .records()
+        .next()
+        .unwrap_or_else(|| {
+            Err(csv::Error::from(io::Error::from(
+                io::ErrorKind::UnexpectedEof,
+            )))
+        })
+        .map_err(|e| invalid(&e))?;
+    let balance: AccountBalance = csv::StringRecord::from_iter(record.iter().take(5))
+        .deserialize(None)
+        .map_err(|e| invalid(&e))?;
+    let profile = match record.get(5) {
+        Some(field) => {
+            let archived: ArchivedProfile = serde_json::from_str(field).map_err(|e| invalid(&e))?;
+            if archived.version > ARCHIVE_VERSION {
+                return Err(invalid(&format!(
+                    "profile of version {}, this build reads up to version {}",
+                    archived.version, ARCHIVE_VERSION
+                )));
+            }
+            Some(archived.profile)
+        }
+        None => None,
+    };
+    Ok((balance, profile))
+}
+
+/// The archived row at offset, with the offset it ends at
+fn read_row(
+    file: &mut io::BufReader<File>,
+    offset: u64,
+) -> io::Result<((AccountBalance, Option<ClientProfile>), u64)> {
+    file.seek(SeekFrom::Start(offset))?;
+    let mut row = String::new();
+    let read = file.read_line(&mut row)?;
+    Ok((parse_row(&row, offset)?, offset + read as u64))
+}
+
+/// Length of the header line of the archive, which ends where its first row starts
+fn header_len(path: &Path) -> io::Result<u64> {
+    let mut header = String::new();
+    io::BufReader::new(File::open(path)?).read_line(&mut header)?;
+    Ok(header.len() as u64)
+}
+
+impl Archive {
+    /// Create (or truncate) the archive file and write the summary header, starting an empty index
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Archive> {
+        let mut file = File::create(&path)?;
+        writeln!(file, "{}", HEADER)?;
+        File::create(index_path(path.as_ref()))?;
+        Ok(Archive {
+            path: path.as_ref().to_path_buf(),
+            offsets: HashMap::new(),
+            rows: 0,
+            detached: false,
+        })
+    }
+
+    /// Reopen an existing archive from its index. An index that is missing, has a corrupted line or does not account for
+    /// every row of the archive is rebuilt from the archive, see rebuild()
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Archive> {
+        let path = path.as_ref().to_path_buf();
+        match Self::load(&path) {
+            Ok(Some(archive)) => Ok(archive),
+            Ok(None) | Err(_) => {
+                eprintln!(
+                    "Index of archive {} is missing or corrupt, rebuilding it",
+                    path.display()
+                );
+                Self::rebuild(path)
+            }
+        }
+    }
+
+    /// The archive as its index describes it, None when the index can not be trusted
+    fn load(path: &Path) -> io::Result<Option<Archive>> {
+        let index = io::BufReader::new(File::open(index_path(path))?);
+        let mut archive = Archive {
+            path: path.to_path_buf(),
+            offsets: HashMap::new(),
+            rows: 0,
+            detached: false,
+        };
+        let mut last: Option<(ClientId, u64)> = None;
+        for line in index.lines() {
+            let Some((archived, client, offset)) = parse_index_entry(&line?) else {
+                return Ok(None);
+            };
+            if archived {
+                archive.offsets.insert(client, offset);
+                archive.rows += 1;
+                if last.is_none_or(|(_, last)| last < offset) {
+                    last = Some((client, offset));
+                }
+            } else if archive.offsets.get(&client) == Some(&offset) {
+                archive.offsets.remove(&client);
+            } else {
+                return Ok(None);
+            }
+        }
+
+        //the last row written must end the file, or rows were appended that the index never heard of
+        let mut file = io::BufReader::new(File::open(path)?);
+        let end = match last {
+            Some((client, offset)) => match read_row(&mut file, offset) {
+                Ok(((balance, _), end)) if balance.client == client => end,
+                _ => return Ok(None),
+            },
+            None => header_len(path)?,
+        };
+        Ok((end == fs::metadata(path)?.len()).then_some(archive))
+    }
+
+    /// Rebuild the index by scanning the archive: the last row of every client is its archived one, unless the old index
+    /// has an intact entry recording that row was read back. The old index is replaced by one listing the archived clients only
+    pub fn rebuild(path: PathBuf) -> io::Result<Archive> {
+        let mut archive = Archive {
+            path,
+            offsets: HashMap::new(),
+            rows: 0,
+            detached: false,
+        };
+        let mut file = io::BufReader::new(File::open(&archive.path)?);
+        let mut line = String::new();
+        let mut offset = file.read_line(&mut line)? as u64;
+        let len = fs::metadata(&archive.path)?.len();
+        while offset < len {
+            let ((balance, _), end) = read_row(&mut file, offset)?;
+            archive.offsets.insert(balance.client, offset);
+            archive.rows += 1;
+            offset = end;
+        }
+
+        if let Ok(index) = File::open(index_path(&archive.path)) {
+            for line in io::BufReader::new(index).lines().map_while(Result::ok) {
+                if let Some((false, client, offset)) = parse_index_entry(&line) {
+                    if archive.offsets.get(&client) == Some(&offset) {
+                        archive.offsets.remove(&client);
+                    }
+                }
+            }
+        }
+        archive.write_index()?;
+        Ok(archive)
+    }
+
+    /// Write the index of the archived clients next to its destination and rename it over the old one
+    fn write_index(&self) -> io::Result<()> {
+        let path = index_path(&self.path);
+        let partial = path.with_extension("partial");
+        let mut index = io::BufWriter::new(File::create(&partial)?);
+        let mut offsets: Vec<(&ClientId, &u64)> = self.offsets.iter().collect();
+        offsets.sort_unstable_by_key(|(_, offset)| **offset);
+        for (client, offset) in offsets {
+            index.write_all(index_entry(true, *client, *offset).as_bytes())?;
+        }
+        index.into_inner()?.sync_all()?;
+        fs::rename(&partial, path)
+    }
+
+    fn append_index(&self, entries: impl Iterator<Item = String>) -> io::Result<()> {
+        let mut index = io::BufWriter::new(
+            OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(index_path(&self.path))?,
+        );
+        for entry in entries {
+            index.write_all(entry.as_bytes())?;
+        }
+        index.flush()
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// A read-only view of the archive for a fork of the exchange (see Exchange::fork()): it rehydrates clients from the same
+    /// file without recording it in the index, and archives nothing, so the fork leaves the files of the live archive as they are
+    pub fn detached(&self) -> Archive {
+        Archive {
+            detached: true,
+            ..self.clone()
+        }
+    }
+
+    pub fn is_detached(&self) -> bool {
+        self.detached
+    }
+
+    fn check_attached(&self) -> io::Result<()> {
+        if self.detached {
+            return Err(io::Error::other(format!(
+                "The archive {} is read-only in a fork",
+                self.path.display()
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn contains(&self, id: ClientId) -> bool {
+        self.offsets.contains_key(&id)
+    }
+
+    /// Number of clients currently archived, rehydrated ones are not counted
+    pub fn len(&self) -> usize {
+        self.offsets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.offsets.is_empty()
+    }
+
+    /// Rows of rehydrated clients still in the file, until the next compact()
+    pub fn dead_rows(&self) -> usize {
+        self.rows - self.offsets.len()
+    }
+
+    /// Append the rows of the clients to the archive and their offsets to the index, and compact the archive once the rows
+    /// of rehydrated clients outnumber the archived ones (and are at least COMPACT_AFTER)
+    pub fn archive<'a>(
+        &mut self,
+        clients: impl Iterator<Item = &'a ClientProfile>,
+    ) -> io::Result<()> {
+        self.check_attached()?;
+        let mut file = io::BufWriter::new(OpenOptions::new().append(true).open(&self.path)?);
+        let mut offset = file.get_mut().seek(SeekFrom::End(0))?;
+        let mut entries = Vec::new();
+        for client in clients {
+            let row = format!("{},{}\n", client, profile_field(client)?);
+            file.write_all(row.as_bytes())?;
+            self.offsets.insert(client.id(), offset);
+            entries.push(index_entry(true, client.id(), offset));
+            offset += row.len() as u64;
+        }
+        file.flush()?;
+        self.rows += entries.len();
+        self.append_index(entries.into_iter())?;
+
+        if self.dead_rows() >= COMPACT_AFTER && self.dead_rows() > self.offsets.len() {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Rewrite the archive with the rows of the archived clients only, dropping the ones of rehydrated clients, and start a
+    /// new index for it. The new archive is written next to the old one and renamed over it. Returns how many rows were dropped
+    pub fn compact(&mut self) -> io::Result<usize> {
+        self.check_attached()?;
+        let live: HashSet<u64> = self.offsets.values().copied().collect();
+        let partial = self.path.with_extension("partial");
+        let mut compacted = io::BufWriter::new(File::create(&partial)?);
+        let mut offsets = HashMap::with_capacity(self.offsets.len());
+
+        let mut file = io::BufReader::new(File::open(&self.path)?);
+        let mut row = String::new();
+        let mut offset = file.read_line(&mut row)? as u64;
+        compacted.write_all(row.as_bytes())?;
+        let mut written = offset;
+        loop {
+            row.clear();
+            let read = file.read_line(&mut row)? as u64;
+            if read == 0 {
+                break;
+            }
+            if live.contains(&offset) {
+                let (balance, _) = parse_row(&row, offset)?;
+                compacted.write_all(row.as_bytes())?;
+                offsets.insert(balance.client, written);
+                written += read;
+            }
+            offset += read;
+        }
+        compacted.into_inner()?.sync_all()?;
+
+        let dropped = self.rows - offsets.len();
+        fs::rename(&partial, &self.path)?;
+        self.offsets = offsets;
+        self.rows = self.offsets.len();
+        self.write_index()?;
+        Ok(dropped)
+    }
+
+    /// Record that a detached view of the archive read the client back (see detached()), as rehydrate() would have
+    pub fn forget(&mut self, id: ClientId) -> io::Result<()> {
+        self.check_attached()?;
+        match self.offsets.remove(&id) {
+            Some(offset) => self.append_index(std::iter::once(index_entry(false, id, offset))),
+            None => Ok(()),
+        }
+    }
+
+    /// Read an archived client back, removing it from the archive index. None if the client was never archived. A row that is not
+    /// the client's means the index is corrupt: it is rebuilt from the archive and the lookup retried once
+    pub fn rehydrate(&mut self, id: ClientId) -> io::Result<Option<ClientProfile>> {
+        let offset = match self.offsets.get(&id) {
+            Some(offset) => *offset,
+            None => return Ok(None),
+        };
+
+        let mut file = io::BufReader::new(File::open(&self.path)?);
+        let ((balance, profile), offset) = match read_row(&mut file, offset) {
+            Ok((row, _)) if row.0.client == id => (row, offset),
+            _ => {
+                self.check_attached()?;
+                eprintln!(
+                    "Index of archive {} is corrupt, rebuilding it",
+                    self.path.display()
+                );
+                *self = Self::rebuild(self.path.clone())?;
+                let offset = match self.offsets.get(&id) {
+                    Some(offset) => *offset,
+                    None => return Ok(None),
+                };
+                match read_row(&mut file, offset)? {
+                    (row, _) if row.0.client == id => (row, offset),
+                    _ => {
+                        return Err(invalid_data(format!(
+                            "Archived row of client {} not found at offset {}",
+                            id, offset
+                        )))
+                    }
+                }
+            }
+        };
+
+        self.offsets.remove(&id);
+        if !self.detached {
+            self.append_index(std::iter::once(index_entry(false, id, offset)))?;
+        }
+        Ok(Some(match profile {
+            Some(mut profile) => {
+                profile.index_disputes();
+                profile
+            }
+            None => ClientProfile::new(
+                balance.client,
+                balance.available,
+                balance.held,
+                balance.total,
+                balance.locked,
+                HashMap::new(),
+            ),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use crate::exchange::transaction::Currency;
+    use crate::exchange::transaction::Money;
+
+    #[test]
+    fn it_should_read_archived_clients_back_by_offset() {
+        let path = std::env::temp_dir().join("payment_engine_archive_offsets.csv");
+        let mut archive = Archive::create(&path).unwrap();
+        let locked = ClientProfile::new(
+            1,
+            Currency::zero(),
+            Currency::zero(),
+            Currency::zero(),
+            true,
+            HashMap::new(),
+        );
+        let unlocked = ClientProfile::new_with_defaults(2);
+
+        archive.archive([&locked, &unlocked].into_iter()).unwrap();
+        assert_eq!(2, archive.len());
+
+        assert_eq!(Some(unlocked), archive.rehydrate(2).unwrap());
+        assert_eq!(Some(locked), archive.rehydrate(1).unwrap());
+        assert_eq!(None, archive.rehydrate(1).unwrap());
+        assert_eq!(true, archive.is_empty());
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(index_path(&path)).unwrap();
+    }
+
+    #[test]
+    fn it_should_reopen_compact_and_rebuild_a_corrupt_index() {
+        let path = std::env::temp_dir().join("payment_engine_archive_index.csv");
+        let clients: Vec<ClientProfile> = (1..=3).map(ClientProfile::new_with_defaults).collect();
+        let mut archive = Archive::create(&path).unwrap();
+        archive.archive(clients.iter()).unwrap();
+        assert_eq!(Some(clients[0].clone()), archive.rehydrate(1).unwrap());
+
+        let reopened = Archive::open(&path).unwrap();
+        assert_eq!(archive.offsets, reopened.offsets);
+        assert_eq!(1, reopened.dead_rows());
+
+        //a torn line: the rebuild scans the archive and still knows client 1 was read back from the intact lines
+        let index = std::fs::read_to_string(index_path(&path)).unwrap();
+        std::fs::write(index_path(&path), format!("{}+,9,12", index)).unwrap();
+        let mut rebuilt = Archive::open(&path).unwrap();
+        assert_eq!(archive.offsets, rebuilt.offsets);
+
+        assert_eq!(1, rebuilt.compact().unwrap());
+        assert_eq!(
+            format!(
+                "{}\n{},{}\n{},{}\n",
+                HEADER,
+                clients[1],
+                profile_field(&clients[1]).unwrap(),
+                clients[2],
+                profile_field(&clients[2]).unwrap()
+            ),
+            std::fs::read_to_string(&path).unwrap()
+        );
+        assert_eq!(rebuilt.offsets, Archive::open(&path).unwrap().offsets);
+
+        //an intact line pointing at another client's row is only caught on lookup, which rebuilds the index
+        let client_3 = rebuilt.offsets[&3];
+        std::fs::write(
+            index_path(&path),
+            index_entry(true, 3, client_3) + &index_entry(true, 2, client_3),
+        )
+        .unwrap();
+        let mut corrupt = Archive::open(&path).unwrap();
+        assert_eq!(Some(&client_3), corrupt.offsets.get(&2));
+        assert_eq!(Some(clients[1].clone()), corrupt.rehydrate(2).unwrap());
+        assert_eq!(
+            vec![3],
+            Archive::open(&path)
+                .unwrap()
+                .offsets
+                .into_keys()
+                .collect::<Vec<_>>()
+        );
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(index_path(&path)).unwrap();
+    }
+}
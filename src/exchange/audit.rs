@@ -0,0 +1,292 @@
+use serde::Deserialize;
+use serde::Serialize;
+use std::fmt;
+
+use crate::exchange::transaction::ClientId;
+use crate::exchange::transaction::Currency;
+use crate::exchange::transaction::TransactionId;
+
+/// Operations changing accounts outside of the regular transaction flow
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub enum AuditEvent {
+    ClientsMerged {
+        from: ClientId,
+        to: ClientId,
+        transactions: usize,
+        forced: bool,
+    },
+    ChargebackApproved {
+        client: ClientId,
+        tx: TransactionId,
+        amount: Currency,
+    },
+    ChargebackDenied {
+        client: ClientId,
+        tx: TransactionId,
+        amount: Currency,
+    },
+    ClientUnlocked {
+        client: ClientId,
+        released: Currency,
+    },
+    /// The status an account was annotated with, None when the annotation was cleared
+    ClientAnnotated {
+        client: ClientId,
+        status: Option<String>,
+    },
+    KycTierChanged {
+        client: ClientId,
+        from: u8,
+        to: u8,
+    },
+    /// The account tier of the client before and after the change, None for the default tier
+    TierChanged {
+        client: ClientId,
+        from: Option<String>,
+        to: Option<String>,
+    },
+    /// The reserve requirement of the client before and after the change
+    ReserveChanged {
+        client: ClientId,
+        from: Currency,
+        to: Currency,
+    },
+    /// The cooling-off period of the client before and after the change, 0 for none
+    CoolingOffChanged {
+        client: ClientId,
+        from: u64,
+        to: u64,
+    },
+    BlocklistUpdated {
+        client: ClientId,
+        blocked: bool,
+    },
+    ClientFrozen {
+        client: ClientId,
+        frozen: bool,
+    },
+    /// A batch of small balances written off, the number of accounts and the sum of their balances
+    BalancesWrittenOff {
+        below: Currency,
+        accounts: usize,
+        amount: Currency,
+    },
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct AuditRecord {
+    seq: u64,
+    event: AuditEvent,
+}
+
+impl AuditRecord {
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    pub fn event(&self) -> &AuditEvent {
+        &self.event
+    }
+}
+
+impl fmt::Display for AuditRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.event {
+            AuditEvent::ClientsMerged {
+                from,
+                to,
+                transactions,
+                forced,
+            } => write!(
+                f,
+                "{},merge,{},{},{},{}",
+                self.seq, from, to, transactions, forced
+            ),
+            AuditEvent::ChargebackApproved { client, tx, amount } => write!(
+                f,
+                "{},chargeback_approved,{},{},{:.4}",
+                self.seq, client, tx, amount
+            ),
+            AuditEvent::ChargebackDenied { client, tx, amount } => write!(
+                f,
+                "{},chargeback_denied,{},{},{:.4}",
+                self.seq, client, tx, amount
+            ),
+            AuditEvent::ClientUnlocked { client, released } => {
+                write!(f, "{},unlock,{},{:.4}", self.seq, client, released)
+            }
+            AuditEvent::ClientAnnotated { client, status } => write!(
+                f,
+                "{},annotate,{},{}",
+                self.seq,
+                client,
+                status.as_deref().unwrap_or_default()
+            ),
+            AuditEvent::KycTierChanged { client, from, to } => {
+                write!(f, "{},kyc_tier,{},{},{}", self.seq, client, from, to)
+            }
+            AuditEvent::TierChanged { client, from, to } => write!(
+                f,
+                "{},tier,{},{},{}",
+                self.seq,
+                client,
+                from.as_deref().unwrap_or_default(),
+                to.as_deref().unwrap_or_default()
+            ),
+            AuditEvent::ReserveChanged { client, from, to } => {
+                write!(f, "{},reserve,{},{:.4},{:.4}", self.seq, client, from, to)
+            }
+            AuditEvent::CoolingOffChanged { client, from, to } => {
+                write!(f, "{},cooling_off,{},{},{}", self.seq, client, from, to)
+            }
+            AuditEvent::BlocklistUpdated { client, blocked } => {
+                write!(f, "{},blocklist,{},{}", self.seq, client, blocked)
+            }
+            AuditEvent::ClientFrozen { client, frozen } => {
+                write!(f, "{},freeze,{},{}", self.seq, client, frozen)
+            }
+            AuditEvent::BalancesWrittenOff {
+                below,
+                accounts,
+                amount,
+            } => write!(
+                f,
+                "{},write_off,{:.4},{},{:.4}",
+                self.seq, below, accounts, amount
+            ),
+        }
+    }
+}
+
+/// Append-only log of audited operations, sequenced in the order they were applied. It is kept in checkpoints, so the
+/// sequence carries on in a resumed run
+#[derive(Debug, Default, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct AuditLog {
+    records: Vec<AuditRecord>,
+}
+
+impl AuditLog {
+    pub fn record(&mut self, event: AuditEvent) {
+        self.records.push(AuditRecord {
+            seq: self.records.len() as u64 + 1,
+            event,
+        });
+    }
+
+    /// Append another log's records after this one's, renumbered to follow on
+    pub fn extend(&mut self, other: AuditLog) {
+        for record in other.records {
+            self.record(record.event);
+        }
+    }
+
+    pub fn records(&self) -> &[AuditRecord] {
+        &self.records
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use crate::exchange::transaction::Money;
+
+    #[test]
+    fn it_should_sequence_the_records_and_renumber_the_extending_ones() {
+        let mut log = AuditLog::default();
+        log.record(AuditEvent::ClientUnlocked {
+            client: 1,
+            released: Currency::str("2.5"),
+        });
+        log.record(AuditEvent::BlocklistUpdated {
+            client: 2,
+            blocked: true,
+        });
+        let mut other = AuditLog::default();
+        other.record(AuditEvent::ClientFrozen {
+            client: 3,
+            frozen: true,
+        });
+        log.extend(other);
+
+        assert_eq!(
+            vec![1, 2, 3],
+            log.records()
+                .iter()
+                .map(AuditRecord::seq)
+                .collect::<Vec<u64>>()
+        );
+        assert_eq!(
+            &AuditEvent::ClientFrozen {
+                client: 3,
+                frozen: true
+            },
+            log.records()[2].event()
+        );
+        let json = serde_json::to_string(&log).unwrap();
+        assert_eq!(true, json.starts_with('['));
+        assert_eq!(log, serde_json::from_str::<AuditLog>(&json).unwrap());
+    }
+
+    #[test]
+    fn it_should_write_every_event_as_a_csv_line() {
+        let mut log = AuditLog::default();
+        for event in [
+            AuditEvent::ClientsMerged {
+                from: 4,
+                to: 1,
+                transactions: 3,
+                forced: false,
+            },
+            AuditEvent::ClientAnnotated {
+                client: 1,
+                status: None,
+            },
+            AuditEvent::KycTierChanged {
+                client: 1,
+                from: 0,
+                to: 2,
+            },
+            AuditEvent::TierChanged {
+                client: 1,
+                from: None,
+                to: Some("gold".to_string()),
+            },
+            AuditEvent::ReserveChanged {
+                client: 1,
+                from: Currency::zero(),
+                to: Currency::str("5"),
+            },
+            AuditEvent::CoolingOffChanged {
+                client: 1,
+                from: 3,
+                to: 0,
+            },
+            AuditEvent::BalancesWrittenOff {
+                below: Currency::str("0.01"),
+                accounts: 2,
+                amount: Currency::str("0.015"),
+            },
+        ] {
+            log.record(event);
+        }
+
+        assert_eq!(
+            vec![
+                "1,merge,4,1,3,false",
+                "2,annotate,1,",
+                "3,kyc_tier,1,0,2",
+                "4,tier,1,,gold",
+                "5,reserve,1,0.0000,5.0000",
+                "6,cooling_off,1,3,0",
+                "7,write_off,0.0100,2,0.0150"
+            ],
+            log.records()
+                .iter()
+                .map(|record| record.to_string())
+                .collect::<Vec<String>>()
+        );
+    }
+}
@@ -0,0 +1,638 @@
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::exchange::anomaly::AnomalyDetector;
+use crate::exchange::approval::PendingChargeback;
+use crate::exchange::audit::AuditLog;
+use crate::exchange::client_profile::ClientProfile;
+use crate::exchange::dedup::RecentTransactions;
+use crate::exchange::deferral::DeferredDisputes;
+use crate::exchange::ordering::OrderingTracker;
+use crate::exchange::stats::Stats;
+use crate::exchange::transaction::ClientId;
+use crate::exchange::transaction::Currency;
+use crate::exchange::transaction::IdMap;
+use crate::exchange::Exchange;
+use crate::exchange::IngestOptions;
+use crate::exchange::RecordReader;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct CheckpointOptions {
+    /// Where the checkpoint is written, and read back from on resume
+    pub path: PathBuf,
+    /// A checkpoint is written every this many records, and once the input is exhausted
+    pub every: u64,
+}
+
+/// Where a run stood when its checkpoint was written: the input position right after the last record applied
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub struct InputPosition {
+    pub byte: u64,
+    pub line: u64,
+    /// Records read so far, the header row included
+    pub records: u64,
+}
+
+/// Version of the checkpoint format written by this build. A checkpoint of an older version is upgraded through MIGRATIONS
+/// when it is restored, one of a newer version is refused
+pub const CHECKPOINT_VERSION: u32 = 3;
+
+/// Upgrade of the JSON document of a checkpoint from one version of the format to the next
+type Migration = fn(&mut serde_json::Value) -> Result<(), String>;
+
+/// MIGRATIONS[n] upgrades a checkpoint of version n + 1 to version n + 2. Checkpoints written before the format was versioned
+/// have no version field and are version 1
+const MIGRATIONS: [Migration; 2] = [from_unversioned, from_version_2];
+
+fn fields<'a>(
+    value: &'a mut serde_json::Value,
+    name: &str,
+) -> Result<&'a mut serde_json::Map<String, serde_json::Value>, String> {
+    value
+        .as_object_mut()
+        .ok_or_else(|| format!("{} is not an object", name))
+}
+
+/// Checkpoints written before the format was versioned predate account tiers, freezes, cooling-off periods, dispute scores and
+/// the archive: their clients are in the default tier, neither frozen nor cooling off and have no scored dispute, and no
+/// client was found in memory or read back from an archive
+fn from_unversioned(document: &mut serde_json::Value) -> Result<(), String> {
+    let clients = fields(document, "The checkpoint")?
+        .get_mut("clients")
+        .and_then(|clients| clients.as_array_mut())
+        .ok_or("The checkpoint has no clients")?;
+    for client in clients {
+        let client = fields(client, "A client")?;
+        client.entry("tier").or_insert(serde_json::Value::Null);
+        client.entry("frozen").or_insert(false.into());
+        client.entry("cooling_off").or_insert(0.into());
+        client
+            .entry("dispute_scores")
+            .or_insert_with(|| serde_json::json!({}));
+    }
+    let stats = fields(document, "The checkpoint")?
+        .get_mut("stats")
+        .ok_or("The checkpoint has no stats")?;
+    let stats = fields(stats, "The stats")?;
+    stats.entry("resident").or_insert(0.into());
+    stats.entry("rehydrated").or_insert(0.into());
+    Ok(())
+}
+
+/// Version 3 keeps the audit log, the anomaly detector baselines and the operator overrides of the configuration. A version 2
+/// checkpoint had none of them: the audit log starts empty, the baselines are learnt again and the configured overrides stay
+fn from_version_2(document: &mut serde_json::Value) -> Result<(), String> {
+    let document = fields(document, "The checkpoint")?;
+    document
+        .entry("audit_log")
+        .or_insert_with(|| serde_json::json!([]));
+    document
+        .entry("anomalies")
+        .or_insert_with(|| serde_json::json!({}));
+    document
+        .entry("overrides")
+        .or_insert(serde_json::Value::Null);
+    Ok(())
+}
+
+/// The document of a checkpoint upgraded to CHECKPOINT_VERSION
+fn migrate(mut document: serde_json::Value) -> io::Result<serde_json::Value> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+    let version = match document.get("version") {
+        None => 1,
+        Some(version) => version
+            .as_u64()
+            .and_then(|version| u32::try_from(version).ok())
+            .filter(|version| *version > 0)
+            .ok_or_else(|| invalid(format!("Invalid checkpoint version {}", version)))?,
+    };
+    if version > CHECKPOINT_VERSION {
+        return Err(invalid(format!(
+            "The checkpoint is of version {}, this build reads up to version {}",
+            version, CHECKPOINT_VERSION
+        )));
+    }
+    for (from, migration) in MIGRATIONS.iter().enumerate().skip(version as usize - 1) {
+        migration(&mut document).map_err(|e| {
+            invalid(format!(
+                "Failed to upgrade the checkpoint from version {}: {}",
+                from + 1,
+                e
+            ))
+        })?;
+    }
+    if let Some(fields) = document.as_object_mut() {
+        fields.insert("version".to_string(), CHECKPOINT_VERSION.into());
+    }
+    Ok(document)
+}
+
+#[derive(Serialize)]
+struct CheckpointRef<'a> {
+    version: u32,
+    position: InputPosition,
+    clients: Vec<&'a ClientProfile>,
+    stats: &'a Stats,
+    pending_chargebacks: &'a [PendingChargeback],
+    retained_transactions: usize,
+    submitted: u64,
+    last_active: &'a IdMap<ClientId, u64>,
+    audit_log: &'a AuditLog,
+    anomalies: &'a AnomalyDetector,
+    overrides: OverridesRef<'a>,
+}
+
+/// The parts of the configuration operators change at runtime, see Exchange::set_blocked, set_client_reserve and
+/// set_client_cooling_off
+#[derive(Serialize)]
+struct OverridesRef<'a> {
+    blocklist: &'a HashSet<ClientId>,
+    client_reserves: &'a HashMap<ClientId, Currency>,
+    client_cooling_off: &'a HashMap<ClientId, u64>,
+}
+
+#[derive(Deserialize)]
+struct Overrides {
+    blocklist: HashSet<ClientId>,
+    client_reserves: HashMap<ClientId, Currency>,
+    client_cooling_off: HashMap<ClientId, u64>,
+}
+
+#[derive(Deserialize)]
+struct Checkpoint {
+    position: InputPosition,
+    clients: Vec<ClientProfile>,
+    stats: Stats,
+    pending_chargebacks: Vec<PendingChargeback>,
+    retained_transactions: usize,
+    submitted: u64,
+    last_active: IdMap<ClientId, u64>,
+    audit_log: AuditLog,
+    anomalies: AnomalyDetector,
+    /// None in checkpoints upgraded from version 2, which leave the configured ones in place
+    overrides: Option<Overrides>,
+}
+
+fn write_json<W: Write>(writer: W, value: &impl Serialize) -> io::Result<()> {
+    let mut writer = io::BufWriter::new(writer);
+    serde_json::to_writer(&mut writer, value)?;
+    writer.flush()
+}
+
+impl Exchange {
+    /// Write the state of the exchange and the input position to the checkpoint file as JSON. The file is written next to
+    /// its destination and renamed over it, so a crash mid-write leaves the previous checkpoint in place
+    pub fn write_checkpoint(&self, path: &Path, position: InputPosition) -> io::Result<()> {
+        let checkpoint = CheckpointRef {
+            version: CHECKPOINT_VERSION,
+            position,
+            clients: self.clients.values().collect(),
+            stats: &self.stats,
+            pending_chargebacks: &self.pending_chargebacks,
+            retained_transactions: self.retained_transactions,
+            submitted: self.submitted,
+            last_active: &self.last_active,
+            audit_log: &self.audit_log,
+            anomalies: &self.anomalies,
+            overrides: OverridesRef {
+                blocklist: &self.config.blocklist,
+                client_reserves: &self.config.client_reserves,
+                client_cooling_off: &self.config.client_cooling_off,
+            },
+        };
+        let partial = path.with_extension("partial");
+        let file = File::create(&partial)?;
+        #[cfg(any(test, feature = "fault-injection"))]
+        let output: Box<dyn Write> = match &self.faults {
+            Some(faults) => Box::new(crate::exchange::fault::FaultyWriter::new(
+                &file,
+                faults.clone(),
+            )),
+            None => Box::new(&file),
+        };
+        #[cfg(not(any(test, feature = "fault-injection")))]
+        let output = &file;
+        #[cfg(feature = "encryption")]
+        match &self.encryption {
+            Some(encryption) => {
+                let mut sealed = encryption.writer(output)?;
+                write_json(&mut sealed, &checkpoint)?;
+                sealed.finish()?;
+            }
+            None => write_json(output, &checkpoint)?,
+        }
+        #[cfg(not(feature = "encryption"))]
+        write_json(output, &checkpoint)?;
+        file.sync_all()?;
+        fs::rename(&partial, path)
+    }
+
+    /// Replace the state of the exchange (configuration, handlers and ledger setting are kept) with the checkpointed one,
+    /// returning the input position to resume from. The blocklist and the per client reserves and cooling-off periods are
+    /// changed by operators at runtime, so the checkpointed ones replace the configured ones. Checkpoints of an older format
+    /// are upgraded, see CHECKPOINT_VERSION. With encryption, checkpoints not sealed with its key are refused
+    pub fn restore_checkpoint(&mut self, path: &Path) -> io::Result<InputPosition> {
+        let mut input = io::BufReader::new(File::open(path)?);
+        #[cfg(feature = "encryption")]
+        let document: serde_json::Value = match &self.encryption {
+            Some(encryption) => serde_json::from_reader(encryption.reader(input)?)?,
+            None if io::BufRead::fill_buf(&mut input)?
+                .starts_with(crate::exchange::encryption::MAGIC) =>
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "The checkpoint is encrypted, restoring it needs its key",
+                ));
+            }
+            None => serde_json::from_reader(input)?,
+        };
+        #[cfg(not(feature = "encryption"))]
+        let document: serde_json::Value = serde_json::from_reader(&mut input)?;
+        let checkpoint: Checkpoint = serde_json::from_value(migrate(document)?)?;
+        self.clients = checkpoint
+            .clients
+            .into_iter()
+            .map(|client| (client.id(), client))
+            .collect();
+        self.index_disputes();
+        self.stats = checkpoint.stats;
+        self.pending_chargebacks = checkpoint.pending_chargebacks;
+        self.retained_transactions = checkpoint.retained_transactions;
+        self.submitted = checkpoint.submitted;
+        if let Some(snapshots) = &mut self.snapshots {
+            snapshots.start(self.submitted);
+        }
+        self.last_active = checkpoint.last_active;
+        if let Some(overrides) = checkpoint.overrides {
+            self.config.blocklist = overrides.blocklist;
+            self.config.client_reserves = overrides.client_reserves;
+            self.config.client_cooling_off = overrides.client_cooling_off;
+        }
+        self.audit_log = checkpoint.audit_log;
+        self.anomalies = checkpoint.anomalies;
+        self.recent = RecentTransactions::new();
+        self.deferred = DeferredDisputes::new();
+        self.ordering = OrderingTracker::resumed(self.clients.keys().copied());
+        Ok(checkpoint.position)
+    }
+}
+
+/// process_transactions_from_csv_with() for multi-hour runs: a checkpoint of the exchange state and of the input position
+/// is written every checkpoint.every records. With resume, and a checkpoint to resume from, the state is restored and the input
+/// is read from the checkpointed position on, so every record is applied exactly once even when the previous run died mid-file.
+/// The checkpoint does not hold the de-duplication window, which starts empty on resume. It can not be combined with a
+/// quarantine or the recovery, nor with an exchange deferring disputes or having an archive or a ledger, whose state it does
+/// not hold either
+pub fn process_transactions_from_csv_with_checkpoints(
+    path: &str,
+    bank: &mut Exchange,
+    options: &IngestOptions,
+    checkpoint: &CheckpointOptions,
+    resume: bool,
+) -> Result<(), Box<dyn Error>> {
+    if options.quarantine.is_some() || options.recover {
+        return Err("Checkpoints can not be combined with a quarantine or the recovery".into());
+    }
+    //none of them is part of the checkpoint, a resumed run would lose the deferred rows, the archived clients or the entries
+    //of the ledger written before the checkpoint
+    if bank.config.defer_disputes.is_some() || bank.archive.is_some() || bank.ledger.is_some() {
+        return Err(
+            "Checkpoints can not be combined with deferred disputes, an archive or a ledger".into(),
+        );
+    }
+
+    let mut reader = csv::Reader::from_path(path)?;
+    let headers = reader.headers()?.clone();
+    let ingest = RecordReader::new(&headers, options);
+
+    if resume && checkpoint.path.exists() {
+        let position = bank.restore_checkpoint(&checkpoint.path)?;
+        let mut resume_at = csv::Position::new();
+        resume_at
+            .set_byte(position.byte)
+            .set_line(position.line)
+            .set_record(position.records);
+        reader.seek(resume_at)?;
+        if !options.quiet {
+            eprintln!(
+                "Resuming after {} records, at byte {}",
+                position.records, position.byte
+            );
+        }
+    }
+
+    let position = |reader: &csv::Reader<File>| InputPosition {
+        byte: reader.position().byte(),
+        line: reader.position().line(),
+        records: reader.position().record(),
+    };
+    let every = checkpoint.every.max(1);
+    let mut raw_record = csv::StringRecord::new();
+    while reader.read_record(&mut raw_record)? {
+        ingest.ingest(bank, &raw_record)?;
+        if reader.position().record().is_multiple_of(every) {
+            bank.write_checkpoint(&checkpoint.path, position(&reader))?;
+        }
+    }
+    bank.write_checkpoint(&checkpoint.path, position(&reader))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use crate::exchange::anomaly::AnomalyDetection;
+    use crate::exchange::archive::Archive;
+    use crate::exchange::config::EngineConfig;
+    use crate::exchange::deferral::DeferralWindow;
+    use crate::exchange::transaction::Money;
+    use crate::exchange::transaction::Transaction;
+    use crate::exchange::transaction::Type;
+
+    #[test]
+    fn it_should_resume_from_the_checkpoint_without_applying_records_twice() {
+        let input = std::env::temp_dir().join("payment_engine_checkpoint_input.csv");
+        let checkpoint_path = std::env::temp_dir().join("payment_engine_checkpoint.json");
+        let _ = fs::remove_file(&checkpoint_path);
+        let rows = "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,2,2,5.0\ndispute,1,1,\ndeposit,1,3,1.0\nwithdrawal,2,4,2.0\n";
+        fs::write(&input, rows).unwrap();
+        let checkpoint = CheckpointOptions {
+            path: checkpoint_path.clone(),
+            every: 2,
+        };
+
+        let mut uninterrupted = Exchange::new();
+        process_transactions_from_csv_with_checkpoints(
+            input.to_str().unwrap(),
+            &mut uninterrupted,
+            &IngestOptions::default(),
+            &checkpoint,
+            false,
+        )
+        .unwrap();
+
+        //a run that died after checkpointing its first two records: whatever it applied after that is lost with the process
+        let mut crashed = Exchange::new();
+        for (client, tx, amount) in [(1, 1, "10.0"), (2, 2, "5.0")] {
+            crashed
+                .process_new_transaction(Transaction {
+                    tx_type: Type::Deposit,
+                    client,
+                    tx,
+                    amount: Some(Currency::str(amount)),
+                    metadata: HashMap::new(),
+                    under_dispute: false,
+                    dispute_metadata: None,
+                })
+                .unwrap();
+        }
+        let after_two_records = rows.match_indices('\n').nth(2).unwrap().0 as u64 + 1;
+        crashed
+            .write_checkpoint(
+                &checkpoint_path,
+                InputPosition {
+                    byte: after_two_records,
+                    line: 4,
+                    records: 3,
+                },
+            )
+            .unwrap();
+
+        let mut resumed = Exchange::new();
+        process_transactions_from_csv_with_checkpoints(
+            input.to_str().unwrap(),
+            &mut resumed,
+            &IngestOptions {
+                quiet: true,
+                ..IngestOptions::default()
+            },
+            &checkpoint,
+            true,
+        )
+        .unwrap();
+
+        for client in [1, 2] {
+            assert_eq!(uninterrupted.client(client), resumed.client(client));
+        }
+        assert_eq!(Currency::str("10.0"), resumed.client(1).unwrap().held());
+        assert_eq!(Currency::str("3.0"), resumed.client(2).unwrap().total());
+        assert_eq!(
+            uninterrupted.stats().processed(),
+            resumed.stats().processed()
+        );
+        assert_eq!(uninterrupted.stats().rejected(), resumed.stats().rejected());
+        assert_eq!(uninterrupted.submitted(), resumed.submitted());
+
+        //resuming a finished run applies nothing
+        let mut finished = Exchange::new();
+        process_transactions_from_csv_with_checkpoints(
+            input.to_str().unwrap(),
+            &mut finished,
+            &IngestOptions::default(),
+            &checkpoint,
+            true,
+        )
+        .unwrap();
+        assert_eq!(resumed.client(1), finished.client(1));
+        assert_eq!(5, finished.submitted());
+
+        fs::remove_file(&input).unwrap();
+        fs::remove_file(&checkpoint_path).unwrap();
+    }
+
+    #[test]
+    fn it_should_refuse_exchanges_whose_state_the_checkpoint_does_not_hold() {
+        let input = std::env::temp_dir().join("payment_engine_checkpoint_refused.csv");
+        let archive = std::env::temp_dir().join("payment_engine_checkpoint_refused_archive.csv");
+        let checkpoint_path = std::env::temp_dir().join("payment_engine_checkpoint_refused.json");
+        fs::write(&input, "type,client,tx,amount\ndeposit,1,1,10.0\n").unwrap();
+        let checkpoint = CheckpointOptions {
+            path: checkpoint_path.clone(),
+            every: 1,
+        };
+        let deferring = EngineConfig {
+            defer_disputes: Some(DeferralWindow::parse("10").unwrap()),
+            ..EngineConfig::default()
+        };
+
+        for mut bank in [
+            Exchange::new().with_config(deferring),
+            Exchange::new().with_archive(Archive::create(&archive).unwrap()),
+            Exchange::new().with_ledger(),
+        ] {
+            assert_eq!(
+                "Checkpoints can not be combined with deferred disputes, an archive or a ledger",
+                process_transactions_from_csv_with_checkpoints(
+                    input.to_str().unwrap(),
+                    &mut bank,
+                    &IngestOptions::default(),
+                    &checkpoint,
+                    false,
+                )
+                .unwrap_err()
+                .to_string()
+            );
+            assert_eq!(0, bank.submitted());
+        }
+        assert_eq!(false, checkpoint_path.exists());
+
+        fs::remove_file(&input).unwrap();
+        fs::remove_file(&archive).unwrap();
+        fs::remove_file(crate::exchange::archive::index_path(&archive)).unwrap();
+    }
+
+    #[test]
+    fn it_should_upgrade_older_checkpoints_and_refuse_newer_ones() {
+        let path = std::env::temp_dir().join("payment_engine_checkpoint_versions.json");
+        let mut exchange = Exchange::new();
+        exchange
+            .process_new_transaction(Transaction {
+                tx_type: Type::Deposit,
+                client: 1,
+                tx: 1,
+                amount: Some(Currency::str("10.0")),
+                metadata: HashMap::new(),
+                under_dispute: false,
+                dispute_metadata: None,
+            })
+            .unwrap();
+        let position = InputPosition {
+            byte: 40,
+            line: 2,
+            records: 2,
+        };
+        exchange.write_checkpoint(&path, position).unwrap();
+        let mut document: serde_json::Value =
+            serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        assert_eq!(CHECKPOINT_VERSION, document["version"]);
+
+        //written before the format was versioned
+        document.as_object_mut().unwrap().remove("version");
+        fs::write(&path, document.to_string()).unwrap();
+        let mut restored = Exchange::new();
+        assert_eq!(position, restored.restore_checkpoint(&path).unwrap());
+        assert_eq!(exchange.client(1), restored.client(1));
+
+        document["version"] = (CHECKPOINT_VERSION + 1).into();
+        fs::write(&path, document.to_string()).unwrap();
+        let error = Exchange::new().restore_checkpoint(&path).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn it_should_keep_the_audit_log_and_the_anomaly_baselines() {
+        let path = std::env::temp_dir().join("payment_engine_checkpoint_audit.json");
+        let configured = || EngineConfig {
+            anomaly_detection: AnomalyDetection::parse("3:20"),
+            ..EngineConfig::default()
+        };
+        let mut exchange = Exchange::new().with_config(configured());
+        for (tx, amount) in [(1, "10.0"), (2, "12.5"), (3, "9.75"), (4, "11.1")] {
+            exchange
+                .process_new_transaction(Transaction {
+                    tx_type: Type::Deposit,
+                    client: 1,
+                    tx,
+                    amount: Some(Currency::str(amount)),
+                    metadata: HashMap::new(),
+                    under_dispute: false,
+                    dispute_metadata: None,
+                })
+                .unwrap();
+        }
+        exchange.set_blocked(2, true).unwrap();
+        assert_eq!(1, exchange.audit_log().len());
+        let position = InputPosition {
+            byte: 0,
+            line: 1,
+            records: 1,
+        };
+        exchange.write_checkpoint(&path, position).unwrap();
+
+        let mut restored = Exchange::new().with_config(configured());
+        restored.restore_checkpoint(&path).unwrap();
+        assert_eq!(exchange.audit_log(), restored.audit_log());
+        assert_eq!(exchange.anomalies, restored.anomalies);
+        assert_ne!(AnomalyDetector::new(), restored.anomalies);
+
+        //written by version 2, which kept neither
+        let mut document: serde_json::Value =
+            serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        for field in ["audit_log", "anomalies", "overrides"] {
+            document.as_object_mut().unwrap().remove(field);
+        }
+        document["version"] = 2.into();
+        fs::write(&path, document.to_string()).unwrap();
+        let mut older = Exchange::new().with_config(configured());
+        older.restore_checkpoint(&path).unwrap();
+        assert_eq!(exchange.client(1), older.client(1));
+        assert_eq!(true, older.audit_log().is_empty());
+        assert_eq!(AnomalyDetector::new(), older.anomalies);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn it_should_keep_the_blocklist_and_client_overrides_changed_at_runtime() {
+        let path = std::env::temp_dir().join("payment_engine_checkpoint_overrides.json");
+        let configured = || EngineConfig {
+            client_reserves: HashMap::from([(2, Currency::str("1.0"))]),
+            ..EngineConfig::default()
+        };
+        let mut exchange = Exchange::new().with_config(configured());
+        exchange.set_blocked(3, true).unwrap();
+        exchange.set_client_reserve(1, Some(Currency::str("5.0")));
+        exchange.set_client_reserve(2, None);
+        exchange.set_client_cooling_off(1, Some(2));
+        let position = InputPosition {
+            byte: 0,
+            line: 1,
+            records: 1,
+        };
+        exchange.write_checkpoint(&path, position).unwrap();
+
+        let mut restored = Exchange::new().with_config(configured());
+        restored.restore_checkpoint(&path).unwrap();
+        assert_eq!(HashSet::from([3]), restored.config.blocklist);
+        assert_eq!(
+            HashMap::from([(1, Currency::str("5.0"))]),
+            restored.config.client_reserves
+        );
+        assert_eq!(HashMap::from([(1, 2)]), restored.config.client_cooling_off);
+        let blocked = restored.process_new_transaction(Transaction {
+            tx_type: Type::Deposit,
+            client: 3,
+            tx: 1,
+            amount: Some(Currency::str("1.0")),
+            metadata: HashMap::new(),
+            under_dispute: false,
+            dispute_metadata: None,
+        });
+        assert_eq!(true, blocked.is_err());
+
+        //written before the overrides were kept: the configured ones stay
+        let mut document: serde_json::Value =
+            serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        document.as_object_mut().unwrap().remove("overrides");
+        fs::write(&path, document.to_string()).unwrap();
+        let mut older = Exchange::new().with_config(configured());
+        older.restore_checkpoint(&path).unwrap();
+        assert_eq!(configured().client_reserves, older.config.client_reserves);
+        assert_eq!(true, older.config.blocklist.is_empty());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
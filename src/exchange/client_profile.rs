@@ -1,25 +1,146 @@
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeSet;
 use std::collections::HashMap;
+use std::error::Error;
 use std::fmt;
 
+use crate::exchange::config::CapPolicy;
+use crate::exchange::config::EngineConfig;
+use crate::exchange::extension::CustomTransactionHandler;
+use crate::exchange::pseudonym::Mention;
+use crate::exchange::pseudonym::Reason;
+use crate::exchange::transaction::ChargebackReason;
 use crate::exchange::transaction::ClientId;
 use crate::exchange::transaction::Currency;
+use crate::exchange::transaction::IdMap;
 use crate::exchange::transaction::Money;
 use crate::exchange::transaction::Transaction;
 use crate::exchange::transaction::TransactionId;
 use crate::exchange::transaction::Type;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct ClientProfile {
     id: ClientId,
     available: Currency,
     held: Currency,
     total: Currency,
     locked: bool,
-    transactions: HashMap<TransactionId, Transaction>,
+    transactions: IdMap<TransactionId, Transaction>,
+    chargebacks: HashMap<ChargebackReason, u64>,
+    /// Outstanding deficit owed by the client: funds that were already withdrawn when a dispute put them on hold
+    receivable: Currency,
+    /// Lifetime amount of deposits that went towards paying a receivable down
+    recovered: Currency,
+    /// Transaction whose chargeback locked the account, see EngineConfig::unlock_on_resolve
+    locked_by: Option<TransactionId>,
+    /// Type of the row (resolve or chargeback) that closed the last dispute of each transaction, to tell repeated rows apart
+    closed_disputes: IdMap<TransactionId, Type>,
+    /// Deposits received while the account was locked, held pending review (see EngineConfig::hold_deposits_when_locked)
+    review_holds: Vec<(TransactionId, Currency)>,
+    /// Lifetime number and sum of accepted deposits, and sum of the chargebacks (counted per reason in chargebacks). A profile
+    /// created with a history counts the deposits (and withdrawals) in it
+    deposits: u64,
+    deposited: Currency,
+    charged_back: Currency,
+    /// Operational status and note attached by operators, see Exchange::annotate
+    #[serde(default)]
+    annotation: Option<Annotation>,
+    /// Number of transactions the exchange had been submitted when each open dispute was opened, see Exchange::open_disputes
+    #[serde(default)]
+    disputes_opened_at: IdMap<TransactionId, u64>,
+    /// Scores the DisputeScorer gave the open disputes, see Exchange::with_dispute_scorer
+    #[serde(default)]
+    dispute_scores: IdMap<TransactionId, f64>,
+    /// Index of the transactions under dispute, kept with their flag so the open disputes are found without going through the
+    /// whole history. Rebuilt from the flags when the profile is restored, see index_disputes()
+    #[serde(skip)]
+    open_disputes: BTreeSet<TransactionId>,
+    /// Transactions of the client the exchange rejected, and the last one it accepted, see Exchange::process_new_transaction
+    #[serde(default)]
+    rejected: u64,
+    #[serde(default)]
+    last_tx: Option<TransactionId>,
+    /// Lifetime number of accepted withdrawals, counted with the deposits against the caps of the KYC tier
+    #[serde(default)]
+    withdrawals: u64,
+    /// KYC tier the client was verified to, see EngineConfig::client_caps
+    #[serde(default)]
+    kyc_tier: u8,
+    /// Set when a transaction over the caps of the tier was applied under CapPolicy::Flag, until the tier changes
+    #[serde(default)]
+    kyc_review: bool,
+    /// Parts of deposits held back by the rolling reserve, see EngineConfig::rolling_reserve
+    #[serde(default)]
+    reserve_holds: Vec<ReserveHold>,
+    /// Withdrawals are rejected while set, unlike a lock deposits and the dispute flow still go through. Set by operators
+    /// (Exchange::set_frozen) or by the risk rules (EngineConfig::freeze_on_anomaly)
+    #[serde(default)]
+    frozen: bool,
+    /// Accepted deposits and withdrawals left before withdrawals are permitted again, see EngineConfig::cooling_off
+    #[serde(default)]
+    cooling_off: u64,
+    /// Account tier the client was moved to, None for the default tier, see EngineConfig::account_tiers
+    #[serde(default)]
+    tier: Option<String>,
 }
 
-#[derive(Debug)]
-pub struct ProcessingError(pub String);
+/// Part of a deposit held back by the rolling reserve, released to available once the client has had remaining more
+/// deposits or withdrawals accepted
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub struct ReserveHold {
+    pub tx: TransactionId,
+    pub amount: Currency,
+    pub remaining: u64,
+}
+
+/// Operational status of an account ("under fraud review", "VIP", ..) with an optional free form note. It does not change how
+/// transactions are processed, custom handlers can read it through BalanceMutator::annotation
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Annotation {
+    pub status: String,
+    pub note: Option<String>,
+}
+
+/// Why a transaction or an operation was refused, naming the client through Mention so the outputs can label it
+#[derive(Debug, PartialEq, Clone)]
+pub struct ProcessingError(pub Reason);
+
+impl ProcessingError {
+    pub fn new(reason: impl Into<Reason>) -> ProcessingError {
+        ProcessingError(reason.into())
+    }
+}
+
+impl fmt::Display for ProcessingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for ProcessingError {}
+
+/// Balances of an account at some point in time, in the layout of the account summaries (client,available,held,total,locked)
+#[derive(Debug, Deserialize, PartialEq, Clone)]
+pub struct AccountBalance {
+    pub client: ClientId,
+    pub available: Currency,
+    pub held: Currency,
+    pub total: Currency,
+    pub locked: bool,
+}
+
+impl From<&ClientProfile> for AccountBalance {
+    fn from(client: &ClientProfile) -> Self {
+        AccountBalance {
+            client: client.id,
+            available: client.available,
+            held: client.held,
+            total: client.total,
+            locked: client.locked,
+        }
+    }
+}
 
 impl ClientProfile {
     pub fn new_with_defaults(id: ClientId) -> ClientProfile {
@@ -29,7 +150,7 @@ impl ClientProfile {
             Currency::zero(),
             Currency::zero(),
             false,
-            HashMap::new(),
+            std::iter::empty(),
         )
     }
 
@@ -39,8 +160,23 @@ impl ClientProfile {
         held: Currency,
         total: Currency,
         locked: bool,
-        transactions: HashMap<TransactionId, Transaction>,
+        transactions: impl IntoIterator<Item = (TransactionId, Transaction)>,
     ) -> ClientProfile {
+        let transactions: IdMap<TransactionId, Transaction> = transactions.into_iter().collect();
+        let deposits: Vec<Currency> = transactions
+            .values()
+            .filter(|t| t.tx_type == Type::Deposit)
+            .filter_map(|t| t.amount)
+            .collect();
+        let withdrawals = transactions
+            .values()
+            .filter(|t| t.tx_type == Type::Withdrawal)
+            .count();
+        let open_disputes = transactions
+            .values()
+            .filter(|t| t.under_dispute)
+            .map(|t| t.tx)
+            .collect();
         ClientProfile {
             id,
             available,
@@ -48,6 +184,326 @@ impl ClientProfile {
             total,
             locked,
             transactions,
+            chargebacks: HashMap::new(),
+            receivable: deficit(available),
+            recovered: Currency::zero(),
+            locked_by: None,
+            closed_disputes: IdMap::default(),
+            review_holds: Vec::new(),
+            deposits: deposits.len() as u64,
+            deposited: deposits.into_iter().sum(),
+            charged_back: Currency::zero(),
+            annotation: None,
+            disputes_opened_at: IdMap::default(),
+            dispute_scores: IdMap::default(),
+            open_disputes,
+            rejected: 0,
+            last_tx: None,
+            withdrawals: withdrawals as u64,
+            kyc_tier: 0,
+            kyc_review: false,
+            reserve_holds: Vec::new(),
+            frozen: false,
+            cooling_off: 0,
+            tier: None,
+        }
+    }
+
+    pub fn id(&self) -> ClientId {
+        self.id
+    }
+
+    pub fn available(&self) -> Currency {
+        self.available
+    }
+
+    pub fn held(&self) -> Currency {
+        self.held
+    }
+
+    pub fn total(&self) -> Currency {
+        self.total
+    }
+
+    pub fn locked(&self) -> bool {
+        self.locked
+    }
+
+    pub fn frozen(&self) -> bool {
+        self.frozen
+    }
+
+    /// Accepted transactions left in the cooling-off period of the account, during which its withdrawals are rejected
+    pub fn cooling_off(&self) -> u64 {
+        self.cooling_off
+    }
+
+    pub fn receivable(&self) -> Currency {
+        self.receivable
+    }
+
+    pub fn recovered(&self) -> Currency {
+        self.recovered
+    }
+
+    pub fn locked_by(&self) -> Option<TransactionId> {
+        self.locked_by
+    }
+
+    pub fn chargebacks(&self, reason: ChargebackReason) -> u64 {
+        self.chargebacks.get(&reason).copied().unwrap_or(0)
+    }
+
+    pub fn deposits(&self) -> u64 {
+        self.deposits
+    }
+
+    pub fn deposited(&self) -> Currency {
+        self.deposited
+    }
+
+    pub fn chargeback_count(&self) -> u64 {
+        self.chargebacks.values().sum()
+    }
+
+    pub fn charged_back(&self) -> Currency {
+        self.charged_back
+    }
+
+    /// Lifetime chargebacks per deposit, to 4 decimals. Zero until the first deposit
+    pub fn chargeback_ratio(&self) -> Currency {
+        if self.deposits == 0 {
+            return Currency::zero();
+        }
+        (Currency::from(self.chargeback_count()) / Currency::from(self.deposits)).round_dp(4)
+    }
+
+    pub fn annotation(&self) -> Option<&Annotation> {
+        self.annotation.as_ref()
+    }
+
+    pub fn rejected(&self) -> u64 {
+        self.rejected
+    }
+
+    /// Id of the last transaction accepted for the client
+    pub fn last_tx(&self) -> Option<TransactionId> {
+        self.last_tx
+    }
+
+    pub fn withdrawals(&self) -> u64 {
+        self.withdrawals
+    }
+
+    pub fn kyc_tier(&self) -> u8 {
+        self.kyc_tier
+    }
+
+    /// The account tier of the client, None for the default tier
+    pub fn tier(&self) -> Option<&str> {
+        self.tier.as_deref()
+    }
+
+    /// The client went over the caps of its tier and awaits a manual tier change
+    pub fn kyc_review(&self) -> bool {
+        self.kyc_review
+    }
+
+    /// Rolling reserve holds not released yet, oldest first
+    pub fn reserve_holds(&self) -> &[ReserveHold] {
+        &self.reserve_holds
+    }
+
+    pub(crate) fn set_frozen(&mut self, frozen: bool) {
+        self.frozen = frozen;
+    }
+
+    /// Reject the withdrawals of the next transactions accepted, unless a longer cooling-off period is already running
+    pub(crate) fn start_cooling_off(&mut self, transactions: u64) {
+        self.cooling_off = self.cooling_off.max(transactions);
+    }
+
+    /// Move the client to another KYC tier, which also clears the review flag
+    pub(crate) fn set_kyc_tier(&mut self, tier: u8) {
+        self.kyc_tier = tier;
+        self.kyc_review = false;
+    }
+
+    pub(crate) fn set_tier(&mut self, tier: Option<String>) {
+        self.tier = tier;
+    }
+
+    pub(crate) fn record_outcome(&mut self, tx: TransactionId, accepted: bool) {
+        if accepted {
+            self.last_tx = Some(tx);
+        } else {
+            self.rejected += 1;
+        }
+    }
+
+    pub(crate) fn annotate(&mut self, annotation: Option<Annotation>) {
+        self.annotation = annotation;
+    }
+
+    /// When the open dispute of the transaction was opened, None when the dispute is not open or predates the tracking
+    pub fn dispute_opened_at(&self, tx: TransactionId) -> Option<u64> {
+        self.disputes_opened_at.get(&tx).copied()
+    }
+
+    /// Keep track of the dispute of the transaction after a dispute, resolve or chargeback row for it went through: an open
+    /// dispute keeps the time it was first seen open, a closed one is forgotten
+    pub(crate) fn track_dispute(&mut self, tx: TransactionId, submitted: u64) {
+        if self.transaction(tx).is_some_and(|t| t.under_dispute) {
+            self.disputes_opened_at.entry(tx).or_insert(submitted);
+        } else {
+            self.disputes_opened_at.remove(&tx);
+            self.dispute_scores.remove(&tx);
+        }
+    }
+
+    /// Score the DisputeScorer gave the open dispute of the transaction, None when it is not open or was not scored
+    pub fn dispute_score(&self, tx: TransactionId) -> Option<f64> {
+        self.dispute_scores.get(&tx).copied()
+    }
+
+    pub(crate) fn score_dispute(&mut self, tx: TransactionId, score: f64) {
+        self.dispute_scores.insert(tx, score);
+    }
+
+    /// Rebuild the index of the open disputes from the flags of the transactions, for a profile that was deserialized
+    pub(crate) fn index_disputes(&mut self) {
+        self.open_disputes = self
+            .transactions
+            .values()
+            .filter(|t| t.under_dispute)
+            .map(|t| t.tx)
+            .collect();
+    }
+
+    /// Shift the dispute opening times by the number of transactions submitted to the exchange this profile is merged into
+    pub(crate) fn offset_disputes(&mut self, offset: u64) {
+        for opened_at in self.disputes_opened_at.values_mut() {
+            *opened_at += offset;
+        }
+    }
+
+    pub fn transaction_ids(&self) -> impl Iterator<Item = &TransactionId> {
+        self.transactions.keys()
+    }
+
+    /// Fold another profile of the same person into this one: balances and chargeback counts are summed, the history (including
+    /// open disputes and their held funds) moves over re-assigned to this client, and the account is locked (or frozen) if either
+    /// one was.
+    /// When both were locked no single resolve can unlock the merged account.
+    /// Callers are expected to have checked that the two histories have no transaction id in common
+    pub fn absorb(&mut self, other: ClientProfile) {
+        self.locked_by = match (self.locked, other.locked) {
+            (true, true) => None,
+            (true, false) => self.locked_by,
+            (false, _) => other.locked_by,
+        };
+        self.available += other.available;
+        self.held += other.held;
+        self.total += other.total;
+        self.locked |= other.locked;
+        self.frozen |= other.frozen;
+        self.cooling_off = self.cooling_off.max(other.cooling_off);
+        self.recovered += other.recovered;
+        self.receivable = deficit(self.available);
+        self.deposits += other.deposits;
+        self.deposited += other.deposited;
+        self.charged_back += other.charged_back;
+        self.withdrawals += other.withdrawals;
+        self.kyc_tier = self.kyc_tier.max(other.kyc_tier);
+        self.kyc_review |= other.kyc_review;
+        if self.annotation.is_none() {
+            self.annotation = other.annotation;
+        }
+        if self.tier.is_none() {
+            self.tier = other.tier;
+        }
+
+        for (tx, mut transaction) in other.transactions {
+            transaction.client = self.id;
+            self.transactions.insert(tx, transaction);
+        }
+        self.closed_disputes.extend(other.closed_disputes);
+        self.review_holds.extend(other.review_holds);
+        self.reserve_holds.extend(other.reserve_holds);
+        self.disputes_opened_at.extend(other.disputes_opened_at);
+        self.dispute_scores.extend(other.dispute_scores);
+        self.open_disputes.extend(other.open_disputes);
+        self.rejected += other.rejected;
+        self.last_tx = other.last_tx.or(self.last_tx);
+        for (reason, count) in other.chargebacks {
+            *self.chargebacks.entry(reason).or_insert(0) += count;
+        }
+    }
+
+    /// The transactions with an open dispute, ordered by transaction id
+    pub fn disputed_transactions(&self) -> impl Iterator<Item = &Transaction> {
+        self.open_disputes
+            .iter()
+            .filter_map(|tx| self.transactions.get(tx))
+    }
+
+    pub fn has_open_disputes(&self) -> bool {
+        !self.open_disputes.is_empty()
+    }
+
+    /// The disputed transactions, the deposits held pending review and the rolling reserve holds that make up the held funds,
+    /// with their amounts. Funds held through a custom transaction handler (BalanceMutator::hold) are not tied to a transaction
+    /// and are not listed
+    pub fn holds(&self) -> impl Iterator<Item = (TransactionId, Currency)> + '_ {
+        self.disputed_transactions()
+            .filter_map(|t| t.amount.map(|amount| (t.tx, amount)))
+            .chain(self.review_holds.iter().copied())
+            .chain(self.reserve_holds.iter().map(|hold| (hold.tx, hold.amount)))
+    }
+
+    /// Take the available funds out of the account, see Exchange::write_off_small_balances. Returns the amount written off
+    pub(crate) fn write_off(&mut self) -> Currency {
+        let amount = self.available;
+        self.available -= amount;
+        self.total -= amount;
+        amount
+    }
+
+    /// Lift the lock, releasing the deposits held pending review to available as if they had just been deposited.
+    /// Returns the released amount
+    pub(crate) fn lift_lock(&mut self) -> Currency {
+        self.locked = false;
+        self.locked_by = None;
+        let mut released = Currency::zero();
+        for (_, amount) in std::mem::take(&mut self.review_holds) {
+            let recovery = amount.min(self.receivable);
+            self.receivable -= recovery;
+            self.recovered += recovery;
+            self.held -= amount;
+            self.available += amount;
+            released += amount;
+        }
+        released
+    }
+
+    pub fn transaction(&self, tx: TransactionId) -> Option<&Transaction> {
+        self.transactions.get(&tx)
+    }
+
+    pub fn transaction_count(&self) -> usize {
+        self.transactions.len()
+    }
+
+    /// Dispute rows carry no id of their own, so a replayed row is recognised by the state it finds: a dispute of a transaction
+    /// already under dispute, or a resolve or chargeback of a transaction whose last dispute was closed by a row of the same type
+    pub fn is_duplicate(&self, transaction: &Transaction) -> bool {
+        match (&transaction.tx_type, self.transactions.get(&transaction.tx)) {
+            (Type::Dispute, Some(disputed)) => disputed.under_dispute,
+            (Type::Resolve | Type::Chargeback, Some(disputed)) => {
+                !disputed.under_dispute
+                    && self.closed_disputes.get(&transaction.tx) == Some(&transaction.tx_type)
+            }
+            _ => false,
         }
     }
 
@@ -55,63 +511,307 @@ impl ClientProfile {
         &mut self,
         transaction: Transaction,
     ) -> Result<(), ProcessingError> {
-        if self.locked {
-            return Err(ProcessingError(format!(
-                "Client's account {} is locked. {:?} not permitted.. Rejecting transaction {}",
-                self.id, transaction.tx_type, transaction
+        self.process_with_config(transaction, &EngineConfig::default())
+    }
+
+    pub fn process_with_config(
+        &mut self,
+        transaction: Transaction,
+        config: &EngineConfig,
+    ) -> Result<(), ProcessingError> {
+        if self.locked
+            && config.unlock_on_resolve
+            && transaction.tx_type == Type::Resolve
+            && self.locked_by == Some(transaction.tx)
+        {
+            return self.unlock_on_resolve(transaction);
+        }
+        if self.locked && config.hold_deposits_when_locked && transaction.tx_type == Type::Deposit {
+            return self.hold_for_review(transaction, config);
+        }
+        self.check_unlocked(&transaction)?;
+        if self.is_duplicate(&transaction) {
+            return Err(ProcessingError::new(format!(
+                "Duplicate {} row. Rejecting transaction {}",
+                transaction.tx_type,
+                transaction.mention()
+            )));
+        }
+
+        if self.frozen && transaction.tx_type == Type::Withdrawal {
+            return Err(ProcessingError::new(format!(
+                "Client's account {} is frozen. Withdrawal not permitted.. Rejecting transaction {}",
+                Mention(self.id), transaction.mention()
+            )));
+        }
+
+        if self.cooling_off > 0 && transaction.tx_type == Type::Withdrawal {
+            return Err(ProcessingError::new(format!(
+                "Client's account {} is cooling off for {} more transactions. Withdrawal not permitted.. Rejecting transaction {}",
+                Mention(self.id), self.cooling_off, transaction.mention()
             )));
         }
 
-        match transaction.tx_type {
-            Type::Deposit => self.deposit(transaction),
+        let moves_funds = matches!(transaction.tx_type, Type::Deposit | Type::Withdrawal);
+        if moves_funds {
+            self.check_caps(&transaction, config)?;
+        }
+
+        let tx = transaction.tx;
+        let result = match transaction.tx_type {
+            Type::Deposit => self.deposit(transaction, config),
 
-            Type::Withdrawal => self.withdrawal(transaction),
+            Type::Withdrawal => self.withdrawal(transaction, config),
 
             Type::Dispute => self.dispute(transaction),
 
             Type::Resolve => self.resolve(transaction),
 
             Type::Chargeback => self.chargeback(transaction),
+
+            Type::Custom(_) => Err(ProcessingError::new(format!(
+                "Unknown transaction type. Rejecting transaction {}",
+                transaction.mention()
+            ))),
+        };
+        if result.is_ok() && moves_funds {
+            self.age_reserve_holds(tx);
+            self.cooling_off = self.cooling_off.saturating_sub(1);
         }
+        result
     }
 
-    fn deposit(&mut self, transaction: Transaction) -> Result<(), ProcessingError> {
-        if let Some(amount_to_deposit) = transaction.amount {
+    /// Count an accepted deposit or withdrawal against the rolling reserve holds of the earlier deposits, releasing the ones it
+    /// was the last transaction for
+    fn age_reserve_holds(&mut self, tx: TransactionId) {
+        let mut released = Currency::zero();
+        self.reserve_holds.retain_mut(|hold| {
+            if hold.tx == tx {
+                return true;
+            }
+            hold.remaining = hold.remaining.saturating_sub(1);
+            if hold.remaining == 0 {
+                released += hold.amount;
+            }
+            hold.remaining > 0
+        });
+        self.held -= released;
+        self.available += released;
+    }
+
+    /// Let the handler of a custom transaction type change the balances through a BalanceMutator. Custom transactions are not
+    /// retained, so they can not be disputed, and a failing handler leaves the balances as they were
+    pub(crate) fn process_custom(
+        &mut self,
+        transaction: &Transaction,
+        handler: &dyn CustomTransactionHandler,
+    ) -> Result<(), ProcessingError> {
+        self.check_unlocked(transaction)?;
+
+        let before = (
+            self.available,
+            self.held,
+            self.total,
+            self.locked,
+            self.receivable,
+            self.recovered,
+        );
+        let result = handler.handle(transaction, &mut BalanceMutator { client: self });
+        if result.is_err() {
+            (
+                self.available,
+                self.held,
+                self.total,
+                self.locked,
+                self.receivable,
+                self.recovered,
+            ) = before;
+        }
+        result
+    }
+
+    /// A resolve of the transaction whose chargeback locked the account lifts the lock. The charged back funds are not restored,
+    /// deposits held pending review are released
+    fn unlock_on_resolve(&mut self, transaction: Transaction) -> Result<(), ProcessingError> {
+        self.lift_lock();
+        self.closed_disputes.insert(transaction.tx, Type::Resolve);
+        if let Some(charged_back) = self.transactions.get_mut(&transaction.tx) {
+            charged_back.annotate_dispute(transaction.dispute_metadata);
+        }
+        Ok(())
+    }
+
+    /// The funds are received but held, and only become available once the account is unlocked
+    fn hold_for_review(
+        &mut self,
+        transaction: Transaction,
+        config: &EngineConfig,
+    ) -> Result<(), ProcessingError> {
+        let amount = transaction.amount.ok_or_else(|| {
+            ProcessingError::new(format!(
+                "Igoring malformed transaction {}..",
+                transaction.mention()
+            ))
+        })?;
+        let held = plus(self.held, amount, &transaction)?;
+        let total = plus(self.total, amount, &transaction)?;
+        let deposited = plus(self.deposited, amount, &transaction)?;
+        self.review_holds.push((transaction.tx, amount));
+        self.retain(transaction, config);
+        (self.held, self.total, self.deposited) = (held, total, deposited);
+        self.deposits += 1;
+        Ok(())
+    }
+
+    fn check_unlocked(&self, transaction: &Transaction) -> Result<(), ProcessingError> {
+        if self.locked {
+            return Err(ProcessingError::new(format!(
+                "Client's account {} is locked. {:?} not permitted.. Rejecting transaction {}",
+                Mention(self.id),
+                transaction.tx_type,
+                transaction.mention()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Deposits and withdrawals count against the caps of the tier and deposits add up against its deposited amount cap. Over
+    /// a cap, the transaction is rejected or the account flagged for review depending on the policy
+    fn check_caps(
+        &mut self,
+        transaction: &Transaction,
+        config: &EngineConfig,
+    ) -> Result<(), ProcessingError> {
+        let Some(caps) = config.caps_for(self.kyc_tier, self.tier()) else {
+            return Ok(());
+        };
+        let exceeded = if caps
+            .max_transactions
+            .is_some_and(|max| self.deposits + self.withdrawals >= max)
+        {
+            "transactions"
+        } else if transaction.tx_type == Type::Deposit
+            && caps.max_deposited.is_some_and(|max| {
+                self.deposited + transaction.amount.unwrap_or_else(Currency::zero) > max
+            })
+        {
+            "deposited amount"
+        } else {
+            return Ok(());
+        };
+        match config.client_caps.on_exceeded {
+            CapPolicy::Reject => Err(ProcessingError::new(format!(
+                "The account of client {} is over the {} cap of {}. Rejecting transaction {}",
+                Mention(self.id),
+                exceeded,
+                match config
+                    .account_tier(self.tier())
+                    .and_then(|tier| tier.caps.as_ref())
+                {
+                    Some(_) => format!("tier {}", self.tier().unwrap_or_default()),
+                    None => format!("KYC tier {}", self.kyc_tier),
+                },
+                transaction.mention()
+            ))),
+            CapPolicy::Flag => {
+                self.kyc_review = true;
+                Ok(())
+            }
+        }
+    }
+
+    fn retain(&mut self, transaction: Transaction, config: &EngineConfig) {
+        if config.retain_transactions {
             self.transactions
                 .entry(transaction.tx)
                 .or_insert_with(|| transaction);
-            self.available += amount_to_deposit;
-            self.total += amount_to_deposit;
+        }
+    }
+
+    fn deposit(
+        &mut self,
+        transaction: Transaction,
+        config: &EngineConfig,
+    ) -> Result<(), ProcessingError> {
+        if let Some(amount_to_deposit) = transaction.amount {
+            positive(amount_to_deposit, &transaction)?;
+            let available = plus(self.available, amount_to_deposit, &transaction)?;
+            let total = plus(self.total, amount_to_deposit, &transaction)?;
+            let deposited = plus(self.deposited, amount_to_deposit, &transaction)?;
+            let reserve = match config.rolling_reserve_for(self.id) {
+                Some(reserve) => Some((
+                    amount_to_deposit
+                        .checked_mul(reserve.percent)
+                        .and_then(|amount| amount.checked_div(Currency::from(100)))
+                        .ok_or_else(|| overflow(amount_to_deposit, &transaction))?
+                        .round_dp(config.amount_scale),
+                    reserve.transactions,
+                )),
+                None => None,
+            };
+            let transaction_id = transaction.tx;
+            self.retain(transaction, config);
+            //the deposit pays the receivable down first, only what is left over is actually available to the client
+            let recovery = amount_to_deposit.min(self.receivable);
+            self.receivable -= recovery;
+            self.recovered += recovery;
+
+            (self.available, self.total, self.deposited) = (available, total, deposited);
+            self.deposits += 1;
+            if let Some((amount, transactions)) = reserve {
+                if amount > Currency::zero() && transactions > 0 {
+                    self.available -= amount;
+                    self.held += amount;
+                    self.reserve_holds.push(ReserveHold {
+                        tx: transaction_id,
+                        amount,
+                        remaining: transactions,
+                    });
+                }
+            }
             Result::Ok(())
         } else {
-            Result::Err(ProcessingError(format!(
+            Result::Err(ProcessingError::new(format!(
                 "Igoring malformed transaction {}..",
-                transaction
+                transaction.mention()
             )))
         }
     }
 
-    fn withdrawal(&mut self, transaction: Transaction) -> Result<(), ProcessingError> {
+    fn withdrawal(
+        &mut self,
+        transaction: Transaction,
+        config: &EngineConfig,
+    ) -> Result<(), ProcessingError> {
         if let Some(amount_to_withdraw) = transaction.amount {
+            positive(amount_to_withdraw, &transaction)?;
             let to_debit = amount_to_withdraw;
-            if self.available - to_debit >= Currency::zero() {
-                self.transactions
-                    .entry(transaction.tx)
-                    .or_insert_with(|| transaction);
-
-                self.available -= to_debit;
-                self.total -= to_debit;
-                Result::Ok(())
-            } else {
-                Result::Err(ProcessingError(format!(
+            let reserve = config.reserve_for(self.id, self.tier());
+            let available = minus(self.available, to_debit, &transaction)?;
+            let total = minus(self.total, to_debit, &transaction)?;
+            if available < Currency::zero() {
+                Result::Err(ProcessingError::new(format!(
                     "{} amount exceeds available funds {}. Igoring transaction {}..",
-                    to_debit, self.available, transaction
+                    to_debit,
+                    self.available,
+                    transaction.mention()
                 )))
+            } else if available < reserve {
+                Result::Err(ProcessingError::new(format!(
+                    "{} amount exceeds available funds {} less the reserve of {}. Igoring transaction {}..",
+                    to_debit, self.available, reserve, transaction.mention()
+                )))
+            } else {
+                self.retain(transaction, config);
+
+                (self.available, self.total) = (available, total);
+                self.withdrawals += 1;
+                Result::Ok(())
             }
         } else {
-            Result::Err(ProcessingError(format!(
+            Result::Err(ProcessingError::new(format!(
                 "Igoring Withdrawal transaction {} with missing the amount field..",
-                transaction
+                transaction.mention()
             )))
         }
     }
@@ -119,9 +819,13 @@ impl ClientProfile {
     fn dispute(&mut self, transaction: Transaction) -> Result<(), ProcessingError> {
         if let Some(open_transaction) = self.transactions.get_mut(&transaction.tx) {
             if let Some(disputed) = open_transaction.amount {
-                self.held += disputed;
-                self.available -= disputed;
+                let held = plus(self.held, disputed, &transaction)?;
+                let available = minus(self.available, disputed, &transaction)?;
+                (self.held, self.available) = (held, available);
+                self.receivable = deficit(self.available);
                 open_transaction.start_dispute();
+                open_transaction.annotate_dispute(transaction.dispute_metadata);
+                self.open_disputes.insert(transaction.tx);
             }
         }
 
@@ -132,9 +836,14 @@ impl ClientProfile {
         if let Some(existing_transaction) = self.transactions.get_mut(&transaction.tx) {
             if existing_transaction.under_dispute {
                 if let Some(to_add) = existing_transaction.amount {
-                    self.held -= to_add;
-                    self.available += to_add;
+                    let held = minus(self.held, to_add, &transaction)?;
+                    let available = plus(self.available, to_add, &transaction)?;
+                    (self.held, self.available) = (held, available);
+                    self.receivable = deficit(self.available);
                     existing_transaction.stop_dispute();
+                    existing_transaction.annotate_dispute(transaction.dispute_metadata);
+                    self.open_disputes.remove(&transaction.tx);
+                    self.closed_disputes.insert(transaction.tx, Type::Resolve);
                 }
             }
         }
@@ -146,10 +855,27 @@ impl ClientProfile {
         if let Some(existing_transaction) = self.transactions.get_mut(&transaction.tx) {
             if existing_transaction.under_dispute {
                 if let Some(chargeback) = existing_transaction.amount {
-                    self.held -= chargeback;
-                    self.total -= chargeback;
-                    self.locked = true;
+                    let held = minus(self.held, chargeback, &transaction)?;
+                    let total = minus(self.total, chargeback, &transaction)?;
+                    let charged_back = plus(self.charged_back, chargeback, &transaction)?;
                     existing_transaction.stop_dispute();
+                    existing_transaction.annotate_dispute(transaction.dispute_metadata);
+                    self.open_disputes.remove(&transaction.tx);
+                    let reason = ChargebackReason::from_code(
+                        existing_transaction
+                            .dispute_metadata()
+                            .and_then(|metadata| metadata.reason()),
+                    );
+
+                    self.closed_disputes
+                        .insert(transaction.tx, Type::Chargeback);
+                    (self.held, self.total) = (held, total);
+                    if reason.locks_account() {
+                        self.locked = true;
+                        self.locked_by = Some(transaction.tx);
+                    }
+                    *self.chargebacks.entry(reason).or_insert(0) += 1;
+                    self.charged_back = charged_back;
                 }
             }
         }
@@ -158,6 +884,148 @@ impl ClientProfile {
     }
 }
 
+/// The only way custom transaction handlers can change an account. Every operation moves funds so that total stays
+/// available + held, and fails without changing anything on a negative amount or when the funds are not there
+pub struct BalanceMutator<'a> {
+    client: &'a mut ClientProfile,
+}
+
+impl BalanceMutator<'_> {
+    pub fn client(&self) -> ClientId {
+        self.client.id
+    }
+
+    pub fn available(&self) -> Currency {
+        self.client.available
+    }
+
+    pub fn held(&self) -> Currency {
+        self.client.held
+    }
+
+    pub fn total(&self) -> Currency {
+        self.client.total
+    }
+
+    pub fn annotation(&self) -> Option<&Annotation> {
+        self.client.annotation.as_ref()
+    }
+
+    /// Add funds to the account, paying the receivable down first as a deposit would
+    pub fn credit(&mut self, amount: Currency) -> Result<(), ProcessingError> {
+        Self::check_amount(amount)?;
+        let (Some(available), Some(total)) = (
+            self.client.available.checked_add(amount),
+            self.client.total.checked_add(amount),
+        ) else {
+            return Err(ProcessingError::new(format!(
+                "{} amount overflows the balances",
+                amount
+            )));
+        };
+        let recovery = amount.min(self.client.receivable);
+        self.client.receivable -= recovery;
+        self.client.recovered += recovery;
+        (self.client.available, self.client.total) = (available, total);
+        Ok(())
+    }
+
+    /// Take available funds out of the account
+    pub fn debit(&mut self, amount: Currency) -> Result<(), ProcessingError> {
+        self.check_available(amount)?;
+        self.client.available -= amount;
+        self.client.total -= amount;
+        Ok(())
+    }
+
+    /// Move available funds to held
+    pub fn hold(&mut self, amount: Currency) -> Result<(), ProcessingError> {
+        self.check_available(amount)?;
+        self.client.available -= amount;
+        self.client.held += amount;
+        Ok(())
+    }
+
+    /// Move held funds back to available
+    pub fn release(&mut self, amount: Currency) -> Result<(), ProcessingError> {
+        Self::check_amount(amount)?;
+        if amount > self.client.held {
+            return Err(ProcessingError::new(format!(
+                "{} amount exceeds held funds {}",
+                amount, self.client.held
+            )));
+        }
+        self.client.held -= amount;
+        self.client.available += amount;
+        Ok(())
+    }
+
+    pub fn lock(&mut self) {
+        self.client.locked = true;
+    }
+
+    fn check_available(&self, amount: Currency) -> Result<(), ProcessingError> {
+        Self::check_amount(amount)?;
+        if amount > self.client.available {
+            return Err(ProcessingError::new(format!(
+                "{} amount exceeds available funds {}",
+                amount, self.client.available
+            )));
+        }
+        Ok(())
+    }
+
+    fn check_amount(amount: Currency) -> Result<(), ProcessingError> {
+        amount
+            .non_negative()
+            .map(|_| ())
+            .map_err(|e| ProcessingError::new(e.to_string()))
+    }
+}
+
+fn deficit(available: Currency) -> Currency {
+    (-available).max(Currency::zero())
+}
+
+/// balance + amount, the rejection of the transaction when it overflows
+pub(crate) fn plus<M: Money>(
+    balance: M,
+    amount: M,
+    transaction: &Transaction,
+) -> Result<M, ProcessingError> {
+    balance
+        .checked_add(amount)
+        .ok_or_else(|| overflow(amount, transaction))
+}
+
+/// balance - amount, the rejection of the transaction when it overflows
+fn minus<M: Money>(balance: M, amount: M, transaction: &Transaction) -> Result<M, ProcessingError> {
+    balance
+        .checked_sub(amount)
+        .ok_or_else(|| overflow(amount, transaction))
+}
+
+fn overflow<M: Money>(amount: M, transaction: &Transaction) -> ProcessingError {
+    ProcessingError::new(format!(
+        "{} amount overflows the balances. Rejecting transaction {}",
+        amount,
+        transaction.mention()
+    ))
+}
+
+/// The rejection of a deposit or withdrawal of a negative or zero amount, which would move the funds the other way or not at all
+pub(crate) fn positive(amount: Currency, transaction: &Transaction) -> Result<(), ProcessingError> {
+    if amount > Currency::zero() {
+        Ok(())
+    } else {
+        Err(ProcessingError::new(format!(
+            "{} amount is not positive. Rejecting transaction {}",
+            amount,
+            transaction.mention()
+        )))
+    }
+}
+
 impl fmt::Display for ClientProfile {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
@@ -174,6 +1042,13 @@ mod tests {
 
     use super::*;
 
+    use std::collections::HashSet;
+
+    use crate::exchange::config::ClientCaps;
+    use crate::exchange::config::RollingReserve;
+    use crate::exchange::config::TierCaps;
+    use crate::exchange::transaction::DisputeMetadata;
+
     #[test]
     fn it_should_add_funds_when_processing_deposits() {
         let mut client_profile = ClientProfile::new_with_defaults(1);
@@ -184,7 +1059,9 @@ mod tests {
                 client: 1,
                 tx: 1000,
                 amount: Some(Currency::str("0.0001")),
+                metadata: HashMap::new(),
                 under_dispute: false,
+                dispute_metadata: None,
             })
             .unwrap_or_default();
 
@@ -196,8 +1073,96 @@ mod tests {
     }
 
     #[test]
-    fn it_should_subtract_funds_when_processing_withdrawals() {
-        let mut client_profile = ClientProfile::new(
+    fn it_should_reject_transactions_overflowing_the_balances() {
+        let transaction = |tx_type, tx, amount: Currency| Transaction {
+            tx_type,
+            client: 1,
+            tx,
+            amount: Some(amount),
+            metadata: HashMap::new(),
+            under_dispute: false,
+            dispute_metadata: None,
+        };
+        let mut client_profile = ClientProfile::new_with_defaults(1);
+        client_profile
+            .process_new_transaction(transaction(Type::Deposit, 1, Currency::MAX))
+            .unwrap();
+
+        let rejected = client_profile
+            .process_new_transaction(transaction(Type::Deposit, 2, Currency::str("1")))
+            .unwrap_err();
+        assert_eq!(
+            format!(
+                "1 amount overflows the balances. Rejecting transaction Deposit,1,2,Some({:?}),false",
+                Currency::str("1")
+            ),
+            rejected.to_string()
+        );
+        assert_eq!(Currency::MAX, client_profile.available);
+        assert_eq!(Currency::MAX, client_profile.total);
+        assert_eq!(1, client_profile.deposits());
+
+        //withdrawing the maximum from a negative balance
+        client_profile
+            .process_new_transaction(transaction(Type::Dispute, 1, Currency::zero()))
+            .unwrap();
+        client_profile.available = -Currency::str("1");
+        assert_eq!(
+            true,
+            client_profile
+                .process_new_transaction(transaction(Type::Withdrawal, 3, Currency::MAX))
+                .unwrap_err()
+                .to_string()
+                .contains("overflows the balances")
+        );
+        assert_eq!(-Currency::str("1"), client_profile.available);
+        assert_eq!(Currency::MAX, client_profile.held);
+    }
+
+    #[test]
+    fn it_should_reject_deposits_and_withdrawals_of_negative_or_zero_amounts() {
+        let transaction = |tx_type, tx, amount: &str| Transaction {
+            tx_type,
+            client: 1,
+            tx,
+            amount: Some(Currency::str(amount)),
+            metadata: HashMap::new(),
+            under_dispute: false,
+            dispute_metadata: None,
+        };
+        let mut client_profile = ClientProfile::new_with_defaults(1);
+        client_profile
+            .process_new_transaction(transaction(Type::Deposit, 1, "10.0"))
+            .unwrap();
+
+        for (tx_type, tx, amount) in [
+            (Type::Deposit, 2, "-50"),
+            (Type::Withdrawal, 3, "-7"),
+            (Type::Deposit, 4, "0"),
+            (Type::Withdrawal, 5, "0.0000"),
+        ] {
+            let rejected = client_profile
+                .process_new_transaction(transaction(tx_type, tx, amount))
+                .unwrap_err();
+            assert_eq!(
+                true,
+                rejected
+                    .to_string()
+                    .starts_with(&format!("{} amount is not positive", Currency::str(amount))),
+                "{}",
+                rejected
+            );
+        }
+        assert_eq!(Currency::str("10.0"), client_profile.available);
+        assert_eq!(Currency::str("10.0"), client_profile.total);
+        assert_eq!(1, client_profile.deposits());
+        assert_eq!(0, client_profile.withdrawals());
+        assert_eq!(1, client_profile.transactions.len());
+    }
+
+    #[test]
+    fn it_should_subtract_funds_when_processing_withdrawals() {
+        let mut client_profile = ClientProfile::new(
             1,
             Currency::str("0.0002"),
             Currency::str("0.0"),
@@ -212,7 +1177,9 @@ mod tests {
                 client: 1,
                 tx: 1000,
                 amount: Some(Currency::str("0.0002")),
+                metadata: HashMap::new(),
                 under_dispute: false,
+                dispute_metadata: None,
             })
             .unwrap_or_default();
 
@@ -240,7 +1207,9 @@ mod tests {
                 client: 1,
                 tx: 1000,
                 amount: Some(Currency::str("0.0003")),
+                metadata: HashMap::new(),
                 under_dispute: false,
+                dispute_metadata: None,
             })
             .err();
 
@@ -267,7 +1236,9 @@ mod tests {
                     client: 1,
                     tx: 1000,
                     amount: Some(Currency::str("0.0002")),
+                    metadata: HashMap::new(),
                     under_dispute: false,
+                    dispute_metadata: None,
                 },
             )]),
         );
@@ -279,7 +1250,9 @@ mod tests {
                 client: 1,
                 tx: 1001,
                 amount: None,
+                metadata: HashMap::new(),
                 under_dispute: false,
+                dispute_metadata: None,
             })
             .unwrap_or_default();
 
@@ -313,7 +1286,9 @@ mod tests {
                     client: 1,
                     tx: 1000,
                     amount: Some(Currency::str("0.0002")),
+                    metadata: HashMap::new(),
                     under_dispute: false,
+                    dispute_metadata: None,
                 },
             )]),
         );
@@ -324,7 +1299,9 @@ mod tests {
                 client: 1,
                 tx: 1000,
                 amount: None,
+                metadata: HashMap::new(),
                 under_dispute: false,
+                dispute_metadata: None,
             })
             .unwrap_or_default();
 
@@ -358,7 +1335,9 @@ mod tests {
                     client: 1,
                     tx: 1000,
                     amount: Some(Currency::str("0.0002")),
+                    metadata: HashMap::new(),
                     under_dispute: true,
+                    dispute_metadata: None,
                 },
             )]),
         );
@@ -369,7 +1348,9 @@ mod tests {
                 client: 1,
                 tx: 1000,
                 amount: None,
+                metadata: HashMap::new(),
                 under_dispute: false,
+                dispute_metadata: None,
             })
             .unwrap_or_default();
 
@@ -403,7 +1384,9 @@ mod tests {
                     client: 1,
                     tx: 1000,
                     amount: Some(Currency::str("0.0002")),
+                    metadata: HashMap::new(),
                     under_dispute: true,
+                    dispute_metadata: None,
                 },
             )]),
         );
@@ -414,7 +1397,9 @@ mod tests {
                 client: 1,
                 tx: 1000,
                 amount: None,
+                metadata: HashMap::new(),
                 under_dispute: false,
+                dispute_metadata: None,
             })
             .unwrap_or_default();
 
@@ -449,7 +1434,9 @@ mod tests {
                         client: 1,
                         tx: 333,
                         amount: Some(Currency::str("0.0002")),
+                        metadata: HashMap::new(),
                         under_dispute: false,
+                        dispute_metadata: None,
                     },
                 ),
                 (
@@ -459,7 +1446,9 @@ mod tests {
                         client: 1,
                         tx: 2222,
                         amount: Some(Currency::str("1.0009")),
+                        metadata: HashMap::new(),
                         under_dispute: false,
+                        dispute_metadata: None,
                     },
                 ),
             ]),
@@ -471,7 +1460,9 @@ mod tests {
                 client: 1,
                 tx: 333,
                 amount: None,
+                metadata: HashMap::new(),
                 under_dispute: false,
+                dispute_metadata: None,
             })
             .unwrap_or_default();
 
@@ -481,7 +1472,9 @@ mod tests {
                 client: 1,
                 tx: 2222,
                 amount: None,
+                metadata: HashMap::new(),
                 under_dispute: false,
+                dispute_metadata: None,
             })
             .unwrap_or_default();
 
@@ -514,7 +1507,9 @@ mod tests {
                 client: 1,
                 tx: 1000,
                 amount: None,
+                metadata: HashMap::new(),
                 under_dispute: false,
+                dispute_metadata: None,
             })
             .unwrap_or_default();
 
@@ -524,7 +1519,9 @@ mod tests {
                 client: 1,
                 tx: 1001,
                 amount: None,
+                metadata: HashMap::new(),
                 under_dispute: false,
+                dispute_metadata: None,
             })
             .unwrap_or_default();
 
@@ -534,4 +1531,641 @@ mod tests {
         assert_eq!(false, client_profile.locked);
         assert_eq!(0, client_profile.transactions.len());
     }
+
+    #[test]
+    fn it_should_carry_dispute_metadata_through_the_dispute_lifecycle() {
+        let mut client_profile = ClientProfile::new(
+            1,
+            Currency::str("0.0002"),
+            Currency::str("0.00"),
+            Currency::str("0.0002"),
+            false,
+            HashMap::from([(
+                1000,
+                Transaction {
+                    tx_type: Type::Deposit,
+                    client: 1,
+                    tx: 1000,
+                    amount: Some(Currency::str("0.0002")),
+                    metadata: HashMap::new(),
+                    under_dispute: false,
+                    dispute_metadata: None,
+                },
+            )]),
+        );
+
+        client_profile
+            .process_new_transaction(Transaction {
+                tx_type: Type::Dispute,
+                client: 1,
+                tx: 1000,
+                amount: None,
+                metadata: HashMap::new(),
+                under_dispute: false,
+                dispute_metadata: Some(DisputeMetadata::new(
+                    Some("fraud".to_string()),
+                    Some("CASE-42".to_string()),
+                    None,
+                )),
+            })
+            .unwrap_or_default();
+
+        client_profile
+            .process_new_transaction(Transaction {
+                tx_type: Type::Resolve,
+                client: 1,
+                tx: 1000,
+                amount: None,
+                metadata: HashMap::new(),
+                under_dispute: false,
+                dispute_metadata: Some(DisputeMetadata::new(
+                    None,
+                    None,
+                    Some("customer recognised the payment".to_string()),
+                )),
+            })
+            .unwrap_or_default();
+
+        let resolved = client_profile.transaction(1000).unwrap();
+        assert_eq!(false, resolved.under_dispute());
+        assert_eq!(
+            Some(&DisputeMetadata::new(
+                Some("fraud".to_string()),
+                Some("CASE-42".to_string()),
+                Some("customer recognised the payment".to_string()),
+            )),
+            resolved.dispute_metadata()
+        );
+    }
+
+    #[test]
+    fn it_should_only_lock_the_account_for_fraud_chargebacks() {
+        let disputed = |tx| Transaction {
+            tx_type: Type::Deposit,
+            client: 1,
+            tx,
+            amount: Some(Currency::str("1.0")),
+            metadata: HashMap::new(),
+            under_dispute: true,
+            dispute_metadata: None,
+        };
+        let chargeback = |tx, reason: &str| Transaction {
+            tx_type: Type::Chargeback,
+            client: 1,
+            tx,
+            amount: None,
+            metadata: HashMap::new(),
+            under_dispute: false,
+            dispute_metadata: Some(DisputeMetadata::new(Some(reason.to_string()), None, None)),
+        };
+
+        let mut client_profile = ClientProfile::new(
+            1,
+            Currency::str("0.0"),
+            Currency::str("3.0"),
+            Currency::str("3.0"),
+            false,
+            HashMap::from([(1, disputed(1)), (2, disputed(2)), (3, disputed(3))]),
+        );
+
+        client_profile
+            .process_new_transaction(chargeback(1, "duplicate"))
+            .unwrap_or_default();
+        client_profile
+            .process_new_transaction(chargeback(2, "goods-not-received"))
+            .unwrap_or_default();
+
+        assert_eq!(Currency::str("1.0"), client_profile.held);
+        assert_eq!(Currency::str("1.0"), client_profile.total);
+        assert_eq!(false, client_profile.locked);
+
+        client_profile
+            .process_new_transaction(chargeback(3, "fraud"))
+            .unwrap_or_default();
+
+        assert_eq!(Currency::str("0.0"), client_profile.total);
+        assert_eq!(true, client_profile.locked);
+        assert_eq!(1, client_profile.chargebacks(ChargebackReason::Fraud));
+        assert_eq!(1, client_profile.chargebacks(ChargebackReason::Duplicate));
+        assert_eq!(
+            1,
+            client_profile.chargebacks(ChargebackReason::GoodsNotReceived)
+        );
+        assert_eq!(0, client_profile.chargebacks(ChargebackReason::Unspecified));
+    }
+
+    #[test]
+    fn it_should_pay_down_the_receivable_with_subsequent_deposits() {
+        let mut client_profile = ClientProfile::new(
+            1,
+            Currency::str("2.0"),
+            Currency::str("0.0"),
+            Currency::str("2.0"),
+            false,
+            HashMap::from([(
+                1,
+                Transaction {
+                    tx_type: Type::Deposit,
+                    client: 1,
+                    tx: 1,
+                    amount: Some(Currency::str("10.0")),
+                    metadata: HashMap::new(),
+                    under_dispute: false,
+                    dispute_metadata: None,
+                },
+            )]),
+        );
+
+        //8.0 of the disputed deposit were already withdrawn
+        client_profile
+            .process_new_transaction(Transaction {
+                tx_type: Type::Dispute,
+                client: 1,
+                tx: 1,
+                amount: None,
+                metadata: HashMap::new(),
+                under_dispute: false,
+                dispute_metadata: None,
+            })
+            .unwrap_or_default();
+
+        assert_eq!(Currency::str("-8.0"), client_profile.available);
+        assert_eq!(Currency::str("8.0"), client_profile.receivable());
+
+        client_profile
+            .process_new_transaction(Transaction {
+                tx_type: Type::Deposit,
+                client: 1,
+                tx: 2,
+                amount: Some(Currency::str("5.0")),
+                metadata: HashMap::new(),
+                under_dispute: false,
+                dispute_metadata: None,
+            })
+            .unwrap_or_default();
+
+        assert_eq!(Currency::str("3.0"), client_profile.receivable());
+        assert_eq!(Currency::str("5.0"), client_profile.recovered());
+
+        client_profile
+            .process_new_transaction(Transaction {
+                tx_type: Type::Deposit,
+                client: 1,
+                tx: 3,
+                amount: Some(Currency::str("4.0")),
+                metadata: HashMap::new(),
+                under_dispute: false,
+                dispute_metadata: None,
+            })
+            .unwrap_or_default();
+
+        assert_eq!(Currency::str("1.0"), client_profile.available);
+        assert_eq!(Currency::str("0.0"), client_profile.receivable());
+        assert_eq!(Currency::str("8.0"), client_profile.recovered());
+    }
+
+    #[test]
+    fn it_should_unlock_on_resolve_of_the_transaction_that_locked_the_account() {
+        let config = EngineConfig {
+            unlock_on_resolve: true,
+            ..EngineConfig::default()
+        };
+        let row = |tx_type, tx| Transaction {
+            tx_type,
+            client: 1,
+            tx,
+            amount: None,
+            metadata: HashMap::new(),
+            under_dispute: false,
+            dispute_metadata: None,
+        };
+        let mut client_profile = ClientProfile::new(
+            1,
+            Currency::str("0.0"),
+            Currency::str("2.0"),
+            Currency::str("2.0"),
+            false,
+            HashMap::from([(
+                7,
+                Transaction {
+                    tx_type: Type::Deposit,
+                    client: 1,
+                    tx: 7,
+                    amount: Some(Currency::str("2.0")),
+                    metadata: HashMap::new(),
+                    under_dispute: true,
+                    dispute_metadata: None,
+                },
+            )]),
+        );
+
+        client_profile
+            .process_with_config(row(Type::Chargeback, 7), &config)
+            .unwrap();
+        assert_eq!(Some(7), client_profile.locked_by());
+
+        //only the resolve of the charged back transaction and only under the policy
+        assert_eq!(
+            true,
+            client_profile
+                .process_with_config(row(Type::Resolve, 8), &config)
+                .is_err()
+        );
+        assert_eq!(
+            true,
+            client_profile
+                .process_new_transaction(row(Type::Resolve, 7))
+                .is_err()
+        );
+        client_profile
+            .process_with_config(row(Type::Resolve, 7), &config)
+            .unwrap();
+
+        assert_eq!(false, client_profile.locked());
+        assert_eq!(None, client_profile.locked_by());
+        assert_eq!(Currency::str("0.0"), client_profile.total());
+    }
+
+    #[test]
+    fn it_should_list_the_disputed_transactions_making_up_the_held_funds() {
+        let deposit = |tx, amount: &str, under_dispute| Transaction {
+            tx_type: Type::Deposit,
+            client: 1,
+            tx,
+            amount: Some(Currency::str(amount)),
+            metadata: HashMap::new(),
+            under_dispute,
+            dispute_metadata: None,
+        };
+        let client_profile = ClientProfile::new(
+            1,
+            Currency::str("1.0"),
+            Currency::str("5.0"),
+            Currency::str("6.0"),
+            false,
+            HashMap::from([
+                (1, deposit(1, "2.0", true)),
+                (2, deposit(2, "1.0", false)),
+                (3, deposit(3, "3.0", true)),
+            ]),
+        );
+
+        let mut holds: Vec<_> = client_profile.holds().collect();
+        holds.sort();
+
+        assert_eq!(
+            vec![(1, Currency::str("2.0")), (3, Currency::str("3.0"))],
+            holds
+        );
+        assert_eq!(
+            client_profile.held(),
+            holds.iter().map(|(_, amount)| amount).sum()
+        );
+    }
+
+    #[test]
+    fn it_should_reject_replayed_dispute_rows_without_holding_twice() {
+        let row = |tx_type| Transaction {
+            tx_type,
+            client: 1,
+            tx: 1,
+            amount: None,
+            metadata: HashMap::new(),
+            under_dispute: false,
+            dispute_metadata: None,
+        };
+        let mut client_profile = ClientProfile::new_with_defaults(1);
+        client_profile
+            .process_new_transaction(Transaction {
+                tx_type: Type::Deposit,
+                amount: Some(Currency::str("4.0")),
+                ..row(Type::Deposit)
+            })
+            .unwrap();
+
+        client_profile
+            .process_new_transaction(row(Type::Dispute))
+            .unwrap();
+        assert_eq!(true, client_profile.is_duplicate(&row(Type::Dispute)));
+        assert_eq!(
+            true,
+            client_profile
+                .process_new_transaction(row(Type::Dispute))
+                .is_err()
+        );
+        assert_eq!(Currency::str("4.0"), client_profile.held());
+
+        client_profile
+            .process_new_transaction(row(Type::Resolve))
+            .unwrap();
+        assert_eq!(
+            true,
+            client_profile
+                .process_new_transaction(row(Type::Resolve))
+                .is_err()
+        );
+
+        //a dispute after the previous one was resolved is a new dispute
+        assert_eq!(false, client_profile.is_duplicate(&row(Type::Dispute)));
+        client_profile
+            .process_new_transaction(row(Type::Dispute))
+            .unwrap();
+        assert_eq!(Currency::str("4.0"), client_profile.held());
+        assert_eq!(Currency::str("0.0"), client_profile.available());
+    }
+
+    #[test]
+    fn it_should_hold_deposits_on_a_locked_account_until_it_is_unlocked() {
+        let config = EngineConfig {
+            hold_deposits_when_locked: true,
+            unlock_on_resolve: true,
+            ..EngineConfig::default()
+        };
+        let row = |tx_type, tx, amount: Option<&str>| Transaction {
+            tx_type,
+            client: 1,
+            tx,
+            amount: amount.map(Currency::str),
+            metadata: HashMap::new(),
+            under_dispute: false,
+            dispute_metadata: None,
+        };
+        let mut client_profile = ClientProfile::new_with_defaults(1);
+        for transaction in [
+            row(Type::Deposit, 1, Some("5.0")),
+            row(Type::Dispute, 1, None),
+            row(Type::Chargeback, 1, None),
+        ] {
+            client_profile
+                .process_with_config(transaction, &config)
+                .unwrap();
+        }
+        assert_eq!(true, client_profile.locked());
+
+        client_profile
+            .process_with_config(row(Type::Deposit, 2, Some("3.0")), &config)
+            .unwrap();
+        assert_eq!(Currency::str("0.0"), client_profile.available());
+        assert_eq!(Currency::str("3.0"), client_profile.held());
+        assert_eq!(Currency::str("3.0"), client_profile.total());
+        assert_eq!(
+            vec![(2, Currency::str("3.0"))],
+            client_profile.holds().collect::<Vec<_>>()
+        );
+        //without the policy the deposit is rejected as before
+        assert_eq!(
+            true,
+            client_profile
+                .process_new_transaction(row(Type::Deposit, 3, Some("1.0")))
+                .is_err()
+        );
+        assert_eq!(
+            true,
+            client_profile
+                .process_with_config(row(Type::Withdrawal, 4, Some("1.0")), &config)
+                .is_err()
+        );
+
+        client_profile
+            .process_with_config(row(Type::Resolve, 1, None), &config)
+            .unwrap();
+        assert_eq!(false, client_profile.locked());
+        assert_eq!(Currency::str("3.0"), client_profile.available());
+        assert_eq!(Currency::str("0.0"), client_profile.held());
+        assert_eq!(0, client_profile.holds().count());
+    }
+
+    #[test]
+    fn it_should_hold_back_a_rolling_reserve_on_high_risk_deposits() {
+        let config = EngineConfig {
+            rolling_reserve: RollingReserve::parse("10:2"),
+            high_risk_clients: HashSet::from([1]),
+            ..EngineConfig::default()
+        };
+        let row = |tx_type, client, tx, amount: Option<&str>| Transaction {
+            tx_type,
+            client,
+            tx,
+            amount: amount.map(Currency::str),
+            metadata: HashMap::new(),
+            under_dispute: false,
+            dispute_metadata: None,
+        };
+        let mut client_profile = ClientProfile::new_with_defaults(1);
+        client_profile
+            .process_with_config(row(Type::Deposit, 1, 1, Some("100.0")), &config)
+            .unwrap();
+        assert_eq!(Currency::str("90.0"), client_profile.available());
+        assert_eq!(Currency::str("10.0"), client_profile.held());
+        assert_eq!(Currency::str("100.0"), client_profile.total());
+        assert_eq!(
+            vec![(1, Currency::str("10.0"))],
+            client_profile.holds().collect::<Vec<_>>()
+        );
+        //the reserve can not be withdrawn, and a rejected transaction does not count towards its release
+        assert_eq!(
+            true,
+            client_profile
+                .process_with_config(row(Type::Withdrawal, 1, 2, Some("95.0")), &config)
+                .is_err()
+        );
+        client_profile
+            .process_with_config(row(Type::Deposit, 1, 3, Some("50.0")), &config)
+            .unwrap();
+        assert_eq!(1, client_profile.reserve_holds()[0].remaining);
+        assert_eq!(Currency::str("15.0"), client_profile.held());
+        //neither does a dispute, accepted though it names no transaction of the client
+        client_profile
+            .process_with_config(row(Type::Dispute, 1, 99, None), &config)
+            .unwrap();
+        assert_eq!(1, client_profile.reserve_holds()[0].remaining);
+
+        client_profile
+            .process_with_config(row(Type::Withdrawal, 1, 4, Some("10.0")), &config)
+            .unwrap();
+        assert_eq!(
+            vec![ReserveHold {
+                tx: 3,
+                amount: Currency::str("5.0"),
+                remaining: 1,
+            }],
+            client_profile.reserve_holds()
+        );
+        assert_eq!(Currency::str("135.0"), client_profile.available());
+        assert_eq!(Currency::str("5.0"), client_profile.held());
+
+        let mut low_risk = ClientProfile::new_with_defaults(2);
+        low_risk
+            .process_with_config(row(Type::Deposit, 2, 5, Some("100.0")), &config)
+            .unwrap();
+        assert_eq!(Currency::str("100.0"), low_risk.available());
+        assert_eq!(0, low_risk.holds().count());
+    }
+
+    #[test]
+    fn it_should_keep_the_reserve_out_of_reach_of_withdrawals() {
+        let config = EngineConfig {
+            reserve: Currency::str("3.0"),
+            client_reserves: HashMap::from([(2, Currency::str("1.0"))]),
+            ..EngineConfig::default()
+        };
+        let withdrawal = |client, tx, amount| Transaction {
+            tx_type: Type::Withdrawal,
+            client,
+            tx,
+            amount: Some(Currency::str(amount)),
+            metadata: HashMap::new(),
+            under_dispute: false,
+            dispute_metadata: None,
+        };
+        let profile = |id| {
+            ClientProfile::new(
+                id,
+                Currency::str("10.0"),
+                Currency::zero(),
+                Currency::str("10.0"),
+                false,
+                HashMap::new(),
+            )
+        };
+
+        let mut client_profile = profile(1);
+        assert_eq!(
+            true,
+            client_profile
+                .process_with_config(withdrawal(1, 1, "8.0"), &config)
+                .is_err()
+        );
+        assert_eq!(Currency::str("10.0"), client_profile.available());
+        client_profile
+            .process_with_config(withdrawal(1, 2, "7.0"), &config)
+            .unwrap();
+        assert_eq!(Currency::str("3.0"), client_profile.available());
+
+        //the per client override replaces the default
+        let mut client_profile = profile(2);
+        client_profile
+            .process_with_config(withdrawal(2, 3, "9.0"), &config)
+            .unwrap();
+        assert_eq!(Currency::str("1.0"), client_profile.available());
+    }
+
+    #[test]
+    fn it_should_track_lifetime_deposits_and_chargebacks() {
+        let row = |tx_type, tx, amount: Option<&str>| Transaction {
+            tx_type,
+            client: 1,
+            tx,
+            amount: amount.map(Currency::str),
+            metadata: HashMap::new(),
+            under_dispute: false,
+            dispute_metadata: None,
+        };
+        let mut client_profile = ClientProfile::new_with_defaults(1);
+        assert_eq!(Currency::zero(), client_profile.chargeback_ratio());
+
+        for transaction in [
+            row(Type::Deposit, 1, Some("5.0")),
+            row(Type::Deposit, 2, Some("2.5")),
+            row(Type::Deposit, 3, Some("1.0")),
+            row(Type::Withdrawal, 4, Some("1.0")),
+            row(Type::Dispute, 2, None),
+            row(Type::Chargeback, 2, None),
+        ] {
+            client_profile.process_new_transaction(transaction).unwrap();
+        }
+
+        assert_eq!(3, client_profile.deposits());
+        assert_eq!(Currency::str("8.5"), client_profile.deposited());
+        assert_eq!(1, client_profile.chargeback_count());
+        assert_eq!(Currency::str("2.5"), client_profile.charged_back());
+        assert_eq!(Currency::str("0.3333"), client_profile.chargeback_ratio());
+    }
+
+    #[test]
+    fn it_should_cap_clients_by_their_kyc_tier() {
+        let row = |tx_type, tx, amount| Transaction {
+            tx_type,
+            client: 1,
+            tx,
+            amount: Some(Currency::str(amount)),
+            metadata: HashMap::new(),
+            under_dispute: false,
+            dispute_metadata: None,
+        };
+        let mut config = EngineConfig {
+            client_caps: ClientCaps {
+                tiers: vec![
+                    TierCaps {
+                        max_deposited: Some(Currency::str("100.0")),
+                        max_transactions: Some(3),
+                    },
+                    TierCaps {
+                        max_deposited: None,
+                        max_transactions: Some(4),
+                    },
+                ],
+                on_exceeded: CapPolicy::Reject,
+            },
+            ..EngineConfig::default()
+        };
+        let mut client_profile = ClientProfile::new_with_defaults(1);
+        assert_eq!(
+            true,
+            client_profile
+                .process_with_config(row(Type::Deposit, 1, "60.0"), &config)
+                .is_ok()
+        );
+        let error = client_profile
+            .process_with_config(row(Type::Deposit, 2, "50.0"), &config)
+            .unwrap_err();
+        assert_eq!(
+            true,
+            error.to_string().starts_with(
+                "The account of client 1 is over the deposited amount cap of KYC tier 0."
+            )
+        );
+        assert_eq!(
+            true,
+            client_profile
+                .process_with_config(row(Type::Withdrawal, 3, "10.0"), &config)
+                .is_ok()
+        );
+        assert_eq!(
+            true,
+            client_profile
+                .process_with_config(row(Type::Deposit, 4, "40.0"), &config)
+                .is_ok()
+        );
+        assert_eq!(
+            true,
+            client_profile
+                .process_with_config(row(Type::Withdrawal, 5, "1.0"), &config)
+                .unwrap_err()
+                .0
+                .contains("over the transactions cap of KYC tier 0")
+        );
+        assert_eq!(Currency::str("90.0"), client_profile.available());
+
+        client_profile.set_kyc_tier(1);
+        assert_eq!(
+            true,
+            client_profile
+                .process_with_config(row(Type::Deposit, 6, "500.0"), &config)
+                .is_ok()
+        );
+
+        config.client_caps.on_exceeded = CapPolicy::Flag;
+        assert_eq!(
+            true,
+            client_profile
+                .process_with_config(row(Type::Deposit, 7, "1.0"), &config)
+                .is_ok()
+        );
+        assert_eq!(Currency::str("591.0"), client_profile.available());
+        assert_eq!(true, client_profile.kyc_review());
+        client_profile.set_kyc_tier(2);
+        assert_eq!(false, client_profile.kyc_review());
+        assert_eq!(5, client_profile.deposits() + client_profile.withdrawals());
+    }
 }
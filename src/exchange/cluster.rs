@@ -0,0 +1,388 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::sync::mpsc;
+use std::thread;
+
+use crate::exchange::config::LimitExceeded;
+use crate::exchange::config::UnknownTransactionType;
+use crate::exchange::pseudonym::ClientIds;
+use crate::exchange::transaction::ClientId;
+use crate::exchange::Exchange;
+use crate::exchange::IngestOptions;
+use crate::exchange::RecordReader;
+
+/// Records buffered for each range before the router waits for its engine to catch up
+const BACKLOG: usize = 10_000;
+
+type EngineError = Box<dyn Error + Send + Sync>;
+
+/// Contiguous range of client ids, both ends included, owned by one engine of a cluster
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct ClientRange {
+    pub first: ClientId,
+    pub last: ClientId,
+}
+
+impl ClientRange {
+    /// <first>-<last> e.g. 0-32767, first not above last
+    pub fn parse(range: &str) -> Option<ClientRange> {
+        let (first, last) = range.split_once('-')?;
+        let range = ClientRange {
+            first: first.trim().parse().ok()?,
+            last: last.trim().parse().ok()?,
+        };
+        (range.first <= range.last).then_some(range)
+    }
+
+    pub fn contains(&self, client: ClientId) -> bool {
+        (self.first..=self.last).contains(&client)
+    }
+
+    /// The whole id space split into count contiguous ranges of even size, count between 1 and the number of client ids
+    pub fn split(count: usize) -> Vec<ClientRange> {
+        let ids = ClientId::MAX as usize + 1;
+        let count = count.clamp(1, ids);
+        (0..count)
+            .map(|i| ClientRange {
+                first: (i * ids / count) as ClientId,
+                last: ((i + 1) * ids / count - 1) as ClientId,
+            })
+            .collect()
+    }
+
+    /// Either a number of even ranges (see split()) or comma separated ranges, which must not overlap e.g. 0-999,1000-65535
+    pub fn parse_list(ranges: &str) -> Option<Vec<ClientRange>> {
+        if let Ok(count) = ranges.parse::<usize>() {
+            return (count > 0).then(|| ClientRange::split(count));
+        }
+        let parsed = ranges
+            .split(',')
+            .map(ClientRange::parse)
+            .collect::<Option<Vec<_>>>()?;
+        let mut sorted = parsed.clone();
+        sorted.sort_by_key(|range| range.first);
+        (!sorted.windows(2).any(|pair| pair[0].last >= pair[1].first)).then_some(parsed)
+    }
+}
+
+impl fmt::Display for ClientRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.first, self.last)
+    }
+}
+
+/// Index of the range owning the client of a raw record. A record whose client can not be read goes to the first range, to
+/// be reported there as any unreadable record; None when no range owns a client that could be read
+fn owner(
+    ranges: &[ClientRange],
+    record: &csv::StringRecord,
+    client_column: Option<usize>,
+) -> Option<usize> {
+    match client_of(record, client_column) {
+        Some(client) => ranges.iter().position(|range| range.contains(client)),
+        None => Some(0),
+    }
+}
+
+fn client_of(record: &csv::StringRecord, client_column: Option<usize>) -> Option<ClientId> {
+    client_column
+        .and_then(|column| record.get(column))
+        .and_then(|client| client.trim().parse::<ClientId>().ok())
+}
+
+/// The error an engine failed with: the ones of LimitPolicy::Abort and UnknownTypePolicy::Fail as they are, so the caller can
+/// tell a stopped run, the others with the range they happened in
+fn range_error(range: &ClientRange, error: Box<dyn Error>, ids: &ClientIds) -> EngineError {
+    let error = match error.downcast::<LimitExceeded>() {
+        Ok(limit) => return limit,
+        Err(error) => error,
+    };
+    let error = match error.downcast::<UnknownTransactionType>() {
+        Ok(unknown) => return unknown,
+        Err(error) => error,
+    };
+    format!(
+        "Failed to process client range {}: {}",
+        range,
+        ids.describe(error.as_ref())
+    )
+    .into()
+}
+
+/// Process the input with one engine per client range, each on its own thread: the input is read once and every record is
+/// routed to the engine owning its client, over a bounded channel, so each client's transactions are applied in input order.
+/// The engines are then merged into bank in the order of the ranges (see Exchange::merge), the first one continuing from the
+/// state of bank. Records of a client no range owns are logged and skipped. Fails, leaving bank as it was, as
+/// process_transactions_from_csv_with() would on a record that can not be read, or when two engines know the same
+/// transaction id. The client ids of the input are routed as they are, so they must not be external ids to be mapped
+pub fn process_client_ranges_from_csv(
+    path: &str,
+    bank: &mut Exchange,
+    ranges: &[ClientRange],
+    options: &IngestOptions,
+) -> Result<(), Box<dyn Error>> {
+    if options.quarantine.is_some() || options.recover {
+        return Err("A quarantine or the recovery can not be combined with client ranges".into());
+    }
+    let mut reader = csv::Reader::from_path(path)?;
+    let headers = reader.headers()?.clone();
+    let client_column = headers.iter().position(|header| header.trim() == "client");
+    let ids = bank.config.client_ids.clone();
+
+    let engines: Vec<Result<Exchange, EngineError>> = thread::scope(|scope| {
+        let (senders, threads): (Vec<_>, Vec<_>) = ranges
+            .iter()
+            .enumerate()
+            .map(|(i, range)| {
+                let (sender, records) = mpsc::sync_channel::<csv::StringRecord>(BACKLOG);
+                let mut engine = if i == 0 {
+                    bank.fork()
+                } else {
+                    bank.empty_like()
+                };
+                let ingest = RecordReader::new(&headers, options);
+                let thread = scope.spawn(move || {
+                    for record in records {
+                        ingest
+                            .ingest(&mut engine, &record)
+                            .map_err(|e| range_error(range, e, &engine.config.client_ids))?;
+                    }
+                    Ok(engine)
+                });
+                (sender, thread)
+            })
+            .unzip();
+
+        let mut record = csv::StringRecord::new();
+        let routed = loop {
+            match reader.read_record(&mut record) {
+                Ok(false) => break Ok(()),
+                Ok(true) => match owner(ranges, &record, client_column) {
+                    //a closed channel is an engine that failed, its error is reported once it is joined
+                    Some(index) if senders[index].send(record.clone()).is_err() => break Ok(()),
+                    Some(_) => {}
+                    None if !options.quiet => eprintln!(
+                        "No client range owns client {}, skipping the record on line {}",
+                        client_of(&record, client_column)
+                            .map(|client| ids.label(client).to_string())
+                            .unwrap_or_default(),
+                        record.position().map_or(0, |position| position.line())
+                    ),
+                    None => {}
+                },
+                Err(e) => break Err(EngineError::from(e)),
+            }
+        };
+        drop(senders);
+        let engines = threads.into_iter().map(|thread| {
+            thread
+                .join()
+                .unwrap_or_else(|_| Err("Client range thread panicked".into()))
+        });
+        match routed {
+            Ok(()) => engines.collect(),
+            Err(e) => engines.chain(std::iter::once(Err(e))).collect(),
+        }
+    });
+
+    let mut engines = engines.into_iter();
+    let mut merged = match engines.next() {
+        Some(first) => first.map_err(|e| e as Box<dyn Error>)?,
+        None => return Ok(()),
+    };
+    for engine in engines {
+        merged.merge(engine.map_err(|e| e as Box<dyn Error>)?)?;
+    }
+    bank.commit(merged);
+    Ok(())
+}
+
+/// Split a CSV input between the engines of a cluster running as processes of their own (see the listen subcommand): the
+/// header is written to every route and each record to the route owning its client, as process_client_ranges_from_csv()
+/// routes them. Every route is flushed once the input is exhausted. Returns the number of records no route owns, which are
+/// skipped
+pub fn route_csv<R: io::Read, W: io::Write>(
+    input: R,
+    routes: &mut [(ClientRange, W)],
+) -> Result<u64, Box<dyn Error>> {
+    let mut reader = csv::Reader::from_reader(input);
+    let headers = reader.headers()?.clone();
+    let client_column = headers.iter().position(|header| header.trim() == "client");
+    let ranges: Vec<ClientRange> = routes.iter().map(|(range, _)| *range).collect();
+    let mut writers: Vec<csv::Writer<&mut W>> = routes
+        .iter_mut()
+        .map(|(_, route)| csv::Writer::from_writer(route))
+        .collect();
+    for writer in &mut writers {
+        writer.write_record(&headers)?;
+    }
+
+    let mut unrouted = 0;
+    let mut record = csv::StringRecord::new();
+    while reader.read_record(&mut record)? {
+        match owner(&ranges, &record, client_column) {
+            Some(index) => writers[index].write_record(&record)?,
+            None => unrouted += 1,
+        }
+    }
+    for writer in &mut writers {
+        writer.flush()?;
+    }
+    Ok(unrouted)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use crate::exchange::client_profile::AccountBalance;
+    use crate::exchange::transaction::Currency;
+    use crate::exchange::transaction::Money;
+
+    #[test]
+    fn it_should_process_every_client_range_on_its_own_engine() {
+        let input = std::env::temp_dir().join("payment_engine_client_ranges.csv");
+        let rows = "type,client,tx,amount\n\
+                    deposit,1,1,10.0\n\
+                    deposit,40000,2,5.0\n\
+                    dispute,1,1,\n\
+                    withdrawal,40000,3,2.0\n\
+                    deposit,2,4,1.0\n";
+        std::fs::write(&input, rows).unwrap();
+
+        let mut serial = Exchange::new();
+        crate::exchange::process_transactions_from_csv(input.to_str().unwrap(), &mut serial)
+            .unwrap();
+        let mut clustered = Exchange::new();
+        process_client_ranges_from_csv(
+            input.to_str().unwrap(),
+            &mut clustered,
+            &ClientRange::split(2),
+            &IngestOptions::default(),
+        )
+        .unwrap();
+        //the dispute positions are the engine's own, only the balances are compared
+        for client in [1, 2, 40000] {
+            assert_eq!(
+                serial.client(client).map(AccountBalance::from),
+                clustered.client(client).map(AccountBalance::from)
+            );
+        }
+        assert_eq!(Currency::str("10.0"), clustered.client(1).unwrap().held());
+        assert_eq!(5, clustered.submitted());
+
+        //client 40000 is owned by no range
+        let mut routes = [
+            (ClientRange::parse("0-1").unwrap(), Vec::new()),
+            (ClientRange::parse("2-2").unwrap(), Vec::new()),
+        ];
+        assert_eq!(2, route_csv(rows.as_bytes(), &mut routes).unwrap());
+        assert_eq!(
+            "type,client,tx,amount\ndeposit,1,1,10.0\ndispute,1,1,\n",
+            String::from_utf8(routes[0].1.clone()).unwrap()
+        );
+        assert_eq!(
+            "type,client,tx,amount\ndeposit,2,4,1.0\n",
+            String::from_utf8(routes[1].1.clone()).unwrap()
+        );
+
+        assert_eq!(
+            vec![
+                ClientRange {
+                    first: 0,
+                    last: 32767
+                },
+                ClientRange {
+                    first: 32768,
+                    last: 65535
+                }
+            ],
+            ClientRange::parse_list("2").unwrap()
+        );
+        assert_eq!(None, ClientRange::parse_list("0-10,10-20"));
+        assert_eq!(None, ClientRange::parse("5-1"));
+        std::fs::remove_file(&input).unwrap();
+    }
+
+    #[test]
+    fn it_should_leave_the_exchange_as_it_was_when_the_engines_can_not_be_merged() {
+        let input = std::env::temp_dir().join("payment_engine_client_ranges_conflict.csv");
+        //both engines accept tx 2, which the merge refuses
+        std::fs::write(
+            &input,
+            "type,client,tx,amount\n\
+             deposit,1,2,10.0\n\
+             deposit,40000,2,5.0\n",
+        )
+        .unwrap();
+        let mut bank = Exchange::new();
+        crate::exchange::process_transactions_from_reader_with(
+            "type,client,tx,amount\ndeposit,3,1,1.0\n".as_bytes(),
+            &mut bank,
+            &IngestOptions::default(),
+        )
+        .unwrap();
+        let options = IngestOptions {
+            quiet: true,
+            ..IngestOptions::default()
+        };
+
+        let result = process_client_ranges_from_csv(
+            input.to_str().unwrap(),
+            &mut bank,
+            &ClientRange::split(2),
+            &options,
+        );
+        assert_eq!(true, result.is_err());
+        assert_eq!(None, bank.client(1));
+        assert_eq!(None, bank.client(40000));
+        assert_eq!(Currency::str("1.0"), bank.client(3).unwrap().total());
+        assert_eq!(1, bank.submitted());
+
+        let recovering = IngestOptions {
+            recover: true,
+            ..options
+        };
+        assert_eq!(
+            "A quarantine or the recovery can not be combined with client ranges",
+            process_client_ranges_from_csv(
+                input.to_str().unwrap(),
+                &mut bank,
+                &ClientRange::split(2),
+                &recovering,
+            )
+            .unwrap_err()
+            .to_string()
+        );
+        std::fs::remove_file(&input).unwrap();
+    }
+
+    #[test]
+    fn it_should_split_the_whole_id_space_and_accept_ranges_in_any_order() {
+        assert_eq!(
+            vec![ClientRange {
+                first: 0,
+                last: ClientId::MAX
+            }],
+            ClientRange::split(0)
+        );
+        let finest = ClientRange::split(usize::MAX);
+        assert_eq!(ClientId::MAX as usize + 1, finest.len());
+        assert_eq!(true, finest.iter().all(|range| range.first == range.last));
+
+        let ranges = ClientRange::parse_list("1000-65535, 0-999").unwrap();
+        assert_eq!(
+            vec!["1000-65535", "0-999"],
+            ranges
+                .iter()
+                .map(ClientRange::to_string)
+                .collect::<Vec<String>>()
+        );
+        assert_eq!(true, ranges[1].contains(999));
+        assert_eq!(false, ranges[1].contains(1000));
+        assert_eq!(None, ClientRange::parse_list("0"));
+        assert_eq!(None, ClientRange::parse_list("0-10,x"));
+    }
+}
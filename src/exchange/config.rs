@@ -0,0 +1,390 @@
+use std::error::Error;
+use std::fmt;
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+use crate::exchange::alerts::ErrorBudget;
+use crate::exchange::anomaly::AnomalyDetection;
+use crate::exchange::dedup::DedupWindow;
+use crate::exchange::deferral::DeferralWindow;
+use crate::exchange::disputes::DisputeAging;
+use crate::exchange::pseudonym::ClientIds;
+use crate::exchange::pseudonym::Reason;
+use crate::exchange::trace::TraceSampling;
+use crate::exchange::transaction::ClientId;
+use crate::exchange::transaction::Currency;
+use crate::exchange::transaction::Money;
+use crate::exchange::transaction::Type;
+
+/// What happens when a transaction would take the exchange over one of its limits
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum LimitPolicy {
+    /// Stop processing the input with a LimitExceeded error
+    Abort,
+    /// Keep going: with an archive, the least recently active clients that can be archived are spilled to it with their
+    /// transactions (see Exchange::with_archive). Once there is nothing left to spill, or without an archive, transactions for
+    /// new clients are rejected, and past the retained transactions limit deposits and withdrawals are still applied but no
+    /// longer retained (so they can not be disputed anymore)
+    Degrade,
+}
+
+/// Hard limits protecting the process from running out of memory on a malicious or corrupt input. None means unbounded
+#[derive(Debug, PartialEq, Clone)]
+pub struct Limits {
+    pub max_clients: Option<usize>,
+    pub max_transactions: Option<usize>,
+    /// Cap on the approximate memory of the clients and retained transactions, in bytes (see Exchange::memory_usage). Once
+    /// reached it is handled as both the clients and the transactions limits
+    pub max_memory: Option<usize>,
+    pub on_exceeded: LimitPolicy,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_clients: None,
+            max_transactions: None,
+            max_memory: None,
+            on_exceeded: LimitPolicy::Abort,
+        }
+    }
+}
+
+/// What happens to transactions of a type the engine does not know: a type string parsed into Type::Custom that no
+/// CustomTransactionHandler is registered for
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum UnknownTypePolicy {
+    /// Hand custom types to their registered handler, transactions of a type without one are rejected
+    Dispatch,
+    /// Reject every custom type, registered handlers included
+    Reject,
+    /// Stop processing the input with an UnknownTransactionType error, as for a record that can not be read. Custom types
+    /// with a registered handler are still dispatched to it
+    Fail,
+}
+
+/// What happens to a deposit or withdrawal that would take a client over the caps of its KYC tier
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum CapPolicy {
+    Reject,
+    /// Apply it anyway and flag the account for review (ClientProfile::kyc_review)
+    Flag,
+}
+
+/// Cumulative caps of a KYC tier over the lifetime of an account. None means uncapped
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct TierCaps {
+    /// Sum of the accepted deposits
+    pub max_deposited: Option<Currency>,
+    /// Number of accepted deposits and withdrawals
+    pub max_transactions: Option<u64>,
+}
+
+impl TierCaps {
+    /// <max deposited>:<max transactions>, either side left empty when uncapped e.g. 1000:50 or :50
+    pub fn parse(caps: &str) -> Option<TierCaps> {
+        let (deposited, transactions) = caps.split_once(':')?;
+        Some(TierCaps {
+            max_deposited: match deposited {
+                "" => None,
+                amount => Some(amount.parse().ok()?),
+            },
+            max_transactions: match transactions {
+                "" => None,
+                count => Some(count.parse().ok()?),
+            },
+        })
+    }
+}
+
+/// Compliance guardrail: clients are capped by the tier they were verified to (ClientProfile::kyc_tier), tier n by tiers[n].
+/// Tiers past the end of the list are uncapped, so the default of no tiers caps nobody. Lifting a client to the next tier is a
+/// manual operation, see Exchange::set_kyc_tier
+#[derive(Debug, PartialEq, Clone)]
+pub struct ClientCaps {
+    pub tiers: Vec<TierCaps>,
+    pub on_exceeded: CapPolicy,
+}
+
+impl ClientCaps {
+    pub fn for_tier(&self, tier: u8) -> Option<&TierCaps> {
+        self.tiers.get(tier as usize)
+    }
+}
+
+impl Default for ClientCaps {
+    fn default() -> Self {
+        ClientCaps {
+            tiers: Vec::new(),
+            on_exceeded: CapPolicy::Reject,
+        }
+    }
+}
+
+/// Product tier of an account (ClientProfile::tier), overriding the engine-wide limits for the clients moved to it. Settings
+/// left None fall back to the default ones; per client overrides (EngineConfig::client_reserves, client_cooling_off) still
+/// take precedence over the tier's
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct AccountTier {
+    pub reserve: Option<Currency>,
+    pub cooling_off: Option<u64>,
+    /// Caps of the tier's clients, in place of the ones of their KYC tier (see ClientCaps)
+    pub caps: Option<TierCaps>,
+}
+
+impl AccountTier {
+    /// <name>:<setting>=<value>,... with the settings reserve, cooling_off, max_deposited and max_transactions e.g.
+    /// gold:reserve=0,max_deposited=50000. Caps not given are uncapped once the other one is
+    pub fn parse(tier: &str) -> Option<(String, AccountTier)> {
+        let (name, settings) = tier.split_once(':')?;
+        if name.is_empty() {
+            return None;
+        }
+        let mut account_tier = AccountTier::default();
+        for setting in settings.split(',') {
+            let (key, value) = setting.split_once('=')?;
+            match key.trim() {
+                "reserve" => {
+                    account_tier.reserve =
+                        Some(value.parse::<Currency>().ok()?.non_negative().ok()?)
+                }
+                "cooling_off" => account_tier.cooling_off = Some(value.parse().ok()?),
+                "max_deposited" => {
+                    account_tier
+                        .caps
+                        .get_or_insert_with(TierCaps::default)
+                        .max_deposited = Some(value.parse().ok()?)
+                }
+                "max_transactions" => {
+                    account_tier
+                        .caps
+                        .get_or_insert_with(TierCaps::default)
+                        .max_transactions = Some(value.parse().ok()?)
+                }
+                _ => return None,
+            }
+        }
+        Some((name.to_string(), account_tier))
+    }
+}
+
+/// Risk rule for high-risk clients: percent of each of their deposits is moved to held, and released back to available once
+/// the client has had the given number of further transactions accepted
+#[derive(Debug, PartialEq, Clone)]
+pub struct RollingReserve {
+    pub percent: Currency,
+    pub transactions: u64,
+}
+
+impl RollingReserve {
+    /// <percent>:<transactions> e.g. 10:5, the percent between 0 and 100
+    pub fn parse(rule: &str) -> Option<RollingReserve> {
+        let (percent, transactions) = rule.split_once(':')?;
+        let percent = percent.parse::<Currency>().ok()?.non_negative().ok()?;
+        if percent > Currency::from(100) {
+            return None;
+        }
+        Some(RollingReserve {
+            percent,
+            transactions: transactions.parse().ok()?,
+        })
+    }
+}
+
+/// Housekeeping of the sub-cent residues left on accounts nobody uses anymore: the balances of the accounts idle for idle_for
+/// submitted transactions whose available funds are below the threshold are written off, see Exchange::write_off_small_balances
+#[derive(Debug, PartialEq, Clone)]
+pub struct WriteOffRule {
+    pub below: Currency,
+    pub idle_for: u64,
+}
+
+impl WriteOffRule {
+    /// <below>:<idle transactions> e.g. 0.01:100000, below positive
+    pub fn parse(rule: &str) -> Option<WriteOffRule> {
+        let (below, idle_for) = rule.split_once(':')?;
+        let below = below.parse::<Currency>().ok()?;
+        if below.is_sign_negative() || below.is_zero() {
+            return None;
+        }
+        Some(WriteOffRule {
+            below,
+            idle_for: idle_for.parse().ok()?,
+        })
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct EngineConfig {
+    /// Deposits and withdrawals are retained so they can be disputed later on
+    pub retain_transactions: bool,
+    pub limits: Limits,
+    /// Chargebacks of disputed amounts above this are parked in a pending queue until approved or denied (Exchange::pending_chargebacks)
+    pub chargeback_approval_threshold: Option<Currency>,
+    /// Every this many submitted transactions, clients idle for as many are moved to the exchange's archive (Exchange::archive_inactive)
+    pub archive_idle: Option<u64>,
+    /// With an archive, at most about this many clients are kept in memory: past it the least recently active clients that can
+    /// be archived are, however recently they were active (Exchange::archive_least_recent). Clients holding funds can not be,
+    /// so it only bounds the memory as far as the clients beyond it are empty
+    pub archive_resident: Option<usize>,
+    /// A locked account accepts a resolve of the transaction whose chargeback locked it, which unlocks the account
+    pub unlock_on_resolve: bool,
+    /// Deposits to a locked account are accepted into held instead of being rejected, and released when the account is unlocked
+    pub hold_deposits_when_locked: bool,
+    /// Decimal places every submitted amount is rescaled to (rounded half away from zero), so amounts compare and print the
+    /// same however the input wrote them: 123.0 is processed as 123.0000. Under the minor-units feature amounts have 4
+    /// decimal places at most, so only a smaller scale changes them
+    pub amount_scale: u32,
+    /// Minimum balance every account keeps: withdrawals can not take available funds below it
+    pub reserve: Currency,
+    /// Per client reserves, overriding the default one
+    pub client_reserves: HashMap<ClientId, Currency>,
+    /// Rolling reserve held back from the deposits of the high-risk clients
+    pub rolling_reserve: Option<RollingReserve>,
+    pub high_risk_clients: HashSet<ClientId>,
+    /// Flag deposits deviating from the client's usual amounts and pace (see anomaly::AnomalyDetector) with
+    /// DomainEvent::AnomalyDetected
+    pub anomaly_detection: Option<AnomalyDetection>,
+    /// Freeze the account of a deposit flagged by the anomaly detection (see ClientProfile::frozen), until an operator thaws it
+    pub freeze_on_anomaly: bool,
+    /// Withdrawals of a client are rejected for this many of its accepted deposits and withdrawals after a chargeback or a
+    /// deposit flagged by the anomaly detection, even once the account is unlocked (see ClientProfile::cooling_off). 0 turns it off
+    pub cooling_off: u64,
+    /// Per client cooling-off periods, overriding the default one
+    pub client_cooling_off: HashMap<ClientId, u64>,
+    /// Absorb redeliveries of the recent transactions, see dedup::RecentTransactions
+    pub dedup_window: Option<DedupWindow>,
+    /// Park the dispute steps of a tx id the client does not have yet until it comes, instead of ignoring them, see
+    /// deferral::DeferredDisputes
+    pub defer_disputes: Option<DeferralWindow>,
+    /// Thresholds beyond which the run is reported as broken, see Exchange::check_error_budget()
+    pub error_budget: ErrorBudget,
+    /// Report the rows out of the order the engine expects, see ordering::OrderingReport and Stats::ordering()
+    pub track_ordering: bool,
+    /// Report the open disputes and their held funds per age bucket in the stats, see Exchange::dispute_aging
+    pub dispute_aging: Option<DisputeAging>,
+    /// Clients whose transactions are all rejected, see Exchange::set_blocked
+    pub blocklist: HashSet<ClientId>,
+    /// Transaction types disabled for the run, e.g. withdrawals and chargebacks for a credits only simulation. Their rows are
+    /// rejected as denied, custom types included
+    pub denied_types: Vec<Type>,
+    pub unknown_types: UnknownTypePolicy,
+    pub client_caps: ClientCaps,
+    /// Account tiers by name, see Exchange::set_tier. Accounts of no tier get the default limits
+    pub account_tiers: HashMap<String, AccountTier>,
+    /// Client ids in the output artifacts, raw, hashed with a salt (ClientIds::hashed) or mapped back to the external ids of
+    /// the input (ClientIds::Mapped)
+    pub client_ids: ClientIds,
+    /// Write the path of the sampled transactions through the engine to stderr, by trace id (see trace::TraceSampling). Every
+    /// transaction is given one while set, the submission number unless the input has a trace_id column
+    pub trace: Option<TraceSampling>,
+}
+
+impl EngineConfig {
+    /// The account tier of that name, None for the default tier or a tier that is not configured
+    pub fn account_tier(&self, tier: Option<&str>) -> Option<&AccountTier> {
+        tier.and_then(|tier| self.account_tiers.get(tier))
+    }
+
+    /// The reserve requirement applying to the client, in the given account tier
+    pub fn reserve_for(&self, client: ClientId, tier: Option<&str>) -> Currency {
+        self.client_reserves
+            .get(&client)
+            .copied()
+            .or_else(|| self.account_tier(tier).and_then(|tier| tier.reserve))
+            .unwrap_or(self.reserve)
+    }
+
+    /// The cooling-off period applying to the client in the given account tier, 0 when there is none
+    pub fn cooling_off_for(&self, client: ClientId, tier: Option<&str>) -> u64 {
+        self.client_cooling_off
+            .get(&client)
+            .copied()
+            .or_else(|| self.account_tier(tier).and_then(|tier| tier.cooling_off))
+            .unwrap_or(self.cooling_off)
+    }
+
+    /// The caps applying to a client of the given KYC and account tiers, see AccountTier::caps
+    pub fn caps_for(&self, kyc_tier: u8, tier: Option<&str>) -> Option<&TierCaps> {
+        self.account_tier(tier)
+            .and_then(|tier| tier.caps.as_ref())
+            .or_else(|| self.client_caps.for_tier(kyc_tier))
+    }
+
+    /// The rolling reserve applying to the deposits of the client, None unless it is high-risk
+    pub fn rolling_reserve_for(&self, client: ClientId) -> Option<&RollingReserve> {
+        self.rolling_reserve
+            .as_ref()
+            .filter(|_| self.high_risk_clients.contains(&client))
+    }
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        EngineConfig {
+            retain_transactions: true,
+            limits: Limits::default(),
+            chargeback_approval_threshold: None,
+            archive_idle: None,
+            archive_resident: None,
+            unlock_on_resolve: false,
+            hold_deposits_when_locked: false,
+            amount_scale: 4,
+            reserve: Currency::zero(),
+            client_reserves: HashMap::new(),
+            rolling_reserve: None,
+            high_risk_clients: HashSet::new(),
+            anomaly_detection: None,
+            freeze_on_anomaly: false,
+            cooling_off: 0,
+            client_cooling_off: HashMap::new(),
+            dedup_window: None,
+            defer_disputes: None,
+            error_budget: ErrorBudget::default(),
+            track_ordering: false,
+            dispute_aging: None,
+            blocklist: HashSet::new(),
+            denied_types: Vec::new(),
+            unknown_types: UnknownTypePolicy::Dispatch,
+            client_caps: ClientCaps::default(),
+            account_tiers: HashMap::new(),
+            client_ids: ClientIds::Raw,
+            trace: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct LimitExceeded(pub Reason);
+
+impl LimitExceeded {
+    pub fn new(reason: impl Into<Reason>) -> LimitExceeded {
+        LimitExceeded(reason.into())
+    }
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for LimitExceeded {}
+
+#[derive(Debug)]
+pub struct UnknownTransactionType(pub Reason);
+
+impl UnknownTransactionType {
+    pub fn new(reason: impl Into<Reason>) -> UnknownTransactionType {
+        UnknownTransactionType(reason.into())
+    }
+}
+
+impl fmt::Display for UnknownTransactionType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for UnknownTransactionType {}
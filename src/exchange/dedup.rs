@@ -0,0 +1,213 @@
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::exchange::transaction::ClientId;
+use crate::exchange::transaction::Transaction;
+use crate::exchange::transaction::TransactionId;
+use crate::exchange::transaction::Type;
+
+/// Settings of the de-duplication window: a transaction with the (client, tx, type) of one among the last size transactions,
+/// and with a ttl seen less than ttl ago, is a redelivery and is absorbed
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct DedupWindow {
+    pub size: usize,
+    pub ttl: Option<Duration>,
+}
+
+impl DedupWindow {
+    /// <size>[:<ttl in seconds>] e.g. 100000:300, the size positive
+    pub fn parse(rule: &str) -> Option<DedupWindow> {
+        let (size, ttl) = match rule.split_once(':') {
+            Some((size, ttl)) => (size, Some(Duration::from_secs(ttl.parse().ok()?))),
+            None => (rule, None),
+        };
+        let size: usize = size.parse().ok()?;
+        (size > 0).then_some(DedupWindow { size, ttl })
+    }
+}
+
+type Key = (ClientId, TransactionId, Type);
+
+/// The keys of the recent transactions, fed by the exchange with every transaction when EngineConfig::dedup_window is set.
+/// Unlike the engine's own checks (a deposit reusing a stored tx id, a replayed dispute step), which need the transactions
+/// retained and reject the repeats, the window holds at most size keys whatever the run length, and absorbs repeats without
+/// them counting as submitted or rejected. It is not part of checkpoints
+#[derive(Debug, Default, Clone)]
+pub struct RecentTransactions {
+    seen: HashSet<Key>,
+    /// Every key of seen once, oldest first, with when it was seen when the window has a ttl
+    order: VecDeque<(Key, Option<Instant>)>,
+}
+
+impl RecentTransactions {
+    pub fn new() -> RecentTransactions {
+        RecentTransactions::default()
+    }
+
+    /// Whether the transaction repeats one within the window. It is remembered otherwise, evicting the oldest keys beyond size
+    pub fn is_repeat(&mut self, window: &DedupWindow, transaction: &Transaction) -> bool {
+        let now = window.ttl.map(|_| Instant::now());
+        if let (Some(ttl), Some(now)) = (window.ttl, now) {
+            while let Some((key, _)) = self
+                .order
+                .front()
+                .filter(|(_, seen)| seen.is_some_and(|seen| now.duration_since(seen) >= ttl))
+            {
+                self.seen.remove(key);
+                self.order.pop_front();
+            }
+        }
+        let key = (
+            transaction.client,
+            transaction.tx,
+            transaction.tx_type.clone(),
+        );
+        if self.seen.contains(&key) {
+            return true;
+        }
+        self.seen.insert(key.clone());
+        self.order.push_back((key, now));
+        while self.order.len() > window.size {
+            if let Some((key, _)) = self.order.pop_front() {
+                self.seen.remove(&key);
+            }
+        }
+        false
+    }
+
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use std::collections::HashMap;
+
+    use crate::exchange::config::EngineConfig;
+    use crate::exchange::transaction::Currency;
+    use crate::exchange::transaction::Money;
+    use crate::exchange::Exchange;
+
+    #[test]
+    fn it_should_absorb_redeliveries_within_the_window() {
+        let mut exchange = Exchange::new().with_config(EngineConfig {
+            retain_transactions: false,
+            dedup_window: DedupWindow::parse("3"),
+            ..Default::default()
+        });
+        let row = |tx_type, tx| Transaction {
+            tx_type,
+            client: 1,
+            tx,
+            amount: Some(Currency::str("10.0")),
+            metadata: HashMap::new(),
+            under_dispute: false,
+            dispute_metadata: None,
+        };
+        for transaction in [
+            row(Type::Deposit, 1),
+            row(Type::Deposit, 1),
+            row(Type::Deposit, 2),
+            row(Type::Withdrawal, 2),
+            row(Type::Deposit, 3),
+            //tx 1 was evicted by the three after it
+            row(Type::Deposit, 1),
+            row(Type::Deposit, 3),
+        ] {
+            exchange.process_new_transaction(transaction).unwrap();
+        }
+        assert_eq!(
+            Currency::str("30.0"),
+            exchange.client(1).unwrap().available()
+        );
+        assert_eq!(5, exchange.submitted());
+        assert_eq!(2, exchange.stats().deduplicated());
+
+        let mut recent = RecentTransactions::new();
+        let window = DedupWindow {
+            size: 10,
+            ttl: Some(Duration::ZERO),
+        };
+        assert_eq!(false, recent.is_repeat(&window, &row(Type::Deposit, 1)));
+        assert_eq!(false, recent.is_repeat(&window, &row(Type::Deposit, 1)));
+        assert_eq!(1, recent.len());
+
+        assert_eq!(
+            Some(DedupWindow {
+                size: 100,
+                ttl: Some(Duration::from_secs(60))
+            }),
+            DedupWindow::parse("100:60")
+        );
+        assert_eq!(None, DedupWindow::parse("0"));
+    }
+
+    fn transaction(tx_type: Type, client: ClientId, tx: TransactionId) -> Transaction {
+        Transaction {
+            tx_type,
+            client,
+            tx,
+            amount: None,
+            metadata: HashMap::new(),
+            under_dispute: false,
+            dispute_metadata: None,
+        }
+    }
+
+    #[test]
+    fn it_should_key_the_window_on_the_client_tx_and_type() {
+        let window = DedupWindow::parse("10").unwrap();
+        let mut recent = RecentTransactions::new();
+        assert_eq!(true, recent.is_empty());
+        for (tx_type, client) in [
+            (Type::Deposit, 1),
+            (Type::Dispute, 1),
+            (Type::Deposit, 2),
+            (Type::Custom("rebate".to_string()), 1),
+        ] {
+            assert_eq!(
+                false,
+                recent.is_repeat(&window, &transaction(tx_type, client, 7))
+            );
+        }
+        assert_eq!(
+            true,
+            recent.is_repeat(&window, &transaction(Type::Dispute, 1, 7))
+        );
+        assert_eq!(4, recent.len());
+        assert_eq!(None, DedupWindow::parse("10:soon"));
+        assert_eq!(None, DedupWindow::parse("-1"));
+    }
+
+    #[test]
+    fn it_should_forget_the_keys_seen_longer_than_the_ttl_ago() {
+        let window = DedupWindow {
+            size: 10,
+            ttl: Some(Duration::from_millis(50)),
+        };
+        let mut recent = RecentTransactions::new();
+        let deposit = transaction(Type::Deposit, 1, 1);
+        assert_eq!(false, recent.is_repeat(&window, &deposit));
+        assert_eq!(true, recent.is_repeat(&window, &deposit));
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(
+            false,
+            recent.is_repeat(&window, &transaction(Type::Deposit, 1, 2))
+        );
+        //evicted by the lookup above, remembered again
+        assert_eq!(1, recent.len());
+        assert_eq!(false, recent.is_repeat(&window, &deposit));
+        assert_eq!(2, recent.len());
+    }
+}
@@ -0,0 +1,253 @@
+use std::collections::HashMap;
+use std::collections::VecDeque;
+
+use crate::exchange::transaction::ClientId;
+use crate::exchange::transaction::Transaction;
+use crate::exchange::transaction::TransactionId;
+
+/// Settings of the deferral of the dispute steps that come before their transaction: at most capacity rows are parked, the
+/// oldest given up on beyond it, and with a max age the ones parked that many submitted transactions ago are given up on too
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct DeferralWindow {
+    pub capacity: usize,
+    pub max_age: Option<u64>,
+}
+
+impl DeferralWindow {
+    /// <capacity>[:<max age in transactions>] e.g. 10000:50000, both positive
+    pub fn parse(rule: &str) -> Option<DeferralWindow> {
+        let (capacity, max_age) = match rule.split_once(':') {
+            Some((capacity, max_age)) => (capacity, Some(max_age.parse().ok()?)),
+            None => (rule, None),
+        };
+        let capacity: usize = capacity.parse().ok()?;
+        (capacity > 0 && max_age != Some(0)).then_some(DeferralWindow { capacity, max_age })
+    }
+}
+
+/// Disputes, resolves and chargebacks of a tx id the client does not have yet, parked when EngineConfig::defer_disputes is set
+/// instead of being ignored, and settled once the deposit or withdrawal they refer to is accepted. The rows given up on (beyond
+/// the capacity, past the max age, or still parked once the input is exhausted, see Exchange::expire_deferred) are settled as
+/// they are, so they are ignored as they would have been without the deferral. It is not part of checkpoints
+#[derive(Debug, Default, Clone)]
+pub struct DeferredDisputes {
+    /// Oldest first, with the number of transactions submitted when they were parked
+    parked: VecDeque<(Transaction, u64)>,
+    /// Number of rows parked per (client, tx id)
+    waiting: HashMap<(ClientId, TransactionId), usize>,
+}
+
+impl DeferredDisputes {
+    pub fn new() -> DeferredDisputes {
+        DeferredDisputes::default()
+    }
+
+    pub fn is_waiting(&self, client: ClientId, tx: TransactionId) -> bool {
+        self.waiting.contains_key(&(client, tx))
+    }
+
+    /// Park the transaction submitted at position submitted. Returns the oldest rows evicted beyond the capacity
+    pub fn park(
+        &mut self,
+        window: &DeferralWindow,
+        transaction: Transaction,
+        submitted: u64,
+    ) -> Vec<Transaction> {
+        *self
+            .waiting
+            .entry((transaction.client, transaction.tx))
+            .or_insert(0) += 1;
+        self.parked.push_back((transaction, submitted));
+        let mut evicted = Vec::new();
+        while self.parked.len() > window.capacity {
+            evicted.extend(self.pop_front());
+        }
+        evicted
+    }
+
+    /// The rows waiting for the client's tx id, in arrival order, no longer parked
+    pub fn take(&mut self, client: ClientId, tx: TransactionId) -> Vec<Transaction> {
+        if self.waiting.remove(&(client, tx)).is_none() {
+            return Vec::new();
+        }
+        let mut taken = Vec::new();
+        self.parked.retain(|(transaction, _)| {
+            let matches = transaction.client == client && transaction.tx == tx;
+            if matches {
+                taken.push(transaction.clone());
+            }
+            !matches
+        });
+        taken
+    }
+
+    /// The rows parked max age or more transactions before submitted, no longer parked
+    pub fn expire(&mut self, window: &DeferralWindow, submitted: u64) -> Vec<Transaction> {
+        let Some(max_age) = window.max_age else {
+            return Vec::new();
+        };
+        let mut expired = Vec::new();
+        while self
+            .parked
+            .front()
+            .is_some_and(|(_, parked)| submitted - parked >= max_age)
+        {
+            expired.extend(self.pop_front());
+        }
+        expired
+    }
+
+    /// Every parked row, oldest first
+    pub fn drain(&mut self) -> Vec<Transaction> {
+        self.waiting.clear();
+        self.parked.drain(..).map(|(transaction, _)| transaction).collect()
+    }
+
+    fn pop_front(&mut self) -> Option<Transaction> {
+        let (transaction, _) = self.parked.pop_front()?;
+        let key = (transaction.client, transaction.tx);
+        if let Some(count) = self.waiting.get_mut(&key) {
+            *count -= 1;
+            if *count == 0 {
+                self.waiting.remove(&key);
+            }
+        }
+        Some(transaction)
+    }
+
+    /// Append the rows parked by an exchange that processed another partition, see Exchange::merge(). Their positions are
+    /// shifted by the transactions submitted to this one before
+    pub(crate) fn merge(&mut self, other: DeferredDisputes, offset: u64) {
+        for (key, count) in other.waiting {
+            *self.waiting.entry(key).or_insert(0) += count;
+        }
+        self.parked.extend(
+            other
+                .parked
+                .into_iter()
+                .map(|(transaction, parked)| (transaction, parked + offset)),
+        );
+    }
+
+    pub fn len(&self) -> usize {
+        self.parked.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.parked.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use std::collections::HashMap;
+
+    use crate::exchange::config::EngineConfig;
+    use crate::exchange::transaction::Currency;
+    use crate::exchange::transaction::Money;
+    use crate::exchange::transaction::Type;
+    use crate::exchange::Exchange;
+
+    fn row(tx_type: Type, client: ClientId, tx: TransactionId) -> Transaction {
+        Transaction {
+            amount: matches!(tx_type, Type::Deposit).then(|| Currency::str("10.0")),
+            tx_type,
+            client,
+            tx,
+            metadata: HashMap::new(),
+            under_dispute: false,
+            dispute_metadata: None,
+        }
+    }
+
+    #[test]
+    fn it_should_settle_the_dispute_steps_once_their_transaction_comes() {
+        let mut exchange = Exchange::new().with_config(EngineConfig {
+            defer_disputes: DeferralWindow::parse("2:3"),
+            ..Default::default()
+        });
+        for transaction in [
+            row(Type::Dispute, 1, 1),
+            row(Type::Deposit, 1, 1),
+            row(Type::Chargeback, 1, 9),
+            row(Type::Deposit, 1, 2),
+            row(Type::Deposit, 1, 3),
+            //the chargeback of tx 9 was parked 3 transactions ago, it is given up on
+            row(Type::Deposit, 1, 4),
+            row(Type::Dispute, 2, 5),
+        ] {
+            let _ = exchange.process_new_transaction(transaction);
+        }
+        let client = exchange.client(1).unwrap();
+        assert_eq!(Currency::str("10.0"), client.held());
+        assert_eq!(Currency::str("30.0"), client.available());
+        assert_eq!(1, exchange.stats().deferred_expired());
+
+        assert_eq!(1, exchange.expire_deferred());
+        let stats = exchange.stats();
+        assert_eq!(0, stats.rejected());
+        assert_eq!(7, stats.processed());
+        assert_eq!(3, stats.deferred());
+        assert_eq!(2, stats.deferred_expired());
+        assert_eq!(7, exchange.submitted());
+
+        let window = DeferralWindow {
+            capacity: 1,
+            max_age: None,
+        };
+        let mut deferred = DeferredDisputes::new();
+        assert_eq!(0, deferred.park(&window, row(Type::Dispute, 1, 1), 1).len());
+        let evicted = deferred.park(&window, row(Type::Dispute, 1, 2), 2);
+        assert_eq!(vec![row(Type::Dispute, 1, 1)], evicted);
+        assert_eq!(false, deferred.is_waiting(1, 1));
+        assert_eq!(vec![row(Type::Dispute, 1, 2)], deferred.take(1, 2));
+        assert_eq!(true, deferred.is_empty());
+
+        assert_eq!(None, DeferralWindow::parse("0"));
+        assert_eq!(None, DeferralWindow::parse("10:0"));
+    }
+
+    #[test]
+    fn it_should_keep_a_tx_id_waiting_until_its_last_row_is_gone() {
+        let window = DeferralWindow::parse("2").unwrap();
+        let mut deferred = DeferredDisputes::new();
+        deferred.park(&window, row(Type::Dispute, 1, 1), 1);
+        deferred.park(&window, row(Type::Resolve, 1, 1), 2);
+        let evicted = deferred.park(&window, row(Type::Dispute, 2, 1), 3);
+        assert_eq!(vec![row(Type::Dispute, 1, 1)], evicted);
+        assert_eq!(true, deferred.is_waiting(1, 1));
+        assert_eq!(true, deferred.is_waiting(2, 1));
+
+        assert_eq!(vec![row(Type::Resolve, 1, 1)], deferred.take(1, 1));
+        assert_eq!(false, deferred.is_waiting(1, 1));
+        assert_eq!(Vec::<Transaction>::new(), deferred.take(1, 1));
+        assert_eq!(vec![row(Type::Dispute, 2, 1)], deferred.drain());
+        assert_eq!(false, deferred.is_waiting(2, 1));
+        assert_eq!(0, deferred.len());
+    }
+
+    #[test]
+    fn it_should_shift_the_positions_of_the_merged_rows() {
+        let window = DeferralWindow::parse("10:5").unwrap();
+        let mut deferred = DeferredDisputes::new();
+        deferred.park(&window, row(Type::Dispute, 1, 1), 1);
+        let mut other = DeferredDisputes::new();
+        other.park(&window, row(Type::Dispute, 2, 2), 1);
+        other.park(&window, row(Type::Chargeback, 2, 2), 3);
+
+        deferred.merge(other, 4);
+        assert_eq!(true, deferred.is_waiting(2, 2));
+        assert_eq!(Vec::<Transaction>::new(), deferred.expire(&window, 5));
+        //parked at 1, and at 1 + 4 in the other partition
+        assert_eq!(
+            vec![row(Type::Dispute, 1, 1), row(Type::Dispute, 2, 2)],
+            deferred.expire(&window, 10)
+        );
+        assert_eq!(true, deferred.is_waiting(2, 2));
+        assert_eq!(vec![row(Type::Chargeback, 2, 2)], deferred.take(2, 2));
+        assert_eq!(true, deferred.is_empty());
+    }
+}
@@ -0,0 +1,190 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+
+use crate::exchange::client_profile::AccountBalance;
+use crate::exchange::pseudonym::ClientIds;
+use crate::exchange::settlement::Baseline;
+use crate::exchange::transaction::ClientId;
+use crate::exchange::Exchange;
+
+pub const HEADER: &str =
+    "client,change,previous_available,previous_held,previous_total,available,held,total,locked";
+
+/// How an account differs from its balances in the baseline
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ChangeKind {
+    /// Not in the baseline
+    New,
+    /// Locked since the baseline, whether or not the balances changed too
+    Locked,
+    Unlocked,
+    /// Same lock state, different balances
+    Changed,
+    /// Only in the baseline, e.g. merged away or archived since
+    Removed,
+}
+
+impl fmt::Display for ChangeKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ChangeKind::New => "new",
+            ChangeKind::Locked => "locked",
+            ChangeKind::Unlocked => "unlocked",
+            ChangeKind::Changed => "changed",
+            ChangeKind::Removed => "removed",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct AccountDelta {
+    pub client: ClientId,
+    pub change: ChangeKind,
+    /// None for new accounts
+    pub previous: Option<AccountBalance>,
+    /// None for removed accounts
+    pub current: Option<AccountBalance>,
+}
+
+/// The accounts that differ from the baseline (the account summaries of a previous run), ordered by client id. Accounts with
+/// the same balances and lock state are left out
+pub fn diff(exchange: &Exchange, baseline: &Baseline) -> Vec<AccountDelta> {
+    let mut deltas: BTreeMap<ClientId, AccountDelta> = BTreeMap::new();
+    for client in exchange.accounts(&Default::default()) {
+        let current = AccountBalance::from(client);
+        let previous = baseline.get(&client.id());
+        let change = match previous {
+            None => ChangeKind::New,
+            Some(previous) if !previous.locked && current.locked => ChangeKind::Locked,
+            Some(previous) if previous.locked && !current.locked => ChangeKind::Unlocked,
+            Some(previous) if *previous != current => ChangeKind::Changed,
+            Some(_) => continue,
+        };
+        deltas.insert(
+            client.id(),
+            AccountDelta {
+                client: client.id(),
+                change,
+                previous: previous.cloned(),
+                current: Some(current),
+            },
+        );
+    }
+    for (client, previous) in baseline {
+        if exchange.client(*client).is_none() {
+            deltas.insert(
+                *client,
+                AccountDelta {
+                    client: *client,
+                    change: ChangeKind::Removed,
+                    previous: Some(previous.clone()),
+                    current: None,
+                },
+            );
+        }
+    }
+    deltas.into_values().collect()
+}
+
+pub fn write_delta<W: io::Write>(
+    mut writer: W,
+    deltas: &[AccountDelta],
+    ids: &ClientIds,
+) -> io::Result<()> {
+    let balances = |balance: &Option<AccountBalance>| match balance {
+        Some(balance) => format!(
+            "{:.4},{:.4},{:.4}",
+            balance.available, balance.held, balance.total
+        ),
+        None => ",,".to_string(),
+    };
+    writeln!(writer, "{}", HEADER)?;
+    for delta in deltas {
+        writeln!(
+            writer,
+            "{},{},{},{},{}",
+            ids.label(delta.client),
+            delta.change,
+            balances(&delta.previous),
+            balances(&delta.current),
+            delta
+                .current
+                .as_ref()
+                .map(|current| current.locked.to_string())
+                .unwrap_or_default()
+        )?;
+    }
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use std::collections::HashMap;
+
+    use crate::exchange::settlement::read_baseline;
+    use crate::exchange::transaction::Currency;
+    use crate::exchange::transaction::Money;
+    use crate::exchange::transaction::Transaction;
+    use crate::exchange::transaction::Type;
+
+    #[test]
+    fn it_should_report_new_changed_locked_and_removed_accounts() {
+        let baseline = read_baseline(
+            "client,available,held,total,locked\n1,10.0000,0.0000,10.0000,false\n2,5.0000,0.0000,5.0000,false\n3,1.0000,0.0000,1.0000,false\n4,2.0000,0.0000,2.0000,false\n"
+                .as_bytes(),
+        )
+        .unwrap();
+
+        let mut exchange = Exchange::new();
+        let row = |tx_type, client, tx, amount: Option<&str>| Transaction {
+            tx_type,
+            client,
+            tx,
+            amount: amount.map(Currency::str),
+            metadata: HashMap::new(),
+            under_dispute: false,
+            dispute_metadata: None,
+        };
+        for transaction in [
+            row(Type::Deposit, 1, 1, Some("10.0")),
+            row(Type::Deposit, 2, 2, Some("5.0")),
+            row(Type::Deposit, 3, 3, Some("1.0")),
+            row(Type::Deposit, 3, 4, Some("1.0")),
+            row(Type::Dispute, 2, 2, None),
+            row(Type::Chargeback, 2, 2, None),
+            row(Type::Deposit, 5, 5, Some("7.5")),
+        ] {
+            exchange.process_new_transaction(transaction).unwrap();
+        }
+
+        let deltas = diff(&exchange, &baseline);
+        assert_eq!(
+            vec![
+                (2, ChangeKind::Locked),
+                (3, ChangeKind::Changed),
+                (4, ChangeKind::Removed),
+                (5, ChangeKind::New)
+            ],
+            deltas
+                .iter()
+                .map(|delta| (delta.client, delta.change))
+                .collect::<Vec<_>>()
+        );
+
+        let mut output = Vec::new();
+        write_delta(&mut output, &deltas, &ClientIds::Raw).unwrap();
+        assert_eq!(
+            "client,change,previous_available,previous_held,previous_total,available,held,total,locked\n\
+             2,locked,5.0000,0.0000,5.0000,0.0000,0.0000,0.0000,true\n\
+             3,changed,1.0000,0.0000,1.0000,2.0000,0.0000,2.0000,false\n\
+             4,removed,2.0000,0.0000,2.0000,,,,\n\
+             5,new,,,,7.5000,0.0000,7.5000,false\n",
+            String::from_utf8(output).unwrap()
+        );
+    }
+}
@@ -0,0 +1,197 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io;
+
+use crate::exchange::client_profile::ProcessingError;
+use crate::exchange::pseudonym::ClientIds;
+use crate::exchange::pseudonym::Reason;
+use crate::exchange::transaction::ClientId;
+use crate::exchange::transaction::DisputeMetadata;
+use crate::exchange::transaction::Transaction;
+use crate::exchange::transaction::TransactionId;
+use crate::exchange::transaction::Type;
+use crate::exchange::Exchange;
+
+pub const REPORT_HEADER: [&str; 5] = ["tx", "client", "case_id", "outcome", "reason"];
+
+/// Row of the daily export of the dispute management system: the transaction it disputes with the evidence of its case. The
+/// client is optional, when given it must be the client of the transaction
+#[derive(Debug, Deserialize)]
+struct DisputeRow {
+    tx: TransactionId,
+    #[serde(default)]
+    client: Option<ClientId>,
+    #[serde(default)]
+    reason: Option<String>,
+    #[serde(default)]
+    case_id: Option<String>,
+    #[serde(default)]
+    note: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ImportOutcome {
+    /// Submitted as a dispute of the transaction
+    Imported,
+    /// No stored transaction with the id (of the client, when given)
+    Unmatched,
+    /// Submitted, but rejected by the exchange with this reason
+    Rejected(Reason),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct ImportedDispute {
+    pub tx: TransactionId,
+    /// Client of the matched transaction, or the one the row gave
+    pub client: Option<ClientId>,
+    pub case_id: Option<String>,
+    pub outcome: ImportOutcome,
+}
+
+/// What became of every row of an imported dispute file, in file order
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct DisputeImport {
+    pub disputes: Vec<ImportedDispute>,
+}
+
+impl DisputeImport {
+    /// The rows that did not match any known transaction
+    pub fn unmatched(&self) -> impl Iterator<Item = &ImportedDispute> {
+        self.disputes
+            .iter()
+            .filter(|dispute| dispute.outcome == ImportOutcome::Unmatched)
+    }
+
+    /// One row per imported dispute: tx,client,case_id,outcome,reason
+    pub fn write_report<W: io::Write>(&self, writer: W, ids: &ClientIds) -> Result<(), csv::Error> {
+        let mut writer = csv::Writer::from_writer(writer);
+        writer.write_record(REPORT_HEADER)?;
+        for dispute in &self.disputes {
+            let (outcome, reason) = match &dispute.outcome {
+                ImportOutcome::Imported => ("imported", String::new()),
+                ImportOutcome::Unmatched => ("unmatched", String::new()),
+                ImportOutcome::Rejected(reason) => ("rejected", ids.render(reason)),
+            };
+            writer.write_record([
+                dispute.tx.to_string(),
+                dispute
+                    .client
+                    .map(|client| ids.label(client).to_string())
+                    .unwrap_or_default(),
+                dispute.case_id.clone().unwrap_or_default(),
+                outcome.to_string(),
+                reason,
+            ])?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+impl Exchange {
+    /// Apply a dispute export (tx, and optionally client, reason, case_id and note columns) to the current state: every row
+    /// matching a stored transaction is submitted as a dispute of it carrying the case evidence, as a dispute row of the input
+    /// would be. Rows referencing no known transaction are reported as unmatched rather than submitted
+    pub fn import_disputes<R: io::Read>(&mut self, reader: R) -> Result<DisputeImport, csv::Error> {
+        let mut reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(reader);
+        let mut import = DisputeImport::default();
+        for row in reader.deserialize() {
+            let row: DisputeRow = row?;
+            let client = self
+                .find_transaction(row.tx)
+                .map(Transaction::client)
+                .filter(|client| row.client.is_none_or(|given| given == *client));
+            let Some(client) = client else {
+                import.disputes.push(ImportedDispute {
+                    tx: row.tx,
+                    client: row.client,
+                    case_id: row.case_id,
+                    outcome: ImportOutcome::Unmatched,
+                });
+                continue;
+            };
+            let metadata = DisputeMetadata::new(row.reason, row.case_id.clone(), row.note);
+            let dispute = Transaction {
+                tx_type: Type::Dispute,
+                client,
+                tx: row.tx,
+                amount: None,
+                metadata: HashMap::new(),
+                under_dispute: false,
+                dispute_metadata: Some(metadata).filter(|metadata| !metadata.is_empty()),
+            };
+            import.disputes.push(ImportedDispute {
+                tx: row.tx,
+                client: Some(client),
+                case_id: row.case_id,
+                outcome: match self.process_new_transaction(dispute) {
+                    Ok(()) => ImportOutcome::Imported,
+                    Err(ProcessingError(reason)) => ImportOutcome::Rejected(reason),
+                },
+            });
+        }
+        Ok(import)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use crate::exchange::transaction::Currency;
+    use crate::exchange::transaction::Money;
+
+    #[test]
+    fn it_should_import_disputes_and_report_unmatched_references() {
+        let mut exchange = Exchange::new();
+        for (client, tx) in [(1, 1), (2, 2)] {
+            exchange
+                .process_new_transaction(Transaction {
+                    tx_type: Type::Deposit,
+                    client,
+                    tx,
+                    amount: Some(Currency::str("3.0")),
+                    metadata: HashMap::new(),
+                    under_dispute: false,
+                    dispute_metadata: None,
+                })
+                .unwrap();
+        }
+
+        let export = "tx,client,reason,case_id\n1,,fraud,CASE-1\n9,,fraud,CASE-9\n2,1,,CASE-2\n1,1,fraud,CASE-1\n";
+        let import = exchange.import_disputes(export.as_bytes()).unwrap();
+        assert_eq!(Currency::str("3.0"), exchange.client(1).unwrap().held());
+        assert_eq!(
+            Some("CASE-1"),
+            exchange
+                .transaction(1, 1)
+                .unwrap()
+                .dispute_metadata()
+                .and_then(DisputeMetadata::case_id)
+        );
+        assert_eq!(Currency::zero(), exchange.client(2).unwrap().held());
+        assert_eq!(
+            vec![9, 2],
+            import
+                .unmatched()
+                .map(|dispute| dispute.tx)
+                .collect::<Vec<_>>()
+        );
+
+        let mut report = Vec::new();
+        import.write_report(&mut report, &ClientIds::Raw).unwrap();
+        assert_eq!(
+            concat!(
+                "tx,client,case_id,outcome,reason\n",
+                "1,1,CASE-1,imported,\n",
+                "9,,CASE-9,unmatched,\n",
+                "2,1,CASE-2,unmatched,\n",
+                "1,1,CASE-1,rejected,\"Duplicate dispute row. Rejecting transaction Dispute,1,1,None,false\"\n"
+            ),
+            String::from_utf8(report).unwrap()
+        );
+    }
+}
@@ -0,0 +1,341 @@
+use std::cmp::Reverse;
+use std::fmt;
+
+use crate::exchange::transaction::ClientId;
+use crate::exchange::transaction::Currency;
+use crate::exchange::transaction::Money;
+use crate::exchange::transaction::TransactionId;
+use crate::exchange::transaction::Type;
+use crate::exchange::Exchange;
+
+/// An open dispute as the back-office works it: the disputed transaction and how long the dispute has been open
+#[derive(Debug, PartialEq, Clone)]
+pub struct DisputeView {
+    pub client: ClientId,
+    pub tx: TransactionId,
+    /// Type of the disputed transaction
+    pub tx_type: Type,
+    pub amount: Option<Currency>,
+    /// Transactions submitted since the dispute was opened. The input has no timestamps, so age is measured in transactions.
+    /// None for disputes opened before the tracking, e.g. restored from an older checkpoint
+    pub age: Option<u64>,
+    /// Outcome score the DisputeScorer gave the dispute when it was opened, None without a scorer or when it gave none
+    pub score: Option<f64>,
+}
+
+/// What a DisputeScorer is told of a dispute being opened: the disputed transaction and the history of the client so far
+#[derive(Debug, PartialEq, Clone)]
+pub struct DisputeContext {
+    pub client: ClientId,
+    pub tx: TransactionId,
+    /// Type of the disputed transaction
+    pub tx_type: Type,
+    pub amount: Option<Currency>,
+    /// Lifetime number and sum of the accepted deposits, and number of accepted withdrawals
+    pub deposits: u64,
+    pub deposited: Currency,
+    pub withdrawals: u64,
+    /// Lifetime number and sum of the chargebacks of the client, before this dispute
+    pub chargebacks: u64,
+    pub charged_back: Currency,
+    /// Other disputes of the client still open
+    pub open_disputes: usize,
+    pub available: Currency,
+    pub held: Currency,
+}
+
+/// Risk scoring hook, registered with Exchange::with_dispute_scorer(). The scorer is called once per dispute, when it is opened,
+/// and the score it returns (e.g. the likelihood the dispute ends in a chargeback) is kept with the dispute until it is
+/// resolved or charged back, see DisputeView::score. None leaves the dispute unscored
+pub trait DisputeScorer: Send + Sync {
+    fn score(&self, dispute: &DisputeContext) -> Option<f64>;
+}
+
+/// Boundaries of the buckets of the dispute aging report, in transactions submitted since the dispute was opened (see
+/// DisputeView::age), e.g. 10000,50000 for the disputes younger than 10000 transactions, the ones from 10000 to 49999 and the
+/// ones of 50000 and more
+#[derive(Debug, PartialEq, Clone)]
+pub struct DisputeAging {
+    pub bounds: Vec<u64>,
+}
+
+impl DisputeAging {
+    /// Comma separated boundaries, positive and increasing
+    pub fn parse(bounds: &str) -> Option<DisputeAging> {
+        let bounds = bounds
+            .split(',')
+            .map(|bound| bound.trim().parse().ok())
+            .collect::<Option<Vec<u64>>>()?;
+        let increasing = bounds.first().is_some_and(|first| *first > 0)
+            && bounds.windows(2).all(|pair| pair[0] < pair[1]);
+        increasing.then_some(DisputeAging { bounds })
+    }
+
+    /// Label of the bucket starting at the ith boundary, 0 being the start of the first one: 0-9999, 10000-49999, 50000+
+    fn label(&self, i: usize) -> String {
+        let first = if i == 0 { 0 } else { self.bounds[i - 1] };
+        match self.bounds.get(i) {
+            Some(next) => format!("{}-{}", first, next - 1),
+            None => format!("{}+", first),
+        }
+    }
+}
+
+/// Open disputes of an age bucket and the funds they hold
+#[derive(Debug, PartialEq, Clone)]
+pub struct AgingBucket {
+    pub label: String,
+    pub disputes: u64,
+    pub held: Currency,
+}
+
+/// The open disputes and their held funds per age bucket, youngest first, the ones of unknown age (see DisputeView::age) in
+/// a last `unknown` bucket when there are some
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct AgingReport {
+    pub buckets: Vec<AgingBucket>,
+}
+
+impl fmt::Display for AgingReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            writeln!(f, "aging.{}.disputes: {}", bucket.label, bucket.disputes)?;
+            write!(f, "aging.{}.held: {:.4}", bucket.label, bucket.held)?;
+        }
+        Ok(())
+    }
+}
+
+impl Exchange {
+    /// The open disputes bucketed by age, see AgingReport. It is part of the stats when EngineConfig::dispute_aging is set
+    pub fn dispute_aging(&self, aging: &DisputeAging) -> AgingReport {
+        let mut buckets: Vec<AgingBucket> = (0..=aging.bounds.len())
+            .map(|i| AgingBucket {
+                label: aging.label(i),
+                disputes: 0,
+                held: Currency::zero(),
+            })
+            .collect();
+        let mut unknown = AgingBucket {
+            label: "unknown".to_string(),
+            disputes: 0,
+            held: Currency::zero(),
+        };
+        for dispute in self.open_disputes() {
+            let bucket = match dispute.age {
+                Some(age) => &mut buckets[aging.bounds.partition_point(|bound| *bound <= age)],
+                None => &mut unknown,
+            };
+            bucket.disputes += 1;
+            bucket.held += dispute.amount.unwrap_or_else(Currency::zero);
+        }
+        if unknown.disputes > 0 {
+            buckets.push(unknown);
+        }
+        AgingReport { buckets }
+    }
+
+    /// The dispute queue: every open dispute, oldest first (ties by client and transaction)
+    pub fn open_disputes(&self) -> Vec<DisputeView> {
+        let mut disputes: Vec<DisputeView> = self
+            .disputed_clients()
+            .flat_map(|client| {
+                client.disputed_transactions().map(move |t| DisputeView {
+                    client: t.client,
+                    tx: t.tx,
+                    tx_type: t.tx_type.clone(),
+                    amount: t.amount,
+                    age: client
+                        .dispute_opened_at(t.tx)
+                        .map(|opened_at| self.submitted - opened_at),
+                    score: client.dispute_score(t.tx),
+                })
+            })
+            .collect();
+        disputes.sort_unstable_by_key(|d| (d.age.is_none(), Reverse(d.age), d.client, d.tx));
+        disputes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use std::collections::HashMap;
+
+    use crate::exchange::config::EngineConfig;
+    use crate::exchange::transaction::Transaction;
+
+    fn row(
+        tx_type: Type,
+        client: ClientId,
+        tx: TransactionId,
+        amount: Option<&str>,
+    ) -> Transaction {
+        Transaction {
+            tx_type,
+            client,
+            tx,
+            amount: amount.map(Currency::str),
+            metadata: HashMap::new(),
+            under_dispute: false,
+            dispute_metadata: None,
+        }
+    }
+
+    #[test]
+    fn it_should_list_open_disputes_oldest_first() {
+        let mut exchange = Exchange::new().with_config(EngineConfig {
+            chargeback_approval_threshold: Some(Currency::str("50.0")),
+            ..EngineConfig::default()
+        });
+        for transaction in [
+            row(Type::Deposit, 1, 1, Some("10.0")),
+            row(Type::Deposit, 2, 2, Some("100.0")),
+            row(Type::Withdrawal, 1, 3, Some("2.0")),
+            row(Type::Dispute, 2, 2, None),
+            row(Type::Dispute, 1, 3, None),
+            row(Type::Dispute, 1, 1, None),
+            row(Type::Resolve, 1, 1, None),
+            //parked for approval, the dispute stays open
+            row(Type::Chargeback, 2, 2, None),
+        ] {
+            exchange.process_new_transaction(transaction).unwrap();
+        }
+
+        assert_eq!(
+            vec![
+                DisputeView {
+                    client: 2,
+                    tx: 2,
+                    tx_type: Type::Deposit,
+                    amount: Some(Currency::str("100.0")),
+                    age: Some(4),
+                    score: None,
+                },
+                DisputeView {
+                    client: 1,
+                    tx: 3,
+                    tx_type: Type::Withdrawal,
+                    amount: Some(Currency::str("2.0")),
+                    age: Some(3),
+                    score: None,
+                },
+            ],
+            exchange.open_disputes()
+        );
+
+        exchange.approve_chargeback(2, 2).unwrap();
+        assert_eq!(
+            vec![(1, 3)],
+            exchange
+                .open_disputes()
+                .iter()
+                .map(|d| (d.client, d.tx))
+                .collect::<Vec<(ClientId, TransactionId)>>()
+        );
+        //disputed again after the resolve, the age starts over
+        exchange
+            .process_new_transaction(row(Type::Dispute, 1, 1, None))
+            .unwrap();
+        assert_eq!(Some(0), exchange.open_disputes()[1].age);
+    }
+
+    #[test]
+    fn it_should_bucket_the_open_disputes_by_age() {
+        let aging = DisputeAging::parse("2,4").unwrap();
+        let mut exchange = Exchange::new().with_config(EngineConfig {
+            dispute_aging: Some(aging.clone()),
+            ..EngineConfig::default()
+        });
+        for transaction in [
+            row(Type::Deposit, 1, 1, Some("10.0")),
+            row(Type::Deposit, 1, 2, Some("20.0")),
+            row(Type::Dispute, 1, 1, None),
+            row(Type::Deposit, 2, 3, Some("5.0")),
+            row(Type::Deposit, 2, 4, Some("1.0")),
+            row(Type::Dispute, 1, 2, None),
+            row(Type::Dispute, 2, 3, None),
+            row(Type::Dispute, 2, 4, None),
+        ] {
+            exchange.process_new_transaction(transaction).unwrap();
+        }
+
+        let report = exchange.dispute_aging(&aging);
+        let buckets: Vec<(&str, u64, Currency)> = report
+            .buckets
+            .iter()
+            .map(|bucket| (bucket.label.as_str(), bucket.disputes, bucket.held))
+            .collect();
+        assert_eq!(
+            vec![
+                ("0-1", 2, Currency::str("6.0")),
+                ("2-3", 1, Currency::str("20.0")),
+                ("4+", 1, Currency::str("10.0")),
+            ],
+            buckets
+        );
+        assert_eq!(Some(report), exchange.stats().aging().cloned());
+        assert_eq!(
+            true,
+            exchange
+                .stats()
+                .to_string()
+                .contains("aging.4+.disputes: 1\naging.4+.held: 10.0000")
+        );
+        assert_eq!(None, DisputeAging::parse("0,10"));
+        assert_eq!(None, DisputeAging::parse("10,10"));
+    }
+
+    /// Scores with the history of the client, and gives none to the disputes of withdrawals
+    struct ChargebackHistory;
+
+    impl DisputeScorer for ChargebackHistory {
+        fn score(&self, dispute: &DisputeContext) -> Option<f64> {
+            (dispute.tx_type == Type::Deposit).then_some(
+                0.5 + dispute.chargebacks as f64 * 0.25 + dispute.open_disputes as f64 * 0.1,
+            )
+        }
+    }
+
+    #[test]
+    fn it_should_keep_the_score_of_each_dispute_while_it_is_open() {
+        let mut exchange = Exchange::new().with_dispute_scorer(ChargebackHistory);
+        for transaction in [
+            row(Type::Deposit, 1, 1, Some("10.0")),
+            row(Type::Deposit, 1, 2, Some("5.0")),
+            row(Type::Deposit, 1, 3, Some("1.0")),
+            row(Type::Withdrawal, 1, 4, Some("1.0")),
+            row(Type::Dispute, 1, 4, None),
+            row(Type::Dispute, 1, 2, None),
+            row(Type::Dispute, 1, 3, None),
+            row(Type::Resolve, 1, 3, None),
+        ] {
+            exchange.process_new_transaction(transaction).unwrap();
+        }
+
+        let scores: Vec<(TransactionId, Option<f64>)> = exchange
+            .open_disputes()
+            .iter()
+            .map(|d| (d.tx, d.score))
+            .collect();
+        assert_eq!(vec![(4, None), (2, Some(0.6))], scores);
+        assert_eq!(None, exchange.client(1).unwrap().dispute_score(3));
+
+        let mut written = Vec::new();
+        crate::exchange::output::write_disputes(
+            &mut written,
+            &exchange.open_disputes(),
+            &exchange.config().client_ids,
+        )
+        .unwrap();
+        assert_eq!(
+            "client,tx,type,amount,age,score\n1,4,withdrawal,1.0000,3,\n1,2,deposit,5.0000,2,0.6000\n",
+            String::from_utf8(written).unwrap()
+        );
+    }
+}
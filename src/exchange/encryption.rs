@@ -0,0 +1,395 @@
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+
+use chacha20poly1305::aead::stream::DecryptorBE32;
+use chacha20poly1305::aead::stream::EncryptorBE32;
+use chacha20poly1305::KeyInit;
+use chacha20poly1305::XChaCha20Poly1305;
+
+use crate::exchange::Exchange;
+
+/// Start of every encrypted file, followed by the 19 byte nonce prefix of the file and the sealed chunks. The last byte before
+/// the newline is the version of the format
+pub const MAGIC: &[u8; 8] = b"PEENC\x00\x02\n";
+/// Plaintext bytes per sealed chunk, each one followed by its 16 byte tag
+const CHUNK: usize = 64 * 1024;
+const TAG: usize = 16;
+/// The 24 byte XChaCha20 nonce, less the 4 byte chunk counter and the last chunk flag STREAM appends
+const NONCE: usize = 19;
+
+#[derive(Debug)]
+pub struct EncryptionError(pub String);
+
+impl fmt::Display for EncryptionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for EncryptionError {}
+
+/// 256 bit key, never printed
+#[derive(Clone, PartialEq)]
+pub struct Key([u8; 32]);
+
+impl Key {
+    pub fn new(bytes: [u8; 32]) -> Key {
+        Key(bytes)
+    }
+
+    /// 64 hexadecimal digits
+    pub fn from_hex(hex: &str) -> Result<Key, EncryptionError> {
+        let hex = hex.trim();
+        let invalid = || EncryptionError("A key is 64 hexadecimal digits".to_string());
+        if hex.len() != 64 || !hex.is_ascii() {
+            return Err(invalid());
+        }
+        let mut key = [0; 32];
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).map_err(|_| invalid())?;
+        }
+        Ok(Key(key))
+    }
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Key(..)")
+    }
+}
+
+/// Where the key comes from: an environment variable (EnvKey), or a KMS client an embedder implements this for
+pub trait KeyProvider {
+    fn key(&self) -> Result<Key, EncryptionError>;
+}
+
+/// Key given in hexadecimal in the named environment variable
+#[derive(Debug, PartialEq, Clone)]
+pub struct EnvKey(pub String);
+
+impl KeyProvider for EnvKey {
+    fn key(&self) -> Result<Key, EncryptionError> {
+        let hex = env::var(&self.0)
+            .map_err(|_| EncryptionError(format!("{} does not hold a key", self.0)))?;
+        Key::from_hex(&hex)
+    }
+}
+
+/// Seals state files (checkpoints, ledgers) with XChaCha20-Poly1305 through the STREAM construction of RustCrypto's
+/// chacha20poly1305. A file is MAGIC, a random nonce prefix and the plaintext in chunks of 64KiB, each sealed with the chunk
+/// counter and a last chunk flag in its nonce, so a tampered, reordered or truncated file is rejected when it is read back.
+/// The nonce prefix is long enough to be drawn at random for every file sealed under the same key
+#[derive(Debug, Clone)]
+pub struct Encryption {
+    key: Key,
+}
+
+impl Encryption {
+    pub fn new(key: Key) -> Encryption {
+        Encryption { key }
+    }
+
+    pub fn from_provider(provider: &dyn KeyProvider) -> Result<Encryption, EncryptionError> {
+        Ok(Encryption::new(provider.key()?))
+    }
+
+    /// Writes the header right away. The file is only complete once EncryptingWriter::finish() sealed the last chunk
+    pub fn writer<W: Write>(&self, mut inner: W) -> io::Result<EncryptingWriter<W>> {
+        let mut nonce = [0; NONCE];
+        getrandom::getrandom(&mut nonce).map_err(|e| io::Error::other(e.to_string()))?;
+        inner.write_all(MAGIC)?;
+        inner.write_all(&nonce)?;
+        Ok(EncryptingWriter {
+            inner,
+            stream: EncryptorBE32::from_aead(self.cipher(), (&nonce).into()),
+            plain: Vec::with_capacity(CHUNK + 1),
+        })
+    }
+
+    /// Reads the header right away, failing for files that are not encrypted
+    pub fn reader<R: Read>(&self, mut inner: R) -> io::Result<DecryptingReader<R>> {
+        let mut header = [0; MAGIC.len() + NONCE];
+        inner.read_exact(&mut header).map_err(|_| not_encrypted())?;
+        if header[..MAGIC.len() - 2] != MAGIC[..MAGIC.len() - 2] {
+            return Err(not_encrypted());
+        }
+        if header[..MAGIC.len()] != MAGIC[..] {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Encrypted file of version {} of the format, this build reads version {}",
+                    header[MAGIC.len() - 2],
+                    MAGIC[MAGIC.len() - 2]
+                ),
+            ));
+        }
+        let nonce: [u8; NONCE] = header[MAGIC.len()..].try_into().unwrap();
+        Ok(DecryptingReader {
+            inner,
+            stream: Some(DecryptorBE32::from_aead(self.cipher(), (&nonce).into())),
+            sealed: Vec::with_capacity(CHUNK + TAG + 1),
+            plain: Vec::new(),
+            read: 0,
+        })
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new((&self.key.0).into())
+    }
+}
+
+impl Exchange {
+    /// Seal checkpoints with the key, and only restore checkpoints sealed with it
+    pub fn with_encryption(mut self, encryption: Encryption) -> Exchange {
+        self.encryption = Some(encryption);
+        self
+    }
+
+    pub fn encryption(&self) -> Option<&Encryption> {
+        self.encryption.as_ref()
+    }
+}
+
+fn not_encrypted() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "Not an encrypted file")
+}
+
+fn tampered() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "Encrypted file was tampered with, truncated or sealed with another key",
+    )
+}
+
+fn sealing_failed() -> io::Error {
+    io::Error::other("Failed to seal a chunk, the file has too many")
+}
+
+pub struct EncryptingWriter<W: Write> {
+    inner: W,
+    stream: EncryptorBE32<XChaCha20Poly1305>,
+    plain: Vec<u8>,
+}
+
+impl<W: Write> EncryptingWriter<W> {
+    /// Seal the last chunk and flush, handing back the inner writer. Without it the file reads back as truncated
+    pub fn finish(mut self) -> io::Result<W> {
+        let sealed = self
+            .stream
+            .encrypt_last(self.plain.as_slice())
+            .map_err(|_| sealing_failed())?;
+        self.inner.write_all(&sealed)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for EncryptingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.plain.extend_from_slice(buf);
+        //a full chunk is only sealed once more data shows it is not the last one
+        while self.plain.len() > CHUNK {
+            let sealed = self
+                .stream
+                .encrypt_next(&self.plain[..CHUNK])
+                .map_err(|_| sealing_failed())?;
+            self.plain.drain(..CHUNK);
+            self.inner.write_all(&sealed)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Only hands out the plaintext of chunks whose tag checked out
+pub struct DecryptingReader<R: Read> {
+    inner: R,
+    /// None once the last chunk was opened
+    stream: Option<DecryptorBE32<XChaCha20Poly1305>>,
+    sealed: Vec<u8>,
+    plain: Vec<u8>,
+    read: usize,
+}
+
+impl<R: Read> DecryptingReader<R> {
+    fn open_chunk(&mut self) -> io::Result<()> {
+        //one byte past a full chunk tells whether it is the last one
+        let mut buffer = [0; 8 * 1024];
+        while self.sealed.len() <= CHUNK + TAG {
+            let read = self.inner.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            self.sealed.extend_from_slice(&buffer[..read]);
+        }
+        let last = self.sealed.len() <= CHUNK + TAG;
+        let len = if last { self.sealed.len() } else { CHUNK + TAG };
+        if len < TAG {
+            return Err(tampered());
+        }
+        let chunk = &self.sealed[..len];
+        let opened = match self.stream.as_mut() {
+            Some(stream) if !last => stream.decrypt_next(chunk),
+            Some(_) => self.stream.take().unwrap().decrypt_last(chunk),
+            None => return Err(tampered()),
+        };
+        self.plain = opened.map_err(|_| tampered())?;
+        self.sealed.drain(..len);
+        self.read = 0;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DecryptingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.read == self.plain.len() {
+            if self.stream.is_none() {
+                return Ok(0);
+            }
+            self.open_chunk()?;
+        }
+        let len = buf.len().min(self.plain.len() - self.read);
+        buf[..len].copy_from_slice(&self.plain[self.read..self.read + len]);
+        self.read += len;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn it_should_reject_tampered_and_truncated_files() {
+        let encryption = Encryption::new(Key::new([7; 32]));
+        //several chunks, the last one partial
+        let plaintext: Vec<u8> = (0..3 * CHUNK + 100).map(|i| (i % 251) as u8).collect();
+        let mut writer = encryption.writer(Vec::new()).unwrap();
+        for part in plaintext.chunks(1000) {
+            writer.write_all(part).unwrap();
+        }
+        let sealed = writer.finish().unwrap();
+        assert_eq!(
+            MAGIC.len() + NONCE + plaintext.len() + 4 * TAG,
+            sealed.len()
+        );
+
+        let read_back = |sealed: &[u8], encryption: &Encryption| -> io::Result<Vec<u8>> {
+            let mut plain = Vec::new();
+            encryption.reader(sealed)?.read_to_end(&mut plain)?;
+            Ok(plain)
+        };
+        assert_eq!(plaintext, read_back(&sealed, &encryption).unwrap());
+
+        let mut tampered = sealed.clone();
+        tampered[MAGIC.len() + NONCE + CHUNK + 10] ^= 1;
+        assert_eq!(true, read_back(&tampered, &encryption).is_err());
+        //dropping the last chunk would leave a file that ends on a chunk boundary
+        let truncated = &sealed[..MAGIC.len() + NONCE + 3 * (CHUNK + TAG)];
+        assert_eq!(true, read_back(truncated, &encryption).is_err());
+        let chunks = MAGIC.len() + NONCE;
+        let mut reordered = sealed.clone();
+        reordered[chunks..chunks + 2 * (CHUNK + TAG)].rotate_left(CHUNK + TAG);
+        assert_eq!(true, read_back(&reordered, &encryption).is_err());
+        let another_key = Encryption::new(Key::new([8; 32]));
+        assert_eq!(true, read_back(&sealed, &another_key).is_err());
+        assert_eq!(
+            true,
+            read_back(b"type,client,tx,amount\n", &encryption).is_err()
+        );
+
+        //an empty plaintext still has its last chunk
+        let empty = encryption.writer(Vec::new()).unwrap().finish().unwrap();
+        assert_eq!(Vec::<u8>::new(), read_back(&empty, &encryption).unwrap());
+    }
+
+    #[test]
+    fn it_should_open_the_stream_of_chacha20poly1305_and_refuse_other_versions() {
+        let key = Key::new([5; 32]);
+        let nonce = [9; NONCE];
+        let plaintext: Vec<u8> = (0..CHUNK + 10).map(|i| (i % 13) as u8).collect();
+        let mut stream =
+            EncryptorBE32::from_aead(XChaCha20Poly1305::new((&key.0).into()), (&nonce).into());
+        let mut sealed = [&MAGIC[..], &nonce].concat();
+        sealed.extend(stream.encrypt_next(&plaintext[..CHUNK]).unwrap());
+        sealed.extend(stream.encrypt_last(&plaintext[CHUNK..]).unwrap());
+
+        let encryption = Encryption::new(key);
+        let mut plain = Vec::new();
+        encryption
+            .reader(sealed.as_slice())
+            .unwrap()
+            .read_to_end(&mut plain)
+            .unwrap();
+        assert_eq!(plaintext, plain);
+
+        //sealed by a build of the first version of the format
+        sealed[MAGIC.len() - 2] = 1;
+        let error = encryption.reader(sealed.as_slice()).err().unwrap();
+        assert_eq!(
+            "Encrypted file of version 1 of the format, this build reads version 2",
+            error.to_string()
+        );
+    }
+
+    #[test]
+    fn it_should_only_restore_checkpoints_sealed_with_the_key() {
+        use crate::exchange::checkpoint::InputPosition;
+        use crate::exchange::transaction::Currency;
+        use crate::exchange::transaction::Money;
+        use std::fs;
+
+        let path = std::env::temp_dir().join("payment_engine_encrypted.checkpoint");
+        let encryption = Encryption::new(Key::new([3; 32]));
+        let mut bank = Exchange::new().with_encryption(encryption.clone());
+        crate::exchange::process_transactions_from_reader_with(
+            "type,client,tx,amount\ndeposit,1,1,10.0\n".as_bytes(),
+            &mut bank,
+            &Default::default(),
+        )
+        .unwrap();
+        let position = InputPosition {
+            byte: 38,
+            line: 3,
+            records: 2,
+        };
+        bank.write_checkpoint(&path, position).unwrap();
+        assert_eq!(MAGIC.to_vec(), fs::read(&path).unwrap()[..MAGIC.len()]);
+
+        let mut restored = Exchange::new().with_encryption(encryption.clone());
+        assert_eq!(position, restored.restore_checkpoint(&path).unwrap());
+        assert_eq!(Currency::str("10.0"), restored.client(1).unwrap().total());
+        assert_eq!(true, Exchange::new().restore_checkpoint(&path).is_err());
+
+        let mut sealed = fs::read(&path).unwrap();
+        let last = sealed.len() - 1;
+        sealed[last] ^= 1;
+        fs::write(&path, sealed).unwrap();
+        let mut restored = Exchange::new().with_encryption(encryption.clone());
+        assert_eq!(true, restored.restore_checkpoint(&path).is_err());
+
+        //a plain checkpoint put in place of the sealed one
+        Exchange::new().write_checkpoint(&path, position).unwrap();
+        let mut restored = Exchange::new().with_encryption(encryption);
+        assert_eq!(true, restored.restore_checkpoint(&path).is_err());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn it_should_read_the_key_in_hexadecimal() {
+        let key = Key::from_hex(&"0f".repeat(32)).unwrap();
+        assert_eq!(Key::new([0x0f; 32]), key);
+        assert_eq!("Key(..)", format!("{:?}", key));
+        assert_eq!(true, Key::from_hex("0f0f").is_err());
+        assert_eq!(true, Key::from_hex(&"zz".repeat(32)).is_err());
+    }
+}
@@ -0,0 +1,143 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io;
+
+use crate::exchange::transaction::ClientId;
+use crate::exchange::transaction::Transaction;
+use crate::exchange::transaction::TransactionId;
+
+/// Hook between the deserialization of a transaction and its processing, registered with Exchange::with_enricher(). The
+/// enricher can rewrite the transaction (normalize client ids, map external ids) or annotate it through its metadata, which
+/// is passed through to the ledger. The exchange works with the enriched transaction only
+pub trait TransactionEnricher: Send + Sync {
+    fn enrich(&self, transaction: Transaction) -> Transaction;
+}
+
+/// The default enricher, handing every transaction over untouched
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoEnrichment;
+
+impl TransactionEnricher for NoEnrichment {
+    fn enrich(&self, transaction: Transaction) -> Transaction {
+        transaction
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct MappingRow {
+    kind: String,
+    from: String,
+    to: String,
+}
+
+/// Enricher driven by a kind,from,to mapping file. client and tx rows map an external id to the one the engine uses, ids
+/// without a mapping are kept; metadata rows set the key from to the value to on every transaction, e.g. metadata,currency,EUR
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct MappingEnricher {
+    clients: HashMap<ClientId, ClientId>,
+    transactions: HashMap<TransactionId, TransactionId>,
+    metadata: Vec<(String, String)>,
+}
+
+impl MappingEnricher {
+    pub fn from_path(path: &str) -> Result<MappingEnricher, Box<dyn Error>> {
+        Self::from_reader(File::open(path)?)
+    }
+
+    pub fn from_reader<R: io::Read>(reader: R) -> Result<MappingEnricher, Box<dyn Error>> {
+        let mut reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(reader);
+        let mut enricher = MappingEnricher::default();
+        for row in reader.deserialize() {
+            let row: MappingRow = row?;
+            match row.kind.as_str() {
+                "client" => {
+                    enricher.clients.insert(row.from.parse()?, row.to.parse()?);
+                }
+                "tx" => {
+                    enricher
+                        .transactions
+                        .insert(row.from.parse()?, row.to.parse()?);
+                }
+                "metadata" => enricher.metadata.push((row.from, row.to)),
+                kind => return Err(format!("Unknown mapping kind {}", kind).into()),
+            }
+        }
+        Ok(enricher)
+    }
+}
+
+impl TransactionEnricher for MappingEnricher {
+    fn enrich(&self, mut transaction: Transaction) -> Transaction {
+        if let Some(client) = self.clients.get(&transaction.client()) {
+            transaction.set_client(*client);
+        }
+        if let Some(tx) = self.transactions.get(&transaction.tx()) {
+            transaction.set_tx(*tx);
+        }
+        for (key, value) in &self.metadata {
+            transaction
+                .metadata_mut()
+                .insert(key.clone(), value.clone());
+        }
+        transaction
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use crate::exchange::transaction::Currency;
+    use crate::exchange::transaction::Money;
+    use crate::exchange::transaction::Type;
+    use crate::exchange::Exchange;
+
+    #[test]
+    fn it_should_map_ids_and_inject_metadata_before_processing() {
+        let enricher = MappingEnricher::from_reader(
+            "kind,from,to\nclient,1001,1\ntx,90001,1\nmetadata,currency,EUR\n".as_bytes(),
+        )
+        .unwrap();
+        let mut exchange = Exchange::new().with_ledger().with_enricher(enricher);
+        for (tx_type, client, tx, amount) in [
+            (Type::Deposit, 1001, 90001, Some("5.0")),
+            (Type::Dispute, 1, 1, None),
+            (Type::Deposit, 2, 2, Some("1.0")),
+        ] {
+            exchange
+                .process_new_transaction(Transaction {
+                    tx_type,
+                    client,
+                    tx,
+                    amount: amount.map(Currency::str),
+                    metadata: HashMap::new(),
+                    under_dispute: false,
+                    dispute_metadata: None,
+                })
+                .unwrap();
+        }
+        assert_eq!(None, exchange.client(1001));
+        assert_eq!(Currency::str("5.0"), exchange.client(1).unwrap().held());
+        assert_eq!(
+            Currency::str("1.0"),
+            exchange.client(2).unwrap().available()
+        );
+        assert_eq!(
+            Some(&"EUR".to_string()),
+            exchange.ledger().unwrap().entries()[0]
+                .transaction()
+                .metadata()
+                .get("currency")
+        );
+
+        assert_eq!(
+            true,
+            MappingEnricher::from_reader("kind,from,to\naccount,1,2\n".as_bytes()).is_err()
+        );
+    }
+}
@@ -0,0 +1,224 @@
+use futures::channel::mpsc;
+
+use crate::exchange::alerts::BudgetBreach;
+use crate::exchange::anomaly::Anomaly;
+use crate::exchange::client_profile::AccountBalance;
+use crate::exchange::pseudonym::Reason;
+use crate::exchange::transaction::ClientId;
+use crate::exchange::transaction::Currency;
+use crate::exchange::transaction::Transaction;
+use crate::exchange::transaction::TransactionId;
+
+/// What a submitted transaction did to the exchange. Every submitted transaction is published as exactly one event carrying it,
+/// with the balances of the client right after, followed by AccountLocked when it locked the account and AnomalyDetected
+/// for each anomaly the deposit was flagged with. BudgetExceeded follows the transaction the error budget check found a
+/// threshold exceeded after
+#[derive(Debug, PartialEq, Clone)]
+pub enum DomainEvent {
+    DepositApplied {
+        transaction: Transaction,
+        balance: AccountBalance,
+    },
+    WithdrawalApplied {
+        transaction: Transaction,
+        balance: AccountBalance,
+    },
+    DisputeOpened {
+        transaction: Transaction,
+        balance: AccountBalance,
+    },
+    DisputeResolved {
+        transaction: Transaction,
+        balance: AccountBalance,
+    },
+    ChargebackApplied {
+        transaction: Transaction,
+        balance: AccountBalance,
+    },
+    /// Chargeback of a disputed amount above EngineConfig::chargeback_approval_threshold, waiting for approval with its dispute open
+    ChargebackParked {
+        transaction: Transaction,
+        amount: Currency,
+        balance: AccountBalance,
+    },
+    /// Transaction of a custom type applied by its handler
+    CustomApplied {
+        transaction: Transaction,
+        balance: AccountBalance,
+    },
+    /// Internal adjustment writing off a small balance, see Exchange::write_off_small_balances
+    BalanceWrittenOff {
+        transaction: Transaction,
+        balance: AccountBalance,
+    },
+    /// Accepted without changing anything: a dispute, resolve or chargeback of a transaction the client does not have, or
+    /// that is not in the state for it
+    TransactionIgnored {
+        transaction: Transaction,
+        balance: AccountBalance,
+    },
+    AccountLocked {
+        client: ClientId,
+        tx: TransactionId,
+    },
+    /// The anomalous deposit tx froze the account, see EngineConfig::freeze_on_anomaly
+    AccountFrozen {
+        client: ClientId,
+        tx: TransactionId,
+    },
+    /// Risk event of the anomaly detector, see EngineConfig::anomaly_detection
+    AnomalyDetected {
+        client: ClientId,
+        tx: TransactionId,
+        anomaly: Anomaly,
+    },
+    /// A threshold of EngineConfig::error_budget exceeded, raised once per threshold
+    BudgetExceeded {
+        breach: BudgetBreach,
+    },
+    /// The balances are None when the client does not exist, e.g. when rejected by the client limit
+    TransactionRejected {
+        transaction: Transaction,
+        reason: Reason,
+        balance: Option<AccountBalance>,
+    },
+}
+
+impl DomainEvent {
+    /// The submitted transaction the event is about, None for the events following it
+    pub fn transaction(&self) -> Option<&Transaction> {
+        match self {
+            DomainEvent::DepositApplied { transaction, .. }
+            | DomainEvent::WithdrawalApplied { transaction, .. }
+            | DomainEvent::DisputeOpened { transaction, .. }
+            | DomainEvent::DisputeResolved { transaction, .. }
+            | DomainEvent::ChargebackApplied { transaction, .. }
+            | DomainEvent::ChargebackParked { transaction, .. }
+            | DomainEvent::CustomApplied { transaction, .. }
+            | DomainEvent::BalanceWrittenOff { transaction, .. }
+            | DomainEvent::TransactionIgnored { transaction, .. }
+            | DomainEvent::TransactionRejected { transaction, .. } => Some(transaction),
+            DomainEvent::AccountLocked { .. }
+            | DomainEvent::AccountFrozen { .. }
+            | DomainEvent::AnomalyDetected { .. }
+            | DomainEvent::BudgetExceeded { .. } => None,
+        }
+    }
+
+    pub fn balance(&self) -> Option<&AccountBalance> {
+        match self {
+            DomainEvent::DepositApplied { balance, .. }
+            | DomainEvent::WithdrawalApplied { balance, .. }
+            | DomainEvent::DisputeOpened { balance, .. }
+            | DomainEvent::DisputeResolved { balance, .. }
+            | DomainEvent::ChargebackApplied { balance, .. }
+            | DomainEvent::ChargebackParked { balance, .. }
+            | DomainEvent::CustomApplied { balance, .. }
+            | DomainEvent::BalanceWrittenOff { balance, .. }
+            | DomainEvent::TransactionIgnored { balance, .. } => Some(balance),
+            DomainEvent::TransactionRejected { balance, .. } => balance.as_ref(),
+            DomainEvent::AccountLocked { .. }
+            | DomainEvent::AccountFrozen { .. }
+            | DomainEvent::AnomalyDetected { .. }
+            | DomainEvent::BudgetExceeded { .. } => None,
+        }
+    }
+
+    /// Why the transaction was rejected, None for every other event
+    pub fn rejection(&self) -> Option<&Reason> {
+        match self {
+            DomainEvent::TransactionRejected { reason, .. } => Some(reason),
+            _ => None,
+        }
+    }
+}
+
+/// The single channel the exchange publishes its events on, broadcasting each one to every subscriber. Subscribers that were
+/// dropped are forgotten on the next event.
+/// A clone, such as a fork of the exchange, does not broadcast: it buffers its events until it is replayed into the bus it
+/// was cloned from, so subscribers only see the transactions of a fork once it is committed
+#[derive(Debug, Default)]
+pub struct EventBus {
+    subscribers: Vec<mpsc::UnboundedSender<DomainEvent>>,
+    buffer: Option<Vec<DomainEvent>>,
+}
+
+impl EventBus {
+    /// A stream of every event published from now on
+    pub fn subscribe(&mut self) -> mpsc::UnboundedReceiver<DomainEvent> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    pub fn publish(&mut self, event: DomainEvent) {
+        if let Some(buffer) = &mut self.buffer {
+            buffer.push(event);
+            return;
+        }
+        self.subscribers
+            .retain(|subscriber| subscriber.unbounded_send(event.clone()).is_ok());
+    }
+
+    /// Whether anybody sees the published events, now or once they are replayed
+    pub(crate) fn is_observed(&self) -> bool {
+        self.buffer.is_some() || !self.subscribers.is_empty()
+    }
+
+    /// Broadcast the events buffered by a clone of this bus
+    pub fn replay(&mut self, clone: EventBus) {
+        for event in clone.buffer.into_iter().flatten() {
+            self.publish(event);
+        }
+    }
+}
+
+impl Clone for EventBus {
+    /// Without subscribers there is nobody to replay the events to, so nothing is buffered either
+    fn clone(&self) -> Self {
+        EventBus {
+            subscribers: Vec::new(),
+            buffer: self.is_observed().then(Vec::new),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use futures::StreamExt;
+
+    fn locked(client: ClientId) -> DomainEvent {
+        DomainEvent::AccountLocked { client, tx: 1 }
+    }
+
+    #[test]
+    fn it_should_broadcast_events_to_every_subscriber() {
+        let mut bus = EventBus::default();
+        let first = bus.subscribe();
+        let second = bus.subscribe();
+        bus.publish(locked(1));
+        drop(second);
+        bus.publish(locked(2));
+        assert_eq!(1, bus.subscribers.len());
+        drop(bus);
+        assert_eq!(
+            vec![locked(1), locked(2)],
+            futures::executor::block_on(first.collect::<Vec<_>>())
+        );
+    }
+
+    #[test]
+    fn it_should_buffer_the_events_of_a_clone_until_replayed() {
+        let mut bus = EventBus::default();
+        let mut events = bus.subscribe();
+        let mut clone = bus.clone();
+        clone.publish(locked(1));
+        assert_eq!(true, events.try_recv().is_err());
+        bus.replay(clone);
+        assert_eq!(Some(locked(1)), events.try_recv().ok());
+        assert_eq!(None, EventBus::default().clone().buffer);
+    }
+}
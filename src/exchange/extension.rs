@@ -0,0 +1,178 @@
+use crate::exchange::client_profile::ProcessingError;
+use crate::exchange::transaction::Transaction;
+
+pub use crate::exchange::client_profile::BalanceMutator;
+
+/// Handler of a bespoke transaction type (loyalty credit, manual adjustment, ..), registered with Exchange::with_handler() for the
+/// type string it handles. Handlers only see the account through a BalanceMutator, so they can not break the invariants of ClientProfile
+pub trait CustomTransactionHandler: Send + Sync {
+    fn handle(
+        &self,
+        transaction: &Transaction,
+        account: &mut BalanceMutator,
+    ) -> Result<(), ProcessingError>;
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use std::collections::HashMap;
+
+    use crate::exchange::config::EngineConfig;
+    use crate::exchange::config::UnknownTypePolicy;
+    use crate::exchange::pseudonym::ClientIds;
+    use crate::exchange::transaction::Currency;
+    use crate::exchange::transaction::Money;
+    use crate::exchange::transaction::Type;
+    use crate::exchange::Exchange;
+
+    /// Credits the amount plus a 10% bonus
+    struct LoyaltyCredit;
+
+    impl CustomTransactionHandler for LoyaltyCredit {
+        fn handle(
+            &self,
+            transaction: &Transaction,
+            account: &mut BalanceMutator,
+        ) -> Result<(), ProcessingError> {
+            let amount = transaction.amount().unwrap_or_default();
+            account.credit(amount + amount / Currency::from(10))
+        }
+    }
+
+    /// Holds the amount back and debits it, failing halfway through when there is not enough left
+    struct ManualAdjustment;
+
+    impl CustomTransactionHandler for ManualAdjustment {
+        fn handle(
+            &self,
+            transaction: &Transaction,
+            account: &mut BalanceMutator,
+        ) -> Result<(), ProcessingError> {
+            let amount = transaction.amount().unwrap_or_default();
+            account.hold(amount)?;
+            account.release(amount)?;
+            account.debit(amount * Currency::from(2))
+        }
+    }
+
+    fn custom(name: &str, tx: u32, amount: &str) -> Transaction {
+        Transaction {
+            tx_type: Type::Custom(name.to_string()),
+            client: 1,
+            tx,
+            amount: Some(Currency::str(amount)),
+            metadata: HashMap::new(),
+            under_dispute: false,
+            dispute_metadata: None,
+        }
+    }
+
+    #[test]
+    fn it_should_dispatch_unknown_types_to_the_registered_handler() {
+        let mut exchange = Exchange::new()
+            .with_handler("loyalty", LoyaltyCredit)
+            .with_handler("adjustment", ManualAdjustment);
+
+        exchange
+            .process_new_transaction(custom("loyalty", 1, "10.0"))
+            .unwrap();
+        assert_eq!(
+            Currency::str("11.0"),
+            exchange.client(1).unwrap().available()
+        );
+
+        //the debit fails after the hold and release went through, none of it is kept
+        assert_eq!(
+            true,
+            exchange
+                .process_new_transaction(custom("adjustment", 2, "6.0"))
+                .is_err()
+        );
+        let client = exchange.client(1).unwrap();
+        assert_eq!(Currency::str("11.0"), client.available());
+        assert_eq!(Currency::str("11.0"), client.total());
+        assert_eq!(Currency::zero(), client.held());
+
+        assert_eq!(
+            true,
+            exchange
+                .process_new_transaction(custom("unregistered", 3, "1.0"))
+                .is_err()
+        );
+        assert_eq!(0, exchange.client(1).unwrap().transaction_count());
+    }
+
+    #[test]
+    fn it_should_read_unknown_types_from_csv_without_aborting() {
+        let path = std::env::temp_dir().join("payment_engine_custom_types.csv");
+        std::fs::write(
+            &path,
+            "type,client,tx,amount\nloyalty,1,1,10.0\nunregistered,1,2,5.0\ndeposit,1,3,1.0\n",
+        )
+        .unwrap();
+
+        let mut exchange = Exchange::new().with_handler("loyalty", LoyaltyCredit);
+        crate::exchange::process_transactions_from_csv(path.to_str().unwrap(), &mut exchange)
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(Currency::str("12.0"), exchange.client(1).unwrap().total());
+        assert_eq!(1, exchange.stats().rejected());
+        assert_eq!(1, exchange.stats().unknown("unregistered"));
+        assert_eq!(0, exchange.stats().unknown("loyalty"));
+    }
+
+    #[test]
+    fn it_should_reject_or_fail_on_unknown_types_according_to_the_policy() {
+        let path = std::env::temp_dir().join("payment_engine_unknown_types.csv");
+        std::fs::write(
+            &path,
+            "type,client,tx,amount\nloyalty,1,1,10.0\nunregistered,1,2,5.0\ndeposit,1,3,1.0\n",
+        )
+        .unwrap();
+        let with_policy = |unknown_types| {
+            Exchange::new()
+                .with_config(EngineConfig {
+                    unknown_types,
+                    ..EngineConfig::default()
+                })
+                .with_handler("loyalty", LoyaltyCredit)
+        };
+
+        let mut rejecting = with_policy(UnknownTypePolicy::Reject).with_ledger();
+        crate::exchange::process_transactions_from_csv(path.to_str().unwrap(), &mut rejecting)
+            .unwrap();
+        assert_eq!(Currency::str("1.0"), rejecting.client(1).unwrap().total());
+        assert_eq!(1, rejecting.stats().unknown("loyalty"));
+        assert_eq!(1, rejecting.stats().unknown("unregistered"));
+        let mut rejects = Vec::new();
+        rejecting
+            .ledger()
+            .unwrap()
+            .write_rejects(&mut rejects, &ClientIds::Raw)
+            .unwrap();
+        assert_eq!(
+            2,
+            String::from_utf8(rejects)
+                .unwrap()
+                .matches("Unknown transaction type")
+                .count()
+        );
+
+        //the registered type still goes through, the input stops at the unregistered one
+        let mut failing = with_policy(UnknownTypePolicy::Fail);
+        let e =
+            crate::exchange::process_transactions_from_csv(path.to_str().unwrap(), &mut failing)
+                .unwrap_err();
+        assert_eq!(
+            true,
+            e.to_string()
+                .starts_with("Unknown transaction type unregistered")
+        );
+        assert_eq!(Currency::str("11.0"), failing.client(1).unwrap().total());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
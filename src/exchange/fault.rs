@@ -0,0 +1,228 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::exchange::Exchange;
+
+/// Which faults to inject and how often, each one as a one in n chance. The same seed and sequence of calls always injects
+/// the same faults, so a failing resilience run can be replayed
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct FaultPlan {
+    pub seed: u64,
+    /// Fail storing an ingested record, before it is applied, the way a crash between two records would
+    pub store_one_in: Option<u64>,
+    /// Fail a write to a checkpoint or a FaultyWriter
+    pub write_one_in: Option<u64>,
+    /// Sleep for delay before applying an ingested record
+    pub delay_one_in: Option<u64>,
+    pub delay: Duration,
+}
+
+#[derive(Debug)]
+pub struct InjectedFault(pub String);
+
+impl fmt::Display for InjectedFault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for InjectedFault {}
+
+/// Draws the faults of a plan from a deterministic xorshift. Clones (e.g. of a forked exchange) share the sequence
+#[derive(Debug, Clone)]
+pub struct FaultInjector {
+    plan: FaultPlan,
+    state: Arc<AtomicU64>,
+    injected: Arc<AtomicU64>,
+}
+
+impl FaultInjector {
+    pub fn new(plan: FaultPlan) -> FaultInjector {
+        //xorshift never leaves zero
+        let seed = plan.seed.max(1);
+        FaultInjector {
+            plan,
+            state: Arc::new(AtomicU64::new(seed)),
+            injected: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Number of faults injected so far, delays included
+    pub fn injected(&self) -> u64 {
+        self.injected.load(Ordering::Relaxed)
+    }
+
+    fn roll(&self, one_in: Option<u64>) -> bool {
+        let Some(one_in) = one_in.filter(|n| *n > 0) else {
+            return false;
+        };
+        let mut x = self.state.load(Ordering::Relaxed);
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.state.store(x, Ordering::Relaxed);
+        let hit = x.is_multiple_of(one_in);
+        if hit {
+            self.injected.fetch_add(1, Ordering::Relaxed);
+        }
+        hit
+    }
+
+    pub fn before_store(&self) -> Result<(), InjectedFault> {
+        if self.roll(self.plan.delay_one_in) {
+            thread::sleep(self.plan.delay);
+        }
+        if self.roll(self.plan.store_one_in) {
+            return Err(InjectedFault(
+                "Injected transaction store failure".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn before_write(&self) -> io::Result<()> {
+        if self.roll(self.plan.write_one_in) {
+            return Err(io::Error::other("Injected write failure"));
+        }
+        Ok(())
+    }
+}
+
+/// Writer failing writes according to the write faults of its injector
+pub struct FaultyWriter<W: io::Write> {
+    inner: W,
+    faults: FaultInjector,
+}
+
+impl<W: io::Write> FaultyWriter<W> {
+    pub fn new(inner: W, faults: FaultInjector) -> FaultyWriter<W> {
+        FaultyWriter { inner, faults }
+    }
+}
+
+impl<W: io::Write> io::Write for FaultyWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.faults.before_write()?;
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Exchange {
+    /// Inject the faults of the plan while ingesting and checkpointing, see FaultPlan
+    pub fn with_faults(mut self, plan: FaultPlan) -> Exchange {
+        self.faults = Some(FaultInjector::new(plan));
+        self
+    }
+
+    pub fn faults(&self) -> Option<&FaultInjector> {
+        self.faults.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use std::fmt::Write;
+    use std::fs;
+
+    use crate::exchange::checkpoint::process_transactions_from_csv_with_checkpoints;
+    use crate::exchange::checkpoint::CheckpointOptions;
+    use crate::exchange::IngestOptions;
+
+    #[test]
+    fn it_should_replay_the_same_faults_for_the_same_seed() {
+        let plan = FaultPlan {
+            seed: 7,
+            store_one_in: Some(3),
+            ..FaultPlan::default()
+        };
+        let outcomes = |faults: FaultInjector| -> Vec<bool> {
+            (0..50).map(|_| faults.before_store().is_err()).collect()
+        };
+        let first = outcomes(FaultInjector::new(plan.clone()));
+        assert_eq!(first, outcomes(FaultInjector::new(plan)));
+        assert_eq!(true, first.contains(&true) && first.contains(&false));
+    }
+
+    #[test]
+    fn it_should_recover_from_injected_faults_by_resuming_from_checkpoints() {
+        let input = std::env::temp_dir().join("payment_engine_faults_input.csv");
+        let checkpoint_path = std::env::temp_dir().join("payment_engine_faults.checkpoint");
+        let _ = fs::remove_file(&checkpoint_path);
+        let mut rows = String::from("type,client,tx,amount\n");
+        for tx in 1..=300u32 {
+            match tx % 10 {
+                7 => writeln!(rows, "dispute,{},{},", tx % 5, tx - 5),
+                9 => writeln!(rows, "resolve,{},{},", (tx - 7) % 5, tx - 7),
+                3 | 6 => writeln!(rows, "withdrawal,{},{},{}.5", tx % 5, tx, tx % 4),
+                _ => writeln!(rows, "deposit,{},{},{}.25", tx % 5, tx, tx % 7),
+            }
+            .unwrap();
+        }
+        fs::write(&input, rows).unwrap();
+        let quiet = IngestOptions {
+            quiet: true,
+            ..IngestOptions::default()
+        };
+        let checkpoint = CheckpointOptions {
+            path: checkpoint_path.clone(),
+            every: 10,
+        };
+
+        let mut uninterrupted = Exchange::new();
+        crate::exchange::process_transactions_from_csv_with(
+            input.to_str().unwrap(),
+            &mut uninterrupted,
+            &quiet,
+        )
+        .unwrap();
+
+        //every attempt is a process restarted from scratch, resuming from the last checkpoint that made it to disk
+        let mut injected = 0;
+        let mut recovered = None;
+        for attempt in 0..200 {
+            let mut bank = Exchange::new().with_faults(FaultPlan {
+                seed: 0x5eed + attempt,
+                store_one_in: Some(40),
+                write_one_in: Some(8),
+                delay_one_in: Some(100),
+                delay: Duration::from_millis(1),
+            });
+            let result = process_transactions_from_csv_with_checkpoints(
+                input.to_str().unwrap(),
+                &mut bank,
+                &quiet,
+                &checkpoint,
+                true,
+            );
+            injected += bank.faults().unwrap().injected();
+            if result.is_ok() {
+                recovered = Some(bank);
+                break;
+            }
+        }
+        let recovered = recovered.expect("never got past the injected faults");
+
+        assert_eq!(true, injected > 0);
+        for client in 0..5 {
+            assert_eq!(uninterrupted.client(client), recovered.client(client));
+        }
+        assert_eq!(uninterrupted.submitted(), recovered.submitted());
+
+        fs::remove_file(&input).unwrap();
+        fs::remove_file(&checkpoint_path).unwrap();
+        let _ = fs::remove_file(checkpoint_path.with_extension("partial"));
+    }
+}
@@ -0,0 +1,196 @@
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+
+use crate::exchange::ledger::Ledger;
+use crate::exchange::pseudonym::ClientIds;
+use crate::exchange::transaction::ClientId;
+use crate::exchange::transaction::Currency;
+use crate::exchange::transaction::Money;
+use crate::exchange::transaction::TransactionId;
+use crate::exchange::transaction::Type;
+
+pub const HEADER: &str = "client,disputes,float,still_held";
+
+/// The float of one client, or of all of them when client is None: the funds its disputes held multiplied by how long they
+/// held them. The input has no timestamps, so durations are measured in transactions submitted between a dispute and its
+/// resolve or chargeback, and the float is in amount x transactions
+#[derive(Debug, PartialEq, Clone)]
+pub struct FloatLine {
+    pub client: Option<ClientId>,
+    /// Disputes that held funds, closed or still open
+    pub disputes: u64,
+    pub float: Currency,
+    /// Funds still held by the disputes open at the end of the ledger, which count until then
+    pub still_held: Currency,
+}
+
+impl FloatLine {
+    fn new(client: Option<ClientId>) -> FloatLine {
+        FloatLine {
+            client,
+            disputes: 0,
+            float: Currency::zero(),
+            still_held: Currency::zero(),
+        }
+    }
+
+    fn amounts(&self) -> String {
+        format!(
+            ",{},{:.4},{:.4}",
+            self.disputes, self.float, self.still_held
+        )
+    }
+
+    fn add(&mut self, other: &FloatLine) {
+        self.disputes += other.disputes;
+        self.float += other.float;
+        self.still_held += other.still_held;
+    }
+
+    /// A dispute that held amount from seq opened to seq closed
+    fn held(&mut self, amount: Currency, opened: u64, closed: u64) {
+        self.disputes += 1;
+        self.float += amount * Currency::from(closed - opened);
+    }
+}
+
+impl fmt::Display for FloatLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.client {
+            Some(client) => write!(f, "{}", client)?,
+            None => write!(f, "TOTAL")?,
+        }
+        write!(f, "{}", self.amounts())
+    }
+}
+
+/// Per-client float lines of the clients with disputes that held funds, ordered by client id, followed by the aggregate line.
+/// A dispute holds funds from the entry where the held funds of its client went up to the resolve or chargeback where they went
+/// down again, so the disputes ignored or rejected do not count, and the ones still open count until the last entry
+pub fn float(ledger: &Ledger) -> Vec<FloatLine> {
+    let mut lines: BTreeMap<ClientId, FloatLine> = BTreeMap::new();
+    let mut held: HashMap<ClientId, Currency> = HashMap::new();
+    let mut open: HashMap<(ClientId, TransactionId), (u64, Currency)> = HashMap::new();
+
+    for entry in ledger.entries().iter().filter(|entry| entry.applied()) {
+        let (transaction, Some(balance)) = (entry.transaction(), entry.balance()) else {
+            continue;
+        };
+        let key = (transaction.client, transaction.tx);
+        let before = held
+            .insert(transaction.client, balance.held)
+            .unwrap_or_else(Currency::zero);
+        match transaction.tx_type {
+            Type::Dispute if balance.held > before => {
+                open.insert(key, (entry.seq(), balance.held - before));
+            }
+            Type::Resolve | Type::Chargeback if balance.held < before => {
+                if let Some((opened, amount)) = open.remove(&key) {
+                    lines
+                        .entry(transaction.client)
+                        .or_insert_with(|| FloatLine::new(Some(transaction.client)))
+                        .held(amount, opened, entry.seq());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let last = ledger.entries().last().map_or(0, |entry| entry.seq());
+    for ((client, _), (opened, amount)) in open {
+        let line = lines
+            .entry(client)
+            .or_insert_with(|| FloatLine::new(Some(client)));
+        line.held(amount, opened, last);
+        line.still_held += amount;
+    }
+
+    let mut aggregate = FloatLine::new(None);
+    lines.values().for_each(|line| aggregate.add(line));
+
+    let mut lines: Vec<FloatLine> = lines.into_values().collect();
+    lines.push(aggregate);
+    lines
+}
+
+pub fn write_float<W: io::Write>(
+    mut writer: W,
+    lines: &[FloatLine],
+    ids: &ClientIds,
+) -> io::Result<()> {
+    writeln!(writer, "{}", HEADER)?;
+    for line in lines {
+        match line.client {
+            Some(client) => writeln!(writer, "{}{}", ids.label(client), line.amounts())?,
+            None => writeln!(writer, "{}", line)?,
+        }
+    }
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use std::collections::HashMap;
+
+    use crate::exchange::transaction::Transaction;
+    use crate::exchange::Exchange;
+
+    fn row(
+        tx_type: Type,
+        client: ClientId,
+        tx: TransactionId,
+        amount: Option<&str>,
+    ) -> Transaction {
+        Transaction {
+            tx_type,
+            client,
+            tx,
+            amount: amount.map(Currency::str),
+            metadata: HashMap::new(),
+            under_dispute: false,
+            dispute_metadata: None,
+        }
+    }
+
+    #[test]
+    fn it_should_weigh_the_held_funds_by_how_long_they_were_held() {
+        let mut exchange = Exchange::new().with_ledger();
+        for transaction in [
+            row(Type::Deposit, 1, 1, Some("10.0")),
+            row(Type::Deposit, 2, 2, Some("4.0")),
+            row(Type::Dispute, 1, 1, None),
+            //ignored, tx 9 does not exist
+            row(Type::Dispute, 2, 9, None),
+            row(Type::Dispute, 2, 2, None),
+            row(Type::Deposit, 3, 3, Some("1.0")),
+            row(Type::Resolve, 1, 1, None),
+            row(Type::Deposit, 3, 4, Some("1.0")),
+        ] {
+            exchange.process_new_transaction(transaction).unwrap();
+        }
+
+        let lines = float(exchange.ledger().unwrap());
+        //client 1 held 10 from seq 3 to 7, client 2 holds 4 from seq 5 to the end at seq 8
+        assert_eq!(
+            vec![
+                "1,1,40.0000,0.0000",
+                "2,1,12.0000,4.0000",
+                "TOTAL,2,52.0000,4.0000"
+            ],
+            lines.iter().map(ToString::to_string).collect::<Vec<_>>()
+        );
+        let mut written = Vec::new();
+        write_float(&mut written, &lines, &ClientIds::Raw).unwrap();
+        assert_eq!(
+            true,
+            String::from_utf8(written)
+                .unwrap()
+                .starts_with("client,disputes,float,still_held\n1,1,40.0000,0.0000\n")
+        );
+    }
+}
@@ -0,0 +1,184 @@
+use std::error::Error;
+use std::fs::File;
+use std::io;
+use std::io::BufRead;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::exchange::checkpoint::InputPosition;
+use crate::exchange::config::LimitExceeded;
+use crate::exchange::Exchange;
+use crate::exchange::IngestOptions;
+use crate::exchange::RecordReader;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct FollowOptions {
+    /// How long to wait for more input once the end of the file is reached
+    pub poll: Duration,
+    /// How often the snapshot callback is called
+    pub snapshot_every: Duration,
+}
+
+impl Default for FollowOptions {
+    fn default() -> Self {
+        FollowOptions {
+            poll: Duration::from_millis(200),
+            snapshot_every: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Process a CSV that keeps growing, like `tail -f`: the file is kept open and lines are processed as they are appended, a
+/// partially written line being held back until its newline shows up. Every snapshot_every, on_snapshot is given the exchange
+/// and the number of transactions submitted at the previous snapshot (see Exchange::accounts_changed_since), and following
+/// stops when it returns false. Records are read one line at a time, so quoted fields spanning lines are not supported.
+/// Unreadable lines are logged and skipped, only a limit exceeded under LimitPolicy::Abort stops following with an error
+pub fn follow_transactions_from_csv(
+    path: &str,
+    bank: &mut Exchange,
+    options: &IngestOptions,
+    follow: &FollowOptions,
+    mut on_snapshot: impl FnMut(&Exchange, u64) -> bool,
+) -> Result<(), Box<dyn Error>> {
+    let mut records: Option<RecordReader> = None;
+    tail_csv(
+        path,
+        bank,
+        follow,
+        |bank, since, _| on_snapshot(bank, since),
+        |bank, record| match &records {
+            None => {
+                records = Some(RecordReader::new(&record, options));
+                Ok(())
+            }
+            Some(reader) => reader.ingest(bank, &record),
+        },
+    )
+}
+
+/// The tailing loop of follow_transactions_from_csv(), handing every complete line to on_record as a record, the header
+/// included. on_snapshot is also given the position in the file right after the last line handed over
+pub(crate) fn tail_csv(
+    path: &str,
+    bank: &mut Exchange,
+    follow: &FollowOptions,
+    mut on_snapshot: impl FnMut(&Exchange, u64, InputPosition) -> bool,
+    mut on_record: impl FnMut(&mut Exchange, csv::StringRecord) -> Result<(), Box<dyn Error>>,
+) -> Result<(), Box<dyn Error>> {
+    let mut input = io::BufReader::new(File::open(path)?);
+    let mut line = Vec::new();
+    let mut position = InputPosition {
+        byte: 0,
+        line: 1,
+        records: 0,
+    };
+    let mut since = bank.submitted();
+    let mut last_snapshot = Instant::now();
+
+    loop {
+        let read = input.read_until(b'\n', &mut line)?;
+        if read > 0 && line.ends_with(b"\n") {
+            position.byte += line.len() as u64;
+            position.line += 1;
+            let record = csv::ReaderBuilder::new()
+                .has_headers(false)
+                .from_reader(line.as_slice())
+                .records()
+                .next();
+            line.clear();
+            match record {
+                None => {}
+                Some(Err(e)) => eprintln!("Skipping unreadable line: {}", e),
+                Some(Ok(record)) => {
+                    position.records += 1;
+                    if let Err(e) = on_record(bank, record) {
+                        if e.is::<LimitExceeded>() {
+                            return Err(e);
+                        }
+                        eprintln!(
+                            "Skipping unreadable line: {}",
+                            bank.config().client_ids.describe(e.as_ref())
+                        );
+                    }
+                }
+            }
+        } else {
+            thread::sleep(follow.poll);
+        }
+
+        if last_snapshot.elapsed() >= follow.snapshot_every {
+            if !on_snapshot(bank, since, position) {
+                return Ok(());
+            }
+            since = bank.submitted();
+            last_snapshot = Instant::now();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use std::io::Write;
+
+    use crate::exchange::client_profile::ClientProfile;
+    use crate::exchange::transaction::Currency;
+    use crate::exchange::transaction::Money;
+
+    #[test]
+    fn it_should_process_lines_as_they_are_appended() {
+        let path = std::env::temp_dir().join("payment_engine_follow.csv");
+        std::fs::write(&path, "type,client,tx,amount\ndeposit,1,1,1.0\n").unwrap();
+        let append = |content: &str| {
+            let mut file = std::fs::OpenOptions::new()
+                .append(true)
+                .open(&path)
+                .unwrap();
+            file.write_all(content.as_bytes()).unwrap();
+        };
+
+        let mut snapshots = 0;
+        let mut exchange = Exchange::new();
+        follow_transactions_from_csv(
+            path.to_str().unwrap(),
+            &mut exchange,
+            &IngestOptions::default(),
+            &FollowOptions {
+                poll: Duration::from_millis(1),
+                snapshot_every: Duration::from_millis(50),
+            },
+            |exchange, since| {
+                snapshots += 1;
+                let changed: Vec<_> = exchange
+                    .accounts_changed_since(since)
+                    .map(ClientProfile::id)
+                    .collect();
+                match snapshots {
+                    1 => {
+                        assert_eq!(vec![1], changed);
+                        append("deposit,1,2,2.0\ndeposit,2,3,");
+                        true
+                    }
+                    2 => {
+                        assert_eq!(Currency::str("3.0"), exchange.client(1).unwrap().total());
+                        assert_eq!(None, exchange.client(2));
+                        append("5.0\n");
+                        true
+                    }
+                    _ => {
+                        assert_eq!(vec![2], changed);
+                        assert_eq!(Currency::str("5.0"), exchange.client(2).unwrap().total());
+                        false
+                    }
+                }
+            },
+        )
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(3, snapshots);
+    }
+}
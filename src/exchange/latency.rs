@@ -0,0 +1,277 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::Duration;
+
+use hdrhistogram::Histogram;
+
+use crate::exchange::transaction::Type;
+
+/// Significant figures kept by the histograms, enough to tell 1000ns from 1001ns
+const SIGNIFICANT_FIGURES: u8 = 3;
+
+/// Stages of the pipeline a record goes through, timed separately to tell an IO-bound run from an engine-bound one
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
+pub enum Stage {
+    /// Reading the next record out of the input, only measured by process_transactions_from_reader_with() (and so
+    /// process_transactions_from_csv_with() without a quarantine or recovery)
+    Read,
+    /// Deserializing a record into a transaction
+    Parse,
+    /// Checking the transaction against the limits and the type policy, and capturing its metadata
+    Validate,
+    /// Applying the transaction, see Exchange::process_new_transaction
+    Apply,
+    /// Writing the outputs, timed by whoever writes them (see Exchange::record_stage): the CLI times all the outputs of a run
+    /// as one
+    Output,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Stage::Read => "read",
+            Stage::Parse => "parse",
+            Stage::Validate => "validate",
+            Stage::Apply => "apply",
+            Stage::Output => "output",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+fn histogram() -> Histogram<u64> {
+    Histogram::new(SIGNIFICANT_FIGURES).expect("valid significant figures")
+}
+
+/// Record the elapsed time in nanoseconds. saturating_record() alone would clamp every value beyond the initial range of the
+/// histogram (a couple of microseconds) instead of resizing it
+fn record(histogram: &mut Histogram<u64>, elapsed: Duration) {
+    let nanos = elapsed.as_nanos().try_into().unwrap_or(u64::MAX);
+    if histogram.record(nanos).is_err() {
+        histogram.saturating_record(nanos);
+    }
+}
+
+/// Processing latency of every submitted transaction, rejected ones included, in nanoseconds, one histogram per transaction type.
+/// Only measures the engine itself (Exchange::process_new_transaction), not reading the CSV. The time spent in each Stage is
+/// kept alongside, per record and in total
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct Latencies {
+    histograms: BTreeMap<String, Histogram<u64>>,
+    stages: BTreeMap<Stage, (Histogram<u64>, Duration)>,
+}
+
+impl Latencies {
+    pub(crate) fn record(&mut self, tx_type: &Type, elapsed: Duration) {
+        record(
+            self.histograms
+                .entry(tx_type.to_string())
+                .or_insert_with(histogram),
+            elapsed,
+        );
+    }
+
+    pub(crate) fn record_stage(&mut self, stage: Stage, elapsed: Duration) {
+        let (histogram, total) = self
+            .stages
+            .entry(stage)
+            .or_insert_with(|| (histogram(), Duration::ZERO));
+        record(histogram, elapsed);
+        *total += elapsed;
+    }
+
+    pub(crate) fn merge(&mut self, other: Latencies) {
+        for (tx_type, histogram) in other.histograms {
+            match self.histograms.get_mut(&tx_type) {
+                //auto-resizing histograms can not fail to add up
+                Some(existing) => existing.add(&histogram).expect("auto-resizing histogram"),
+                None => {
+                    self.histograms.insert(tx_type, histogram);
+                }
+            }
+        }
+        for (stage, (histogram, total)) in other.stages {
+            match self.stages.get_mut(&stage) {
+                Some((existing, existing_total)) => {
+                    existing.add(&histogram).expect("auto-resizing histogram");
+                    *existing_total += total;
+                }
+                None => {
+                    self.stages.insert(stage, (histogram, total));
+                }
+            }
+        }
+    }
+
+    /// True until a transaction or a stage is measured
+    pub fn is_empty(&self) -> bool {
+        self.histograms.is_empty() && self.stages.is_empty()
+    }
+
+    /// Time spent in the stage over the whole run
+    pub fn stage_total(&self, stage: Stage) -> Duration {
+        self.stages
+            .get(&stage)
+            .map_or(Duration::ZERO, |(_, total)| *total)
+    }
+
+    /// Time spent in the stage by one record at the percentile (0.0 to 100.0), None if the stage was not measured
+    pub fn stage_percentile(&self, stage: Stage, percentile: f64) -> Option<Duration> {
+        self.stages
+            .get(&stage)
+            .map(|(histogram, _)| Duration::from_nanos(histogram.value_at_percentile(percentile)))
+    }
+
+    /// Number of transactions of the type measured
+    pub fn count(&self, tx_type: &Type) -> u64 {
+        self.histograms
+            .get(&tx_type.to_string())
+            .map_or(0, Histogram::len)
+    }
+
+    /// Latency at the percentile (0.0 to 100.0) for the type, None if none was measured
+    pub fn percentile(&self, tx_type: &Type, percentile: f64) -> Option<Duration> {
+        self.histograms
+            .get(&tx_type.to_string())
+            .filter(|histogram| !histogram.is_empty())
+            .map(|histogram| Duration::from_nanos(histogram.value_at_percentile(percentile)))
+    }
+}
+
+/// One `latency.<type>: count=N p50=Xns p99=Yns max=Zns` line per type measured, then one
+/// `stage.<stage>: count=N total=Tms p50=Xns p99=Yns max=Zns` line per stage measured, in pipeline order
+impl fmt::Display for Latencies {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let types = self
+            .histograms
+            .iter()
+            .map(|(tx_type, histogram)| (format!("latency.{}", tx_type), histogram, None));
+        let stages = self.stages.iter().map(|(stage, (histogram, total))| {
+            (format!("stage.{}", stage), histogram, Some(total))
+        });
+        for (i, (name, histogram, total)) in types.chain(stages).enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}: count={}", name, histogram.len())?;
+            if let Some(total) = total {
+                write!(f, " total={:.3}ms", total.as_secs_f64() * 1000.0)?;
+            }
+            write!(
+                f,
+                " p50={}ns p99={}ns max={}ns",
+                histogram.value_at_quantile(0.5),
+                histogram.value_at_quantile(0.99),
+                histogram.max()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use std::collections::HashMap;
+
+    use crate::exchange::transaction::Currency;
+    use crate::exchange::transaction::Money;
+    use crate::exchange::transaction::Transaction;
+    use crate::exchange::Exchange;
+
+    #[test]
+    fn it_should_report_percentiles_per_type() {
+        let mut latencies = Latencies::default();
+        for nanos in 1..=100 {
+            latencies.record(&Type::Deposit, Duration::from_nanos(nanos * 10));
+        }
+        latencies.record(&Type::Withdrawal, Duration::from_nanos(5));
+
+        assert_eq!(100, latencies.count(&Type::Deposit));
+        assert_eq!(1, latencies.count(&Type::Withdrawal));
+        assert_eq!(0, latencies.count(&Type::Dispute));
+        assert_eq!(
+            Some(Duration::from_nanos(500)),
+            latencies.percentile(&Type::Deposit, 50.0)
+        );
+        assert_eq!(None, latencies.percentile(&Type::Dispute, 50.0));
+        assert_eq!(
+            "latency.deposit: count=100 p50=500ns p99=990ns max=1000ns\nlatency.withdrawal: count=1 p50=5ns p99=5ns max=5ns",
+            latencies.to_string()
+        );
+    }
+
+    #[test]
+    fn it_should_measure_every_submitted_transaction() {
+        let mut exchange = Exchange::new();
+        for (tx_type, tx) in [
+            (Type::Deposit, 1),
+            (Type::Withdrawal, 2),
+            (Type::Withdrawal, 3),
+        ] {
+            let _ = exchange.process_new_transaction(Transaction {
+                tx_type,
+                client: 1,
+                tx,
+                amount: Some(Currency::str("1.0")),
+                metadata: HashMap::new(),
+                under_dispute: false,
+                dispute_metadata: None,
+            });
+        }
+
+        let stats = exchange.stats();
+        assert_eq!(1, stats.latencies().count(&Type::Deposit));
+        //the second withdrawal is rejected, it is still measured
+        assert_eq!(2, stats.latencies().count(&Type::Withdrawal));
+        assert_eq!(
+            true,
+            stats.to_string().contains("\nlatency.withdrawal: count=2 ")
+        );
+    }
+
+    #[test]
+    fn it_should_time_every_stage_of_the_pipeline() {
+        let mut exchange = Exchange::new();
+        crate::exchange::process_transactions_from_reader_with(
+            "type,client,tx,amount\ndeposit,1,1,1.0\nwithdrawal,1,2,5.0\n".as_bytes(),
+            &mut exchange,
+            &crate::exchange::IngestOptions::default(),
+        )
+        .unwrap();
+        exchange.record_stage(Stage::Output, Duration::from_millis(2));
+
+        let latencies = exchange.stats().latencies().clone();
+        let report = latencies.to_string();
+        for stage in ["read", "parse", "validate", "apply"] {
+            assert_eq!(
+                true,
+                report.contains(&format!("\nstage.{}: count=2 total=", stage)),
+                "{}",
+                report
+            );
+        }
+        assert_eq!(
+            Duration::from_millis(2),
+            latencies.stage_total(Stage::Output)
+        );
+        assert_eq!(
+            true,
+            report.contains("\nstage.output: count=1 total=2.000ms ")
+        );
+        //beyond the initial range of the histogram, to 3 significant figures
+        assert_eq!(
+            2,
+            latencies
+                .stage_percentile(Stage::Output, 50.0)
+                .unwrap()
+                .as_millis()
+        );
+        assert_eq!(
+            None,
+            Latencies::default().stage_percentile(Stage::Parse, 50.0)
+        );
+    }
+}
@@ -0,0 +1,212 @@
+use futures::Stream;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+use crate::exchange::client_profile::AccountBalance;
+use crate::exchange::events::DomainEvent;
+use crate::exchange::pseudonym::ClientIds;
+use crate::exchange::pseudonym::Reason;
+use crate::exchange::transaction::ClientId;
+use crate::exchange::transaction::Transaction;
+use crate::exchange::transaction::TransactionId;
+
+pub const HEADER: [&str; 8] = [
+    "seq", "type", "client", "tx", "amount", "status", "reason", "metadata",
+];
+
+/// A transaction as it was submitted to the exchange, in input order, with the outcome of processing it
+#[derive(Debug, PartialEq, Clone)]
+pub struct LedgerEntry {
+    seq: u64,
+    transaction: Transaction,
+    rejection: Option<Reason>,
+    /// The client's balances right after the transaction was processed, None if the client does not exist (e.g. rejected by the client limit)
+    balance: Option<AccountBalance>,
+}
+
+impl LedgerEntry {
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    pub fn transaction(&self) -> &Transaction {
+        &self.transaction
+    }
+
+    pub fn applied(&self) -> bool {
+        self.rejection.is_none()
+    }
+
+    pub fn rejection(&self) -> Option<&Reason> {
+        self.rejection.as_ref()
+    }
+
+    pub fn balance(&self) -> Option<&AccountBalance> {
+        self.balance.as_ref()
+    }
+
+    /// The entry of an event carrying a transaction, numbered seq
+    fn from_event(seq: u64, event: &DomainEvent) -> Option<LedgerEntry> {
+        Some(LedgerEntry {
+            seq,
+            transaction: event.transaction()?.clone(),
+            rejection: event.rejection().cloned(),
+            balance: event.balance().cloned(),
+        })
+    }
+
+    /// The row of the entry in the layout of HEADER
+    fn record(&self, ids: &ClientIds) -> [String; 8] {
+        let transaction = &self.transaction;
+        let mut metadata: Vec<String> = transaction
+            .metadata
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect();
+        metadata.sort();
+
+        [
+            self.seq.to_string(),
+            transaction.tx_type.to_string(),
+            ids.label(transaction.client).to_string(),
+            transaction.tx.to_string(),
+            transaction
+                .amount
+                .map(|amount| format!("{:.4}", amount))
+                .unwrap_or_default(),
+            if self.applied() {
+                "applied".to_string()
+            } else {
+                "rejected".to_string()
+            },
+            self.rejection
+                .as_ref()
+                .map(|reason| ids.render(reason))
+                .unwrap_or_default(),
+            metadata.join(";"),
+        ]
+    }
+}
+
+/// Every transaction submitted to the exchange, applied or rejected. Keeping it costs a copy of each transaction, so it is off unless enabled
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct Ledger {
+    entries: Vec<LedgerEntry>,
+}
+
+impl Ledger {
+    /// Subscriber of the exchange's events (see Exchange::subscribe), keeping an entry for every one carrying a transaction
+    pub fn observe(&mut self, event: &DomainEvent) {
+        if let Some(entry) = LedgerEntry::from_event(self.entries.len() as u64 + 1, event) {
+            self.entries.push(entry);
+        }
+    }
+
+    /// Append another ledger's entries after this one's, renumbered to follow on
+    pub fn extend(&mut self, other: Ledger) {
+        let offset = self.entries.len() as u64;
+        self.entries
+            .extend(other.entries.into_iter().map(|entry| LedgerEntry {
+                seq: entry.seq + offset,
+                ..entry
+            }));
+    }
+
+    /// The client's balances as of the given sequence number: right after the last of its transactions with seq <= the given one.
+    /// None if the client had no transaction yet at that point. Use seq - 1 for the state a transaction arrived to
+    pub fn balance_at(&self, client: ClientId, seq: u64) -> Option<&AccountBalance> {
+        //seq n is entry n - 1, so everything up to seq is the first seq entries
+        let until = (seq as usize).min(self.entries.len());
+        self.entries[..until]
+            .iter()
+            .rev()
+            .filter(|entry| entry.transaction.client == client)
+            .find_map(|entry| entry.balance.as_ref())
+    }
+
+    /// Sequence number of the first submission of the given transaction id for the client
+    pub fn seq_of(&self, client: ClientId, tx: TransactionId) -> Option<u64> {
+        self.entries
+            .iter()
+            .find(|entry| entry.transaction.client == client && entry.transaction.tx == tx)
+            .map(|entry| entry.seq)
+    }
+
+    pub fn entries(&self) -> &[LedgerEntry] {
+        &self.entries
+    }
+
+    /// The pass-through metadata is written as key=value pairs separated by ';', ordered by key. The client ids, in their
+    /// column and in the rejection reasons, are written as labelled by ids
+    pub fn write_csv<W: io::Write>(&self, writer: W, ids: &ClientIds) -> Result<(), csv::Error> {
+        Self::write_entries(writer, self.entries.iter(), ids)
+    }
+
+    /// Only the rejected entries (with their reason), in the layout of write_csv()
+    pub fn write_rejects<W: io::Write>(
+        &self,
+        writer: W,
+        ids: &ClientIds,
+    ) -> Result<(), csv::Error> {
+        Self::write_entries(
+            writer,
+            self.entries.iter().filter(|entry| !entry.applied()),
+            ids,
+        )
+    }
+
+    fn write_entries<'a, W: io::Write>(
+        writer: W,
+        entries: impl Iterator<Item = &'a LedgerEntry>,
+        ids: &ClientIds,
+    ) -> Result<(), csv::Error> {
+        let mut writer = csv::Writer::from_writer(writer);
+        writer.write_record(HEADER)?;
+        for entry in entries {
+            writer.write_record(entry.record(ids))?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// The ledger written to a file entry by entry as the transactions are processed, in the layout of Ledger::write_csv() with
+/// the raw client ids, for a replica to follow (see replica::follow_ledger). Each entry is flushed as it is written, so the
+/// file is never more than a partial line behind the exchange. Fed from the events of the exchange (see Exchange::subscribe)
+/// rather than kept by it, so the transactions of a fork only reach it once the fork is committed
+pub struct LedgerStream {
+    writer: csv::Writer<File>,
+    seq: u64,
+}
+
+impl LedgerStream {
+    /// Create the file, or truncate it, and write the header
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<LedgerStream, csv::Error> {
+        let mut writer = csv::Writer::from_path(path)?;
+        writer.write_record(HEADER)?;
+        writer.flush()?;
+        Ok(LedgerStream { writer, seq: 0 })
+    }
+
+    /// Append the entry of the event, if it carries a transaction
+    pub fn observe(&mut self, event: &DomainEvent) -> Result<(), csv::Error> {
+        if let Some(entry) = LedgerEntry::from_event(self.seq + 1, event) {
+            self.writer.write_record(entry.record(&ClientIds::Raw))?;
+            self.writer.flush()?;
+            self.seq += 1;
+        }
+        Ok(())
+    }
+
+    /// Append the entries of every event until the exchange publishing them is dropped. Returns the number of entries
+    pub fn write_all(
+        mut self,
+        events: impl Stream<Item = DomainEvent> + Unpin,
+    ) -> Result<u64, csv::Error> {
+        for event in futures::executor::block_on_stream(events) {
+            self.observe(&event)?;
+        }
+        Ok(self.seq)
+    }
+}
@@ -0,0 +1,161 @@
+use serde::Deserialize;
+use serde::Serialize;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// Content fingerprint of an input file. The hash is 64 bit FNV-1a over the whole content: it tells resubmitted files apart
+/// from new ones, it is not meant to resist crafted collisions
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub hash: String,
+    pub size: u64,
+    /// Lines after the header
+    pub records: u64,
+    pub file: String,
+}
+
+impl ManifestEntry {
+    /// Read the file once, hashing its content and counting its lines
+    pub fn fingerprint<P: AsRef<Path>>(path: P) -> io::Result<ManifestEntry> {
+        let mut input = File::open(&path)?;
+        let mut buffer = vec![0; 64 * 1024];
+        let (mut hash, mut size, mut lines) = (0xcbf29ce484222325u64, 0u64, 0u64);
+        let mut last = b'\n';
+        loop {
+            let read = input.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            for byte in &buffer[..read] {
+                hash = (hash ^ *byte as u64).wrapping_mul(0x100000001b3);
+                lines += (*byte == b'\n') as u64;
+            }
+            last = buffer[read - 1];
+            size += read as u64;
+        }
+        //a last line without a trailing newline
+        lines += (last != b'\n') as u64;
+        Ok(ManifestEntry {
+            hash: format!("{:016x}", hash),
+            size,
+            records: lines.saturating_sub(1),
+            file: path.as_ref().display().to_string(),
+        })
+    }
+
+    pub fn same_content(&self, other: &ManifestEntry) -> bool {
+        self.hash == other.hash && self.size == other.size
+    }
+}
+
+/// An input file with the content of one the manifest lists, refused unless forced
+#[derive(Debug, PartialEq, Clone)]
+pub struct AlreadyProcessed {
+    pub file: String,
+    pub known: ManifestEntry,
+}
+
+impl fmt::Display for AlreadyProcessed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} has the content of {} ({} records), which was already processed. Use --force to process it again",
+            self.file, self.known.file, self.known.records
+        )
+    }
+}
+
+impl Error for AlreadyProcessed {}
+
+/// CSV file (hash,size,records,file) listing the input files processed so far, one row appended per file, so the same
+/// content submitted again under any name can be refused
+#[derive(Debug, Clone)]
+pub struct Manifest {
+    path: PathBuf,
+    entries: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    /// Load the manifest file, empty when it does not exist yet
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Manifest, csv::Error> {
+        let entries = match File::open(&path) {
+            Ok(file) => csv::Reader::from_reader(file)
+                .deserialize()
+                .collect::<Result<_, _>>()?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Manifest {
+            path: path.as_ref().to_path_buf(),
+            entries,
+        })
+    }
+
+    pub fn entries(&self) -> &[ManifestEntry] {
+        &self.entries
+    }
+
+    /// The entry of an earlier file with the same content
+    pub fn find(&self, entry: &ManifestEntry) -> Option<&ManifestEntry> {
+        self.entries.iter().find(|known| known.same_content(entry))
+    }
+
+    /// Append the entry of a processed file to the manifest file
+    pub fn record(&mut self, entry: ManifestEntry) -> Result<(), csv::Error> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        let new = file.metadata()?.len() == 0;
+        let mut writer = csv::WriterBuilder::new().has_headers(new).from_writer(file);
+        writer.serialize(&entry)?;
+        writer.flush()?;
+        self.entries.push(entry);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use std::fs;
+
+    #[test]
+    fn it_should_recognise_a_resubmitted_file_by_its_content() {
+        let dir = std::env::temp_dir().join("payment_engine_manifest");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let rows = "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,2,2,5.0";
+        fs::write(dir.join("monday.csv"), rows).unwrap();
+        fs::write(dir.join("monday-copy.csv"), rows).unwrap();
+        fs::write(
+            dir.join("tuesday.csv"),
+            "type,client,tx,amount\ndeposit,1,3,1.0\n",
+        )
+        .unwrap();
+
+        let mut manifest = Manifest::open(dir.join("manifest.csv")).unwrap();
+        let monday = ManifestEntry::fingerprint(dir.join("monday.csv")).unwrap();
+        assert_eq!(2, monday.records);
+        assert_eq!(rows.len() as u64, monday.size);
+        assert_eq!(None, manifest.find(&monday));
+        manifest.record(monday.clone()).unwrap();
+
+        let reopened = Manifest::open(dir.join("manifest.csv")).unwrap();
+        let copy = ManifestEntry::fingerprint(dir.join("monday-copy.csv")).unwrap();
+        assert_eq!(Some(&monday), reopened.find(&copy));
+        let tuesday = ManifestEntry::fingerprint(dir.join("tuesday.csv")).unwrap();
+        assert_eq!(1, tuesday.records);
+        assert_eq!(None, reopened.find(&tuesday));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
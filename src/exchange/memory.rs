@@ -0,0 +1,70 @@
+use std::fmt;
+use std::mem::size_of;
+
+use crate::exchange::client_profile::ClientProfile;
+use crate::exchange::transaction::ClientId;
+use crate::exchange::transaction::Transaction;
+use crate::exchange::transaction::TransactionId;
+use crate::exchange::Exchange;
+
+/// Bytes a retained transaction takes in its client's map: key, value and the control byte of the hash table, at the
+/// table's maximum load factor of 7/8
+pub const TRANSACTION_BYTES: usize =
+    (size_of::<TransactionId>() + size_of::<Transaction>() + 1) * 8 / 7;
+
+/// Bytes a slot of the client maps takes: the profile in the clients map and the activity clock in the last active one
+pub const CLIENT_SLOT_BYTES: usize = size_of::<ClientId>()
+    + size_of::<ClientProfile>()
+    + 1
+    + size_of::<ClientId>()
+    + size_of::<u64>()
+    + 1;
+
+/// Approximate memory held by an exchange, from the number of entries of its stores and the size of their items. Heap data
+/// behind the entries (metadata strings, annotations, dispute metadata) is not counted, so it is a lower bound that is
+/// cheap enough to check on every transaction
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub struct MemoryUsage {
+    pub clients: usize,
+    /// Allocated slots of the client maps, grown ahead of the number of clients
+    pub client_bytes: usize,
+    pub transactions: usize,
+    pub transaction_bytes: usize,
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> usize {
+        self.client_bytes + self.transaction_bytes
+    }
+}
+
+impl fmt::Display for MemoryUsage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "memory.clients: {}", self.clients)?;
+        writeln!(f, "memory.client_bytes: {}", self.client_bytes)?;
+        writeln!(f, "memory.transactions: {}", self.transactions)?;
+        writeln!(f, "memory.transaction_bytes: {}", self.transaction_bytes)?;
+        write!(f, "memory.total_bytes: {}", self.total())
+    }
+}
+
+impl Exchange {
+    /// Approximate memory held by the clients and their retained transactions, see MemoryUsage
+    pub fn memory_usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            clients: self.clients.len(),
+            client_bytes: self.clients.capacity().max(self.last_active.capacity())
+                * CLIENT_SLOT_BYTES,
+            transactions: self.retained_transactions,
+            transaction_bytes: self.retained_transactions * TRANSACTION_BYTES,
+        }
+    }
+
+    /// Whether the memory cap of the limits is reached, see Limits::max_memory
+    pub(crate) fn memory_cap_reached(&self) -> Option<usize> {
+        self.config
+            .limits
+            .max_memory
+            .filter(|max_memory| self.memory_usage().total() >= *max_memory)
+    }
+}
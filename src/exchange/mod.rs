@@ -1,171 +1,4077 @@
+use std::collections::BTreeSet;
 use std::collections::HashMap;
 use std::error::Error;
+use std::io;
+use std::sync::Arc;
 
-mod client_profile;
-mod transaction;
+pub mod aggregate;
+pub mod alerts;
+pub mod anomaly;
+pub mod anonymize;
+pub mod approval;
+pub mod archive;
+pub mod audit;
+pub mod checkpoint;
+pub mod client_profile;
+pub mod cluster;
+pub mod config;
+pub mod dedup;
+pub mod deferral;
+pub mod delta;
+pub mod dispute_import;
+pub mod disputes;
+#[cfg(feature = "encryption")]
+pub mod encryption;
+pub mod enrichment;
+pub mod events;
+pub mod extension;
+#[cfg(any(test, feature = "fault-injection"))]
+pub mod fault;
+pub mod float;
+pub mod follow;
+#[cfg(feature = "latency-histograms")]
+pub mod latency;
+pub mod ledger;
+pub mod manifest;
+pub mod memory;
+#[cfg(feature = "object-store")]
+pub mod object_store;
+pub mod ordering;
+pub mod output;
+pub mod partition;
+pub mod processor;
+pub mod pseudonym;
+pub mod quarantine;
+pub mod query;
+pub mod reconcile;
+pub mod recovery;
+pub mod remap;
+pub mod replica;
+pub mod scheduler;
+pub mod settlement;
+#[cfg(any(test, feature = "differential"))]
+pub mod shadow;
+pub mod snapshot;
+pub mod speculative;
+pub mod sql;
+pub mod stats;
+pub mod stream;
+pub mod table;
+pub mod trace;
+pub mod transaction;
+pub mod watch;
 
+use alerts::BudgetBreach;
+#[cfg(feature = "alert-webhook")]
+use alerts::Webhook;
+use anomaly::AnomalyDetector;
+use approval::PendingChargeback;
+use archive::Archive;
+use audit::AuditEvent;
+use audit::AuditLog;
+use audit::AuditRecord;
+use client_profile::AccountBalance;
+use client_profile::Annotation;
 use client_profile::ClientProfile;
 use client_profile::ProcessingError;
+use config::EngineConfig;
+use config::LimitExceeded;
+use config::LimitPolicy;
+use config::UnknownTransactionType;
+use config::UnknownTypePolicy;
+use config::WriteOffRule;
+use dedup::RecentTransactions;
+use deferral::DeferredDisputes;
+use disputes::DisputeContext;
+use disputes::DisputeScorer;
+use enrichment::NoEnrichment;
+use enrichment::TransactionEnricher;
+use events::DomainEvent;
+use events::EventBus;
+use extension::CustomTransactionHandler;
+#[cfg(feature = "latency-histograms")]
+use latency::Stage;
+use ledger::Ledger;
+use ordering::OrderingTracker;
+use output::AmountFormat;
+use pseudonym::ClientIds;
+use pseudonym::Mention;
+use quarantine::Quarantine;
+use query::AccountFilter;
+use query::AccountPage;
+use query::AccountSort;
+use snapshot::Snapshots;
+use stats::Stats;
+use trace::TraceStage;
+use transaction::schema::SchemaVersion;
+use transaction::AmountBounds;
+use transaction::ChargebackReason;
 use transaction::ClientId;
+use transaction::Currency;
+use transaction::IdHasher;
+use transaction::IdMap;
+use transaction::Money;
 use transaction::Transaction;
+use transaction::TransactionId;
+use transaction::Type;
 
+#[derive(Clone)]
 pub struct Exchange {
-    clients: HashMap<ClientId, ClientProfile>,
+    clients: IdMap<ClientId, ClientProfile>,
+    stats: Stats,
+    audit_log: AuditLog,
+    ledger: Option<Ledger>,
+    config: EngineConfig,
+    retained_transactions: usize,
+    pending_chargebacks: Vec<PendingChargeback>,
+    archive: Option<Archive>,
+    snapshots: Option<Snapshots>,
+    /// Number of transactions submitted so far, the clock inactivity is measured with
+    submitted: u64,
+    last_active: IdMap<ClientId, u64>,
+    /// Clients with an open dispute, the exchange-wide index over ClientProfile::disputed_transactions
+    disputed: BTreeSet<ClientId>,
+    handlers: HashMap<String, Arc<dyn CustomTransactionHandler>>,
+    enricher: Arc<dyn TransactionEnricher>,
+    scorer: Option<Arc<dyn DisputeScorer>>,
+    anomalies: AnomalyDetector,
+    recent: RecentTransactions,
+    ordering: OrderingTracker,
+    deferred: DeferredDisputes,
+    /// Thresholds of EngineConfig::error_budget exceeded so far, see check_error_budget()
+    breaches: Vec<BudgetBreach>,
+    #[cfg(feature = "alert-webhook")]
+    webhook: Option<Webhook>,
+    events: EventBus,
+    #[cfg(any(test, feature = "fault-injection"))]
+    faults: Option<fault::FaultInjector>,
+    #[cfg(feature = "encryption")]
+    encryption: Option<encryption::Encryption>,
 }
 
+/// What apply() did with a transaction it accepted, with whether the account was locked before
+enum Applied {
+    Parked(Currency),
+    Custom {
+        was_locked: bool,
+    },
+    /// With the state of the transaction a dispute, resolve or chargeback refers to before it was processed, None when the
+    /// client does not have it
+    Processed {
+        was_disputed: Option<bool>,
+        was_locked: bool,
+    },
+}
+
+impl Applied {
+    fn was_locked(&self) -> bool {
+        match self {
+            Applied::Parked(_) => false,
+            Applied::Custom { was_locked } | Applied::Processed { was_locked, .. } => *was_locked,
+        }
+    }
+}
+
+impl Default for Exchange {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Exchange {
+    pub fn new() -> Exchange {
+        Self::with_capacity(0)
+    }
+
+    /// Pre-allocate room for the expected number of clients, so the client maps are not rehashed while they grow
+    pub fn with_capacity(clients_hint: usize) -> Exchange {
+        Exchange {
+            clients: IdMap::with_capacity_and_hasher(clients_hint, IdHasher::default()),
+            stats: Stats::default(),
+            audit_log: AuditLog::default(),
+            ledger: None,
+            config: EngineConfig::default(),
+            retained_transactions: 0,
+            pending_chargebacks: Vec::new(),
+            archive: None,
+            snapshots: None,
+            submitted: 0,
+            last_active: IdMap::with_capacity_and_hasher(clients_hint, IdHasher::default()),
+            disputed: BTreeSet::new(),
+            handlers: HashMap::new(),
+            enricher: Arc::new(NoEnrichment),
+            scorer: None,
+            anomalies: AnomalyDetector::new(),
+            recent: RecentTransactions::new(),
+            ordering: OrderingTracker::new(),
+            deferred: DeferredDisputes::new(),
+            breaches: Vec::new(),
+            #[cfg(feature = "alert-webhook")]
+            webhook: None,
+            events: EventBus::default(),
+            #[cfg(any(test, feature = "fault-injection"))]
+            faults: None,
+            #[cfg(feature = "encryption")]
+            encryption: None,
+        }
+    }
+
+    pub fn with_config(mut self, config: EngineConfig) -> Exchange {
+        self.config = config;
+        self
+    }
+
+    /// The configuration in effect, which can differ from the one given to with_config() once a degrading limit was hit
+    pub fn config(&self) -> &EngineConfig {
+        &self.config
+    }
+
+    /// Only fails when the limits are exceeded under LimitPolicy::Abort: the caller is expected to stop feeding transactions,
+    /// whereas under LimitPolicy::Degrade process_new_transaction() takes care of it
+    pub fn check_limits(&self, transaction: &Transaction) -> Result<(), LimitExceeded> {
+        let limits = &self.config.limits;
+        if limits.on_exceeded != LimitPolicy::Abort {
+            return Ok(());
+        }
+
+        if let Some(max_clients) = limits.max_clients {
+            if !self.clients.contains_key(&transaction.client) && self.clients.len() >= max_clients
+            {
+                return Err(LimitExceeded::new(format!(
+                    "Client limit of {} reached by transaction {}",
+                    max_clients,
+                    transaction.mention()
+                )));
+            }
+        }
+        if let Some(max_transactions) = limits.max_transactions {
+            let retained = matches!(transaction.tx_type, Type::Deposit | Type::Withdrawal);
+            if retained && self.retained_transactions >= max_transactions {
+                return Err(LimitExceeded::new(format!(
+                    "Retained transactions limit of {} reached by transaction {}",
+                    max_transactions,
+                    transaction.mention()
+                )));
+            }
+        }
+        if let Some(max_memory) = self.memory_cap_reached() {
+            let grows = !self.clients.contains_key(&transaction.client)
+                || (self.config.retain_transactions
+                    && matches!(transaction.tx_type, Type::Deposit | Type::Withdrawal));
+            if grows {
+                return Err(LimitExceeded::new(format!(
+                    "Memory limit of {} bytes reached by transaction {}",
+                    max_memory,
+                    transaction.mention()
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Keep a copy of every submitted transaction and its outcome, see ledger()
+    pub fn with_ledger(mut self) -> Exchange {
+        self.ledger = Some(Ledger::default());
+        self
+    }
+
+    pub fn ledger(&self) -> Option<&Ledger> {
+        self.ledger.as_ref()
+    }
+
+    /// Point-in-time balances of a client as of the given input sequence number (see Ledger::balance_at), reconstructed from the
+    /// ledger, so None when the exchange was not created with_ledger(). The input has no timestamps, positions are sequence numbers only
+    pub fn balance_at(&self, client: ClientId, seq: u64) -> Option<&AccountBalance> {
+        self.ledger.as_ref()?.balance_at(client, seq)
+    }
+
+    /// Handle transactions whose type string is tx_type (see extension::CustomTransactionHandler). What happens to transactions
+    /// of a type without a handler is up to EngineConfig::unknown_types
+    pub fn with_handler(
+        mut self,
+        tx_type: &str,
+        handler: impl CustomTransactionHandler + 'static,
+    ) -> Exchange {
+        self.handlers.insert(tx_type.to_string(), Arc::new(handler));
+        self
+    }
+
+    /// Pass every submitted transaction through the enricher before processing it, see enrichment::TransactionEnricher
+    pub fn with_enricher(mut self, enricher: impl TransactionEnricher + 'static) -> Exchange {
+        self.enricher = Arc::new(enricher);
+        self
+    }
+
+    /// Score every dispute as it is opened, keeping the score with the dispute, see disputes::DisputeScorer
+    pub fn with_dispute_scorer(mut self, scorer: impl DisputeScorer + 'static) -> Exchange {
+        self.scorer = Some(Arc::new(scorer));
+        self
+    }
+
+    /// Move inactive zero-balance clients to the given archive, see archive_inactive()
+    pub fn with_archive(mut self, archive: Archive) -> Exchange {
+        self.archive = Some(archive);
+        self
+    }
+
+    pub fn archive(&self) -> Option<&Archive> {
+        self.archive.as_ref()
+    }
+
+    /// Post every breach of the error budget to the webhook, see check_error_budget()
+    #[cfg(feature = "alert-webhook")]
+    pub fn with_alert_webhook(mut self, webhook: Webhook) -> Exchange {
+        self.webhook = Some(webhook);
+        self
+    }
+
+    /// Check the run so far against EngineConfig::error_budget, which process_new_transaction() does every alerts::CHECK_EVERY
+    /// transactions. A threshold exceeded for the first time is logged, published as DomainEvent::BudgetExceeded and posted to
+    /// the alert webhook, if any. Returns every threshold exceeded during the run
+    pub fn check_error_budget(&mut self) -> &[BudgetBreach] {
+        let budget = &self.config.error_budget;
+        if budget.is_empty() {
+            return &self.breaches;
+        }
+        let negative_balances = match budget.max_negative_balances {
+            Some(_) => self
+                .clients
+                .values()
+                .filter(|client| client.available() < Currency::zero())
+                .count(),
+            None => 0,
+        };
+        for breach in budget.check(&self.stats, self.submitted, negative_balances) {
+            if self
+                .breaches
+                .iter()
+                .any(|known| known.same_threshold(&breach))
+            {
+                continue;
+            }
+            eprintln!("Error budget exceeded: {}", breach);
+            #[cfg(feature = "alert-webhook")]
+            if let Some(webhook) = &self.webhook {
+                if let Err(e) = webhook.post(&breach) {
+                    eprintln!("Failed to post alert with exception: {}", e)
+                }
+            }
+            self.publish(DomainEvent::BudgetExceeded { breach });
+            self.breaches.push(breach);
+        }
+        &self.breaches
+    }
+
+    /// Write intermediate snapshots of the accounts while the input is ingested, see snapshot::Snapshots
+    pub fn with_snapshots(mut self, mut snapshots: Snapshots) -> Exchange {
+        snapshots.start(self.submitted);
+        self.snapshots = Some(snapshots);
+        self
+    }
+
+    pub fn snapshots(&self) -> Option<&Snapshots> {
+        self.snapshots.as_ref()
+    }
+
+    /// Write a snapshot when one is due. A snapshot that can not be written is logged and skipped, it does not fail the run
+    fn snapshot_if_due(&mut self) {
+        let Some(mut snapshots) = self.snapshots.take() else {
+            return;
+        };
+        if let Err(e) = snapshots.write_if_due(self) {
+            eprintln!("Failed to write snapshot with exception: {}", e)
+        }
+        self.snapshots = Some(snapshots);
+    }
+
+    /// Export the clients with no funds available or held, no open dispute, cooling-off period or pending chargeback and no
+    /// transaction among the last idle_for submitted ones to the archive, and drop them from memory. A later transaction for an
+    /// archived client rehydrates it.
+    /// Archived clients are not in the account summaries, they are listed in the archive file. Returns how many were archived
+    pub fn archive_inactive(&mut self, idle_for: u64) -> io::Result<usize> {
+        if self.archive.is_none() {
+            return Ok(0);
+        }
+        let mut inactive: Vec<ClientId> = self
+            .clients
+            .values()
+            .filter(|client| self.archivable(client))
+            .map(|client| client.id())
+            .filter(|id| self.submitted - self.last_active(*id) >= idle_for)
+            .collect();
+        inactive.sort_unstable();
+        self.move_to_archive(&inactive)
+    }
+
+    /// Archive the least recently active clients that can be archived (see archive_inactive()) until at most keep clients are
+    /// left in memory, or none can be archived. Clients holding funds are never archived, as the account summaries and the
+    /// reports only cover the clients in memory: keep is not a bound on the funded ones. Returns how many were archived
+    pub fn archive_least_recent(&mut self, keep: usize) -> io::Result<usize> {
+        if self.archive.is_none() || self.clients.len() <= keep {
+            return Ok(0);
+        }
+        let mut candidates: Vec<(u64, ClientId)> = self
+            .clients
+            .values()
+            .filter(|client| self.archivable(client))
+            .map(|client| (self.last_active(client.id()), client.id()))
+            .collect();
+        candidates.sort_unstable();
+        candidates.truncate(self.clients.len() - keep);
+        let mut evicted: Vec<ClientId> = candidates.into_iter().map(|(_, id)| id).collect();
+        evicted.sort_unstable();
+        self.move_to_archive(&evicted)
+    }
+
+    /// No funds available or held, no open dispute, no cooling-off period left and no pending chargeback
+    fn archivable(&self, client: &ClientProfile) -> bool {
+        client.available().is_zero()
+            && client.held().is_zero()
+            && client.total().is_zero()
+            && !client.has_open_disputes()
+            && client.cooling_off() == 0
+            && !self
+                .pending_chargebacks
+                .iter()
+                .any(|pending| pending.client() == client.id())
+    }
+
+    fn last_active(&self, id: ClientId) -> u64 {
+        self.last_active.get(&id).copied().unwrap_or(0)
+    }
+
+    fn move_to_archive(&mut self, ids: &[ClientId]) -> io::Result<usize> {
+        let Some(archive) = self
+            .archive
+            .as_mut()
+            .filter(|archive| !archive.is_detached())
+        else {
+            return Ok(0);
+        };
+        archive.archive(ids.iter().filter_map(|id| self.clients.get(id)))?;
+        for id in ids {
+            if let Some(client) = self.clients.remove(id) {
+                self.retained_transactions -= client.transaction_count();
+            }
+            self.last_active.remove(id);
+        }
+        Ok(ids.len())
+    }
+
+    fn rehydrate(&mut self, id: ClientId) -> Result<(), ProcessingError> {
+        let archive = match &mut self.archive {
+            Some(archive) if archive.contains(id) => archive,
+            Some(_) => {
+                self.stats.resident += self.clients.contains_key(&id) as u64;
+                return Ok(());
+            }
+            None => return Ok(()),
+        };
+        self.stats.rehydrated += 1;
+        let client = archive.rehydrate(id).map_err(|e| {
+            ProcessingError::new(format!(
+                "Failed to rehydrate archived client {}: {}",
+                Mention(id),
+                e
+            ))
+        })?;
+        if let Some(client) = client {
+            self.retained_transactions += client.transaction_count();
+            self.clients.insert(id, client);
+        }
+        Ok(())
+    }
+
+    /// Under LimitPolicy::Degrade with an archive, the spill-to-disk store of the retained transactions: once transaction
+    /// would take the exchange over a limit, the least recently active clients that can be archived (see archive_inactive())
+    /// are moved to the archive with their transaction history, until the clients, retained transactions and memory are down
+    /// to 7/8 of their limits, so the spill is not repeated on every transaction. The client of transaction stays in memory.
+    /// New clients are only rejected, and transactions only no longer retained, when there is nothing left to spill
+    fn spill(&mut self, transaction: &Transaction) {
+        let limits = &self.config.limits;
+        let new_client = !self.clients.contains_key(&transaction.client);
+        let reached = limits
+            .max_clients
+            .is_some_and(|max| new_client && self.clients.len() >= max)
+            || limits.max_transactions.is_some_and(|max| {
+                self.config.retain_transactions && self.retained_transactions >= max
+            })
+            || self.memory_cap_reached().is_some();
+        if !reached {
+            return;
+        }
+        let below = |max: usize| max.saturating_sub((max / 8).max(1));
+        let client_bytes = self.memory_usage().client_bytes;
+        let max_clients = limits.max_clients.map_or(usize::MAX, below);
+        let max_transactions = limits.max_transactions.map_or(usize::MAX, below).min(
+            limits.max_memory.map_or(usize::MAX, |max| {
+                below(max).saturating_sub(client_bytes) / memory::TRANSACTION_BYTES
+            }),
+        );
+
+        let mut candidates: Vec<(u64, ClientId)> = self
+            .clients
+            .values()
+            .filter(|client| client.id() != transaction.client && self.archivable(client))
+            .map(|client| (self.last_active(client.id()), client.id()))
+            .collect();
+        candidates.sort_unstable();
+        let (mut clients, mut transactions) = (self.clients.len(), self.retained_transactions);
+        let mut spilled = Vec::new();
+        for (_, id) in candidates {
+            if clients <= max_clients && transactions <= max_transactions {
+                break;
+            }
+            clients -= 1;
+            transactions -= self.clients[&id].transaction_count();
+            spilled.push(id);
+        }
+        spilled.sort_unstable();
+        if let Err(e) = self.move_to_archive(&spilled) {
+            eprintln!("Failed to spill clients to the archive: {}", e);
+        }
+    }
+
+    /// Number of transactions submitted so far, accepted or rejected
+    pub fn submitted(&self) -> u64 {
+        self.submitted
+    }
+
+    /// Accounts with a transaction submitted after the first `submitted` ones, e.g. the clients changed since a snapshot
+    /// taken when submitted() returned that number
+    pub fn accounts_changed_since(&self, submitted: u64) -> impl Iterator<Item = &ClientProfile> {
+        self.clients.values().filter(move |client| {
+            self.last_active
+                .get(&client.id())
+                .is_some_and(|last_active| *last_active > submitted)
+        })
+    }
+
+    /// Independent copy of the current state. Hypothetical transactions can be applied to the fork and its balances inspected
+    /// without touching the live exchange; the fork is then either dropped or handed back through commit(). The fork writes
+    /// no snapshots and only reads the archive (see Archive::detached()): it rehydrates archived clients but archives none
+    pub fn fork(&self) -> Exchange {
+        Exchange {
+            archive: self.archive.as_ref().map(Archive::detached),
+            snapshots: None,
+            ..self.clone()
+        }
+    }
+
+    /// A new exchange with the same configuration, handlers and ledger setting but no state, to process another partition of the input with
+    fn empty_like(&self) -> Exchange {
+        Exchange {
+            ledger: self.ledger.as_ref().map(|_| Ledger::default()),
+            config: self.config.clone(),
+            handlers: self.handlers.clone(),
+            enricher: self.enricher.clone(),
+            scorer: self.scorer.clone(),
+            events: self.events.clone(),
+            ..Exchange::new()
+        }
+    }
+
+    /// Replace the live state with a fork's state, making every transaction applied to the fork effective. The events of the
+    /// fork are broadcast to the subscribers of this exchange, which keeps its archive and snapshots: the clients the fork
+    /// rehydrated are recorded as read back in the archive
+    pub fn commit(&mut self, mut fork: Exchange) {
+        let forked = std::mem::replace(&mut fork.events, std::mem::take(&mut self.events));
+        if let Some(archive) = &mut self.archive {
+            let rehydrated: Vec<ClientId> = fork
+                .clients
+                .keys()
+                .copied()
+                .filter(|id| archive.contains(*id))
+                .collect();
+            for id in rehydrated {
+                if let Err(e) = archive.forget(id) {
+                    eprintln!(
+                        "Failed to record rehydrated client {} in the archive: {}",
+                        self.config.client_ids.label(id),
+                        e
+                    );
+                }
+            }
+        }
+        fork.archive = self.archive.take();
+        fork.snapshots = self.snapshots.take();
+        *self = fork;
+        self.events.replay(forked);
+    }
+
+    /// A stream of the events of every transaction submitted from now on, see events::DomainEvent. The exchange broadcasts
+    /// each event to every subscriber, and feeds its own ledger and stats from the same events
+    pub fn subscribe(&mut self) -> futures::channel::mpsc::UnboundedReceiver<DomainEvent> {
+        self.events.subscribe()
+    }
+
+    fn publish(&mut self, event: DomainEvent) {
+        self.stats.observe(&event);
+        if let Some(ledger) = &mut self.ledger {
+            ledger.observe(&event);
+        }
+        self.events.publish(event);
+    }
+
+    pub fn client(&self, id: ClientId) -> Option<&ClientProfile> {
+        self.clients.get(&id)
+    }
+
+    pub fn transaction(&self, client: ClientId, tx: TransactionId) -> Option<&Transaction> {
+        self.client(client)?.transaction(tx)
+    }
+
+    /// The stored transaction with this id whatever its client, for lookups by tx alone. Scans every client
+    pub fn find_transaction(&self, tx: TransactionId) -> Option<&Transaction> {
+        self.clients
+            .values()
+            .find_map(|client| client.transaction(tx))
+    }
+
+    pub fn accounts<'a>(
+        &'a self,
+        filter: &'a AccountFilter,
+    ) -> impl Iterator<Item = &'a ClientProfile> + 'a {
+        self.clients
+            .values()
+            .filter(move |client| filter.matches(client))
+    }
+
+    /// Snapshots of the accounts from offset to offset + limit in the given order, for callers listing millions of accounts a
+    /// page at a time. Only the accounts up to the end of the page are sorted, not the whole population
+    pub fn accounts_page(&self, offset: usize, limit: usize, sort: AccountSort) -> AccountPage {
+        let mut clients: Vec<&ClientProfile> = self.clients.values().collect();
+        let total = clients.len();
+        let end = offset.saturating_add(limit).min(total);
+        if end < total {
+            clients.select_nth_unstable_by(end, |a, b| sort.compare(a, b));
+            clients.truncate(end);
+        }
+        clients.sort_unstable_by(|a, b| sort.compare(a, b));
+        AccountPage {
+            offset,
+            accounts: clients
+                .into_iter()
+                .skip(offset)
+                .map(AccountBalance::from)
+                .collect(),
+            total,
+        }
+    }
+
+    /// Stored transactions with an open dispute, across all clients, ordered by client and transaction id
+    pub fn disputed_transactions(&self) -> impl Iterator<Item = &Transaction> {
+        self.disputed_clients()
+            .flat_map(|client| client.disputed_transactions())
+    }
+
+    /// Clients with an open dispute, ordered by client id
+    fn disputed_clients(&self) -> impl Iterator<Item = &ClientProfile> {
+        self.disputed.iter().filter_map(|id| self.clients.get(id))
+    }
+
+    /// Rebuild the index of the open disputes, of every client and exchange-wide, after the clients were restored
+    fn index_disputes(&mut self) {
+        for client in self.clients.values_mut() {
+            client.index_disputes();
+        }
+        self.disputed = self
+            .clients
+            .values()
+            .filter(|client| client.has_open_disputes())
+            .map(|client| client.id())
+            .collect();
+    }
+
+    pub fn stats(&self) -> Stats {
+        let mut stats = self.stats.clone();
+        stats.memory = self.memory_usage();
+        stats.aging = self
+            .config
+            .dispute_aging
+            .as_ref()
+            .map(|aging| self.dispute_aging(aging));
+        for client in self.clients.values() {
+            for reason in ChargebackReason::ALL {
+                *stats.chargeback_reasons.entry(reason).or_insert(0) += client.chargebacks(reason);
+            }
+        }
+        stats
+    }
+
+    /// Count time spent outside of the engine in a stage of the pipeline, e.g. writing the outputs, in the stats
+    #[cfg(feature = "latency-histograms")]
+    pub fn record_stage(&mut self, stage: Stage, elapsed: std::time::Duration) {
+        self.stats.latencies.record_stage(stage, elapsed);
+    }
+
+    pub fn audit_log(&self) -> &[AuditRecord] {
+        self.audit_log.records()
+    }
+
+    /// Fold the duplicate profile `from` into `to` (created if it does not exist yet) and record the merge in the audit log.
+    /// Archived profiles are rehydrated first.
+    /// Merges involving a locked account are rejected unless forced, and so are merges where both profiles know the same tx id
+    pub fn merge_clients(
+        &mut self,
+        from: ClientId,
+        to: ClientId,
+        force: bool,
+    ) -> Result<(), ProcessingError> {
+        if from == to {
+            return Err(ProcessingError::new(format!(
+                "Cannot merge client {} into itself",
+                Mention(from)
+            )));
+        }
+        //an archived profile would otherwise be taken for an unknown client, or rehydrated over the merged one later
+        self.rehydrate(from)?;
+        self.rehydrate(to)?;
+
+        let source = self.clients.get(&from).ok_or_else(|| {
+            ProcessingError::new(format!(
+                "Cannot merge unknown client {} into {}",
+                Mention(from),
+                Mention(to)
+            ))
+        })?;
+        let target = self.clients.get(&to);
+
+        if !force && (source.locked() || target.is_some_and(|t| t.locked())) {
+            return Err(ProcessingError::new(format!(
+                "Cannot merge client {} into {}: locked accounts can only be merged when forced",
+                Mention(from),
+                Mention(to)
+            )));
+        }
+
+        if let Some(target) = target {
+            if let Some(tx) = source
+                .transaction_ids()
+                .find(|tx| target.transaction(**tx).is_some())
+            {
+                return Err(ProcessingError::new(format!(
+                    "Cannot merge client {} into {}: transaction {} exists in both accounts",
+                    Mention(from),
+                    Mention(to),
+                    tx
+                )));
+            }
+        }
+
+        let source = self.clients.remove(&from).unwrap();
+        let transactions = source.transaction_ids().count();
+        self.clients
+            .entry(to)
+            .or_insert_with(|| ClientProfile::new_with_defaults(to))
+            .absorb(source);
+        if self.disputed.remove(&from) {
+            self.disputed.insert(to);
+        }
+
+        self.audit_log.record(AuditEvent::ClientsMerged {
+            from,
+            to,
+            transactions,
+            forced: force,
+        });
+        Ok(())
+    }
+
+    /// Fold an exchange that processed another partition of the input into this one: balances of clients known to both are summed
+    /// and their transactions unioned (see ClientProfile::absorb), stats are added up, and the other's ledger, audit log, pending
+    /// chargebacks and deferred rows are appended after this one's. Partitions must be disjoint: the merge is rejected, leaving this exchange as it
+    /// was, when a stored transaction id is known to both, or when either has archived clients, which can not be checked
+    pub fn merge(&mut self, other: Exchange) -> Result<(), ProcessingError> {
+        if self
+            .archive
+            .as_ref()
+            .is_some_and(|archive| !archive.is_empty())
+            || other
+                .archive
+                .as_ref()
+                .is_some_and(|archive| !archive.is_empty())
+        {
+            return Err(ProcessingError::new(
+                "Cannot merge exchanges with archived clients",
+            ));
+        }
+
+        let owners: IdMap<TransactionId, ClientId> = self
+            .clients
+            .values()
+            .flat_map(|client| client.transaction_ids().map(|tx| (*tx, client.id())))
+            .collect();
+        let mut conflicts: Vec<(TransactionId, ClientId, ClientId)> = other
+            .clients
+            .values()
+            .flat_map(|client| {
+                client
+                    .transaction_ids()
+                    .filter_map(|tx| owners.get(tx).map(|owner| (*tx, *owner, client.id())))
+            })
+            .collect();
+        conflicts.sort_unstable();
+        if let Some((tx, ours, theirs)) = conflicts.first() {
+            return Err(ProcessingError::new(format!(
+                "Cannot merge exchanges: transaction {} exists in both (client {} and client {}), {} conflicting transaction id(s) in total",
+                tx,
+                Mention(*ours),
+                Mention(*theirs),
+                conflicts.len()
+            )));
+        }
+
+        let offset = self.submitted;
+        for (id, mut client) in other.clients {
+            client.offset_disputes(offset);
+            match self.clients.get_mut(&id) {
+                Some(existing) => existing.absorb(client),
+                None => {
+                    self.clients.insert(id, client);
+                }
+            }
+        }
+        for (id, last_active) in other.last_active {
+            self.last_active.insert(id, last_active + offset);
+        }
+        self.disputed.extend(other.disputed);
+        self.submitted += other.submitted;
+        self.retained_transactions += other.retained_transactions;
+        self.stats.merge(other.stats);
+        self.audit_log.extend(other.audit_log);
+        if let (Some(ledger), Some(other)) = (&mut self.ledger, other.ledger) {
+            ledger.extend(other);
+        }
+        self.pending_chargebacks.extend(other.pending_chargebacks);
+        self.deferred.merge(other.deferred, offset);
+        self.anomalies.merge(other.anomalies);
+        for breach in other.breaches {
+            if !self
+                .breaches
+                .iter()
+                .any(|known| known.same_threshold(&breach))
+            {
+                self.breaches.push(breach);
+            }
+        }
+        self.events.replay(other.events);
+        Ok(())
+    }
+
+    /// Chargebacks waiting for approval, in arrival order
+    pub fn pending_chargebacks(&self) -> &[PendingChargeback] {
+        &self.pending_chargebacks
+    }
+
+    /// Apply a parked chargeback, published as any submitted one, and record the approval in the audit log. A chargeback that
+    /// is rejected, e.g. once the account was locked by another one, stays pending
+    pub fn approve_chargeback(
+        &mut self,
+        client: ClientId,
+        tx: TransactionId,
+    ) -> Result<(), ProcessingError> {
+        let pending = self.take_pending_chargeback(client, tx)?;
+        let amount = pending.amount();
+        if let Err(e) = self.settle_as(pending.clone().into_chargeback(), true) {
+            self.pending_chargebacks.push(pending);
+            return Err(e);
+        }
+        self.audit_log
+            .record(AuditEvent::ChargebackApproved { client, tx, amount });
+        Ok(())
+    }
+
+    fn track_dispute(&mut self, client: ClientId, tx: TransactionId) {
+        if let Some(profile) = self.clients.get_mut(&client) {
+            let opened = profile.dispute_opened_at(tx).is_none();
+            profile.track_dispute(tx, self.submitted);
+            if let (Some(scorer), Some(t)) = (&self.scorer, profile.transaction(tx)) {
+                let context = (opened && t.under_dispute).then(|| DisputeContext {
+                    client,
+                    tx,
+                    tx_type: t.tx_type.clone(),
+                    amount: t.amount,
+                    deposits: profile.deposits(),
+                    deposited: profile.deposited(),
+                    withdrawals: profile.withdrawals(),
+                    chargebacks: profile.chargeback_count(),
+                    charged_back: profile.charged_back(),
+                    open_disputes: profile.disputed_transactions().count() - 1,
+                    available: profile.available(),
+                    held: profile.held(),
+                });
+                if let Some(score) = context.and_then(|context| scorer.score(&context)) {
+                    profile.score_dispute(tx, score);
+                }
+            }
+            if profile.has_open_disputes() {
+                self.disputed.insert(client);
+            } else {
+                self.disputed.remove(&client);
+            }
+        }
+    }
+
+    /// Manually unlock a locked account, releasing the deposits held pending review (EngineConfig::hold_deposits_when_locked),
+    /// and record it in the audit log. Returns the released amount
+    pub fn unlock(&mut self, client: ClientId) -> Result<Currency, ProcessingError> {
+        let profile = self
+            .clients
+            .get_mut(&client)
+            .filter(|profile| profile.locked())
+            .ok_or_else(|| {
+                ProcessingError::new(format!("Client {} is not locked", Mention(client)))
+            })?;
+        let released = profile.lift_lock();
+        self.audit_log
+            .record(AuditEvent::ClientUnlocked { client, released });
+        Ok(released)
+    }
+
+    /// Attach an operational status and note to an account, or clear it with None, and record it in the audit log. The
+    /// annotation is kept in checkpoints and can be included in the JSON output
+    pub fn annotate(
+        &mut self,
+        client: ClientId,
+        annotation: Option<Annotation>,
+    ) -> Result<(), ProcessingError> {
+        let profile = self
+            .clients
+            .get_mut(&client)
+            .ok_or_else(|| ProcessingError::new(format!("Unknown client {}", Mention(client))))?;
+        self.audit_log.record(AuditEvent::ClientAnnotated {
+            client,
+            status: annotation.as_ref().map(|a| a.status.clone()),
+        });
+        profile.annotate(annotation);
+        Ok(())
+    }
+
+    /// Move a client to another KYC tier once it was verified to it, lifting or tightening the caps it is held to
+    /// (EngineConfig::client_caps) and clearing its review flag, and record it in the audit log
+    pub fn set_kyc_tier(&mut self, client: ClientId, tier: u8) -> Result<(), ProcessingError> {
+        let profile = self
+            .clients
+            .get_mut(&client)
+            .ok_or_else(|| ProcessingError::new(format!("Unknown client {}", Mention(client))))?;
+        self.audit_log.record(AuditEvent::KycTierChanged {
+            client,
+            from: profile.kyc_tier(),
+            to: tier,
+        });
+        profile.set_kyc_tier(tier);
+        Ok(())
+    }
+
+    /// Move a client to one of the account tiers of EngineConfig::account_tiers, or back to the default tier with None, changing
+    /// the limits it is held to, and record it in the audit log. The tier is kept in checkpoints
+    pub fn set_tier(
+        &mut self,
+        client: ClientId,
+        tier: Option<String>,
+    ) -> Result<(), ProcessingError> {
+        if let Some(tier) = tier
+            .as_ref()
+            .filter(|tier| !self.config.account_tiers.contains_key(*tier))
+        {
+            return Err(ProcessingError::new(format!(
+                "Unknown account tier {}",
+                tier
+            )));
+        }
+        let profile = self
+            .clients
+            .get_mut(&client)
+            .ok_or_else(|| ProcessingError::new(format!("Unknown client {}", Mention(client))))?;
+        self.audit_log.record(AuditEvent::TierChanged {
+            client,
+            from: profile.tier().map(str::to_string),
+            to: tier.clone(),
+        });
+        profile.set_tier(tier);
+        Ok(())
+    }
+
+    /// The account tier of a client, None for the default tier or an unknown client
+    fn tier_of(&self, client: ClientId) -> Option<&str> {
+        self.clients.get(&client).and_then(ClientProfile::tier)
+    }
+
+    /// Override the reserve requirement of a client (EngineConfig::client_reserves), or fall back to the one of its tier with
+    /// None, and record it in the audit log
+    pub fn set_client_reserve(&mut self, client: ClientId, reserve: Option<Currency>) {
+        let from = self.config.reserve_for(client, self.tier_of(client));
+        match reserve {
+            Some(reserve) => self.config.client_reserves.insert(client, reserve),
+            None => self.config.client_reserves.remove(&client),
+        };
+        self.audit_log.record(AuditEvent::ReserveChanged {
+            client,
+            from,
+            to: self.config.reserve_for(client, self.tier_of(client)),
+        });
+    }
+
+    /// Override the cooling-off period of a client (EngineConfig::client_cooling_off), 0 exempting it, or fall back to the
+    /// one of its tier with None, and record it in the audit log. A period already running is left as it is
+    pub fn set_client_cooling_off(&mut self, client: ClientId, transactions: Option<u64>) {
+        let from = self.config.cooling_off_for(client, self.tier_of(client));
+        match transactions {
+            Some(transactions) => self.config.client_cooling_off.insert(client, transactions),
+            None => self.config.client_cooling_off.remove(&client),
+        };
+        self.audit_log.record(AuditEvent::CoolingOffChanged {
+            client,
+            from,
+            to: self.config.cooling_off_for(client, self.tier_of(client)),
+        });
+    }
+
+    /// Add a client to the blocklist (EngineConfig::blocklist), rejecting all of its transactions from now on, or take it off,
+    /// and record it in the audit log. Clients can be blocked before they are seen
+    pub fn set_blocked(&mut self, client: ClientId, blocked: bool) -> Result<(), ProcessingError> {
+        let changed = if blocked {
+            self.config.blocklist.insert(client)
+        } else {
+            self.config.blocklist.remove(&client)
+        };
+        if !changed {
+            return Err(ProcessingError::new(format!(
+                "Client {} is already {}",
+                Mention(client),
+                if blocked { "blocked" } else { "not blocked" }
+            )));
+        }
+        self.audit_log
+            .record(AuditEvent::BlocklistUpdated { client, blocked });
+        Ok(())
+    }
+
+    /// Freeze an account, rejecting its withdrawals while deposits and disputes still go through, or thaw it, and record it in
+    /// the audit log. Unlike a lock, a freeze does not stop the account from being credited
+    pub fn set_frozen(&mut self, client: ClientId, frozen: bool) -> Result<(), ProcessingError> {
+        let profile = self
+            .clients
+            .get_mut(&client)
+            .ok_or_else(|| ProcessingError::new(format!("Unknown client {}", Mention(client))))?;
+        if profile.frozen() == frozen {
+            return Err(ProcessingError::new(format!(
+                "Client {} is already {}",
+                Mention(client),
+                if frozen { "frozen" } else { "not frozen" }
+            )));
+        }
+        profile.set_frozen(frozen);
+        self.audit_log
+            .record(AuditEvent::ClientFrozen { client, frozen });
+        Ok(())
+    }
+
+    /// Write off the residual balances of the inactive accounts in memory: every client idle for at least rule.idle_for submitted
+    /// transactions with available funds above zero and below rule.below, nothing held, no open dispute and no pending
+    /// chargeback has them taken out by an internal write_off adjustment. The adjustments are published as
+    /// DomainEvent::BalanceWrittenOff, so they are in the ledger, and the batch is recorded in the audit log. They are not
+    /// retained transactions: their tx is the sequence number of the audit record of the batch. Returns the accounts written
+    /// off and their amounts, by client id
+    pub fn write_off_small_balances(&mut self, rule: &WriteOffRule) -> Vec<(ClientId, Currency)> {
+        let mut small: Vec<ClientId> = self
+            .clients
+            .values()
+            .filter(|client| {
+                !client.available().is_sign_negative()
+                    && !client.available().is_zero()
+                    && client.available() < rule.below
+                    && client.held().is_zero()
+                    && !client.has_open_disputes()
+                    && !self
+                        .pending_chargebacks
+                        .iter()
+                        .any(|pending| pending.client() == client.id())
+            })
+            .map(|client| client.id())
+            .filter(|id| self.submitted - self.last_active(*id) >= rule.idle_for)
+            .collect();
+        small.sort_unstable();
+
+        let seq = self.audit_log.records().len() as TransactionId + 1;
+        let mut written_off = Vec::with_capacity(small.len());
+        for client in small {
+            let Some(profile) = self.clients.get_mut(&client) else {
+                continue;
+            };
+            let amount = profile.write_off();
+            let balance = AccountBalance::from(&*profile);
+            written_off.push((client, amount));
+            self.publish(DomainEvent::BalanceWrittenOff {
+                transaction: Transaction {
+                    tx_type: Type::Custom("write_off".to_string()),
+                    client,
+                    tx: seq,
+                    amount: Some(amount),
+                    metadata: HashMap::new(),
+                    under_dispute: false,
+                    dispute_metadata: None,
+                },
+                balance,
+            });
+        }
+        self.audit_log.record(AuditEvent::BalancesWrittenOff {
+            below: rule.below,
+            accounts: written_off.len(),
+            amount: written_off.iter().map(|(_, amount)| *amount).sum(),
+        });
+        written_off
+    }
+
+    /// Drop a parked chargeback, leaving its dispute open so it can still be resolved, and record the denial in the audit log
+    pub fn deny_chargeback(
+        &mut self,
+        client: ClientId,
+        tx: TransactionId,
+    ) -> Result<(), ProcessingError> {
+        let pending = self.take_pending_chargeback(client, tx)?;
+        self.audit_log.record(AuditEvent::ChargebackDenied {
+            client,
+            tx,
+            amount: pending.amount(),
+        });
+        Ok(())
+    }
+
+    fn take_pending_chargeback(
+        &mut self,
+        client: ClientId,
+        tx: TransactionId,
+    ) -> Result<PendingChargeback, ProcessingError> {
+        let index = self
+            .pending_chargebacks
+            .iter()
+            .position(|pending| pending.client() == client && pending.tx() == tx)
+            .ok_or_else(|| {
+                ProcessingError::new(format!(
+                    "No chargeback pending approval for client {} transaction {}",
+                    Mention(client),
+                    tx
+                ))
+            })?;
+        Ok(self.pending_chargebacks.remove(index))
+    }
+
+    /// The disputed amount when the transaction is a chargeback that has to wait for approval
+    fn requires_approval(&self, transaction: &Transaction) -> Option<Currency> {
+        let threshold = self.config.chargeback_approval_threshold?;
+        if transaction.tx_type != Type::Chargeback {
+            return None;
+        }
+        let client = self.clients.get(&transaction.client)?;
+        let disputed = client.transaction(transaction.tx)?;
+        let amount = disputed.amount?;
+        (!client.locked() && disputed.under_dispute && amount > threshold).then_some(amount)
+    }
+
+    /// Only fails under UnknownTypePolicy::Fail, for a transaction of a type the engine does not know
+    pub fn check_type(&self, transaction: &Transaction) -> Result<(), UnknownTransactionType> {
+        if self.config.unknown_types == UnknownTypePolicy::Fail
+            && self.is_unknown(&transaction.tx_type)
+        {
+            return Err(UnknownTransactionType::new(format!(
+                "Unknown transaction type {} in transaction {}",
+                transaction.tx_type,
+                transaction.mention()
+            )));
+        }
+        Ok(())
+    }
+
+    /// A custom type that is not dispatched to a handler
+    fn is_unknown(&self, tx_type: &Type) -> bool {
+        match tx_type {
+            Type::Custom(name) => {
+                self.config.unknown_types == UnknownTypePolicy::Reject
+                    || !self.handlers.contains_key(name)
+            }
+            _ => false,
+        }
+    }
+
+    fn process_new_transaction(&mut self, transaction: Transaction) -> Result<(), ProcessingError> {
+        let mut transaction = self.enricher.enrich(transaction);
+        if let Some(amount) = &mut transaction.amount {
+            amount.rescale(self.config.amount_scale);
+        }
+        if self.config.trace.is_some() {
+            let submission = self.submitted + 1;
+            transaction
+                .metadata
+                .entry(trace::TRACE_ID.to_string())
+                .or_insert_with(|| submission.to_string());
+        }
+        if let Some(window) = &self.config.dedup_window {
+            if self.recent.is_repeat(window, &transaction) {
+                self.trace(&transaction, TraceStage::Dedup, "absorbed as a redelivery");
+                self.stats.deduplicated += 1;
+                return Ok(());
+            }
+        }
+        if self.is_unknown(&transaction.tx_type) {
+            *self
+                .stats
+                .unknown_types
+                .entry(transaction.tx_type.to_string())
+                .or_insert(0) += 1;
+        }
+        self.submitted += 1;
+        self.trace(
+            &transaction,
+            TraceStage::Submit,
+            &format!(
+                "submission {}, {}",
+                self.submitted,
+                self.config.client_ids.render(&transaction.mention().into())
+            ),
+        );
+        self.last_active.insert(transaction.client, self.submitted);
+        if self.config.track_ordering {
+            let referenced = self
+                .transaction(transaction.client, transaction.tx)
+                .map(|disputed| disputed.under_dispute);
+            self.ordering.observe(
+                &mut self.stats.ordering,
+                &transaction,
+                referenced,
+                self.submitted,
+            );
+        }
+        let result = match self.config.defer_disputes {
+            Some(window) if self.is_early_dispute_step(&transaction) => {
+                self.trace(
+                    &transaction,
+                    TraceStage::Defer,
+                    &format!("transaction {} is not known yet", transaction.tx),
+                );
+                self.stats.deferred += 1;
+                let evicted = self.deferred.park(&window, transaction, self.submitted);
+                self.give_up(evicted);
+                Ok(())
+            }
+            _ => self.settle(transaction),
+        };
+        if let Some(window) = self.config.defer_disputes {
+            let expired = self.deferred.expire(&window, self.submitted);
+            self.give_up(expired);
+        }
+        if let Some(idle) = self.config.archive_idle.filter(|idle| *idle > 0) {
+            if self.submitted.is_multiple_of(idle) {
+                if let Err(e) = self.archive_inactive(idle) {
+                    eprintln!("Failed to archive inactive clients: {}", e);
+                }
+            }
+        }
+        //checked every eighth of the capacity, so the clients can not outgrow it by more than that between two checks, and
+        //evicted down to 7/8 of it so the scan is not repeated on every new client
+        if let Some(resident) = self
+            .config
+            .archive_resident
+            .filter(|resident| *resident > 0)
+        {
+            if self.clients.len() > resident
+                && self.submitted.is_multiple_of((resident as u64 / 8).max(1))
+            {
+                if let Err(e) = self.archive_least_recent(resident - resident / 8) {
+                    eprintln!("Failed to archive least recently active clients: {}", e);
+                }
+            }
+        }
+        if self.submitted.is_multiple_of(alerts::CHECK_EVERY) {
+            self.check_error_budget();
+        }
+        result
+    }
+
+    /// A dispute, resolve or chargeback of a tx id the client does not have, or not yet, see EngineConfig::defer_disputes
+    fn is_early_dispute_step(&self, transaction: &Transaction) -> bool {
+        matches!(
+            transaction.tx_type,
+            Type::Dispute | Type::Resolve | Type::Chargeback
+        ) && self
+            .transaction(transaction.client, transaction.tx)
+            .is_none()
+    }
+
+    /// Settle the deferred rows given up on, as they would have been without the deferral
+    fn give_up(&mut self, transactions: Vec<Transaction>) {
+        for transaction in transactions {
+            self.stats.deferred_expired += 1;
+            let _ = self.settle(transaction);
+        }
+    }
+
+    /// Give up on the rows still deferred (see EngineConfig::defer_disputes), once the input is exhausted: they are settled
+    /// as they would have been without the deferral. Returns how many there were
+    pub fn expire_deferred(&mut self) -> usize {
+        let parked = self.deferred.drain();
+        let count = parked.len();
+        self.give_up(parked);
+        count
+    }
+
+    /// Apply a submitted transaction and publish its outcome. A deposit or withdrawal accepted settles the rows deferred until
+    /// it came, right after it
+    fn settle(&mut self, transaction: Transaction) -> Result<(), ProcessingError> {
+        self.settle_as(transaction, false)
+    }
+
+    /// settle(), with a chargeback approved by an operator applied whatever its amount instead of parked
+    fn settle_as(
+        &mut self,
+        transaction: Transaction,
+        approved: bool,
+    ) -> Result<(), ProcessingError> {
+        let submitted = transaction.clone();
+        let submitted_type = transaction.tx_type.clone();
+        let duplicate = self
+            .clients
+            .get(&transaction.client)
+            .filter(|client| client.is_duplicate(&transaction))
+            .map(|_| transaction.tx_type.clone());
+        let (client, tx) = (transaction.client, transaction.tx);
+        let dispute_step = matches!(
+            transaction.tx_type,
+            Type::Dispute | Type::Resolve | Type::Chargeback
+        );
+        let traced = self.trace_id(&transaction).map(|trace_id| {
+            let before = self.clients.get(&client).map(AccountBalance::from);
+            (trace_id.to_string(), before, self.retained_transactions)
+        });
+        #[cfg(feature = "latency-histograms")]
+        let (tx_type, start) = (transaction.tx_type.clone(), std::time::Instant::now());
+        let result = self.apply(transaction, approved);
+        #[cfg(feature = "latency-histograms")]
+        {
+            let elapsed = start.elapsed();
+            self.stats.latencies.record(&tx_type, elapsed);
+            self.stats.latencies.record_stage(Stage::Apply, elapsed);
+        }
+        if let Some(profile) = self.clients.get_mut(&client) {
+            profile.record_outcome(tx, result.is_ok());
+        }
+        if dispute_step && result.is_ok() {
+            self.track_dispute(client, tx);
+        }
+        if let (Some(tx_type), Err(_)) = (duplicate, &result) {
+            self.stats.record_duplicate(&tx_type);
+        }
+        let balance = self.clients.get(&client).map(AccountBalance::from);
+        if let Some((trace_id, before, retained)) = traced {
+            self.trace_settled(&trace_id, &result, before, balance.as_ref(), retained);
+        }
+        let result = match result {
+            Ok(applied) => {
+                //accepted transactions always leave a client behind
+                let balance = balance.unwrap();
+                let was_locked = applied.was_locked();
+                let event = self.event(submitted, applied, balance);
+                let charged_back = matches!(event, DomainEvent::ChargebackApplied { .. });
+                let anomalies = match (&event, &self.config.anomaly_detection) {
+                    (DomainEvent::DepositApplied { transaction, .. }, Some(detection)) => {
+                        let amount = transaction.amount.unwrap_or_else(Currency::zero);
+                        self.anomalies
+                            .observe(detection, client, &amount, self.submitted)
+                    }
+                    _ => Vec::new(),
+                };
+                self.publish(event);
+                if !was_locked && self.clients.get(&client).is_some_and(|c| c.locked()) {
+                    self.publish(DomainEvent::AccountLocked { client, tx });
+                }
+                let anomalous = !anomalies.is_empty();
+                for anomaly in anomalies {
+                    self.publish(DomainEvent::AnomalyDetected {
+                        client,
+                        tx,
+                        anomaly,
+                    });
+                }
+                let cooling_off = self.config.cooling_off_for(client, self.tier_of(client));
+                if (charged_back || anomalous) && cooling_off > 0 {
+                    if let Some(profile) = self.clients.get_mut(&client) {
+                        profile.start_cooling_off(cooling_off);
+                    }
+                }
+                if anomalous && self.config.freeze_on_anomaly {
+                    if let Some(profile) = self.clients.get_mut(&client).filter(|c| !c.frozen()) {
+                        profile.set_frozen(true);
+                        self.publish(DomainEvent::AccountFrozen { client, tx });
+                    }
+                }
+                Ok(())
+            }
+            Err(e) => {
+                self.publish(DomainEvent::TransactionRejected {
+                    transaction: submitted,
+                    reason: e.0.clone(),
+                    balance,
+                });
+                Err(e)
+            }
+        };
+        if result.is_ok() && matches!(submitted_type, Type::Deposit | Type::Withdrawal) {
+            for deferred in self.deferred.take(client, tx) {
+                let _ = self.settle(deferred);
+            }
+        }
+        result
+    }
+
+    /// The trace id of the transaction when EngineConfig::trace samples it
+    fn trace_id<'a>(&self, transaction: &'a Transaction) -> Option<&'a str> {
+        let trace_id = transaction.metadata.get(trace::TRACE_ID)?;
+        self.config
+            .trace
+            .as_ref()
+            .filter(|sampling| sampling.sampled(trace_id))
+            .map(|_| trace_id.as_str())
+    }
+
+    fn trace(&self, transaction: &Transaction, stage: TraceStage, detail: &str) {
+        if let Some(trace_id) = self.trace_id(transaction) {
+            trace::emit(trace_id, stage, detail);
+        }
+    }
+
+    /// Trace the outcome of a sampled transaction: the check that rejected it, or the change of the balance and of the
+    /// transactions retained it made
+    fn trace_settled(
+        &self,
+        trace_id: &str,
+        result: &Result<Applied, ProcessingError>,
+        before: Option<AccountBalance>,
+        after: Option<&AccountBalance>,
+        retained: usize,
+    ) {
+        match (result, after) {
+            (Ok(Applied::Parked(amount)), _) => trace::emit(
+                trace_id,
+                TraceStage::Validate,
+                &format!("parked pending approval, amount {}", amount),
+            ),
+            (Ok(_), Some(after)) => {
+                trace::emit(trace_id, TraceStage::Validate, "accepted");
+                trace::emit(
+                    trace_id,
+                    TraceStage::Balance,
+                    &trace::balance_delta(before.as_ref(), after),
+                );
+                trace::emit(
+                    trace_id,
+                    TraceStage::Store,
+                    &format!(
+                        "retained transactions {} -> {}",
+                        retained, self.retained_transactions
+                    ),
+                );
+            }
+            (Ok(_), None) => trace::emit(trace_id, TraceStage::Validate, "accepted"),
+            (Err(ProcessingError(reason)), _) => trace::emit(
+                trace_id,
+                TraceStage::Validate,
+                &format!("rejected, {}", self.config.client_ids.render(reason)),
+            ),
+        }
+    }
+
+    /// The event an accepted transaction is published as. Dispute steps are told apart from the ones that changed nothing by
+    /// the state they left the disputed transaction in, and a resolve lifting the lock (EngineConfig::unlock_on_resolve)
+    /// counts as resolving
+    fn event(
+        &self,
+        transaction: Transaction,
+        applied: Applied,
+        balance: AccountBalance,
+    ) -> DomainEvent {
+        let (was_disputed, was_locked) = match applied {
+            Applied::Parked(amount) => {
+                return DomainEvent::ChargebackParked {
+                    transaction,
+                    amount,
+                    balance,
+                }
+            }
+            Applied::Custom { .. } => {
+                return DomainEvent::CustomApplied {
+                    transaction,
+                    balance,
+                }
+            }
+            Applied::Processed {
+                was_disputed,
+                was_locked,
+            } => (was_disputed, was_locked),
+        };
+        let disputed = self
+            .transaction(transaction.client, transaction.tx)
+            .map(|t| t.under_dispute);
+        let opened = was_disputed == Some(false) && disputed == Some(true);
+        let closed = was_disputed == Some(true) && disputed == Some(false);
+        match transaction.tx_type {
+            Type::Deposit => DomainEvent::DepositApplied {
+                transaction,
+                balance,
+            },
+            Type::Withdrawal => DomainEvent::WithdrawalApplied {
+                transaction,
+                balance,
+            },
+            Type::Dispute if opened => DomainEvent::DisputeOpened {
+                transaction,
+                balance,
+            },
+            Type::Resolve if closed || (was_locked && !balance.locked) => {
+                DomainEvent::DisputeResolved {
+                    transaction,
+                    balance,
+                }
+            }
+            Type::Chargeback if closed => DomainEvent::ChargebackApplied {
+                transaction,
+                balance,
+            },
+            _ => DomainEvent::TransactionIgnored {
+                transaction,
+                balance,
+            },
+        }
+    }
+
+    fn apply(
+        &mut self,
+        transaction: Transaction,
+        approved: bool,
+    ) -> Result<Applied, ProcessingError> {
+        if self.config.blocklist.contains(&transaction.client) {
+            return Err(ProcessingError::new(format!(
+                "Client {} is blocklisted. Rejecting transaction {}",
+                Mention(transaction.client),
+                transaction.mention()
+            )));
+        }
+        if self.config.denied_types.contains(&transaction.tx_type) {
+            return Err(ProcessingError::new(format!(
+                "Transactions of type {} are denied for this run. Rejecting transaction {}",
+                transaction.tx_type,
+                transaction.mention()
+            )));
+        }
+        if self.config.limits.on_exceeded == LimitPolicy::Degrade && self.archive.is_some() {
+            self.spill(&transaction);
+        }
+        if let Some(max_clients) = self.config.limits.max_clients {
+            if !self.clients.contains_key(&transaction.client) && self.clients.len() >= max_clients
+            {
+                return Err(ProcessingError::new(format!(
+                    "Client limit of {} reached. Rejecting transaction {}",
+                    max_clients,
+                    transaction.mention()
+                )));
+            }
+        }
+        if let Some(max_transactions) = self.config.limits.max_transactions {
+            if self.config.retain_transactions && self.retained_transactions >= max_transactions {
+                eprintln!(
+                    "Retained transactions limit of {} reached, transactions from now on can not be disputed",
+                    max_transactions
+                );
+                self.config.retain_transactions = false;
+            }
+        }
+        if let Some(max_memory) = self.memory_cap_reached() {
+            if !self.clients.contains_key(&transaction.client) {
+                return Err(ProcessingError::new(format!(
+                    "Memory limit of {} bytes reached. Rejecting transaction {}",
+                    max_memory,
+                    transaction.mention()
+                )));
+            }
+            if self.config.retain_transactions {
+                eprintln!(
+                    "Memory limit of {} bytes reached, transactions from now on can not be disputed",
+                    max_memory
+                );
+                self.config.retain_transactions = false;
+            }
+        }
+
+        self.rehydrate(transaction.client)?;
+
+        if let Some(amount) = self.requires_approval(&transaction).filter(|_| !approved) {
+            let already_pending = self.pending_chargebacks.iter().any(|pending| {
+                pending.client() == transaction.client && pending.tx() == transaction.tx
+            });
+            if !already_pending {
+                self.pending_chargebacks
+                    .push(PendingChargeback::new(transaction, amount));
+            }
+            return Ok(Applied::Parked(amount));
+        }
+
+        if let Type::Custom(name) = &transaction.tx_type {
+            if self.config.unknown_types == UnknownTypePolicy::Reject {
+                return Err(ProcessingError::new(format!(
+                    "Unknown transaction type {}. Rejecting transaction {}",
+                    name,
+                    transaction.mention()
+                )));
+            }
+            let handler = self.handlers.get(name).cloned().ok_or_else(|| {
+                ProcessingError::new(format!(
+                    "No handler registered for transaction type {}. Rejecting transaction {}",
+                    name,
+                    transaction.mention()
+                ))
+            })?;
+            let client = self
+                .clients
+                .entry(transaction.client)
+                .or_insert_with(|| ClientProfile::new_with_defaults(transaction.client));
+            let was_locked = client.locked();
+            return client
+                .process_custom(&transaction, handler.as_ref())
+                .map(|_| Applied::Custom { was_locked });
+        }
+
+        // If the client does not exist, create a new one.
+        // ClientProfile::new() is only called when the client does not exist: or_insert_with with the default closure guarantee that a new ClientProfile is not created every time .entry() is called
+        let client = self
+            .clients
+            .entry(transaction.client)
+            .or_insert_with(|| ClientProfile::new_with_defaults(transaction.client));
+        let retained_before = client.transaction_count();
+        let applied = Applied::Processed {
+            was_disputed: client.transaction(transaction.tx).map(|t| t.under_dispute),
+            was_locked: client.locked(),
+        };
+        let result = client.process_with_config(transaction, &self.config);
+        self.retained_transactions += client.transaction_count() - retained_before;
+        result.map(|_| applied)
+    }
+
+    /// Account summaries with amounts rendered by the given strategy, for reports that are not the machine CSV of to_csv()
+    pub fn to_writer<W: io::Write>(&self, writer: W, format: &AmountFormat) -> io::Result<()> {
+        output::write_accounts(
+            writer,
+            self.clients.values(),
+            format,
+            &self.config.client_ids,
+        )
+    }
+
+    /// Account summaries with the risk columns, see output::write_accounts_extended
+    pub fn to_extended_writer<W: io::Write>(
+        &self,
+        writer: W,
+        format: &AmountFormat,
+    ) -> io::Result<()> {
+        output::write_accounts_extended(writer, self.clients.values(), format, &self.config)
+    }
+
+    /// Account summaries as a table for people to read, see output::write_accounts_table
+    pub fn to_table_writer<W: io::Write>(
+        &self,
+        writer: W,
+        format: &AmountFormat,
+        colored: bool,
+    ) -> io::Result<()> {
+        output::write_accounts_table(
+            writer,
+            self.clients.values(),
+            format,
+            &self.config.client_ids,
+            colored,
+        )
+    }
+
+    /// Account summaries with the activity columns, see output::write_accounts_composite
+    pub fn to_composite_writer<W: io::Write>(
+        &self,
+        writer: W,
+        format: &AmountFormat,
+    ) -> Result<(), csv::Error> {
+        output::write_accounts_composite(
+            writer,
+            self.clients.values(),
+            format,
+            &self.config.client_ids,
+        )
+    }
+
+    /// Account summaries as JSON, optionally with the held funds broken down per disputed transaction and with the account
+    /// annotations (see output::write_accounts_json)
+    pub fn to_json_writer<W: io::Write>(
+        &self,
+        writer: W,
+        format: &AmountFormat,
+        holds: bool,
+        annotations: bool,
+    ) -> io::Result<()> {
+        output::write_accounts_json(
+            writer,
+            self.clients.values(),
+            format,
+            &self.config.client_ids,
+            holds,
+            annotations,
+        )
+    }
+
+    /// to_writer() with the rows formatted by several threads (see output::write_accounts_parallel)
+    pub fn to_writer_parallel<W: io::Write>(
+        &self,
+        writer: W,
+        format: &AmountFormat,
+        threads: usize,
+    ) -> io::Result<()> {
+        output::write_accounts_parallel(
+            writer,
+            self.clients.values(),
+            format,
+            &self.config.client_ids,
+            threads,
+        )
+    }
+
+    /// to_writer_parallel() flushed one range of clients at a time, see output::write_accounts_in_ranges
+    pub fn to_writer_in_ranges<W: io::Write>(
+        &self,
+        writer: W,
+        format: &AmountFormat,
+        threads: usize,
+        range: usize,
+        on_range: impl FnMut(usize, usize),
+    ) -> io::Result<()> {
+        output::write_accounts_in_ranges(
+            writer,
+            self.clients.values(),
+            format,
+            &self.config.client_ids,
+            threads,
+            range,
+            on_range,
+        )
+    }
+
+    /// Account summaries split across one file per shard, written concurrently (see output::write_account_shards)
+    pub fn to_shard_files(
+        &self,
+        paths: &[std::path::PathBuf],
+        format: &AmountFormat,
+    ) -> io::Result<()> {
+        output::write_account_shards(
+            self.clients.values(),
+            paths,
+            format,
+            &self.config.client_ids,
+        )
+    }
+
+    pub fn receivables_to_writer<W: io::Write>(&self, writer: W) -> io::Result<()> {
+        output::write_receivables(writer, self.clients.values(), &self.config.client_ids)
+    }
+
+    /// The accounts and their retained transactions as a Postgres script of INSERT statements, see sql::write_sql
+    pub fn to_sql_writer<W: io::Write>(&self, writer: W) -> io::Result<()> {
+        sql::write_sql(writer, self.clients.values(), &self.config.client_ids)
+    }
+
+    /// Machine CSV of the account summaries on stdout, buffered rather than locking and flushing stdout for every account
+    pub fn to_csv(&self) {
+        if let Err(e) = self.to_writer(io::stdout().lock(), &AmountFormat::Plain) {
+            eprintln!("Failed to write accounts with exception: {}", e)
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct IngestOptions {
+    /// Input schema version, detected from the headers when None
+    pub schema: Option<SchemaVersion>,
+    /// Capture the columns unknown to the schema version into Transaction::metadata
+    pub capture_metadata: bool,
+    /// Write records that are not valid CSV to this file (see quarantine::Quarantine) and carry on, instead of failing the input
+    pub quarantine: Option<std::path::PathBuf>,
+    /// Skip the corrupted regions of the input file and carry on, instead of failing the input, see
+    /// recovery::recover_transactions_from_csv
+    pub recover: bool,
+    /// Bounds the amount of every record must be within, a record out of them can not be read
+    pub amount_bounds: Option<AmountBounds>,
+    /// Do not log rejected transactions and ingestion progress to stderr
+    pub quiet: bool,
+}
+
+//read one record at the time and only deserialize the current one. This avoids loading a huge dataset into memory and also to only deserilaise the current row that is being processed
+pub fn process_transactions_from_csv(
+    path: &str,
+    bank: &mut Exchange,
+) -> Result<(), Box<dyn Error>> {
+    process_transactions_from_csv_with(path, bank, &IngestOptions::default())
+}
+
+/// Turns the records of an input into transactions for an exchange, according to the schema of its headers and the ingest options
+struct RecordReader {
+    headers: csv::StringRecord,
+    schema: SchemaVersion,
+    extra_columns: Vec<(usize, String)>,
+    /// Position of the client column, read through the mapping with ClientIds::Mapped
+    client_column: Option<usize>,
+    /// Position of the trace_id column, captured while tracing even when the other extra columns are not
+    trace_column: Option<usize>,
+    amount_bounds: Option<AmountBounds>,
+    quiet: bool,
+}
+
+impl RecordReader {
+    fn new(headers: &csv::StringRecord, options: &IngestOptions) -> RecordReader {
+        let schema = options
+            .schema
+            .unwrap_or_else(|| SchemaVersion::detect(headers));
+        let extra_columns = if options.capture_metadata {
+            schema.extra_columns(headers)
+        } else {
+            Vec::new()
+        };
+        RecordReader {
+            headers: headers.clone(),
+            schema,
+            extra_columns,
+            client_column: headers.iter().position(|header| header.trim() == "client"),
+            trace_column: headers
+                .iter()
+                .position(|header| header.trim() == trace::TRACE_ID),
+            amount_bounds: options.amount_bounds.clone(),
+            quiet: options.quiet,
+        }
+    }
+
+    /// Rejected transactions are only logged, records that can not be read, limits exceeded under LimitPolicy::Abort and unknown
+    /// types under UnknownTypePolicy::Fail fail
+    fn ingest(
+        &self,
+        bank: &mut Exchange,
+        raw_record: &csv::StringRecord,
+    ) -> Result<(), Box<dyn Error>> {
+        #[cfg(feature = "latency-histograms")]
+        let start = std::time::Instant::now();
+        let t = self.parse(bank, raw_record)?;
+        #[cfg(feature = "latency-histograms")]
+        let (parsed, start) = (start.elapsed(), std::time::Instant::now());
+        let t = self.validate(bank, raw_record, t)?;
+        #[cfg(feature = "latency-histograms")]
+        {
+            let validated = start.elapsed();
+            bank.stats.latencies.record_stage(Stage::Parse, parsed);
+            bank.stats
+                .latencies
+                .record_stage(Stage::Validate, validated);
+        }
+        if let Err(error) = bank.process_new_transaction(t) {
+            self.log_rejection(bank, &error);
+        }
+        bank.snapshot_if_due();
+        Ok(())
+    }
+
+    /// The transaction of a record as its schema reads it, before any check against the state of bank
+    fn parse(
+        &self,
+        bank: &Exchange,
+        raw_record: &csv::StringRecord,
+    ) -> Result<Transaction, Box<dyn Error>> {
+        let t = match (&bank.config.client_ids, self.client_column) {
+            (ClientIds::Mapped(mapping), Some(index)) => self.schema.read(
+                &remap::remap_record(mapping, raw_record, index)?,
+                &self.headers,
+            )?,
+            _ => self.schema.read(raw_record, &self.headers)?,
+        };
+        if let (Some(bounds), Some(amount)) = (&self.amount_bounds, t.amount) {
+            bounds.check(t.tx, amount)?;
+        }
+        Ok(t)
+    }
+
+    /// The transaction of a record, once the limits and its type were checked against the current state of bank
+    fn read(
+        &self,
+        bank: &Exchange,
+        raw_record: &csv::StringRecord,
+    ) -> Result<Transaction, Box<dyn Error>> {
+        let t = self.parse(bank, raw_record)?;
+        self.validate(bank, raw_record, t)
+    }
+
+    /// Check the limits and the type of the transaction parsed from raw_record against the current state of bank, and capture
+    /// its metadata
+    fn validate(
+        &self,
+        bank: &Exchange,
+        raw_record: &csv::StringRecord,
+        mut t: Transaction,
+    ) -> Result<Transaction, Box<dyn Error>> {
+        #[cfg(any(test, feature = "fault-injection"))]
+        if let Some(faults) = &bank.faults {
+            faults.before_store()?;
+        }
+        bank.check_limits(&t)?;
+        bank.check_type(&t)?;
+        t.metadata = self
+            .extra_columns
+            .iter()
+            .filter_map(|(index, column)| {
+                raw_record
+                    .get(*index)
+                    .filter(|value| !value.is_empty())
+                    .map(|value| (column.clone(), value.to_string()))
+            })
+            .collect();
+        if let (Some(_), Some(index)) = (&bank.config.trace, self.trace_column) {
+            if let Some(trace_id) = raw_record.get(index).filter(|value| !value.is_empty()) {
+                t.metadata
+                    .entry(trace::TRACE_ID.to_string())
+                    .or_insert_with(|| trace_id.trim().to_string());
+            }
+        }
+        Ok(t)
+    }
+
+    fn log_rejection(&self, bank: &Exchange, ProcessingError(reason): &ProcessingError) {
+        if !self.quiet {
+            eprintln!("{}", bank.config.client_ids.render(reason));
+        }
+    }
+}
+
+pub fn process_transactions_from_csv_with(
+    path: &str,
+    bank: &mut Exchange,
+    options: &IngestOptions,
+) -> Result<(), Box<dyn Error>> {
+    if options.recover {
+        return recovery::recover_transactions_from_csv(path, bank, options).map(drop);
+    }
+    let Some(quarantine_path) = &options.quarantine else {
+        return process_transactions_from_reader_with(std::fs::File::open(path)?, bank, options);
+    };
+    let mut reader = csv::Reader::from_path(path)?;
+
+    let headers = reader.headers()?.clone();
+    let ingest = RecordReader::new(&headers, options);
+
+    //records are read as bytes so invalid UTF-8 does not fail the reader, and the input offsets delimit the raw record
+    let mut quarantine = Quarantine::create(path, quarantine_path)?;
+    let mut byte_record = csv::ByteRecord::new();
+    loop {
+        let start = reader.position().byte();
+        match reader.read_byte_record(&mut byte_record) {
+            Ok(false) => break,
+            Ok(true) => {
+                match csv::StringRecord::from_byte_record(std::mem::take(&mut byte_record)) {
+                    Ok(raw_record) => ingest.ingest(bank, &raw_record)?,
+                    Err(e) => {
+                        bank.stats.malformed += 1;
+                        quarantine.record(start, reader.position().byte(), &e)?
+                    }
+                }
+            }
+            Err(e) if !matches!(e.kind(), csv::ErrorKind::Io(_)) => {
+                bank.stats.malformed += 1;
+                quarantine.record(start, reader.position().byte(), &e)?
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    quarantine.flush()?;
+    if quarantine.records() > 0 && !options.quiet {
+        eprintln!("{} malformed records quarantined", quarantine.records());
+    }
+
+    Ok(())
+}
+
+/// process_transactions_from_csv_with() for inputs that are not files, e.g. objects streamed from object storage. A quarantine
+/// copies malformed records out of the input file, so it needs one
+pub fn process_transactions_from_reader_with<R: io::Read>(
+    input: R,
+    bank: &mut Exchange,
+    options: &IngestOptions,
+) -> Result<(), Box<dyn Error>> {
+    if options.quarantine.is_some() {
+        return Err("A quarantine needs an input file".into());
+    }
+    let mut reader = csv::Reader::from_reader(input);
+
+    let headers = reader.headers()?.clone();
+    let ingest = RecordReader::new(&headers, options);
+
+    let mut raw_record = csv::StringRecord::new();
+    loop {
+        #[cfg(feature = "latency-histograms")]
+        let start = std::time::Instant::now();
+        if !reader.read_record(&mut raw_record)? {
+            break;
+        }
+        #[cfg(feature = "latency-histograms")]
+        bank.stats
+            .latencies
+            .record_stage(Stage::Read, start.elapsed());
+        ingest.ingest(bank, &raw_record)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use std::collections::HashMap;
+
+    use transaction::DisputeMetadata;
+    use transaction::Money;
+
+    #[test]
+    fn it_should_handle_deposits_and_withdrawals_for_multiple_clients() {
+        let mut exchange = Exchange::new();
+
+        let tx91 = Transaction {
+            tx_type: Type::Deposit,
+            client: 1,
+            tx: 91,
+            amount: Some(Currency::str("123.0")),
+            metadata: HashMap::new(),
+            under_dispute: false,
+            dispute_metadata: None,
+        };
+
+        let tx92 = Transaction {
+            tx_type: Type::Deposit,
+            client: 2,
+            tx: 92,
+            amount: Some(Currency::str("55.0")),
+            metadata: HashMap::new(),
+            under_dispute: false,
+            dispute_metadata: None,
+        };
+
+        let tx93 = Transaction {
+            tx_type: Type::Withdrawal,
+            client: 2,
+            tx: 93,
+            amount: Some(Currency::str("44.0")),
+            metadata: HashMap::new(),
+            under_dispute: false,
+            dispute_metadata: None,
+        };
+
+        let tx94 = Transaction {
+            tx_type: Type::Withdrawal,
+            client: 1,
+            tx: 94,
+            amount: Some(Currency::str("33.0")),
+            metadata: HashMap::new(),
+            under_dispute: false,
+            dispute_metadata: None,
+        };
+
+        exchange
+            .process_new_transaction(tx91.clone())
+            .unwrap_or_default();
+        exchange
+            .process_new_transaction(tx92.clone())
+            .unwrap_or_default();
+        exchange
+            .process_new_transaction(tx93.clone())
+            .unwrap_or_default();
+        exchange
+            .process_new_transaction(tx94.clone())
+            .unwrap_or_default();
+
+        let mut client1 = ClientProfile::new(
+            1,
+            Currency::str("90.0"),
+            Currency::str("0.0"),
+            Currency::str("90.0"),
+            false,
+            HashMap::from([(tx91.tx, tx91), (tx94.tx, tx94)]),
+        );
+        client1.record_outcome(94, true);
+
+        let mut client2 = ClientProfile::new(
+            2,
+            Currency::str("11.0"),
+            Currency::str("0.0"),
+            Currency::str("11.0"),
+            false,
+            HashMap::from([(tx92.tx, tx92), (tx93.tx, tx93)]),
+        );
+        client2.record_outcome(93, true);
+
+        assert_eq!(
+            [(1, client1), (2, client2)]
+                .into_iter()
+                .collect::<IdMap<_, _>>(),
+            exchange.clients
+        );
+    }
+
+    #[test]
+    fn it_should_not_change_live_state_when_processing_on_a_fork() {
+        let mut exchange = Exchange::new();
+        exchange
+            .process_new_transaction(Transaction {
+                tx_type: Type::Deposit,
+                client: 1,
+                tx: 1,
+                amount: Some(Currency::str("10.0")),
+                metadata: HashMap::new(),
+                under_dispute: false,
+                dispute_metadata: None,
+            })
+            .unwrap_or_default();
+
+        let mut fork = exchange.fork();
+        fork.process_new_transaction(Transaction {
+            tx_type: Type::Dispute,
+            client: 1,
+            tx: 1,
+            amount: None,
+            metadata: HashMap::new(),
+            under_dispute: false,
+            dispute_metadata: None,
+        })
+        .unwrap_or_default();
+        fork.process_new_transaction(Transaction {
+            tx_type: Type::Chargeback,
+            client: 1,
+            tx: 1,
+            amount: None,
+            metadata: HashMap::new(),
+            under_dispute: false,
+            dispute_metadata: None,
+        })
+        .unwrap_or_default();
+
+        let live = exchange.clients.get(&1).unwrap();
+        assert_eq!(Currency::str("10.0"), live.available());
+        assert_eq!(false, live.locked());
+
+        let forked = fork.clients.get(&1).unwrap();
+        assert_eq!(Currency::str("0.0"), forked.total());
+        assert_eq!(true, forked.locked());
+
+        exchange.commit(fork);
+        assert_eq!(true, exchange.clients.get(&1).unwrap().locked());
+    }
+
+    #[test]
+    fn it_should_ignore_transaction_if_account_is_locked() {
+        let locked_client_profile = ClientProfile::new(
+            1,
+            Currency::str("10.0"),
+            Currency::str("00.0"),
+            Currency::str("-10.0"),
+            true,
+            HashMap::new(),
+        );
+
+        let mut exchange = Exchange {
+            clients: [(1, locked_client_profile)].into_iter().collect(),
+            ..Exchange::new()
+        };
+
+        let result = exchange
+            .process_new_transaction(Transaction {
+                tx_type: Type::Deposit,
+                client: 1,
+                tx: 91,
+                amount: Some(Currency::str("123.0")),
+                metadata: HashMap::new(),
+                under_dispute: false,
+                dispute_metadata: None,
+            })
+            .err();
+
+        assert_eq!(true, result.is_some());
+    }
+
+    #[test]
+    fn it_should_read_dispute_metadata_from_optional_csv_columns() {
+        let path = std::env::temp_dir().join("payment_engine_dispute_metadata.csv");
+        std::fs::write(
+            &path,
+            "type,client,tx,amount,reason,case_id,note\n\
+             deposit,1,1,10.0,,,\n\
+             dispute,1,1,,fraud,CASE-1,\n\
+             chargeback,1,1,,,,card reported stolen\n",
+        )
+        .unwrap();
+
+        let mut exchange = Exchange::new();
+        process_transactions_from_csv(path.to_str().unwrap(), &mut exchange).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            Some(&DisputeMetadata::new(
+                Some("fraud".to_string()),
+                Some("CASE-1".to_string()),
+                Some("card reported stolen".to_string()),
+            )),
+            exchange.transaction(1, 1).unwrap().dispute_metadata()
+        );
+    }
+
+    #[test]
+    fn it_should_report_chargeback_reasons_across_clients() {
+        let mut exchange = Exchange::new();
+        for (client, reason) in [(1, "fraud"), (2, "duplicate"), (3, "")] {
+            for tx_type in [Type::Deposit, Type::Dispute, Type::Chargeback] {
+                let is_deposit = tx_type == Type::Deposit;
+                exchange
+                    .process_new_transaction(Transaction {
+                        tx_type,
+                        client,
+                        tx: client as TransactionId,
+                        amount: Some(Currency::str("1.0")).filter(|_| is_deposit),
+                        metadata: HashMap::new(),
+                        under_dispute: false,
+                        dispute_metadata: Some(DisputeMetadata::new(
+                            Some(reason.to_string()),
+                            None,
+                            None,
+                        ))
+                        .filter(|_| !is_deposit),
+                    })
+                    .unwrap_or_default();
+            }
+        }
+        exchange
+            .process_new_transaction(Transaction {
+                tx_type: Type::Deposit,
+                client: 1,
+                tx: 10,
+                amount: Some(Currency::str("1.0")),
+                metadata: HashMap::new(),
+                under_dispute: false,
+                dispute_metadata: None,
+            })
+            .unwrap_or_default();
+
+        let stats = exchange.stats();
+        assert_eq!(9, stats.processed());
+        assert_eq!(1, stats.rejected());
+        assert_eq!(1, stats.chargebacks(ChargebackReason::Fraud));
+        assert_eq!(1, stats.chargebacks(ChargebackReason::Duplicate));
+        assert_eq!(0, stats.chargebacks(ChargebackReason::GoodsNotReceived));
+        assert_eq!(1, stats.chargebacks(ChargebackReason::Unspecified));
+        assert_eq!(false, exchange.client(2).unwrap().locked());
+    }
+
+    fn deposit(client: ClientId, tx: TransactionId, amount: &str) -> Transaction {
+        Transaction {
+            tx_type: Type::Deposit,
+            client,
+            tx,
+            amount: Some(Currency::str(amount)),
+            metadata: HashMap::new(),
+            under_dispute: false,
+            dispute_metadata: None,
+        }
+    }
+
+    fn dispute(client: ClientId, tx: TransactionId) -> Transaction {
+        Transaction {
+            tx_type: Type::Dispute,
+            client,
+            tx,
+            amount: None,
+            metadata: HashMap::new(),
+            under_dispute: false,
+            dispute_metadata: None,
+        }
+    }
+
+    #[test]
+    fn it_should_merge_balances_history_and_open_disputes_into_the_target() {
+        let mut exchange = Exchange::new();
+        exchange
+            .process_new_transaction(deposit(1, 1, "10.0"))
+            .unwrap();
+        exchange
+            .process_new_transaction(deposit(2, 2, "5.0"))
+            .unwrap();
+        exchange.process_new_transaction(dispute(2, 2)).unwrap();
+
+        exchange.merge_clients(2, 1, false).unwrap();
+
+        assert_eq!(true, exchange.client(2).is_none());
+        let merged = exchange.client(1).unwrap();
+        assert_eq!(Currency::str("10.0"), merged.available());
+        assert_eq!(Currency::str("5.0"), merged.held());
+        assert_eq!(Currency::str("15.0"), merged.total());
+        assert_eq!(1, exchange.transaction(1, 2).unwrap().client());
+
+        //the dispute opened on the duplicate profile is still resolvable on the target
+        exchange
+            .process_new_transaction(Transaction {
+                tx_type: Type::Resolve,
+                ..dispute(1, 2)
+            })
+            .unwrap();
+        assert_eq!(
+            Currency::str("15.0"),
+            exchange.client(1).unwrap().available()
+        );
+
+        assert_eq!(
+            &AuditEvent::ClientsMerged {
+                from: 2,
+                to: 1,
+                transactions: 1,
+                forced: false,
+            },
+            exchange.audit_log()[0].event()
+        );
+    }
+
+    #[test]
+    fn it_should_index_the_open_disputes_as_they_open_and_close() {
+        let mut exchange = Exchange::new();
+        for (client, tx) in [(3, 1), (1, 2), (1, 3), (2, 4), (2, 5)] {
+            exchange
+                .process_new_transaction(deposit(client, tx, "1.0"))
+                .unwrap();
+        }
+        for (client, tx) in [(3, 1), (1, 3), (1, 2), (2, 4), (2, 5)] {
+            exchange
+                .process_new_transaction(dispute(client, tx))
+                .unwrap();
+        }
+        exchange
+            .process_new_transaction(Transaction {
+                tx_type: Type::Resolve,
+                ..dispute(2, 4)
+            })
+            .unwrap();
+        exchange
+            .process_new_transaction(Transaction {
+                tx_type: Type::Chargeback,
+                ..dispute(3, 1)
+            })
+            .unwrap();
+        let open = |exchange: &Exchange| {
+            exchange
+                .disputed_transactions()
+                .map(|t| (t.client(), t.tx()))
+                .collect::<Vec<(ClientId, TransactionId)>>()
+        };
+        assert_eq!(vec![(1, 2), (1, 3), (2, 5)], open(&exchange));
+        assert_eq!(false, exchange.client(3).unwrap().has_open_disputes());
+
+        exchange.merge_clients(2, 4, false).unwrap();
+        assert_eq!(vec![(1, 2), (1, 3), (4, 5)], open(&exchange));
+
+        //the index is not part of the checkpoint, it is rebuilt from the transactions
+        let path = std::env::temp_dir().join("payment_engine_dispute_index.json");
+        let position = checkpoint::InputPosition {
+            byte: 0,
+            line: 0,
+            records: 0,
+        };
+        exchange.write_checkpoint(&path, position).unwrap();
+        let mut restored = Exchange::new();
+        restored.restore_checkpoint(&path).unwrap();
+        assert_eq!(open(&exchange), open(&restored));
+        assert_eq!(exchange.client(1), restored.client(1));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn it_should_reject_merging_locked_accounts_unless_forced() {
+        let mut exchange = Exchange::new();
+        exchange
+            .process_new_transaction(deposit(1, 1, "10.0"))
+            .unwrap();
+        exchange
+            .process_new_transaction(deposit(2, 2, "5.0"))
+            .unwrap();
+        exchange.process_new_transaction(dispute(2, 2)).unwrap();
+        exchange
+            .process_new_transaction(Transaction {
+                tx_type: Type::Chargeback,
+                ..dispute(2, 2)
+            })
+            .unwrap();
+
+        assert_eq!(true, exchange.merge_clients(2, 1, false).is_err());
+        assert_eq!(0, exchange.audit_log().len());
+
+        exchange.merge_clients(2, 1, true).unwrap();
+        assert_eq!(true, exchange.client(1).unwrap().locked());
+        assert_eq!(1, exchange.audit_log().len());
+    }
+
+    #[test]
+    fn it_should_reject_merging_clients_sharing_a_transaction_id() {
+        let mut exchange = Exchange::new();
+        exchange
+            .process_new_transaction(deposit(1, 7, "10.0"))
+            .unwrap();
+        exchange
+            .process_new_transaction(deposit(2, 7, "5.0"))
+            .unwrap();
+
+        assert_eq!(true, exchange.merge_clients(2, 1, false).is_err());
+        assert_eq!(true, exchange.merge_clients(3, 1, false).is_err());
+        assert_eq!(true, exchange.merge_clients(1, 1, false).is_err());
+        assert_eq!(Currency::str("5.0"), exchange.client(2).unwrap().total());
+    }
+
+    #[test]
+    fn it_should_merge_exchanges_processed_independently() {
+        let mut exchange = Exchange::new().with_ledger();
+        exchange
+            .process_new_transaction(deposit(1, 1, "10.0"))
+            .unwrap();
+        exchange
+            .process_new_transaction(deposit(2, 2, "5.0"))
+            .unwrap();
+
+        let mut other = Exchange::new().with_ledger();
+        other.process_new_transaction(deposit(1, 3, "2.0")).unwrap();
+        other.process_new_transaction(dispute(1, 3)).unwrap();
+        other.process_new_transaction(deposit(3, 4, "1.0")).unwrap();
+        other
+            .process_new_transaction(Transaction {
+                tx_type: Type::Withdrawal,
+                ..deposit(3, 5, "2.0")
+            })
+            .unwrap_err();
+
+        exchange.merge(other).unwrap();
+
+        let client = exchange.client(1).unwrap();
+        assert_eq!(Currency::str("10.0"), client.available());
+        assert_eq!(Currency::str("2.0"), client.held());
+        assert_eq!(Currency::str("12.0"), client.total());
+        assert_eq!(2, client.transaction_count());
+        assert_eq!(true, exchange.transaction(1, 3).unwrap().under_dispute);
+        assert_eq!(Currency::str("1.0"), exchange.client(3).unwrap().total());
+
+        assert_eq!(5, exchange.stats().processed());
+        assert_eq!(1, exchange.stats().rejected());
+        let seqs: Vec<_> = exchange
+            .ledger()
+            .unwrap()
+            .entries()
+            .iter()
+            .map(|entry| entry.seq())
+            .collect();
+        assert_eq!(vec![1, 2, 3, 4, 5, 6], seqs);
+    }
+
+    #[test]
+    fn it_should_reject_merging_exchanges_sharing_a_transaction_id() {
+        let mut exchange = Exchange::new();
+        exchange
+            .process_new_transaction(deposit(1, 1, "10.0"))
+            .unwrap();
+
+        let mut other = Exchange::new();
+        other.process_new_transaction(deposit(2, 1, "5.0")).unwrap();
+        other.process_new_transaction(deposit(2, 2, "5.0")).unwrap();
+
+        assert_eq!(
+            "Cannot merge exchanges: transaction 1 exists in both (client 1 and client 2), 1 conflicting transaction id(s) in total",
+            exchange.merge(other).unwrap_err().to_string()
+        );
+        assert_eq!(None, exchange.client(2));
+        assert_eq!(1, exchange.stats().processed());
+    }
+
+    #[test]
+    fn it_should_query_accounts_and_open_disputes() {
+        let mut exchange = Exchange::new();
+        exchange
+            .process_new_transaction(deposit(1, 1, "10.0"))
+            .unwrap();
+        exchange
+            .process_new_transaction(deposit(1, 2, "1.0"))
+            .unwrap();
+        exchange
+            .process_new_transaction(deposit(2, 3, "5.0"))
+            .unwrap();
+        exchange.process_new_transaction(dispute(1, 2)).unwrap();
+        exchange
+            .process_new_transaction(deposit(3, 4, "7.0"))
+            .unwrap();
+        exchange.process_new_transaction(dispute(3, 4)).unwrap();
+        exchange
+            .process_new_transaction(Transaction {
+                tx_type: Type::Chargeback,
+                ..dispute(3, 4)
+            })
+            .unwrap();
+
+        let locked = AccountFilter {
+            locked: Some(true),
+            ..AccountFilter::default()
+        };
+        assert_eq!(
+            vec![3],
+            exchange
+                .accounts(&locked)
+                .map(|c| c.id())
+                .collect::<Vec<ClientId>>()
+        );
+
+        let mut rich = exchange
+            .accounts(&AccountFilter {
+                min_total: Some(Currency::str("5.0")),
+                ..AccountFilter::default()
+            })
+            .map(|c| c.id())
+            .collect::<Vec<ClientId>>();
+        rich.sort();
+        assert_eq!(vec![1, 2], rich);
+
+        let page = exchange.accounts_page(1, 1, AccountSort::Total);
+        assert_eq!(3, page.total);
+        assert_eq!(
+            vec![2],
+            page.accounts
+                .iter()
+                .map(|a| a.client)
+                .collect::<Vec<ClientId>>()
+        );
+        let pages: Vec<ClientId> = (0..4)
+            .flat_map(|offset| {
+                exchange
+                    .accounts_page(offset, 1, AccountSort::Client)
+                    .accounts
+            })
+            .map(|a| a.client)
+            .collect();
+        assert_eq!(vec![1, 2, 3], pages);
+        //2 and 3 hold nothing, the tie is broken by client id
+        assert_eq!(
+            vec![1, 2, 3],
+            exchange
+                .accounts_page(0, 10, AccountSort::Held)
+                .accounts
+                .iter()
+                .map(|a| a.client)
+                .collect::<Vec<ClientId>>()
+        );
+
+        assert_eq!(
+            vec![(1, 2)],
+            exchange
+                .disputed_transactions()
+                .map(|t| (t.client(), t.tx()))
+                .collect::<Vec<(ClientId, TransactionId)>>()
+        );
+    }
+
+    #[test]
+    fn it_should_pass_extra_columns_through_to_the_ledger() {
+        let path = std::env::temp_dir().join("payment_engine_metadata_passthrough.csv");
+        std::fs::write(
+            &path,
+            "type,client,tx,amount,correlation_id,batch\n\
+             deposit,1,1,10.0,abc-1,7\n\
+             withdrawal,1,2,20.0,abc-2,\n",
+        )
+        .unwrap();
+
+        let mut exchange = Exchange::new().with_ledger();
+        process_transactions_from_csv_with(
+            path.to_str().unwrap(),
+            &mut exchange,
+            &IngestOptions {
+                capture_metadata: true,
+                ..IngestOptions::default()
+            },
+        )
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut output = Vec::new();
+        exchange
+            .ledger()
+            .unwrap()
+            .write_csv(&mut output, &ClientIds::Raw)
+            .unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+
+        assert_eq!(3, lines.len());
+        assert_eq!(
+            "1,deposit,1,1,10.0000,applied,,batch=7;correlation_id=abc-1",
+            lines[1]
+        );
+        assert_eq!(
+            true,
+            lines[2].starts_with("2,withdrawal,1,2,20.0000,rejected,")
+        );
+        assert_eq!(true, lines[2].ends_with(",correlation_id=abc-2"));
+        assert_eq!(
+            Some(&"abc-1".to_string()),
+            exchange
+                .transaction(1, 1)
+                .unwrap()
+                .metadata()
+                .get("correlation_id")
+        );
+    }
+
+    #[test]
+    fn it_should_write_hashed_client_ids_to_the_output_artifacts() {
+        let path = std::env::temp_dir().join("payment_engine_hashed_client_ids.csv");
+        std::fs::write(
+            &path,
+            "type,client,tx,amount\ndeposit,1,1,10.0\nwithdrawal,1,2,20.0\n",
+        )
+        .unwrap();
+
+        let ids = ClientIds::hashed("s4lt");
+        let label = ids.label(1).to_string();
+        let mut exchange = Exchange::new()
+            .with_config(EngineConfig {
+                client_ids: ids.clone(),
+                ..EngineConfig::default()
+            })
+            .with_ledger();
+        process_transactions_from_csv(path.to_str().unwrap(), &mut exchange).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(Currency::str("10.0"), exchange.client(1).unwrap().total());
+
+        let mut output = Vec::new();
+        exchange
+            .to_writer(&mut output, &AmountFormat::Plain)
+            .unwrap();
+        assert_eq!(
+            format!(
+                "client,available,held,total,locked\n{},10.0000,0.0000,10.0000,false\n",
+                label
+            ),
+            String::from_utf8(output).unwrap()
+        );
+
+        let mut rejects = Vec::new();
+        exchange
+            .ledger()
+            .unwrap()
+            .write_rejects(&mut rejects, &ids)
+            .unwrap();
+        let rejects = String::from_utf8(rejects).unwrap();
+        assert_eq!(true, rejects.contains(&format!(",withdrawal,{},2,", label)));
+        assert_eq!(false, rejects.contains(",1,2,"));
+    }
+
+    #[test]
+    fn it_should_not_capture_extra_columns_unless_asked_for() {
+        let path = std::env::temp_dir().join("payment_engine_metadata_disabled.csv");
+        std::fs::write(
+            &path,
+            "type,client,tx,amount,correlation_id\ndeposit,1,1,10.0,abc-1\n",
+        )
+        .unwrap();
+
+        let mut exchange = Exchange::new();
+        process_transactions_from_csv(path.to_str().unwrap(), &mut exchange).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            true,
+            exchange.transaction(1, 1).unwrap().metadata().is_empty()
+        );
+        assert_eq!(true, exchange.ledger().is_none());
+    }
+
+    #[test]
+    fn it_should_reject_new_clients_and_stop_retaining_when_degrading() {
+        let mut exchange = Exchange::new().with_config(EngineConfig {
+            limits: config::Limits {
+                max_clients: Some(2),
+                max_transactions: Some(2),
+                max_memory: None,
+                on_exceeded: LimitPolicy::Degrade,
+            },
+            ..EngineConfig::default()
+        });
+
+        exchange
+            .process_new_transaction(deposit(1, 1, "10.0"))
+            .unwrap();
+        exchange
+            .process_new_transaction(deposit(2, 2, "10.0"))
+            .unwrap();
+        assert_eq!(
+            true,
+            exchange
+                .process_new_transaction(deposit(3, 3, "10.0"))
+                .is_err()
+        );
+        assert_eq!(true, exchange.check_limits(&deposit(3, 3, "10.0")).is_ok());
+
+        //applied but no longer retained
+        exchange
+            .process_new_transaction(deposit(1, 4, "5.0"))
+            .unwrap();
+        assert_eq!(
+            Currency::str("15.0"),
+            exchange.client(1).unwrap().available()
+        );
+        assert_eq!(true, exchange.transaction(1, 4).is_none());
+        assert_eq!(false, exchange.config().retain_transactions);
+        assert_eq!(2, exchange.clients.len());
+    }
+
+    #[test]
+    fn it_should_spill_to_the_archive_when_degrading_with_one() {
+        let path = std::env::temp_dir().join("payment_engine_exchange_archive_spill.csv");
+        let mut exchange = Exchange::new()
+            .with_config(EngineConfig {
+                limits: config::Limits {
+                    max_clients: Some(2),
+                    max_transactions: Some(4),
+                    max_memory: None,
+                    on_exceeded: LimitPolicy::Degrade,
+                },
+                ..EngineConfig::default()
+            })
+            .with_archive(Archive::create(&path).unwrap());
+        let withdrawal = |client, tx, amount| Transaction {
+            tx_type: Type::Withdrawal,
+            ..deposit(client, tx, amount)
+        };
+        for transaction in [
+            deposit(1, 1, "5.0"),
+            withdrawal(1, 2, "5.0"),
+            deposit(2, 3, "10.0"),
+            withdrawal(2, 4, "10.0"),
+        ] {
+            exchange.process_new_transaction(transaction).unwrap();
+        }
+
+        //both limits are reached, client 1 is spilled to make room for client 3 and its deposit is still retained
+        exchange
+            .process_new_transaction(deposit(3, 5, "1.0"))
+            .unwrap();
+        assert_eq!(true, exchange.archive().unwrap().contains(1));
+        assert_eq!(true, exchange.transaction(3, 5).is_some());
+        assert_eq!(true, exchange.config().retain_transactions);
+
+        //client 1 is read back with its history, client 2 spilled in turn, so its withdrawn deposit can still be disputed
+        exchange.process_new_transaction(dispute(1, 1)).unwrap();
+        assert_eq!(true, exchange.archive().unwrap().contains(2));
+        assert_eq!(Currency::str("5.0"), exchange.client(1).unwrap().held());
+        assert_eq!(
+            -Currency::str("5.0"),
+            exchange.client(1).unwrap().available()
+        );
+        assert_eq!(2, exchange.clients.len());
+        assert_eq!(3, exchange.memory_usage().transactions);
+        assert_eq!(true, exchange.config().retain_transactions);
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(archive::index_path(&path)).unwrap();
+    }
+
+    #[test]
+    fn it_should_write_the_extended_schema_with_the_account_activity() {
+        let mut exchange = Exchange::new();
+        exchange
+            .process_new_transaction(deposit(1, 1, "5.0"))
+            .unwrap();
+        exchange
+            .process_new_transaction(deposit(1, 2, "3.0"))
+            .unwrap();
+        exchange.process_new_transaction(dispute(1, 1)).unwrap();
+        assert_eq!(
+            true,
+            exchange
+                .process_new_transaction(Transaction {
+                    tx_type: Type::Withdrawal,
+                    ..deposit(1, 3, "100.0")
+                })
+                .is_err()
+        );
+        exchange.process_new_transaction(dispute(1, 2)).unwrap();
+        exchange.process_new_transaction(chargeback(1, 2)).unwrap();
+
+        let mut output = Vec::new();
+        exchange
+            .to_composite_writer(&mut output, &AmountFormat::Plain)
+            .unwrap();
+        assert_eq!(
+            format!(
+                "{}\n1,0.0000,5.0000,5.0000,true,false,1,1,1,2\n",
+                output::COMPOSITE_HEADER
+            ),
+            String::from_utf8(output).unwrap()
+        );
+
+        let mut output = Vec::new();
+        Exchange::new()
+            .to_composite_writer(&mut output, &AmountFormat::Plain)
+            .unwrap();
+        assert_eq!(
+            format!("{}\n", output::COMPOSITE_HEADER),
+            String::from_utf8(output).unwrap()
+        );
+    }
+
+    #[test]
+    fn it_should_account_for_memory_and_degrade_at_the_memory_cap() {
+        let mut exchange = Exchange::new();
+        exchange
+            .process_new_transaction(deposit(1, 1, "10.0"))
+            .unwrap();
+        exchange
+            .process_new_transaction(deposit(1, 2, "10.0"))
+            .unwrap();
+        let usage = exchange.memory_usage();
+        assert_eq!(1, usage.clients);
+        assert_eq!(2, usage.transactions);
+        assert_eq!(2 * memory::TRANSACTION_BYTES, usage.transaction_bytes);
+        assert_eq!(true, usage.client_bytes >= memory::CLIENT_SLOT_BYTES);
+        assert_eq!(usage, *exchange.stats().memory());
+
+        let mut capped = Exchange::new().with_config(EngineConfig {
+            limits: config::Limits {
+                max_memory: Some(usage.total()),
+                on_exceeded: LimitPolicy::Degrade,
+                ..config::Limits::default()
+            },
+            ..EngineConfig::default()
+        });
+        capped
+            .process_new_transaction(deposit(1, 1, "10.0"))
+            .unwrap();
+        capped
+            .process_new_transaction(deposit(1, 2, "10.0"))
+            .unwrap();
+        assert_eq!(
+            true,
+            capped
+                .process_new_transaction(deposit(2, 3, "1.0"))
+                .is_err()
+        );
+        capped
+            .process_new_transaction(deposit(1, 4, "1.0"))
+            .unwrap();
+        assert_eq!(true, capped.transaction(1, 4).is_none());
+        assert_eq!(Currency::str("21.0"), capped.client(1).unwrap().total());
+
+        let aborting = Exchange {
+            config: EngineConfig {
+                limits: config::Limits {
+                    max_memory: Some(usage.total()),
+                    ..config::Limits::default()
+                },
+                ..EngineConfig::default()
+            },
+            ..exchange
+        };
+        assert_eq!(true, aborting.check_limits(&deposit(1, 5, "1.0")).is_err());
+        assert_eq!(true, aborting.check_limits(&dispute(1, 1)).is_ok());
+    }
+
+    #[test]
+    fn it_should_abort_ingestion_when_a_limit_is_exceeded() {
+        let path = std::env::temp_dir().join("payment_engine_client_limit.csv");
+        std::fs::write(
+            &path,
+            "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,2,2,1.0\ndeposit,3,3,1.0\n",
+        )
+        .unwrap();
+
+        let mut exchange = Exchange::new().with_config(EngineConfig {
+            limits: config::Limits {
+                max_clients: Some(2),
+                ..config::Limits::default()
+            },
+            ..EngineConfig::default()
+        });
+        let result = process_transactions_from_csv(path.to_str().unwrap(), &mut exchange);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            true,
+            result
+                .unwrap_err()
+                .to_string()
+                .contains("Client limit of 2")
+        );
+        assert_eq!(2, exchange.clients.len());
+    }
+
+    fn exchange_with_open_disputes() -> Exchange {
+        let mut exchange = Exchange::new().with_config(EngineConfig {
+            chargeback_approval_threshold: Some(Currency::str("100.0")),
+            ..EngineConfig::default()
+        });
+        exchange
+            .process_new_transaction(deposit(1, 1, "500.0"))
+            .unwrap();
+        exchange
+            .process_new_transaction(deposit(1, 2, "50.0"))
+            .unwrap();
+        exchange.process_new_transaction(dispute(1, 1)).unwrap();
+        exchange.process_new_transaction(dispute(1, 2)).unwrap();
+        exchange
+    }
+
+    fn chargeback(client: ClientId, tx: TransactionId) -> Transaction {
+        Transaction {
+            tx_type: Type::Chargeback,
+            ..dispute(client, tx)
+        }
+    }
+
+    #[test]
+    fn it_should_park_chargebacks_above_the_approval_threshold() {
+        let mut exchange = exchange_with_open_disputes();
+
+        exchange.process_new_transaction(chargeback(1, 1)).unwrap();
+        exchange.process_new_transaction(chargeback(1, 1)).unwrap();
+
+        assert_eq!(1, exchange.pending_chargebacks().len());
+        assert_eq!(
+            Currency::str("500.0"),
+            exchange.pending_chargebacks()[0].amount()
+        );
+        assert_eq!(Currency::str("550.0"), exchange.client(1).unwrap().held());
+        assert_eq!(false, exchange.client(1).unwrap().locked());
+
+        //below the threshold applies right away
+        exchange.process_new_transaction(chargeback(1, 2)).unwrap();
+        assert_eq!(Currency::str("500.0"), exchange.client(1).unwrap().held());
+        assert_eq!(true, exchange.client(1).unwrap().locked());
+    }
+
+    #[test]
+    fn it_should_apply_approved_chargebacks_and_keep_denied_disputes_open() {
+        let mut exchange = exchange_with_open_disputes();
+        exchange.process_new_transaction(chargeback(1, 1)).unwrap();
+
+        exchange.deny_chargeback(1, 1).unwrap();
+        assert_eq!(0, exchange.pending_chargebacks().len());
+        assert_eq!(true, exchange.transaction(1, 1).unwrap().under_dispute());
+        assert_eq!(true, exchange.deny_chargeback(1, 1).is_err());
+
+        exchange.process_new_transaction(chargeback(1, 1)).unwrap();
+        exchange.approve_chargeback(1, 1).unwrap();
+
+        let client = exchange.client(1).unwrap();
+        assert_eq!(Currency::str("50.0"), client.held());
+        assert_eq!(Currency::str("50.0"), client.total());
+        assert_eq!(true, client.locked());
+        assert_eq!(
+            vec![
+                "1,chargeback_denied,1,1,500.0000",
+                "2,chargeback_approved,1,1,500.0000"
+            ],
+            exchange
+                .audit_log()
+                .iter()
+                .map(|record| record.to_string())
+                .collect::<Vec<String>>()
+        );
+    }
+
+    #[test]
+    fn it_should_publish_approved_chargebacks_and_audit_only_the_applied_ones() {
+        let mut exchange = exchange_with_open_disputes().with_ledger();
+        exchange.config.cooling_off = 3;
+        exchange.process_new_transaction(chargeback(1, 1)).unwrap();
+        //below the threshold, locks the account
+        exchange.process_new_transaction(chargeback(1, 2)).unwrap();
+
+        assert_eq!(true, exchange.approve_chargeback(1, 1).is_err());
+        assert_eq!(1, exchange.pending_chargebacks().len());
+        assert_eq!(true, exchange.audit_log().is_empty());
+
+        exchange.unlock(1).unwrap();
+        exchange.approve_chargeback(1, 1).unwrap();
+        assert_eq!(0, exchange.pending_chargebacks().len());
+        let entry = exchange.ledger().unwrap().entries().last().unwrap();
+        assert_eq!(
+            (Type::Chargeback, 1, true),
+            (
+                entry.transaction().tx_type.clone(),
+                entry.transaction().tx,
+                entry.applied()
+            )
+        );
+        let client = exchange.client(1).unwrap();
+        assert_eq!(
+            (true, 2, 3),
+            (
+                client.locked(),
+                client.chargeback_count(),
+                client.cooling_off()
+            )
+        );
+        assert_eq!(
+            "2,chargeback_approved,1,1,500.0000",
+            exchange.audit_log()[1].to_string()
+        );
+    }
+
+    #[test]
+    fn it_should_reconstruct_balances_as_of_a_sequence_number() {
+        let mut exchange = Exchange::new().with_ledger();
+        exchange
+            .process_new_transaction(deposit(1, 1, "10.0"))
+            .unwrap();
+        exchange
+            .process_new_transaction(deposit(2, 2, "3.0"))
+            .unwrap();
+        exchange
+            .process_new_transaction(deposit(1, 3, "5.0"))
+            .unwrap();
+        exchange.process_new_transaction(dispute(1, 1)).unwrap();
+        exchange
+            .process_new_transaction(Transaction {
+                tx_type: Type::Withdrawal,
+                ..deposit(1, 123, "1.0")
+            })
+            .unwrap();
+
+        //what was available when tx 123 arrived?
+        let arrival = exchange.ledger().unwrap().seq_of(1, 123).unwrap();
+        let before = exchange.balance_at(1, arrival - 1).unwrap();
+        assert_eq!(Currency::str("5.0"), before.available);
+        assert_eq!(Currency::str("10.0"), before.held);
+
+        assert_eq!(
+            Currency::str("10.0"),
+            exchange.balance_at(1, 2).unwrap().available
+        );
+        assert_eq!(
+            Currency::str("4.0"),
+            exchange.balance_at(1, 100).unwrap().available
+        );
+        assert_eq!(None, exchange.balance_at(2, 1));
+        assert_eq!(None, Exchange::new().balance_at(1, 1));
+    }
+
+    #[test]
+    fn it_should_archive_inactive_empty_clients_and_rehydrate_them_on_new_activity() {
+        let path = std::env::temp_dir().join("payment_engine_exchange_archive.csv");
+        let mut exchange = Exchange::new()
+            .with_config(EngineConfig {
+                archive_idle: Some(2),
+                ..EngineConfig::default()
+            })
+            .with_archive(Archive::create(&path).unwrap());
+
+        exchange
+            .process_new_transaction(deposit(1, 1, "5.0"))
+            .unwrap();
+        exchange
+            .process_new_transaction(Transaction {
+                tx_type: Type::Withdrawal,
+                ..deposit(1, 2, "5.0")
+            })
+            .unwrap();
+        exchange
+            .process_new_transaction(deposit(2, 3, "1.0"))
+            .unwrap();
+        exchange
+            .process_new_transaction(deposit(2, 4, "1.0"))
+            .unwrap();
+
+        //client 1 has been idle for the last 2 transactions with nothing left on the account, client 2 still holds funds
+        assert_eq!(None, exchange.client(1));
+        assert_eq!(true, exchange.archive().unwrap().contains(1));
+        assert_eq!(true, exchange.client(2).is_some());
+
+        exchange
+            .process_new_transaction(deposit(1, 5, "3.0"))
+            .unwrap();
+        assert_eq!(
+            Currency::str("3.0"),
+            exchange.client(1).unwrap().available()
+        );
+        assert_eq!(true, exchange.archive().unwrap().is_empty());
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(archive::index_path(&path)).unwrap();
+    }
+
+    #[test]
+    fn it_should_rehydrate_archived_clients_before_merging_them() {
+        let path = std::env::temp_dir().join("payment_engine_exchange_archive_merge.csv");
+        let mut exchange = Exchange::new().with_archive(Archive::create(&path).unwrap());
+        for client in [1, 3] {
+            exchange
+                .process_new_transaction(deposit(client, client as u32, "1.0"))
+                .unwrap();
+            exchange
+                .process_new_transaction(Transaction {
+                    tx_type: Type::Withdrawal,
+                    ..deposit(client, client as u32 + 1, "1.0")
+                })
+                .unwrap();
+        }
+        exchange
+            .process_new_transaction(deposit(2, 10, "101.0"))
+            .unwrap();
+        assert_eq!(2, exchange.archive_inactive(0).unwrap());
+
+        //into an archived client, which must not be rehydrated over the merged profile by its next transaction
+        exchange.merge_clients(2, 1, false).unwrap();
+        assert_eq!(false, exchange.archive().unwrap().contains(1));
+        exchange
+            .process_new_transaction(deposit(1, 11, "1.0"))
+            .unwrap();
+        assert_eq!(Currency::str("102.0"), exchange.client(1).unwrap().total());
+        assert_eq!(3, exchange.client(1).unwrap().deposits());
+
+        //from an archived client, which is not unknown
+        exchange.merge_clients(3, 1, false).unwrap();
+        assert_eq!(true, exchange.archive().unwrap().is_empty());
+        assert_eq!(None, exchange.client(3));
+        assert_eq!(4, exchange.client(1).unwrap().deposits());
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(archive::index_path(&path)).unwrap();
+    }
+
+    #[test]
+    fn it_should_leave_the_archive_and_snapshots_to_the_live_exchange_when_forking() {
+        let path = std::env::temp_dir().join("payment_engine_exchange_archive_fork.csv");
+        let dir = std::env::temp_dir().join("payment_engine_exchange_fork_snapshots");
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut exchange = Exchange::new()
+            .with_config(EngineConfig {
+                archive_idle: Some(2),
+                ..EngineConfig::default()
+            })
+            .with_archive(Archive::create(&path).unwrap())
+            .with_snapshots(
+                Snapshots::create(snapshot::SnapshotOptions {
+                    dir: dir.clone(),
+                    every_records: Some(1),
+                    ..snapshot::SnapshotOptions::default()
+                })
+                .unwrap(),
+            );
+        exchange
+            .process_new_transaction(deposit(1, 1, "5.0"))
+            .unwrap();
+        exchange
+            .process_new_transaction(Transaction {
+                tx_type: Type::Withdrawal,
+                ..deposit(1, 2, "5.0")
+            })
+            .unwrap();
+        exchange
+            .process_new_transaction(deposit(2, 3, "1.0"))
+            .unwrap();
+        exchange
+            .process_new_transaction(deposit(2, 4, "1.0"))
+            .unwrap();
+        assert_eq!(true, exchange.archive().unwrap().contains(1));
+        let files =
+            || [path.clone(), archive::index_path(&path)].map(|file| std::fs::read(file).unwrap());
+        let (archived, snapshots) = (files(), exchange.snapshots().unwrap().written().count());
+
+        //the fork rehydrates client 1 and would archive client 3 once idle, without touching the files of the live archive
+        let mut fork = exchange.fork();
+        assert_eq!(true, fork.snapshots().is_none());
+        fork.process_new_transaction(deposit(1, 5, "3.0")).unwrap();
+        fork.process_new_transaction(deposit(3, 6, "1.0")).unwrap();
+        fork.process_new_transaction(Transaction {
+            tx_type: Type::Withdrawal,
+            ..deposit(3, 7, "1.0")
+        })
+        .unwrap();
+        fork.process_new_transaction(deposit(2, 8, "1.0")).unwrap();
+        fork.process_new_transaction(deposit(2, 9, "1.0")).unwrap();
+        assert_eq!(true, fork.client(3).is_some());
+        assert_eq!(archived, files());
+        assert_eq!(snapshots, exchange.snapshots().unwrap().written().count());
+        assert_eq!(None, exchange.client(1));
+        assert_eq!(true, exchange.archive().unwrap().contains(1));
+
+        exchange.commit(fork);
+        assert_eq!(false, exchange.archive().unwrap().is_detached());
+        assert_eq!(true, exchange.snapshots().is_some());
+        assert_eq!(
+            Currency::str("3.0"),
+            exchange.client(1).unwrap().available()
+        );
+        assert_eq!(false, exchange.archive().unwrap().contains(1));
+        assert_eq!(false, Archive::open(&path).unwrap().contains(1));
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(archive::index_path(&path)).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn it_should_restore_the_whole_profile_of_a_rehydrated_client() {
+        let path = std::env::temp_dir().join("payment_engine_exchange_archive_profile.csv");
+        let mut exchange = Exchange::new()
+            .with_config(EngineConfig {
+                account_tiers: HashMap::from([
+                    config::AccountTier::parse("gold:reserve=0").unwrap()
+                ]),
+                ..EngineConfig::default()
+            })
+            .with_archive(Archive::create(&path).unwrap());
+
+        exchange
+            .process_new_transaction(deposit(1, 1, "5.0"))
+            .unwrap();
+        exchange.process_new_transaction(dispute(1, 1)).unwrap();
+        exchange
+            .process_new_transaction(Transaction {
+                tx_type: Type::Chargeback,
+                ..dispute(1, 1)
+            })
+            .unwrap();
+        exchange.set_kyc_tier(1, 2).unwrap();
+        exchange.set_tier(1, Some("gold".to_string())).unwrap();
+        exchange
+            .annotate(
+                1,
+                Some(Annotation {
+                    status: "watch".to_string(),
+                    note: Some("charged back".to_string()),
+                }),
+            )
+            .unwrap();
+        let before = exchange.client(1).unwrap().clone();
+
+        assert_eq!(1, exchange.archive_inactive(0).unwrap());
+        assert_eq!(None, exchange.client(1));
+        exchange.rehydrate(1).unwrap();
+
+        let after = exchange.client(1).unwrap();
+        assert_eq!(before, *after);
+        assert_eq!(true, after.locked());
+        assert_eq!(1, after.chargeback_count());
+        assert_eq!(Some("gold"), exchange.tier_of(1));
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(archive::index_path(&path)).unwrap();
+    }
+
+    #[test]
+    fn it_should_archive_the_least_recently_active_clients_beyond_the_resident_ones() {
+        let path = std::env::temp_dir().join("payment_engine_exchange_resident.csv");
+        let mut exchange = Exchange::new()
+            .with_config(EngineConfig {
+                archive_resident: Some(2),
+                ..EngineConfig::default()
+            })
+            .with_archive(Archive::create(&path).unwrap());
+        let withdrawal = |client, tx, amount| Transaction {
+            tx_type: Type::Withdrawal,
+            ..deposit(client, tx, amount)
+        };
+
+        for transaction in [
+            deposit(1, 1, "5.0"),
+            withdrawal(1, 2, "5.0"),
+            deposit(2, 3, "1.0"),
+            withdrawal(2, 4, "1.0"),
+            //a third client: client 1, emptied before client 2, is archived
+            deposit(3, 5, "1.0"),
+            withdrawal(2, 6, "1.0"),
+            deposit(1, 7, "3.0"),
+        ] {
+            let _ = exchange.process_new_transaction(transaction);
+        }
+
+        //client 1 was read back, so client 2, now the least recently active empty one, made room for it
+        assert_eq!(None, exchange.client(2));
+        assert_eq!(true, exchange.archive().unwrap().contains(2));
+        assert_eq!(
+            Currency::str("3.0"),
+            exchange.client(1).unwrap().available()
+        );
+        assert_eq!(true, exchange.client(3).is_some());
+        assert_eq!(3, exchange.stats().resident());
+        assert_eq!(1, exchange.stats().rehydrated());
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(archive::index_path(&path)).unwrap();
+    }
+
+    #[test]
+    fn it_should_keep_funded_clients_in_memory_beyond_the_resident_ones() {
+        let path = std::env::temp_dir().join("payment_engine_exchange_resident_funded.csv");
+        let mut exchange = Exchange::new()
+            .with_config(EngineConfig {
+                archive_resident: Some(2),
+                ..EngineConfig::default()
+            })
+            .with_archive(Archive::create(&path).unwrap());
+        for client in 1..=5 {
+            exchange
+                .process_new_transaction(deposit(client, client as u32, "1.0"))
+                .unwrap();
+        }
+        assert_eq!(0, exchange.archive_least_recent(2).unwrap());
+
+        //every client holds funds, so none is evicted however far beyond the bound they are
+        assert_eq!(5, exchange.clients.len());
+        assert_eq!(true, exchange.archive().unwrap().is_empty());
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(archive::index_path(&path)).unwrap();
+    }
+
+    #[test]
+    fn it_should_quarantine_records_that_are_not_valid_csv_and_carry_on() {
+        let input = std::env::temp_dir().join("payment_engine_poison_input.csv");
+        let quarantine = std::env::temp_dir().join("payment_engine_poison_quarantine.txt");
+        let mut content = b"type,client,tx,amount\ndeposit,1,1,1.0\n".to_vec();
+        content.extend_from_slice(b"deposit,1,2,\xff2.0\n");
+        content.extend_from_slice(b"deposit,1,3\n");
+        content.extend_from_slice(b"deposit,1,4,4.0\n");
+        std::fs::write(&input, &content).unwrap();
+
+        let mut exchange = Exchange::new();
+        process_transactions_from_csv_with(
+            input.to_str().unwrap(),
+            &mut exchange,
+            &IngestOptions {
+                quarantine: Some(quarantine.clone()),
+                ..IngestOptions::default()
+            },
+        )
+        .unwrap();
+
+        assert_eq!(Currency::str("5.0"), exchange.client(1).unwrap().total());
+        let mut expected = b"offset=38 length=17 error=".to_vec();
+        let quarantined = std::fs::read(&quarantine).unwrap();
+        assert_eq!(true, quarantined.starts_with(&expected));
+        expected = b"\ndeposit,1,2,\xff2.0\n\n".to_vec();
+        assert_eq!(
+            true,
+            quarantined
+                .windows(expected.len())
+                .any(|window| window == expected)
+        );
+        assert_eq!(
+            true,
+            String::from_utf8_lossy(&quarantined).contains("offset=55 length=12 error=")
+        );
+        assert_eq!(
+            true,
+            process_transactions_from_csv(input.to_str().unwrap(), &mut Exchange::new()).is_err()
+        );
 
-impl Exchange {
-    pub fn new() -> Exchange {
-        Exchange {
-            clients: HashMap::new(),
-        }
+        std::fs::remove_file(&input).unwrap();
+        std::fs::remove_file(&quarantine).unwrap();
     }
 
-    /// If the client does not exist, create a new one.
-    /// ClientProfile::new() is only called when the client does not exist: or_insert_with with the default closure guarantee that a new ClientProfile is not created every time .entry() is called
-    fn process_new_transaction(&mut self, transaction: Transaction) -> Result<(), ProcessingError> {
-        let client = self
-            .clients
-            .entry(transaction.client)
-            .or_insert_with(|| ClientProfile::new_with_defaults(transaction.client));
-        client.process_new_transaction(transaction)
+    #[test]
+    fn it_should_count_replayed_dispute_rows_in_stats() {
+        let mut exchange = Exchange::new().with_ledger();
+        exchange
+            .process_new_transaction(deposit(1, 1, "2.0"))
+            .unwrap();
+        exchange.process_new_transaction(dispute(1, 1)).unwrap();
+        assert_eq!(
+            true,
+            exchange.process_new_transaction(dispute(1, 1)).is_err()
+        );
+        exchange.process_new_transaction(chargeback(1, 1)).unwrap();
+
+        let stats = exchange.stats();
+        assert_eq!(1, stats.duplicates(&Type::Dispute));
+        assert_eq!(0, stats.duplicates(&Type::Chargeback));
+        assert_eq!(1, stats.rejected());
+        assert_eq!(Currency::str("0.0"), exchange.client(1).unwrap().total());
+
+        let mut rejects = Vec::new();
+        exchange
+            .ledger()
+            .unwrap()
+            .write_rejects(&mut rejects, &ClientIds::Raw)
+            .unwrap();
+        let rejects = String::from_utf8(rejects).unwrap();
+        assert_eq!(2, rejects.lines().count());
+        assert_eq!(true, rejects.contains("Duplicate dispute row"));
     }
 
-    pub fn to_csv(&self) {
-        println!("client,available,held,total,locked");
-        self.clients.iter().for_each(|(_, client)| {
-            println!("{}", client);
+    #[test]
+    fn it_should_release_deposits_held_while_locked_on_manual_unlock() {
+        let mut exchange = Exchange::new().with_config(EngineConfig {
+            hold_deposits_when_locked: true,
+            ..EngineConfig::default()
         });
+        exchange
+            .process_new_transaction(deposit(1, 1, "2.0"))
+            .unwrap();
+        exchange.process_new_transaction(dispute(1, 1)).unwrap();
+        exchange.process_new_transaction(chargeback(1, 1)).unwrap();
+        exchange
+            .process_new_transaction(deposit(1, 2, "7.0"))
+            .unwrap();
+        assert_eq!(Currency::str("7.0"), exchange.client(1).unwrap().held());
+
+        assert_eq!(Currency::str("7.0"), exchange.unlock(1).unwrap());
+        let client = exchange.client(1).unwrap();
+        assert_eq!(false, client.locked());
+        assert_eq!(Currency::str("7.0"), client.available());
+        assert_eq!("1,unlock,1,7.0000", exchange.audit_log()[0].to_string());
+        assert_eq!(true, exchange.unlock(1).is_err());
     }
-}
 
-//read one record at the time and only deserialize the current one. This avoids loading a huge dataset into memory and also to only deserilaise the current row that is being processed
-pub fn process_transactions_from_csv(
-    path: &str,
-    bank: &mut Exchange,
-) -> Result<(), Box<dyn Error>> {
-    let mut reader = csv::Reader::from_path(path)?;
+    #[test]
+    fn it_should_annotate_accounts_and_keep_the_annotation_in_checkpoints() {
+        let mut exchange = Exchange::new();
+        exchange
+            .process_new_transaction(deposit(1, 1, "2.0"))
+            .unwrap();
+        let review = Annotation {
+            status: "under fraud review".to_string(),
+            note: Some("CASE-7".to_string()),
+        };
+        exchange.annotate(1, Some(review.clone())).unwrap();
+        assert_eq!(true, exchange.annotate(2, Some(review.clone())).is_err());
+        assert_eq!(Some(&review), exchange.client(1).unwrap().annotation());
 
-    let headers = reader.headers()?.clone();
+        let path = std::env::temp_dir().join("payment_engine_annotation_checkpoint.json");
+        exchange
+            .write_checkpoint(
+                &path,
+                checkpoint::InputPosition {
+                    byte: 0,
+                    line: 1,
+                    records: 1,
+                },
+            )
+            .unwrap();
+        exchange.annotate(1, None).unwrap();
+        assert_eq!(None, exchange.client(1).unwrap().annotation());
+        assert_eq!(
+            vec!["1,annotate,1,under fraud review", "2,annotate,1,"],
+            exchange
+                .audit_log()
+                .iter()
+                .map(|record| record.to_string())
+                .collect::<Vec<String>>()
+        );
 
-    let mut raw_record = csv::StringRecord::new();
-    while reader.read_record(&mut raw_record)? {
-        let t: Transaction = raw_record.deserialize(Some(&headers))?;
-        if let Err(ProcessingError(error)) = bank.process_new_transaction(t) {
-            eprintln!("{}", error);
-        }
+        let mut restored = Exchange::new();
+        restored.restore_checkpoint(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(Some(&review), restored.client(1).unwrap().annotation());
     }
 
-    Ok(())
-}
+    #[test]
+    fn it_should_hold_clients_to_their_kyc_tier_until_it_is_raised() {
+        let mut exchange = Exchange::new().with_config(EngineConfig {
+            client_caps: config::ClientCaps {
+                tiers: vec![config::TierCaps {
+                    max_deposited: Some(Currency::str("10.0")),
+                    max_transactions: None,
+                }],
+                on_exceeded: config::CapPolicy::Reject,
+            },
+            ..EngineConfig::default()
+        });
+        exchange
+            .process_new_transaction(deposit(1, 1, "8.0"))
+            .unwrap();
+        assert_eq!(
+            true,
+            exchange
+                .process_new_transaction(deposit(1, 2, "8.0"))
+                .is_err()
+        );
+        assert_eq!(1, exchange.client(1).unwrap().rejected());
 
-#[cfg(test)]
-mod tests {
+        exchange.set_kyc_tier(1, 1).unwrap();
+        assert_eq!(true, exchange.set_kyc_tier(2, 1).is_err());
+        exchange
+            .process_new_transaction(deposit(1, 2, "8.0"))
+            .unwrap();
+        assert_eq!(Currency::str("16.0"), exchange.client(1).unwrap().total());
+        assert_eq!(1, exchange.client(1).unwrap().kyc_tier());
+        assert_eq!("1,kyc_tier,1,0,1", exchange.audit_log()[0].to_string());
+    }
 
-    use super::*;
+    #[test]
+    fn it_should_hold_clients_to_the_limits_of_their_account_tier() {
+        let mut exchange = Exchange::new().with_config(EngineConfig {
+            reserve: Currency::str("5.0"),
+            client_caps: config::ClientCaps {
+                tiers: vec![config::TierCaps {
+                    max_deposited: Some(Currency::str("20.0")),
+                    max_transactions: None,
+                }],
+                on_exceeded: config::CapPolicy::Reject,
+            },
+            account_tiers: HashMap::from([
+                config::AccountTier::parse("gold:reserve=0,max_deposited=100").unwrap(),
+                config::AccountTier::parse("silver:reserve=1").unwrap(),
+            ]),
+            ..EngineConfig::default()
+        });
+        let withdrawal = |tx, amount| Transaction {
+            tx_type: Type::Withdrawal,
+            ..deposit(1, tx, amount)
+        };
+        exchange
+            .process_new_transaction(deposit(1, 1, "10.0"))
+            .unwrap();
+        assert_eq!(
+            true,
+            exchange
+                .process_new_transaction(withdrawal(2, "6.0"))
+                .is_err()
+        );
 
-    use transaction::Currency;
-    use transaction::Money;
-    use transaction::Type;
+        assert_eq!(
+            true,
+            exchange.set_tier(1, Some("platinum".to_string())).is_err()
+        );
+        exchange.set_tier(1, Some("silver".to_string())).unwrap();
+        exchange
+            .process_new_transaction(withdrawal(2, "6.0"))
+            .unwrap();
+        //silver leaves the caps of the KYC tier in place
+        assert_eq!(
+            true,
+            exchange
+                .process_new_transaction(deposit(1, 3, "15.0"))
+                .is_err()
+        );
+        exchange.set_tier(1, Some("gold".to_string())).unwrap();
+        exchange
+            .process_new_transaction(deposit(1, 3, "15.0"))
+            .unwrap();
+        exchange
+            .process_new_transaction(withdrawal(4, "19.0"))
+            .unwrap();
+        assert_eq!(Currency::zero(), exchange.client(1).unwrap().total());
+
+        exchange.set_tier(1, None).unwrap();
+        assert_eq!(None, exchange.client(1).unwrap().tier());
+        assert_eq!(
+            vec!["1,tier,1,,silver", "2,tier,1,silver,gold", "3,tier,1,gold,"],
+            exchange
+                .audit_log()
+                .iter()
+                .map(|event| event.to_string())
+                .collect::<Vec<String>>()
+        );
+        assert_eq!(None, config::AccountTier::parse("gold:fees=1"));
+        assert_eq!(None, config::AccountTier::parse(":reserve=1"));
+    }
 
     #[test]
-    fn it_should_handle_deposits_and_withdrawals_for_multiple_clients() {
+    fn it_should_only_reject_the_withdrawals_of_a_frozen_account() {
         let mut exchange = Exchange::new();
+        exchange
+            .process_new_transaction(deposit(1, 1, "10.0"))
+            .unwrap();
+        assert_eq!(true, exchange.set_frozen(2, true).is_err());
+        exchange.set_frozen(1, true).unwrap();
+        assert_eq!(true, exchange.set_frozen(1, true).is_err());
 
-        let tx91 = Transaction {
-            tx_type: Type::Deposit,
-            client: 1,
-            tx: 91,
-            amount: Some(Currency::str("123.0")),
-            under_dispute: false,
+        let withdrawal = |tx| Transaction {
+            tx_type: Type::Withdrawal,
+            ..deposit(1, tx, "1.0")
         };
+        assert_eq!(
+            true,
+            exchange
+                .process_new_transaction(withdrawal(2))
+                .unwrap_err()
+                .0
+                .contains("is frozen")
+        );
+        exchange
+            .process_new_transaction(deposit(1, 3, "5.0"))
+            .unwrap();
+        exchange.process_new_transaction(dispute(1, 3)).unwrap();
+        exchange.process_new_transaction(chargeback(1, 3)).unwrap();
+        let client = exchange.client(1).unwrap();
+        assert_eq!(
+            (true, true, Currency::str("10.0")),
+            (client.frozen(), client.locked(), client.total())
+        );
 
-        let tx92 = Transaction {
-            tx_type: Type::Deposit,
-            client: 2,
-            tx: 92,
-            amount: Some(Currency::str("55.0")),
-            under_dispute: false,
-        };
+        let mut thawed = Exchange::new();
+        thawed
+            .process_new_transaction(deposit(1, 1, "10.0"))
+            .unwrap();
+        thawed.set_frozen(1, true).unwrap();
+        thawed.set_frozen(1, false).unwrap();
+        thawed.process_new_transaction(withdrawal(2)).unwrap();
+        assert_eq!(Currency::str("9.0"), thawed.client(1).unwrap().available());
+        assert_eq!(
+            vec!["1,freeze,1,true", "2,freeze,1,false"],
+            thawed
+                .audit_log()
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+        );
+    }
 
-        let tx93 = Transaction {
-            tx_type: Type::Withdrawal,
-            client: 2,
-            tx: 93,
-            amount: Some(Currency::str("44.0")),
-            under_dispute: false,
-        };
+    #[test]
+    fn it_should_keep_an_archived_account_frozen_once_rehydrated() {
+        let path = std::env::temp_dir().join("payment_engine_exchange_archive_frozen.csv");
+        let mut exchange = Exchange::new().with_archive(Archive::create(&path).unwrap());
+        exchange
+            .process_new_transaction(deposit(1, 1, "1.0"))
+            .unwrap();
+        exchange
+            .process_new_transaction(Transaction {
+                tx_type: Type::Withdrawal,
+                ..deposit(1, 2, "1.0")
+            })
+            .unwrap();
+        exchange.set_frozen(1, true).unwrap();
+        assert_eq!(1, exchange.archive_inactive(0).unwrap());
 
-        let tx94 = Transaction {
+        exchange
+            .process_new_transaction(deposit(1, 3, "5.0"))
+            .unwrap();
+        assert_eq!(true, exchange.client(1).unwrap().frozen());
+        assert_eq!(
+            true,
+            exchange
+                .process_new_transaction(Transaction {
+                    tx_type: Type::Withdrawal,
+                    ..deposit(1, 4, "1.0")
+                })
+                .unwrap_err()
+                .0
+                .contains("is frozen")
+        );
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(archive::index_path(&path)).unwrap();
+    }
+
+    #[test]
+    fn it_should_not_archive_an_account_still_cooling_off() {
+        let path = std::env::temp_dir().join("payment_engine_exchange_archive_cooling_off.csv");
+        let mut exchange = Exchange::new()
+            .with_config(EngineConfig {
+                cooling_off: 2,
+                ..EngineConfig::default()
+            })
+            .with_archive(Archive::create(&path).unwrap());
+        for transaction in [deposit(1, 1, "5.0"), dispute(1, 1), chargeback(1, 1)] {
+            exchange.process_new_transaction(transaction).unwrap();
+        }
+        let client = exchange.client(1).unwrap();
+        assert_eq!((true, 2), (client.total().is_zero(), client.cooling_off()));
+
+        assert_eq!(0, exchange.archive_inactive(0).unwrap());
+        assert_eq!(2, exchange.client(1).unwrap().cooling_off());
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(archive::index_path(&path)).unwrap();
+    }
+
+    #[test]
+    fn it_should_label_the_client_in_every_rejection_and_failure() {
+        let ids = ClientIds::hashed("s4lt");
+        let mut exchange = Exchange::new().with_config(EngineConfig {
+            cooling_off: 1,
+            limits: config::Limits {
+                max_clients: Some(1),
+                on_exceeded: config::LimitPolicy::Abort,
+                ..config::Limits::default()
+            },
+            unknown_types: config::UnknownTypePolicy::Fail,
+            client_ids: ids.clone(),
+            ..EngineConfig::default()
+        });
+        let withdrawal = |tx, amount| Transaction {
             tx_type: Type::Withdrawal,
-            client: 1,
-            tx: 94,
-            amount: Some(Currency::str("33.0")),
-            under_dispute: false,
+            ..deposit(4242, tx, amount)
         };
+        let mut failures: Vec<Box<dyn std::error::Error>> = Vec::new();
 
+        failures.push(Box::new(exchange.unlock(4242).unwrap_err()));
+        failures.push(Box::new(exchange.set_kyc_tier(4242, 1).unwrap_err()));
         exchange
-            .process_new_transaction(tx91.clone())
-            .unwrap_or_default();
+            .process_new_transaction(deposit(4242, 1, "5.0"))
+            .unwrap();
+        failures.push(Box::new(
+            exchange
+                .process_new_transaction(withdrawal(2, "50.0"))
+                .unwrap_err(),
+        ));
+        exchange.set_frozen(4242, true).unwrap();
+        failures.push(Box::new(exchange.set_frozen(4242, true).unwrap_err()));
+        failures.push(Box::new(
+            exchange
+                .process_new_transaction(withdrawal(3, "1.0"))
+                .unwrap_err(),
+        ));
+        exchange.set_frozen(4242, false).unwrap();
+        failures.push(Box::new(exchange.unlock(4242).unwrap_err()));
+        exchange.process_new_transaction(dispute(4242, 1)).unwrap();
+        failures.push(Box::new(
+            exchange
+                .process_new_transaction(dispute(4242, 1))
+                .unwrap_err(),
+        ));
         exchange
-            .process_new_transaction(tx92.clone())
-            .unwrap_or_default();
+            .process_new_transaction(chargeback(4242, 1))
+            .unwrap();
+        failures.push(Box::new(
+            exchange
+                .process_new_transaction(deposit(4242, 4, "1.0"))
+                .unwrap_err(),
+        ));
+        exchange.unlock(4242).unwrap();
+        failures.push(Box::new(
+            exchange
+                .process_new_transaction(withdrawal(5, "1.0"))
+                .unwrap_err(),
+        ));
+        exchange.set_blocked(4242, true).unwrap();
+        failures.push(Box::new(exchange.set_blocked(4242, true).unwrap_err()));
+        failures.push(Box::new(
+            exchange
+                .process_new_transaction(deposit(4242, 6, "1.0"))
+                .unwrap_err(),
+        ));
+        failures.push(Box::new(
+            exchange.check_limits(&deposit(4343, 7, "1.0")).unwrap_err(),
+        ));
+        failures.push(Box::new(
+            exchange
+                .check_type(&Transaction {
+                    tx_type: Type::Custom("rebate".to_string()),
+                    ..deposit(4242, 8, "1.0")
+                })
+                .unwrap_err(),
+        ));
+
+        assert_eq!(13, failures.len());
+        for failure in &failures {
+            let raw = failure.to_string();
+            let client = if raw.contains("4343") { 4343 } else { 4242 };
+            let described = ids.describe(failure.as_ref());
+            assert_eq!(
+                raw.replace(&client.to_string(), &ids.label(client).to_string()),
+                described
+            );
+            assert_eq!(
+                false,
+                described.contains(&client.to_string()),
+                "{}",
+                described
+            );
+        }
+    }
+
+    #[test]
+    fn it_should_block_withdrawals_for_a_cooling_off_period_after_a_chargeback() {
+        let mut exchange = Exchange::new().with_config(EngineConfig {
+            cooling_off: 2,
+            ..EngineConfig::default()
+        });
+        exchange.set_client_cooling_off(2, Some(0));
+        let withdrawal = |client, tx| Transaction {
+            tx_type: Type::Withdrawal,
+            ..deposit(client, tx, "1.0")
+        };
+        for client in [1, 2] {
+            for transaction in [
+                deposit(client, client as TransactionId * 10, "10.0"),
+                deposit(client, client as TransactionId * 10 + 1, "5.0"),
+                dispute(client, client as TransactionId * 10 + 1),
+                chargeback(client, client as TransactionId * 10 + 1),
+            ] {
+                exchange.process_new_transaction(transaction).unwrap();
+            }
+            exchange.unlock(client).unwrap();
+        }
+        assert_eq!(2, exchange.client(1).unwrap().cooling_off());
+        assert_eq!(0, exchange.client(2).unwrap().cooling_off());
+
+        //disputes, resolves and chargebacks of unknown transactions are accepted, but do not count
+        for tx_type in [Type::Dispute, Type::Resolve, Type::Chargeback] {
+            exchange
+                .process_new_transaction(Transaction {
+                    tx_type,
+                    ..dispute(1, 999)
+                })
+                .unwrap();
+        }
+        assert_eq!(2, exchange.client(1).unwrap().cooling_off());
+
+        //unlocked, but still cooling off until two more deposits or withdrawals were accepted
+        assert_eq!(
+            true,
+            exchange
+                .process_new_transaction(withdrawal(1, 12))
+                .unwrap_err()
+                .0
+                .contains("is cooling off for 2 more transactions")
+        );
         exchange
-            .process_new_transaction(tx93.clone())
-            .unwrap_or_default();
+            .process_new_transaction(deposit(1, 13, "1.0"))
+            .unwrap();
+        assert_eq!(
+            true,
+            exchange.process_new_transaction(withdrawal(1, 14)).is_err()
+        );
         exchange
-            .process_new_transaction(tx94.clone())
-            .unwrap_or_default();
+            .process_new_transaction(deposit(1, 15, "1.0"))
+            .unwrap();
+        exchange.process_new_transaction(withdrawal(1, 16)).unwrap();
+        exchange.process_new_transaction(withdrawal(2, 22)).unwrap();
 
-        let client1 = ClientProfile::new(
-            1,
-            Currency::str("90.0"),
-            Currency::str("0.0"),
-            Currency::str("90.0"),
-            false,
-            HashMap::from([(tx91.tx, tx91), (tx94.tx, tx94)]),
-        );
+        assert_eq!("1,cooling_off,2,2,0", exchange.audit_log()[0].to_string());
+    }
 
-        let client2 = ClientProfile::new(
-            2,
-            Currency::str("11.0"),
-            Currency::str("0.0"),
-            Currency::str("11.0"),
-            false,
-            HashMap::from([(tx92.tx, tx92), (tx93.tx, tx93)]),
+    #[test]
+    fn it_should_write_off_the_small_balances_of_inactive_accounts() {
+        let mut exchange = Exchange::new().with_ledger();
+        for transaction in [
+            deposit(1, 1, "0.004"),
+            deposit(2, 2, "0.005"),
+            dispute(2, 2),
+            deposit(3, 3, "10.0"),
+            deposit(4, 4, "0.001"),
+        ] {
+            exchange.process_new_transaction(transaction).unwrap();
+        }
+        let rule = WriteOffRule::parse("0.01:2").unwrap();
+        //client 2 has its funds held, client 3 too much and client 4 was just active
+        assert_eq!(
+            vec![(1, Currency::str("0.004"))],
+            exchange.write_off_small_balances(&rule)
         );
+        assert_eq!(true, exchange.client(1).unwrap().total().is_zero());
+        assert_eq!(Currency::str("0.005"), exchange.client(2).unwrap().total());
+        assert_eq!(
+            "1,write_off,0.0100,1,0.0040",
+            exchange.audit_log()[0].to_string()
+        );
+        let adjustment = exchange.ledger().unwrap().entries().last().unwrap();
+        assert_eq!(
+            (&Type::Custom("write_off".to_string()), 1, 1),
+            (
+                adjustment.transaction().tx_type(),
+                adjustment.transaction().client(),
+                adjustment.transaction().tx()
+            )
+        );
+        assert_eq!(5, exchange.stats().processed());
+        assert_eq!(None, WriteOffRule::parse("0:10"));
+    }
 
+    #[test]
+    fn it_should_reject_rows_of_denied_types() {
+        let mut exchange = Exchange::new().with_config(EngineConfig {
+            denied_types: vec![Type::Withdrawal, Type::Chargeback],
+            ..EngineConfig::default()
+        });
+        exchange
+            .process_new_transaction(deposit(1, 1, "5.0"))
+            .unwrap();
+        let mut withdrawal = deposit(1, 2, "2.0");
+        withdrawal.tx_type = Type::Withdrawal;
         assert_eq!(
-            HashMap::from([(1, client1), (2, client2)]),
-            exchange.clients
+            true,
+            exchange
+                .process_new_transaction(withdrawal)
+                .unwrap_err()
+                .to_string()
+                .starts_with("Transactions of type withdrawal are denied for this run")
         );
+        exchange.process_new_transaction(dispute(1, 1)).unwrap();
+        let mut chargeback = dispute(1, 1);
+        chargeback.tx_type = Type::Chargeback;
+        assert_eq!(true, exchange.process_new_transaction(chargeback).is_err());
+        assert_eq!(Currency::str("5.0"), exchange.client(1).unwrap().held());
+        assert_eq!(false, exchange.client(1).unwrap().locked());
+        assert_eq!(2, exchange.stats().rejected());
     }
 
     #[test]
-    fn it_should_ignore_transaction_if_account_is_locked() {
-        let locked_client_profile = ClientProfile::new(
-            1,
-            Currency::str("10.0"),
-            Currency::str("00.0"),
-            Currency::str("-10.0"),
+    fn it_should_block_clients_and_override_their_reserve_as_audited_operations() {
+        let mut exchange = Exchange::new();
+        exchange.set_blocked(2, true).unwrap();
+        assert_eq!(true, exchange.set_blocked(2, true).is_err());
+        assert_eq!(
             true,
-            HashMap::new(),
+            exchange
+                .process_new_transaction(deposit(2, 1, "5.0"))
+                .is_err()
         );
+        assert_eq!(None, exchange.client(2));
+        exchange.set_blocked(2, false).unwrap();
+        exchange
+            .process_new_transaction(deposit(2, 2, "5.0"))
+            .unwrap();
 
-        let mut exchange = Exchange {
-            clients: HashMap::from([(1, locked_client_profile)]),
+        exchange.set_client_reserve(2, Some(Currency::str("4.0")));
+        let mut withdrawal = deposit(2, 3, "2.0");
+        withdrawal.tx_type = Type::Withdrawal;
+        assert_eq!(
+            true,
+            exchange
+                .process_new_transaction(withdrawal.clone())
+                .is_err()
+        );
+        exchange.set_client_reserve(2, None);
+        exchange.process_new_transaction(withdrawal).unwrap();
+        assert_eq!(
+            vec![
+                "1,blocklist,2,true",
+                "2,blocklist,2,false",
+                "3,reserve,2,0.0000,4.0000",
+                "4,reserve,2,4.0000,0.0000"
+            ],
+            exchange
+                .audit_log()
+                .iter()
+                .map(|record| record.to_string())
+                .collect::<Vec<String>>()
+        );
+    }
+
+    #[test]
+    fn it_should_publish_an_event_for_every_submitted_transaction() {
+        //variant name and transaction id of the events received so far
+        let received = |events: &mut futures::channel::mpsc::UnboundedReceiver<DomainEvent>| {
+            std::iter::from_fn(|| events.try_recv().ok())
+                .map(|event| {
+                    let name = format!("{:?}", event);
+                    let name = name.split(' ').next().unwrap().to_string();
+                    match event {
+                        DomainEvent::AccountLocked { tx, .. } => format!("{} {}", name, tx),
+                        event => format!("{} {}", name, event.transaction().unwrap().tx),
+                    }
+                })
+                .collect::<Vec<String>>()
         };
+        let mut exchange = Exchange::new().with_ledger();
+        let mut events = exchange.subscribe();
+        exchange
+            .process_new_transaction(deposit(1, 1, "5.0"))
+            .unwrap();
+        exchange.process_new_transaction(dispute(1, 1)).unwrap();
+        exchange.process_new_transaction(dispute(1, 9)).unwrap();
+        exchange.process_new_transaction(chargeback(1, 1)).unwrap();
+        assert_eq!(
+            true,
+            exchange
+                .process_new_transaction(deposit(1, 2, "1.0"))
+                .is_err()
+        );
+        assert_eq!(
+            vec![
+                "DepositApplied 1",
+                "DisputeOpened 1",
+                "TransactionIgnored 9",
+                "ChargebackApplied 1",
+                "AccountLocked 1",
+                "TransactionRejected 2",
+            ],
+            received(&mut events)
+        );
 
-        let result = exchange
-            .process_new_transaction(Transaction {
-                tx_type: Type::Deposit,
-                client: 1,
-                tx: 91,
-                amount: Some(Currency::str("123.0")),
-                under_dispute: false,
-            })
-            .err();
+        let mut fork = exchange.fork();
+        fork.process_new_transaction(deposit(2, 3, "1.0")).unwrap();
+        assert_eq!(0, received(&mut events).len());
+        exchange.commit(fork);
+        assert_eq!(vec!["DepositApplied 3"], received(&mut events));
 
-        assert_eq!(true, result.is_some());
+        assert_eq!(6, exchange.ledger().unwrap().entries().len());
+        assert_eq!(false, exchange.ledger().unwrap().entries()[4].applied());
+        assert_eq!(5, exchange.stats().processed());
+        assert_eq!(1, exchange.stats().rejected());
+    }
+
+    #[cfg(feature = "minor-units")]
+    #[test]
+    fn it_should_reject_deposits_overflowing_the_minor_units_balances() {
+        let mut exchange = Exchange::new();
+        exchange
+            .process_new_transaction(deposit(1, 1, "500000000000000"))
+            .unwrap();
+        let rejected = exchange
+            .process_new_transaction(deposit(1, 2, "500000000000000"))
+            .unwrap_err();
+        assert_eq!(
+            "500000000000000 amount overflows the balances. Rejecting transaction Deposit,1,2,Some(500000000000000),false",
+            rejected.to_string()
+        );
+        assert_eq!(
+            Currency::str("500000000000000"),
+            exchange.client(1).unwrap().total()
+        );
+    }
+
+    #[test]
+    fn it_should_rescale_amounts_to_the_configured_scale_on_ingest() {
+        let mut exchange = Exchange::new();
+        exchange
+            .process_new_transaction(deposit(1, 1, "123.0"))
+            .unwrap();
+        exchange
+            .process_new_transaction(deposit(1, 2, "0.00005"))
+            .unwrap();
+        assert_eq!(
+            Currency::str("123.0001"),
+            exchange.client(1).unwrap().total()
+        );
+        #[cfg(not(feature = "minor-units"))]
+        assert_eq!(
+            "123.0000",
+            exchange
+                .transaction(1, 1)
+                .unwrap()
+                .amount()
+                .unwrap()
+                .to_string()
+        );
+
+        let mut cents = Exchange::new().with_config(EngineConfig {
+            amount_scale: 2,
+            ..EngineConfig::default()
+        });
+        cents
+            .process_new_transaction(deposit(1, 1, "1.005"))
+            .unwrap();
+        assert_eq!(Currency::str("1.01"), cents.client(1).unwrap().available());
+    }
+
+    #[test]
+    fn it_should_find_a_transaction_by_id_alone() {
+        let mut exchange = Exchange::new();
+        exchange
+            .process_new_transaction(deposit(1, 1, "2.0"))
+            .unwrap();
+        exchange
+            .process_new_transaction(deposit(7, 12345, "3.0"))
+            .unwrap();
+        assert_eq!(7, exchange.find_transaction(12345).unwrap().client());
+        assert_eq!(None, exchange.find_transaction(2));
     }
 }
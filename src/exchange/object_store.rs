@@ -0,0 +1,205 @@
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::exchange::process_transactions_from_reader_with;
+use crate::exchange::Exchange;
+use crate::exchange::IngestOptions;
+
+/// Location of an object in object storage, written as <scheme>://<bucket>/<key> e.g. s3://bucket/txns/2024-06-01.csv
+#[derive(Debug, PartialEq, Clone)]
+pub struct ObjectUrl {
+    pub scheme: String,
+    pub bucket: String,
+    pub key: String,
+}
+
+impl ObjectUrl {
+    pub const SCHEMES: [&'static str; 2] = ["s3", "gs"];
+
+    /// None for anything but an object url with a bucket and a key, local paths included
+    pub fn parse(url: &str) -> Option<ObjectUrl> {
+        let (scheme, location) = url.split_once("://")?;
+        let (bucket, key) = location.split_once('/')?;
+        if !Self::SCHEMES.contains(&scheme) || bucket.is_empty() || key.is_empty() {
+            return None;
+        }
+        Some(ObjectUrl {
+            scheme: scheme.to_string(),
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for ObjectUrl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}://{}/{}", self.scheme, self.bucket, self.key)
+    }
+}
+
+/// Objects are streamed in and out, never copied to local files first. The engine only implements it for buckets mounted on
+/// the local file system (MountedStore) and talks to no S3 or GCS API itself: embedders reaching the storage over the network
+/// implement it with their own client
+pub trait ObjectStore: Send + Sync {
+    fn get(&self, url: &ObjectUrl) -> io::Result<Box<dyn io::Read + Send>>;
+
+    /// Creates or replaces the object, which is complete once the writer is flushed and dropped
+    fn put(&self, url: &ObjectUrl) -> io::Result<Box<dyn io::Write + Send>>;
+}
+
+/// Object storage mounted on the local file system (mountpoint-s3, s3fs, gcsfuse...), with the bucket b of every scheme in the
+/// directory root/b. Keys can not leave their bucket, and a bucket with no directory under root is reported as not mounted
+#[derive(Debug, PartialEq, Clone)]
+pub struct MountedStore {
+    root: PathBuf,
+}
+
+impl MountedStore {
+    pub fn new<P: AsRef<Path>>(root: P) -> MountedStore {
+        MountedStore {
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+
+    pub fn path(&self, url: &ObjectUrl) -> io::Result<PathBuf> {
+        let key = Path::new(&url.key);
+        let within_bucket = key
+            .components()
+            .all(|part| matches!(part, Component::Normal(_)));
+        if !within_bucket || url.bucket.contains(['/', '\\']) || url.bucket == ".." {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is outside of its bucket", url),
+            ));
+        }
+        let bucket = self.root.join(&url.bucket);
+        if !bucket.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "Bucket {} of {} is not mounted under {}",
+                    url.bucket,
+                    url,
+                    self.root.display()
+                ),
+            ));
+        }
+        Ok(bucket.join(key))
+    }
+}
+
+impl ObjectStore for MountedStore {
+    fn get(&self, url: &ObjectUrl) -> io::Result<Box<dyn io::Read + Send>> {
+        Ok(Box::new(File::open(self.path(url)?)?))
+    }
+
+    fn put(&self, url: &ObjectUrl) -> io::Result<Box<dyn io::Write + Send>> {
+        let path = self.path(url)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        Ok(Box::new(io::BufWriter::new(File::create(path)?)))
+    }
+}
+
+/// process_transactions_from_csv_with() streaming the input object from the store
+pub fn process_transactions_from_object(
+    url: &ObjectUrl,
+    store: &dyn ObjectStore,
+    bank: &mut Exchange,
+    options: &IngestOptions,
+) -> Result<(), Box<dyn Error>> {
+    process_transactions_from_reader_with(store.get(url)?, bank, options)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use std::io::Write;
+
+    use crate::exchange::output::AmountFormat;
+    use crate::exchange::transaction::Currency;
+    use crate::exchange::transaction::Money;
+
+    #[test]
+    fn it_should_only_parse_object_urls() {
+        assert_eq!(
+            Some(ObjectUrl {
+                scheme: "s3".to_string(),
+                bucket: "bucket".to_string(),
+                key: "txns/2024-06-01.csv".to_string(),
+            }),
+            ObjectUrl::parse("s3://bucket/txns/2024-06-01.csv")
+        );
+        assert_eq!(None, ObjectUrl::parse("transactions.csv"));
+        assert_eq!(None, ObjectUrl::parse("s3://bucket"));
+        assert_eq!(None, ObjectUrl::parse("ftp://bucket/txns.csv"));
+    }
+
+    #[test]
+    fn it_should_stream_objects_in_and_out_of_a_mounted_store() {
+        let root = std::env::temp_dir().join("payment_engine_object_store");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("bucket")).unwrap();
+        let store = MountedStore::new(&root);
+        let input = ObjectUrl::parse("s3://bucket/txns/2024-06-01.csv").unwrap();
+        let mut writer = store.put(&input).unwrap();
+        writer
+            .write_all(b"type,client,tx,amount\ndeposit,1,1,10.0\nwithdrawal,1,2,2.5\n")
+            .unwrap();
+        drop(writer);
+
+        let mut bank = Exchange::new();
+        process_transactions_from_object(&input, &store, &mut bank, &IngestOptions::default())
+            .unwrap();
+        assert_eq!(Currency::str("7.5"), bank.client(1).unwrap().total());
+
+        let output = ObjectUrl::parse("s3://bucket/accounts/2024-06-01.csv").unwrap();
+        let mut writer = store.put(&output).unwrap();
+        bank.to_writer(&mut writer, &AmountFormat::Plain).unwrap();
+        drop(writer);
+        assert_eq!(
+            "client,available,held,total,locked\n1,7.5000,0.0000,7.5000,false\n",
+            fs::read_to_string(root.join("bucket/accounts/2024-06-01.csv")).unwrap()
+        );
+
+        let escaping = ObjectUrl::parse("s3://bucket/../other/txns.csv").unwrap();
+        assert_eq!(true, store.get(&escaping).is_err());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn it_should_report_a_bucket_that_is_not_mounted() {
+        let root = std::env::temp_dir().join("payment_engine_object_store_unmounted");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        let store = MountedStore::new(&root);
+        let url = ObjectUrl::parse("gs://bucket/accounts.csv").unwrap();
+
+        let error = store.put(&url).err().unwrap();
+        assert_eq!(io::ErrorKind::NotFound, error.kind());
+        assert_eq!(
+            format!(
+                "Bucket bucket of gs://bucket/accounts.csv is not mounted under {}",
+                root.display()
+            ),
+            error.to_string()
+        );
+        assert_eq!(false, root.join("bucket").exists());
+        assert_eq!(
+            io::ErrorKind::NotFound,
+            store.get(&url).err().unwrap().kind()
+        );
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,320 @@
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+
+use crate::exchange::pseudonym::ClientIds;
+use crate::exchange::transaction::ClientId;
+use crate::exchange::transaction::Transaction;
+use crate::exchange::transaction::TransactionId;
+use crate::exchange::transaction::Type;
+
+/// Anomalies listed one by one in the report, the ones beyond are only counted
+pub const MAX_LISTED: usize = 1000;
+
+/// Rows waiting for the row they should have come after, beyond which new ones are not remembered
+const MAX_PENDING: usize = 100_000;
+
+/// Rows of the input out of the order the engine expects them in
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderingAnomalyKind {
+    /// A dispute, resolve or chargeback of a tx id the client only deposited or withdrew later
+    DisputeBeforeTransaction,
+    /// A chargeback of an undisputed transaction that was disputed later
+    ChargebackBeforeDispute,
+    /// A withdrawal of a client that had not deposited anything yet
+    WithdrawalBeforeDeposit,
+}
+
+impl OrderingAnomalyKind {
+    pub const ALL: [OrderingAnomalyKind; 3] = [
+        OrderingAnomalyKind::DisputeBeforeTransaction,
+        OrderingAnomalyKind::ChargebackBeforeDispute,
+        OrderingAnomalyKind::WithdrawalBeforeDeposit,
+    ];
+}
+
+impl fmt::Display for OrderingAnomalyKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self {
+            OrderingAnomalyKind::DisputeBeforeTransaction => "dispute_before_transaction",
+            OrderingAnomalyKind::ChargebackBeforeDispute => "chargeback_before_dispute",
+            OrderingAnomalyKind::WithdrawalBeforeDeposit => "withdrawal_before_deposit",
+        };
+        write!(f, "{}", kind)
+    }
+}
+
+/// One out of order row. Positions count the transactions submitted, the first one is 1
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct OrderingAnomaly {
+    pub kind: OrderingAnomalyKind,
+    pub client: ClientId,
+    pub tx: TransactionId,
+    /// The row that came too early
+    pub position: u64,
+    /// The row it should have come after, None for withdrawals before any deposit
+    pub expected_after: Option<u64>,
+}
+
+/// The ordering anomalies of a run, part of the stats when EngineConfig::track_ordering is set: the count of every kind and
+/// the first MAX_LISTED anomalies, for feed providers to be shown what is out of order
+#[derive(Debug, Default, PartialEq, Clone, Serialize, Deserialize)]
+pub struct OrderingReport {
+    pub(crate) counts: BTreeMap<OrderingAnomalyKind, u64>,
+    pub(crate) anomalies: Vec<OrderingAnomaly>,
+}
+
+impl OrderingReport {
+    pub fn count(&self, kind: OrderingAnomalyKind) -> u64 {
+        self.counts.get(&kind).copied().unwrap_or(0)
+    }
+
+    pub fn total(&self) -> u64 {
+        self.counts.values().sum()
+    }
+
+    /// The listed anomalies, in input order within a run
+    pub fn anomalies(&self) -> &[OrderingAnomaly] {
+        &self.anomalies
+    }
+
+    fn record(&mut self, anomaly: OrderingAnomaly) {
+        *self.counts.entry(anomaly.kind).or_insert(0) += 1;
+        if self.anomalies.len() < MAX_LISTED {
+            self.anomalies.push(anomaly);
+        }
+    }
+
+    /// Add up the anomalies of another run, see Stats::merge()
+    pub(crate) fn merge(&mut self, other: OrderingReport) {
+        for (kind, count) in other.counts {
+            *self.counts.entry(kind).or_insert(0) += count;
+        }
+        self.anomalies.extend(other.anomalies);
+        self.anomalies.truncate(MAX_LISTED);
+    }
+
+    /// The report as a JSON document, with the client ids written as the other outputs write them
+    pub fn write_json<W: io::Write>(&self, writer: W, client_ids: &ClientIds) -> io::Result<()> {
+        let anomalies: Vec<serde_json::Value> = self
+            .anomalies
+            .iter()
+            .map(|anomaly| {
+                serde_json::json!({
+                    "kind": anomaly.kind,
+                    "client": client_ids.label(anomaly.client),
+                    "tx": anomaly.tx,
+                    "position": anomaly.position,
+                    "expected_after": anomaly.expected_after,
+                })
+            })
+            .collect();
+        let counts: BTreeMap<String, u64> = OrderingAnomalyKind::ALL
+            .iter()
+            .map(|kind| (kind.to_string(), self.count(*kind)))
+            .collect();
+        serde_json::to_writer_pretty(
+            writer,
+            &serde_json::json!({
+                "total": self.total(),
+                "counts": counts,
+                "anomalies": anomalies,
+            }),
+        )
+        .map_err(io::Error::from)
+    }
+}
+
+/// Rows seen early, waiting for the one they should have come after, and the clients that deposited. Fed by the exchange with
+/// every transaction before it is applied, along with whether the client has the tx id it refers to and whether it is under
+/// dispute. It is not part of checkpoints: a resumed run starts over with the clients of the checkpoint taken as deposited
+#[derive(Debug, Default, Clone)]
+pub struct OrderingTracker {
+    /// Position of the first dispute step of every tx id the client did not have yet
+    early_disputes: HashMap<(ClientId, TransactionId), u64>,
+    /// Position of the first chargeback of every undisputed tx id
+    early_chargebacks: HashMap<(ClientId, TransactionId), u64>,
+    /// One bit per client id
+    deposited: Vec<u64>,
+}
+
+impl OrderingTracker {
+    pub fn new() -> OrderingTracker {
+        OrderingTracker::default()
+    }
+
+    /// A tracker taking these clients as having deposited already
+    pub fn resumed(clients: impl Iterator<Item = ClientId>) -> OrderingTracker {
+        let mut tracker = OrderingTracker::new();
+        for client in clients {
+            tracker.set_deposited(client);
+        }
+        tracker
+    }
+
+    fn set_deposited(&mut self, client: ClientId) {
+        let (word, bit) = (client as usize / 64, client as usize % 64);
+        if self.deposited.len() <= word {
+            self.deposited.resize(word + 1, 0);
+        }
+        self.deposited[word] |= 1 << bit;
+    }
+
+    fn has_deposited(&self, client: ClientId) -> bool {
+        let (word, bit) = (client as usize / 64, client as usize % 64);
+        self.deposited
+            .get(word)
+            .is_some_and(|word| word & (1 << bit) != 0)
+    }
+
+    fn remember(
+        pending: &mut HashMap<(ClientId, TransactionId), u64>,
+        key: (ClientId, TransactionId),
+        position: u64,
+    ) {
+        if pending.len() < MAX_PENDING {
+            pending.entry(key).or_insert(position);
+        }
+    }
+
+    /// Record the anomalies the transaction at position reveals. referenced is whether the tx id the transaction refers to is
+    /// under dispute, None when the client does not have it
+    pub fn observe(
+        &mut self,
+        report: &mut OrderingReport,
+        transaction: &Transaction,
+        referenced: Option<bool>,
+        position: u64,
+    ) {
+        let key = (transaction.client, transaction.tx);
+        let anomaly = |kind, expected_after| OrderingAnomaly {
+            kind,
+            client: transaction.client,
+            tx: transaction.tx,
+            position,
+            expected_after,
+        };
+        match transaction.tx_type {
+            Type::Deposit | Type::Withdrawal => {
+                if let Some(early) = self.early_disputes.remove(&key) {
+                    report.record(OrderingAnomaly {
+                        position: early,
+                        ..anomaly(
+                            OrderingAnomalyKind::DisputeBeforeTransaction,
+                            Some(position),
+                        )
+                    });
+                }
+                if transaction.tx_type == Type::Deposit {
+                    self.set_deposited(transaction.client);
+                } else if !self.has_deposited(transaction.client) {
+                    report.record(anomaly(OrderingAnomalyKind::WithdrawalBeforeDeposit, None));
+                }
+            }
+            Type::Dispute | Type::Resolve | Type::Chargeback if referenced.is_none() => {
+                Self::remember(&mut self.early_disputes, key, position);
+            }
+            Type::Dispute => {
+                if let Some(early) = self.early_chargebacks.remove(&key) {
+                    report.record(OrderingAnomaly {
+                        position: early,
+                        ..anomaly(OrderingAnomalyKind::ChargebackBeforeDispute, Some(position))
+                    });
+                }
+            }
+            Type::Chargeback if referenced == Some(false) => {
+                Self::remember(&mut self.early_chargebacks, key, position);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use crate::exchange::config::EngineConfig;
+    use crate::exchange::Exchange;
+
+    #[test]
+    fn it_should_report_the_rows_that_came_before_the_ones_they_depend_on() {
+        let dir = std::env::temp_dir().join("payment_engine_ordering");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("input.csv");
+        std::fs::write(
+            &input,
+            "type,client,tx,amount\n\
+             withdrawal,1,1,5.0\n\
+             dispute,1,2,\n\
+             deposit,1,2,10.0\n\
+             deposit,2,3,10.0\n\
+             chargeback,2,3,\n\
+             dispute,2,3,\n\
+             dispute,2,99,\n\
+             withdrawal,2,4,1.0\n",
+        )
+        .unwrap();
+        let mut exchange = Exchange::new().with_config(EngineConfig {
+            track_ordering: true,
+            ..Default::default()
+        });
+        crate::exchange::process_transactions_from_csv(input.to_str().unwrap(), &mut exchange)
+            .unwrap();
+
+        let report = exchange.stats().ordering().clone();
+        assert_eq!(
+            vec![
+                OrderingAnomaly {
+                    kind: OrderingAnomalyKind::WithdrawalBeforeDeposit,
+                    client: 1,
+                    tx: 1,
+                    position: 1,
+                    expected_after: None
+                },
+                OrderingAnomaly {
+                    kind: OrderingAnomalyKind::DisputeBeforeTransaction,
+                    client: 1,
+                    tx: 2,
+                    position: 2,
+                    expected_after: Some(3)
+                },
+                OrderingAnomaly {
+                    kind: OrderingAnomalyKind::ChargebackBeforeDispute,
+                    client: 2,
+                    tx: 3,
+                    position: 5,
+                    expected_after: Some(6)
+                },
+            ],
+            report.anomalies()
+        );
+        //a dispute of a tx id that never showed up is rejected, it is not out of order
+        assert_eq!(3, report.total());
+        assert_eq!(
+            true,
+            exchange
+                .stats()
+                .to_string()
+                .contains("ordering.dispute_before_transaction: 1")
+        );
+
+        let mut output = Vec::new();
+        report.write_json(&mut output, &ClientIds::Raw).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&output).unwrap();
+        assert_eq!(1, json["counts"]["chargeback_before_dispute"]);
+        assert_eq!("withdrawal_before_deposit", json["anomalies"][0]["kind"]);
+
+        let mut tracked = Exchange::new();
+        crate::exchange::process_transactions_from_csv(input.to_str().unwrap(), &mut tracked)
+            .unwrap();
+        assert_eq!(0, tracked.stats().ordering().total());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,1082 @@
+use serde::Serialize;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::BufRead;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::thread;
+
+use crate::exchange::approval::PendingChargeback;
+use crate::exchange::client_profile::Annotation;
+use crate::exchange::client_profile::ClientProfile;
+use crate::exchange::config::EngineConfig;
+use crate::exchange::disputes::DisputeView;
+use crate::exchange::pseudonym::ClientIds;
+use crate::exchange::pseudonym::ClientLabel;
+use crate::exchange::table::Cell;
+use crate::exchange::table::Color;
+use crate::exchange::table::Table;
+use crate::exchange::transaction::ClientId;
+use crate::exchange::transaction::Currency;
+use crate::exchange::transaction::Money;
+use crate::exchange::transaction::Transaction;
+use crate::exchange::transaction::TransactionId;
+
+pub const HEADER: &str = "client,available,held,total,locked";
+pub const EXTENDED_HEADER: &str = "client,available,held,total,locked,reserved,chargeback_ratio";
+pub const COMPOSITE_HEADER: &str =
+    "client,available,held,total,locked,frozen,open_disputes,chargebacks,rejected_txs,last_tx_id";
+
+/// Rows are written through a buffer this large, so millions of accounts do not cost a write (or a stdout lock and flush) each
+pub const OUTPUT_BUFFER: usize = 1 << 20;
+
+/// How amounts are rendered in the account summaries. Plain is the machine CSV, the other strategies are meant for human-facing reports
+#[derive(Debug, PartialEq, Clone)]
+pub enum AmountFormat {
+    /// 4 decimal places, no grouping: 1234567.5000
+    Plain,
+    /// 4 decimal places with grouped thousands, e.g. 1,234,567.5000 (en-US) or 1.234.567,5000 (de-DE)
+    Grouped {
+        group_separator: char,
+        decimal_separator: char,
+    },
+    /// Integer amount of 1/10000 units: 12345675000
+    MinorUnits,
+}
+
+impl AmountFormat {
+    pub fn from_locale(locale: &str) -> Option<AmountFormat> {
+        let (group_separator, decimal_separator) = match locale {
+            "en-US" | "en-GB" | "ja-JP" | "zh-CN" => (',', '.'),
+            "de-DE" | "es-ES" | "it-IT" | "nl-NL" | "pt-BR" => ('.', ','),
+            "fr-FR" | "pl-PL" | "sv-SE" => ('\u{a0}', ','),
+            "de-CH" => ('\'', '.'),
+            _ => return None,
+        };
+        Some(AmountFormat::Grouped {
+            group_separator,
+            decimal_separator,
+        })
+    }
+
+    /// plain, minor-units or one of the locales known by from_locale
+    pub fn parse(format: &str) -> Option<AmountFormat> {
+        match format {
+            "plain" => Some(AmountFormat::Plain),
+            "minor-units" => Some(AmountFormat::MinorUnits),
+            locale => Self::from_locale(locale),
+        }
+    }
+
+    pub fn format(&self, amount: Currency) -> String {
+        match self {
+            AmountFormat::Plain => format!("{:.4}", amount),
+            AmountFormat::MinorUnits => {
+                format!("{}", (amount.round_dp(4) * Currency::from(10_000)).trunc())
+            }
+            AmountFormat::Grouped {
+                group_separator,
+                decimal_separator,
+            } => {
+                let plain = format!("{:.4}", amount);
+                let (integer, fraction) = plain.split_once('.').unwrap_or((&plain, ""));
+                let (sign, digits) = match integer.strip_prefix('-') {
+                    Some(digits) => ("-", digits),
+                    None => ("", integer),
+                };
+
+                let mut grouped = String::with_capacity(plain.len() + digits.len() / 3);
+                grouped.push_str(sign);
+                for (i, digit) in digits.chars().enumerate() {
+                    if i > 0 && (digits.len() - i) % 3 == 0 {
+                        grouped.push(*group_separator);
+                    }
+                    grouped.push(digit);
+                }
+                grouped.push(*decimal_separator);
+                grouped.push_str(fraction);
+                grouped
+            }
+        }
+    }
+}
+
+/// Display strategy rendering an account summary row with the given amount format and client ids.
+/// Amounts containing a comma (grouping or decimal separator) are quoted so the row stays valid CSV
+pub struct FormattedAccount<'a> {
+    client: &'a ClientProfile,
+    format: &'a AmountFormat,
+    ids: &'a ClientIds,
+}
+
+impl<'a> FormattedAccount<'a> {
+    pub fn new(
+        client: &'a ClientProfile,
+        format: &'a AmountFormat,
+        ids: &'a ClientIds,
+    ) -> FormattedAccount<'a> {
+        FormattedAccount {
+            client,
+            format,
+            ids,
+        }
+    }
+
+    fn field(&self, amount: Currency) -> String {
+        csv_amount(self.format, amount)
+    }
+}
+
+/// The formatted amount, quoted when it contains a comma
+fn csv_amount(format: &AmountFormat, amount: Currency) -> String {
+    let formatted = format.format(amount);
+    if formatted.contains(',') {
+        format!("\"{}\"", formatted)
+    } else {
+        formatted
+    }
+}
+
+impl fmt::Display for FormattedAccount<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if *self.format == AmountFormat::Plain && *self.ids == ClientIds::Raw {
+            return write!(f, "{}", self.client);
+        }
+        write!(
+            f,
+            "{},{},{},{},{}",
+            self.ids.label(self.client.id()),
+            self.field(self.client.available()),
+            self.field(self.client.held()),
+            self.field(self.client.total()),
+            self.client.locked()
+        )
+    }
+}
+
+/// Account summaries as an aligned table sorted by client, for interactive use. With colors, locked accounts are printed in
+/// red and negative available funds in yellow
+pub fn write_accounts_table<'a, W: io::Write>(
+    writer: W,
+    clients: impl Iterator<Item = &'a ClientProfile>,
+    format: &AmountFormat,
+    ids: &ClientIds,
+    colored: bool,
+) -> io::Result<()> {
+    let mut clients: Vec<&ClientProfile> = clients.collect();
+    clients.sort_unstable_by_key(|client| client.id());
+    let mut table = Table::new(HEADER.split(','));
+    for client in clients {
+        let row = client.locked().then_some(Color::Red);
+        let negative = (client.available() < Currency::zero()).then_some(Color::Yellow);
+        table.push(vec![
+            Cell::new(ids.label(client.id()).to_string()).colored(row),
+            Cell::new(format.format(client.available())).colored(row.or(negative)),
+            Cell::new(format.format(client.held())).colored(row),
+            Cell::new(format.format(client.total())).colored(row),
+            Cell::new(client.locked().to_string()).colored(row),
+        ]);
+    }
+    let mut writer = io::BufWriter::with_capacity(OUTPUT_BUFFER, writer);
+    table.write(&mut writer, colored)?;
+    writer.flush()
+}
+
+pub fn write_accounts<'a, W: io::Write>(
+    writer: W,
+    clients: impl Iterator<Item = &'a ClientProfile>,
+    format: &AmountFormat,
+    ids: &ClientIds,
+) -> io::Result<()> {
+    let mut writer = io::BufWriter::with_capacity(OUTPUT_BUFFER, writer);
+    writeln!(writer, "{}", HEADER)?;
+    for client in clients {
+        writeln!(writer, "{}", FormattedAccount::new(client, format, ids))?;
+    }
+    writer.flush()
+}
+
+/// Same output as write_accounts(), with the rows formatted by the given number of threads, each one into its own buffer
+/// for a contiguous slice of the clients. The buffers are then written in order, so the row order is preserved
+pub fn write_accounts_parallel<'a, W: io::Write>(
+    writer: W,
+    clients: impl Iterator<Item = &'a ClientProfile>,
+    format: &AmountFormat,
+    ids: &ClientIds,
+    threads: usize,
+) -> io::Result<()> {
+    write_accounts_in_ranges(writer, clients, format, ids, threads, usize::MAX, |_, _| {})
+}
+
+/// write_accounts_parallel() one range of at most range clients at a time: each range is formatted by the threads, written
+/// and flushed before the next one is, so only one range of rows is held in memory and the consumer gets the rows as they
+/// are ready. on_range is given the rows written so far and the total once each range is flushed
+pub fn write_accounts_in_ranges<'a, W: io::Write>(
+    writer: W,
+    clients: impl Iterator<Item = &'a ClientProfile>,
+    format: &AmountFormat,
+    ids: &ClientIds,
+    threads: usize,
+    range: usize,
+    mut on_range: impl FnMut(usize, usize),
+) -> io::Result<()> {
+    let clients: Vec<&ClientProfile> = clients.collect();
+    let mut writer = io::BufWriter::with_capacity(OUTPUT_BUFFER, writer);
+    writeln!(writer, "{}", HEADER)?;
+    let mut written = 0;
+    for range in clients.chunks(range.max(1)) {
+        for buffer in format_rows(range, format, ids, threads) {
+            writer.write_all(buffer.as_bytes())?;
+        }
+        writer.flush()?;
+        written += range.len();
+        on_range(written, clients.len());
+    }
+    writer.flush()
+}
+
+/// The rows of the clients, formatted by the given number of threads into one buffer each, in order
+fn format_rows(
+    clients: &[&ClientProfile],
+    format: &AmountFormat,
+    ids: &ClientIds,
+    threads: usize,
+) -> Vec<String> {
+    let chunk = clients.len().div_ceil(threads.max(1)).max(1);
+    thread::scope(|scope| {
+        let formatters: Vec<_> = clients
+            .chunks(chunk)
+            .map(|slice| {
+                scope.spawn(move || {
+                    let mut buffer = String::with_capacity(slice.len() * 48);
+                    for client in slice {
+                        buffer.push_str(&FormattedAccount::new(client, format, ids).to_string());
+                        buffer.push('\n');
+                    }
+                    buffer
+                })
+            })
+            .collect();
+        formatters
+            .into_iter()
+            .map(|formatter| formatter.join().expect("row formatter panicked"))
+            .collect()
+    })
+}
+
+/// The account summaries with risk columns appended: reserved is the part of the available funds that can not be withdrawn
+/// (see EngineConfig::reserve_for), chargeback_ratio the lifetime chargebacks per deposit (see ClientProfile::chargeback_ratio)
+pub fn write_accounts_extended<'a, W: io::Write>(
+    writer: W,
+    clients: impl Iterator<Item = &'a ClientProfile>,
+    format: &AmountFormat,
+    config: &EngineConfig,
+) -> io::Result<()> {
+    let mut writer = io::BufWriter::with_capacity(OUTPUT_BUFFER, writer);
+    writeln!(writer, "{}", EXTENDED_HEADER)?;
+    for client in clients {
+        let account = FormattedAccount::new(client, format, &config.client_ids);
+        let reserved = config
+            .reserve_for(client.id(), client.tier())
+            .min(client.available())
+            .max(Currency::zero());
+        writeln!(
+            writer,
+            "{},{},{:.4}",
+            account,
+            account.field(reserved),
+            client.chargeback_ratio()
+        )?;
+    }
+    writer.flush()
+}
+
+/// Layout of the account summaries. Classic is the one of the specification and stays the default
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub enum OutputSchema {
+    #[default]
+    Classic,
+    /// The classic columns followed by the activity columns of write_accounts_composite()
+    Extended,
+}
+
+impl OutputSchema {
+    pub fn parse(name: &str) -> Option<OutputSchema> {
+        match name {
+            "classic" => Some(OutputSchema::Classic),
+            "extended" => Some(OutputSchema::Extended),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct CompositeAccount {
+    client: ClientLabel,
+    available: String,
+    held: String,
+    total: String,
+    locked: bool,
+    frozen: bool,
+    open_disputes: usize,
+    chargebacks: u64,
+    rejected_txs: u64,
+    last_tx_id: Option<TransactionId>,
+}
+
+/// The account summaries with the activity of every account: whether it is frozen (see ClientProfile::frozen), its open
+/// disputes, lifetime chargebacks, rejected transactions and the id of the last transaction accepted (empty when none was)
+pub fn write_accounts_composite<'a, W: io::Write>(
+    writer: W,
+    clients: impl Iterator<Item = &'a ClientProfile>,
+    format: &AmountFormat,
+    ids: &ClientIds,
+) -> Result<(), csv::Error> {
+    //the header is written upfront, so an exchange without clients still gets one
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(io::BufWriter::with_capacity(OUTPUT_BUFFER, writer));
+    writer.write_record(COMPOSITE_HEADER.split(','))?;
+    for client in clients {
+        writer.serialize(CompositeAccount {
+            client: ids.label(client.id()),
+            available: format.format(client.available()),
+            held: format.format(client.held()),
+            total: format.format(client.total()),
+            locked: client.locked(),
+            frozen: client.frozen(),
+            open_disputes: client.disputed_transactions().count(),
+            chargebacks: client.chargeback_count(),
+            rejected_txs: client.rejected(),
+            last_tx_id: client.last_tx(),
+        })?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct JsonHold {
+    tx: TransactionId,
+    amount: String,
+}
+
+#[derive(Serialize)]
+struct JsonAccount<'a> {
+    client: ClientLabel,
+    available: String,
+    held: String,
+    total: String,
+    locked: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    holds: Option<Vec<JsonHold>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    annotation: Option<&'a Annotation>,
+}
+
+/// Account summaries as a JSON array of objects. Amounts are strings rendered with the amount format so no precision is lost
+/// to JSON numbers. With holds, every account lists the disputed transactions (tx and amount) making up its held funds, and
+/// with annotations the annotated accounts carry their status and note
+pub fn write_accounts_json<'a, W: io::Write>(
+    writer: W,
+    clients: impl Iterator<Item = &'a ClientProfile>,
+    format: &AmountFormat,
+    ids: &ClientIds,
+    holds: bool,
+    annotations: bool,
+) -> io::Result<()> {
+    let accounts: Vec<JsonAccount> = clients
+        .map(|client| JsonAccount {
+            client: ids.label(client.id()),
+            available: format.format(client.available()),
+            held: format.format(client.held()),
+            total: format.format(client.total()),
+            locked: client.locked(),
+            holds: holds.then(|| {
+                let mut holds: Vec<_> = client.holds().collect();
+                holds.sort_unstable();
+                holds
+                    .into_iter()
+                    .map(|(tx, amount)| JsonHold {
+                        tx,
+                        amount: format.format(amount),
+                    })
+                    .collect()
+            }),
+            annotation: client.annotation().filter(|_| annotations),
+        })
+        .collect();
+
+    let mut writer = io::BufWriter::with_capacity(OUTPUT_BUFFER, writer);
+    serde_json::to_writer(&mut writer, &accounts)?;
+    writeln!(writer)?;
+    writer.flush()
+}
+
+pub fn shard_of(client: ClientId, shards: usize) -> usize {
+    client as usize % shards
+}
+
+/// <prefix>.0.csv .. <prefix>.<shards - 1>.csv
+pub fn shard_paths(prefix: &str, shards: usize) -> Vec<PathBuf> {
+    (0..shards)
+        .map(|shard| PathBuf::from(format!("{}.{}.csv", prefix, shard)))
+        .collect()
+}
+
+/// Write each shard's account summaries to its own file, one writer thread per shard, so no single writer has to go through
+/// every account. Clients are assigned to shards by shard_of(); every file gets the summary header
+pub fn write_account_shards<'a>(
+    clients: impl Iterator<Item = &'a ClientProfile>,
+    paths: &[PathBuf],
+    format: &AmountFormat,
+    ids: &ClientIds,
+) -> io::Result<()> {
+    let mut shards: Vec<Vec<&ClientProfile>> = vec![Vec::new(); paths.len()];
+    for client in clients {
+        shards[shard_of(client.id(), paths.len())].push(client);
+    }
+
+    thread::scope(|scope| {
+        let writers: Vec<_> = shards
+            .iter()
+            .zip(paths)
+            .map(|(shard, path)| {
+                scope.spawn(move || {
+                    write_accounts(File::create(path)?, shard.iter().copied(), format, ids)
+                })
+            })
+            .collect();
+
+        writers
+            .into_iter()
+            .try_for_each(|writer| writer.join().expect("shard writer panicked"))
+    })
+}
+
+/// Where the trailer of the account summaries goes, see TrailerWriter
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TrailerPlacement {
+    /// Appended to the output as its last line
+    Footer,
+    /// Written to <output>.trailer next to the output
+    Sidecar,
+}
+
+impl TrailerPlacement {
+    pub fn parse(placement: &str) -> Option<TrailerPlacement> {
+        match placement {
+            "footer" => Some(TrailerPlacement::Footer),
+            "sidecar" => Some(TrailerPlacement::Sidecar),
+            _ => None,
+        }
+    }
+}
+
+/// Row count and checksum of an output, for loaders to check it arrived whole before ingesting it. The checksum is 64 bit
+/// FNV-1a over every byte of the output before the trailer, as the manifest hashes inputs (see ManifestEntry::fingerprint)
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Trailer {
+    /// Lines after the header
+    pub rows: u64,
+    pub checksum: u64,
+}
+
+impl fmt::Display for Trailer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "#trailer,rows={},fnv1a64={:016x}",
+            self.rows, self.checksum
+        )
+    }
+}
+
+/// Writer counting the lines and hashing the bytes written through it, whatever the format of the output, to end it with
+/// its Trailer
+pub struct TrailerWriter<W: io::Write> {
+    inner: W,
+    checksum: u64,
+    lines: u64,
+    last: u8,
+}
+
+impl<W: io::Write> TrailerWriter<W> {
+    pub fn new(inner: W) -> TrailerWriter<W> {
+        TrailerWriter {
+            inner,
+            checksum: 0xcbf29ce484222325,
+            lines: 0,
+            last: b'\n',
+        }
+    }
+
+    /// The trailer of what was written so far. A last line without a trailing newline is counted
+    pub fn trailer(&self) -> Trailer {
+        Trailer {
+            rows: (self.lines + (self.last != b'\n') as u64).saturating_sub(1),
+            checksum: self.checksum,
+        }
+    }
+
+    /// Append the trailer line to the output, on a line of its own, and flush it. Nothing should be written after it
+    pub fn write_trailer(&mut self) -> io::Result<Trailer> {
+        let trailer = self.trailer();
+        if self.last != b'\n' {
+            writeln!(self.inner)?;
+        }
+        writeln!(self.inner, "{}", trailer)?;
+        self.inner.flush()?;
+        Ok(trailer)
+    }
+}
+
+impl<W: io::Write> io::Write for TrailerWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        for byte in &buf[..written] {
+            self.checksum = (self.checksum ^ *byte as u64).wrapping_mul(0x100000001b3);
+            self.lines += (*byte == b'\n') as u64;
+        }
+        if let Some(last) = buf[..written].last() {
+            self.last = *last;
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// <output>.trailer, where TrailerPlacement::Sidecar writes the trailer of output
+pub fn trailer_path(output: &str) -> String {
+    format!("{}.trailer", output)
+}
+
+/// Concatenate shard files into a single summary, keeping only the first file's header
+pub fn merge_account_files<W: io::Write, P: AsRef<Path>>(
+    inputs: &[P],
+    mut writer: W,
+) -> io::Result<()> {
+    let mut header_written = false;
+    for input in inputs {
+        let mut lines = io::BufReader::new(File::open(input)?).lines();
+        if let Some(header) = lines.next() {
+            let header = header?;
+            if !header_written {
+                writeln!(writer, "{}", header)?;
+                header_written = true;
+            }
+        }
+        for line in lines {
+            writeln!(writer, "{}", line?)?;
+        }
+    }
+    writer.flush()
+}
+
+/// Clients with an outstanding receivable, with how much of their deficits deposits already recovered
+pub fn write_receivables<'a, W: io::Write>(
+    mut writer: W,
+    clients: impl Iterator<Item = &'a ClientProfile>,
+    ids: &ClientIds,
+) -> io::Result<()> {
+    writeln!(writer, "client,receivable,recovered")?;
+    for client in clients.filter(|c| c.receivable() > Currency::zero()) {
+        writeln!(
+            writer,
+            "{},{:.4},{:.4}",
+            ids.label(client.id()),
+            client.receivable(),
+            client.recovered()
+        )?;
+    }
+    writer.flush()
+}
+
+pub fn write_pending_chargebacks<W: io::Write>(
+    mut writer: W,
+    pending: &[PendingChargeback],
+    ids: &ClientIds,
+) -> io::Result<()> {
+    writeln!(writer, "client,tx,amount")?;
+    for chargeback in pending {
+        writeln!(
+            writer,
+            "{},{},{:.4}",
+            ids.label(chargeback.client()),
+            chargeback.tx(),
+            chargeback.amount()
+        )?;
+    }
+    writer.flush()
+}
+
+/// The dispute queue of Exchange::open_disputes(), the age and score columns are empty when unknown
+pub fn write_disputes<W: io::Write>(
+    writer: W,
+    disputes: &[DisputeView],
+    ids: &ClientIds,
+) -> io::Result<()> {
+    let mut writer = io::BufWriter::with_capacity(OUTPUT_BUFFER, writer);
+    writeln!(writer, "client,tx,type,amount,age,score")?;
+    for dispute in disputes {
+        writeln!(
+            writer,
+            "{},{},{},{},{},{}",
+            ids.label(dispute.client),
+            dispute.tx,
+            dispute.tx_type,
+            dispute
+                .amount
+                .map(|amount| format!("{:.4}", amount))
+                .unwrap_or_default(),
+            dispute.age.map(|age| age.to_string()).unwrap_or_default(),
+            dispute
+                .score
+                .map(|score| format!("{:.4}", score))
+                .unwrap_or_default()
+        )?;
+    }
+    writer.flush()
+}
+
+/// Stored transactions, one row each with whether they are under dispute
+pub fn write_transactions<'a, W: io::Write>(
+    writer: W,
+    transactions: impl Iterator<Item = &'a Transaction>,
+    format: &AmountFormat,
+    ids: &ClientIds,
+) -> io::Result<()> {
+    let mut writer = io::BufWriter::with_capacity(OUTPUT_BUFFER, writer);
+    writeln!(writer, "client,tx,type,amount,disputed")?;
+    for transaction in transactions {
+        writeln!(
+            writer,
+            "{},{},{},{},{}",
+            ids.label(transaction.client()),
+            transaction.tx(),
+            transaction.tx_type(),
+            transaction
+                .amount()
+                .map(|amount| csv_amount(format, amount))
+                .unwrap_or_default(),
+            transaction.under_dispute()
+        )?;
+    }
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use std::collections::HashMap;
+
+    use crate::exchange::transaction::Type;
+
+    #[test]
+    fn it_should_end_the_output_with_its_row_count_and_checksum() {
+        let mut output = TrailerWriter::new(Vec::new());
+        write!(output, "{}\n1,1.0000,0.0000,1.0000,false\n2,2.0000", HEADER).unwrap();
+        let mut written = Vec::new();
+        let trailer = {
+            let mut output = TrailerWriter::new(&mut written);
+            write!(output, "{}\n1,1.0000,0.0000,1.0000,false\n2,2.0000", HEADER).unwrap();
+            output.write_trailer().unwrap()
+        };
+        assert_eq!(output.trailer(), trailer);
+        assert_eq!(2, trailer.rows);
+
+        let written = String::from_utf8(written).unwrap();
+        let (content, last) = written.trim_end().rsplit_once('\n').unwrap();
+        let checksum = content.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        });
+        assert_eq!(format!("#trailer,rows=2,fnv1a64={:016x}", checksum), last);
+        assert_eq!(0, TrailerWriter::new(io::sink()).trailer().rows);
+    }
+
+    #[test]
+    fn it_should_group_thousands_with_locale_separators() {
+        let amount = Currency::str("-1234567.5");
+
+        assert_eq!(
+            "-1,234,567.5000",
+            AmountFormat::from_locale("en-US").unwrap().format(amount)
+        );
+        assert_eq!(
+            "-1.234.567,5000",
+            AmountFormat::from_locale("de-DE").unwrap().format(amount)
+        );
+        assert_eq!(
+            "999,0001",
+            AmountFormat::from_locale("pt-BR")
+                .unwrap()
+                .format(Currency::str("999.0001"))
+        );
+        assert_eq!(None, AmountFormat::from_locale("xx-XX"));
+    }
+
+    #[test]
+    fn it_should_format_amounts_as_minor_units() {
+        assert_eq!(
+            "12345675000",
+            AmountFormat::MinorUnits.format(Currency::str("1234567.5"))
+        );
+        assert_eq!(
+            "-1",
+            AmountFormat::MinorUnits.format(Currency::str("-0.0001"))
+        );
+        assert_eq!("0", AmountFormat::MinorUnits.format(Currency::zero()));
+    }
+
+    #[test]
+    fn it_should_quote_amounts_containing_commas() {
+        let client = ClientProfile::new(
+            3,
+            Currency::str("1000.0"),
+            Currency::str("0.5"),
+            Currency::str("1000.5"),
+            false,
+            HashMap::new(),
+        );
+
+        let mut output = Vec::new();
+        write_accounts(
+            &mut output,
+            [&client].into_iter(),
+            &AmountFormat::from_locale("en-US").unwrap(),
+            &ClientIds::Raw,
+        )
+        .unwrap();
+
+        assert_eq!(
+            "client,available,held,total,locked\n3,\"1,000.0000\",0.5000,\"1,000.5000\",false\n",
+            String::from_utf8(output).unwrap()
+        );
+    }
+
+    #[test]
+    fn it_should_write_a_table_sorted_by_client_highlighting_locked_and_negative_accounts() {
+        let overdrawn = ClientProfile::new(
+            12,
+            Currency::str("-5.0"),
+            Currency::str("5.0"),
+            Currency::zero(),
+            false,
+            HashMap::new(),
+        );
+        let locked = ClientProfile::new(
+            3,
+            Currency::str("1000.0"),
+            Currency::zero(),
+            Currency::str("1000.0"),
+            true,
+            HashMap::new(),
+        );
+
+        let mut output = Vec::new();
+        write_accounts_table(
+            &mut output,
+            [&overdrawn, &locked].into_iter(),
+            &AmountFormat::from_locale("en-US").unwrap(),
+            &ClientIds::Raw,
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            concat!(
+                "client   available    held       total  locked\n",
+                "     3  1,000.0000  0.0000  1,000.0000    true\n",
+                "    12     -5.0000  5.0000      0.0000   false\n",
+            ),
+            String::from_utf8(output).unwrap()
+        );
+
+        let mut output = Vec::new();
+        write_accounts_table(
+            &mut output,
+            [&overdrawn, &locked].into_iter(),
+            &AmountFormat::Plain,
+            &ClientIds::Raw,
+            true,
+        )
+        .unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(true, output.contains("\x1b[31mtrue\x1b[0m"));
+        assert_eq!(true, output.contains("\x1b[33m-5.0000\x1b[0m"));
+        assert_eq!(false, output.contains("\x1b[31m12"));
+    }
+
+    #[test]
+    fn it_should_report_the_reserved_funds_in_the_extended_summaries() {
+        let config = EngineConfig {
+            reserve: Currency::str("3.0"),
+            client_reserves: HashMap::from([(2, Currency::str("1.0"))]),
+            ..EngineConfig::default()
+        };
+        let clients = [
+            ClientProfile::new(
+                1,
+                Currency::str("2.0"),
+                Currency::zero(),
+                Currency::str("2.0"),
+                false,
+                HashMap::new(),
+            ),
+            ClientProfile::new(
+                2,
+                Currency::str("1200.0"),
+                Currency::zero(),
+                Currency::str("1200.0"),
+                false,
+                HashMap::new(),
+            ),
+        ];
+
+        let mut output = Vec::new();
+        write_accounts_extended(&mut output, clients.iter(), &AmountFormat::Plain, &config)
+            .unwrap();
+        assert_eq!(
+            "client,available,held,total,locked,reserved,chargeback_ratio\n1,2.0000,0.0000,2.0000,false,2.0000,0.0000\n2,1200.0000,0.0000,1200.0000,false,1.0000,0.0000\n",
+            String::from_utf8(output).unwrap()
+        );
+
+        let mut output = Vec::new();
+        let format = AmountFormat::from_locale("en-US").unwrap();
+        write_accounts_extended(&mut output, clients[1..].iter(), &format, &config).unwrap();
+        assert_eq!(
+            "client,available,held,total,locked,reserved,chargeback_ratio\n2,\"1,200.0000\",0.0000,\"1,200.0000\",false,1.0000,0.0000\n",
+            String::from_utf8(output).unwrap()
+        );
+    }
+
+    #[test]
+    fn it_should_write_json_summaries_with_an_optional_holds_section() {
+        let disputed = Transaction {
+            tx_type: Type::Deposit,
+            client: 2,
+            tx: 9,
+            amount: Some(Currency::str("0.5")),
+            metadata: HashMap::new(),
+            under_dispute: true,
+            dispute_metadata: None,
+        };
+        let client = ClientProfile::new(
+            2,
+            Currency::str("1.0"),
+            Currency::str("0.5"),
+            Currency::str("1.5"),
+            false,
+            HashMap::from([(9, disputed)]),
+        );
+
+        let mut output = Vec::new();
+        write_accounts_json(
+            &mut output,
+            [&client].into_iter(),
+            &AmountFormat::Plain,
+            &ClientIds::Raw,
+            false,
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            "[{\"client\":2,\"available\":\"1.0000\",\"held\":\"0.5000\",\"total\":\"1.5000\",\"locked\":false}]\n",
+            String::from_utf8(output).unwrap()
+        );
+
+        let mut output = Vec::new();
+        write_accounts_json(
+            &mut output,
+            [&client].into_iter(),
+            &AmountFormat::Plain,
+            &ClientIds::Raw,
+            true,
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            true,
+            String::from_utf8(output)
+                .unwrap()
+                .contains("\"holds\":[{\"tx\":9,\"amount\":\"0.5000\"}]")
+        );
+
+        let mut annotated = client.clone();
+        annotated.annotate(Some(Annotation {
+            status: "VIP".to_string(),
+            note: None,
+        }));
+        let mut output = Vec::new();
+        write_accounts_json(
+            &mut output,
+            [&annotated, &client].into_iter(),
+            &AmountFormat::Plain,
+            &ClientIds::Raw,
+            false,
+            true,
+        )
+        .unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(1, output.matches("\"annotation\"").count());
+        assert_eq!(
+            true,
+            output.contains("\"locked\":false,\"annotation\":{\"status\":\"VIP\",\"note\":null}}")
+        );
+    }
+
+    #[test]
+    fn it_should_write_shards_concurrently_and_merge_them_back() {
+        let clients: Vec<ClientProfile> = (1..=5)
+            .map(|id| {
+                ClientProfile::new(
+                    id,
+                    Currency::str("1.0"),
+                    Currency::zero(),
+                    Currency::str("1.0"),
+                    false,
+                    HashMap::new(),
+                )
+            })
+            .collect();
+        let prefix = std::env::temp_dir().join("payment_engine_shards");
+        let paths = shard_paths(prefix.to_str().unwrap(), 2);
+
+        write_account_shards(
+            clients.iter(),
+            &paths,
+            &AmountFormat::Plain,
+            &ClientIds::Raw,
+        )
+        .unwrap();
+
+        assert_eq!(
+            "client,available,held,total,locked\n2,1.0000,0.0000,1.0000,false\n4,1.0000,0.0000,1.0000,false\n",
+            std::fs::read_to_string(&paths[0]).unwrap()
+        );
+
+        let mut merged = Vec::new();
+        merge_account_files(&paths, &mut merged).unwrap();
+        paths
+            .iter()
+            .for_each(|path| std::fs::remove_file(path).unwrap());
+
+        let merged = String::from_utf8(merged).unwrap();
+        assert_eq!(6, merged.lines().count());
+        assert_eq!(1, merged.matches("client,available").count());
+    }
+
+    #[test]
+    fn it_should_format_rows_in_parallel_in_the_same_order() {
+        let clients: Vec<ClientProfile> = (1..=10)
+            .map(|id| {
+                ClientProfile::new(
+                    id,
+                    Currency::from(id),
+                    Currency::zero(),
+                    Currency::from(id),
+                    id % 3 == 0,
+                    HashMap::new(),
+                )
+            })
+            .collect();
+
+        let mut sequential = Vec::new();
+        write_accounts(
+            &mut sequential,
+            clients.iter(),
+            &AmountFormat::Plain,
+            &ClientIds::Raw,
+        )
+        .unwrap();
+        for threads in [1, 3, 16] {
+            let mut parallel = Vec::new();
+            write_accounts_parallel(
+                &mut parallel,
+                clients.iter(),
+                &AmountFormat::Plain,
+                &ClientIds::Raw,
+                threads,
+            )
+            .unwrap();
+            assert_eq!(sequential, parallel);
+        }
+    }
+
+    #[test]
+    fn it_should_flush_the_rows_one_range_at_a_time() {
+        let clients: Vec<ClientProfile> = (1..=10)
+            .map(|id| {
+                ClientProfile::new(
+                    id,
+                    Currency::from(id),
+                    Currency::zero(),
+                    Currency::from(id),
+                    false,
+                    HashMap::new(),
+                )
+            })
+            .collect();
+        let mut sequential = Vec::new();
+        write_accounts(
+            &mut sequential,
+            clients.iter(),
+            &AmountFormat::Plain,
+            &ClientIds::Raw,
+        )
+        .unwrap();
+
+        let mut ranged = Vec::new();
+        let mut progress = Vec::new();
+        write_accounts_in_ranges(
+            &mut ranged,
+            clients.iter(),
+            &AmountFormat::Plain,
+            &ClientIds::Raw,
+            2,
+            4,
+            |written, total| progress.push((written, total)),
+        )
+        .unwrap();
+        assert_eq!(sequential, ranged);
+        assert_eq!(vec![(4, 10), (8, 10), (10, 10)], progress);
+    }
+
+    #[test]
+    fn it_should_write_transactions_with_their_dispute_state() {
+        let deposit = Transaction {
+            tx_type: Type::Deposit,
+            client: 7,
+            tx: 12345,
+            amount: Some(Currency::str("1000.5")),
+            metadata: HashMap::new(),
+            under_dispute: true,
+            dispute_metadata: None,
+        };
+        let dispute = Transaction {
+            tx_type: Type::Dispute,
+            amount: None,
+            under_dispute: false,
+            ..deposit.clone()
+        };
+
+        let mut output = Vec::new();
+        write_transactions(
+            &mut output,
+            [&deposit, &dispute].into_iter(),
+            &AmountFormat::from_locale("en-US").unwrap(),
+            &ClientIds::Raw,
+        )
+        .unwrap();
+        assert_eq!(
+            "client,tx,type,amount,disputed\n7,12345,deposit,\"1,000.5000\",true\n7,12345,dispute,,false\n",
+            String::from_utf8(output).unwrap()
+        );
+    }
+}
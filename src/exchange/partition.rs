@@ -0,0 +1,105 @@
+use std::error::Error;
+use std::thread;
+
+use crate::exchange::process_transactions_from_csv_with;
+use crate::exchange::Exchange;
+use crate::exchange::IngestOptions;
+
+/// Process disjoint partitions of the input (e.g. one file per upstream shard) concurrently, each on its own thread with its own
+/// exchange, then merge the results into bank in the order the paths are given (see Exchange::merge). Fails, leaving bank as it
+/// was, if a partition can not be read or two partitions know the same transaction id
+pub fn process_partitions_from_csv(
+    paths: &[String],
+    bank: &mut Exchange,
+    options: &IngestOptions,
+) -> Result<(), Box<dyn Error>> {
+    let partitions: Vec<Result<Exchange, String>> = thread::scope(|scope| {
+        let threads: Vec<_> = paths
+            .iter()
+            .enumerate()
+            .map(|(i, path)| {
+                //the first partition continues from the state of bank, the others start empty
+                let mut partition = if i == 0 {
+                    bank.fork()
+                } else {
+                    bank.empty_like()
+                };
+                scope.spawn(move || {
+                    process_transactions_from_csv_with(path, &mut partition, options)
+                        .map(|_| partition)
+                        .map_err(|e| format!("Failed to read {}: {}", path, e))
+                })
+            })
+            .collect();
+        threads
+            .into_iter()
+            .map(|thread| {
+                thread
+                    .join()
+                    .unwrap_or_else(|_| Err("Partition thread panicked".to_string()))
+            })
+            .collect()
+    });
+
+    let mut partitions = partitions.into_iter();
+    let mut merged = match partitions.next() {
+        Some(first) => first?,
+        None => return Ok(()),
+    };
+    for partition in partitions {
+        merged.merge(partition?)?;
+    }
+    bank.commit(merged);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use crate::exchange::transaction::Currency;
+    use crate::exchange::transaction::Money;
+
+    #[test]
+    fn it_should_process_partitions_concurrently_and_merge_them() {
+        let dir = std::env::temp_dir();
+        let paths: Vec<String> = [
+            (
+                "payment_engine_partition_a.csv",
+                "type,client,tx,amount\ndeposit,1,1,10.0\nwithdrawal,1,2,4.0\n",
+            ),
+            (
+                "payment_engine_partition_b.csv",
+                "type,client,tx,amount\ndeposit,1,3,1.0\ndeposit,2,4,2.0\n",
+            ),
+            (
+                "payment_engine_partition_c.csv",
+                "type,client,tx,amount\ndeposit,3,1,7.0\n",
+            ),
+        ]
+        .iter()
+        .map(|(name, content)| {
+            let path = dir.join(name);
+            std::fs::write(&path, content).unwrap();
+            path.to_string_lossy().to_string()
+        })
+        .collect();
+
+        let mut exchange = Exchange::new();
+        process_partitions_from_csv(&paths[..2], &mut exchange, &IngestOptions::default()).unwrap();
+        assert_eq!(Currency::str("7.0"), exchange.client(1).unwrap().total());
+        assert_eq!(Currency::str("2.0"), exchange.client(2).unwrap().total());
+
+        //c reuses tx 1 of a
+        let mut conflicting = Exchange::new();
+        let e = process_partitions_from_csv(&paths, &mut conflicting, &IngestOptions::default())
+            .unwrap_err();
+        assert_eq!(true, e.to_string().contains("transaction 1 exists in both"));
+        assert_eq!(None, conflicting.client(1));
+
+        for path in &paths {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+}
@@ -0,0 +1,224 @@
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+
+use crate::exchange::client_profile::AccountBalance;
+use crate::exchange::client_profile::ProcessingError;
+use crate::exchange::pseudonym::Reason;
+use crate::exchange::stream::ExchangeHandle;
+use crate::exchange::stream::PipelineError;
+use crate::exchange::transaction::ClientId;
+use crate::exchange::transaction::Transaction;
+use crate::exchange::transaction::TransactionId;
+use crate::exchange::Exchange;
+
+/// What became of a transaction handed to a PaymentProcessor
+#[derive(Debug, PartialEq, Clone)]
+pub enum Outcome {
+    Accepted,
+    /// Rejected by the engine with this reason, e.g. insufficient funds. The run carries on
+    Rejected(Reason),
+}
+
+/// Object-safe façade over the engine for applications embedding it: their ingestion glue takes a `&mut dyn PaymentProcessor`,
+/// an Exchange (or ExchangeHandle) in production and a MockExchange in their unit tests. Balances are returned by value so
+/// implementations are free to compute them on demand
+pub trait PaymentProcessor {
+    /// Process one transaction. It fails only when the run has to stop, as with ExchangeHandle: a limit exceeded under
+    /// LimitPolicy::Abort or an unknown type under UnknownTypePolicy::Fail
+    fn process(&mut self, transaction: Transaction) -> Result<Outcome, PipelineError>;
+
+    fn balance(&self, client: ClientId) -> Option<AccountBalance>;
+
+    /// The balances of every account, by client
+    fn balances(&self) -> Vec<AccountBalance>;
+
+    /// Number of transactions submitted so far, accepted or rejected
+    fn submitted(&self) -> u64;
+
+    /// Number of transactions rejected so far
+    fn rejected(&self) -> u64;
+}
+
+impl PaymentProcessor for Exchange {
+    fn process(&mut self, transaction: Transaction) -> Result<Outcome, PipelineError> {
+        self.check_limits(&transaction)?;
+        self.check_type(&transaction)?;
+        Ok(match self.process_new_transaction(transaction) {
+            Ok(()) => Outcome::Accepted,
+            Err(ProcessingError(reason)) => Outcome::Rejected(reason),
+        })
+    }
+
+    fn balance(&self, client: ClientId) -> Option<AccountBalance> {
+        self.client(client).map(AccountBalance::from)
+    }
+
+    fn balances(&self) -> Vec<AccountBalance> {
+        let mut balances: Vec<AccountBalance> =
+            self.clients.values().map(AccountBalance::from).collect();
+        balances.sort_unstable_by_key(|balance| balance.client);
+        balances
+    }
+
+    fn submitted(&self) -> u64 {
+        Exchange::submitted(self)
+    }
+
+    fn rejected(&self) -> u64 {
+        self.stats.rejected()
+    }
+}
+
+/// The exchange behind the handle, locked for every call
+impl PaymentProcessor for ExchangeHandle {
+    fn process(&mut self, transaction: Transaction) -> Result<Outcome, PipelineError> {
+        self.lock().process(transaction)
+    }
+
+    fn balance(&self, client: ClientId) -> Option<AccountBalance> {
+        self.lock().balance(client)
+    }
+
+    fn balances(&self) -> Vec<AccountBalance> {
+        self.lock().balances()
+    }
+
+    fn submitted(&self) -> u64 {
+        self.lock().submitted()
+    }
+
+    fn rejected(&self) -> u64 {
+        self.lock().rejected()
+    }
+}
+
+/// PaymentProcessor for tests of code driving the engine, with no engine state behind it: it records the transactions it is
+/// given, rejects the ones it was told to and answers balance queries with the balances it was given. Processing never
+/// changes the balances
+#[derive(Debug, Default, Clone)]
+pub struct MockExchange {
+    processed: Vec<Transaction>,
+    rejections: HashMap<TransactionId, String>,
+    balances: BTreeMap<ClientId, AccountBalance>,
+    rejected: u64,
+}
+
+impl MockExchange {
+    pub fn new() -> MockExchange {
+        MockExchange::default()
+    }
+
+    /// Answer queries about balance.client with balance
+    pub fn with_balance(mut self, balance: AccountBalance) -> MockExchange {
+        self.balances.insert(balance.client, balance);
+        self
+    }
+
+    /// Reject every transaction with this tx id, with this reason
+    pub fn rejecting(mut self, tx: TransactionId, reason: &str) -> MockExchange {
+        self.rejections.insert(tx, reason.to_string());
+        self
+    }
+
+    /// The transactions processed so far, accepted or rejected, in order
+    pub fn processed(&self) -> &[Transaction] {
+        &self.processed
+    }
+}
+
+impl PaymentProcessor for MockExchange {
+    fn process(&mut self, transaction: Transaction) -> Result<Outcome, PipelineError> {
+        let outcome = match self.rejections.get(&transaction.tx) {
+            Some(reason) => {
+                self.rejected += 1;
+                Outcome::Rejected(reason.as_str().into())
+            }
+            None => Outcome::Accepted,
+        };
+        self.processed.push(transaction);
+        Ok(outcome)
+    }
+
+    fn balance(&self, client: ClientId) -> Option<AccountBalance> {
+        self.balances.get(&client).cloned()
+    }
+
+    fn balances(&self) -> Vec<AccountBalance> {
+        self.balances.values().cloned().collect()
+    }
+
+    fn submitted(&self) -> u64 {
+        self.processed.len() as u64
+    }
+
+    fn rejected(&self) -> u64 {
+        self.rejected
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use crate::exchange::transaction::Currency;
+    use crate::exchange::transaction::Money;
+    use crate::exchange::transaction::Type;
+
+    fn row(tx_type: Type, tx: TransactionId, amount: &str) -> Transaction {
+        Transaction {
+            tx_type,
+            client: 1,
+            tx,
+            amount: Some(Currency::str(amount)),
+            metadata: HashMap::new(),
+            under_dispute: false,
+            dispute_metadata: None,
+        }
+    }
+
+    /// Glue as an embedding application would write it, counting the rejections it has to report
+    fn ingest(processor: &mut dyn PaymentProcessor, transactions: Vec<Transaction>) -> usize {
+        transactions
+            .into_iter()
+            .map(|transaction| processor.process(transaction).unwrap())
+            .filter(|outcome| matches!(outcome, Outcome::Rejected(_)))
+            .count()
+    }
+
+    #[test]
+    fn it_should_drive_the_engine_and_the_mock_through_the_same_facade() {
+        let transactions = || {
+            vec![
+                row(Type::Deposit, 1, "10.0"),
+                row(Type::Withdrawal, 2, "50.0"),
+            ]
+        };
+
+        let mut exchange = Exchange::new();
+        assert_eq!(1, ingest(&mut exchange, transactions()));
+        let processor: &dyn PaymentProcessor = &exchange;
+        assert_eq!(2, processor.submitted());
+        assert_eq!(1, processor.rejected());
+        assert_eq!(
+            Currency::str("10.0"),
+            processor.balance(1).unwrap().available
+        );
+
+        let balance = AccountBalance {
+            client: 1,
+            available: Currency::str("3.0"),
+            held: Currency::zero(),
+            total: Currency::str("3.0"),
+            locked: false,
+        };
+        let mut mock = MockExchange::new()
+            .rejecting(2, "Insufficient funds")
+            .with_balance(balance.clone());
+        assert_eq!(1, ingest(&mut mock, transactions()));
+        assert_eq!(2, mock.processed().len());
+        assert_eq!((2, 1), (mock.submitted(), mock.rejected()));
+        assert_eq!(vec![balance], mock.balances());
+        assert_eq!(None, mock.balance(2));
+    }
+}
@@ -0,0 +1,242 @@
+use serde::Serialize;
+use siphasher::sip::SipHasher24;
+use std::error::Error;
+use std::fmt;
+
+use crate::exchange::client_profile::ProcessingError;
+use crate::exchange::config::LimitExceeded;
+use crate::exchange::config::UnknownTransactionType;
+use crate::exchange::remap::SharedMapping;
+use crate::exchange::transaction::ClientId;
+
+/// How client ids are written to the output artifacts (summaries, ledger, rejects, reports and logs). Clients keep the ids
+/// they were read with in memory either way
+#[derive(Debug, Default, PartialEq, Clone)]
+pub enum ClientIds {
+    #[default]
+    Raw,
+    /// Salted hashes, so output files can be handed out without the real customer ids
+    Hashed(Pseudonymizer),
+    /// The external ids the internal ones were assigned to, see remap::ClientMapping. Inputs are read through the mapping too
+    Mapped(SharedMapping),
+}
+
+impl ClientIds {
+    pub fn hashed(salt: &str) -> ClientIds {
+        ClientIds::Hashed(Pseudonymizer::new(salt))
+    }
+
+    pub fn label(&self, client: ClientId) -> ClientLabel {
+        match self {
+            ClientIds::Raw => ClientLabel::Raw(client),
+            ClientIds::Hashed(pseudonymizer) => ClientLabel::Hashed(pseudonymizer.hash(client)),
+            ClientIds::Mapped(mapping) => mapping
+                .external(client)
+                .map_or(ClientLabel::Raw(client), ClientLabel::External),
+        }
+    }
+
+    /// The reason with the clients it names written as their labels
+    pub fn render(&self, reason: &Reason) -> String {
+        let mut rendered = String::with_capacity(reason.0.len());
+        let mut rest = reason.0.as_str();
+        while let Some((text, mention)) = rest.split_once(MENTION_START) {
+            rendered.push_str(text);
+            let (client, after) = mention
+                .split_once(MENTION_END)
+                .expect("mentions are closed");
+            match client.parse::<ClientId>() {
+                Ok(client) => rendered.push_str(&self.label(client).to_string()),
+                Err(_) => rendered.push_str(client),
+            }
+            rest = after;
+        }
+        rendered.push_str(rest);
+        rendered
+    }
+
+    /// An error of the engine as the outputs write it: the reasons of ProcessingError, LimitExceeded and
+    /// UnknownTransactionType with the clients they name written as their labels, any other error as it is
+    pub fn describe(&self, error: &(dyn Error + 'static)) -> String {
+        if let Some(ProcessingError(reason)) = error.downcast_ref() {
+            self.render(reason)
+        } else if let Some(LimitExceeded(reason)) = error.downcast_ref() {
+            self.render(reason)
+        } else if let Some(UnknownTransactionType(reason)) = error.downcast_ref() {
+            self.render(reason)
+        } else {
+            error.to_string()
+        }
+    }
+}
+
+const MENTION_START: char = '\u{2}';
+const MENTION_END: char = '\u{3}';
+
+/// Writes a client id into a Reason, where it is kept apart from the text so the outputs can label it
+pub struct Mention(pub ClientId);
+
+impl fmt::Display for Mention {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}{}", MENTION_START, self.0, MENTION_END)
+    }
+}
+
+/// Message of an error naming clients, e.g. why a transaction was rejected, formatted with the ids written through Mention
+/// (or Transaction::mention()). Display writes the raw ids, the outputs write them through ClientIds::render
+#[derive(Default, PartialEq, Clone)]
+pub struct Reason(String);
+
+impl Reason {
+    /// Whether the raw text holds the pattern
+    pub fn contains(&self, pattern: &str) -> bool {
+        self.to_string().contains(pattern)
+    }
+}
+
+impl From<String> for Reason {
+    fn from(reason: String) -> Self {
+        Reason(reason)
+    }
+}
+
+impl From<&str> for Reason {
+    fn from(reason: &str) -> Self {
+        Reason(reason.to_string())
+    }
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", ClientIds::Raw.render(self))
+    }
+}
+
+impl fmt::Debug for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.to_string())
+    }
+}
+
+/// A client id as written out: the number, its hash in hexadecimal or the external id it stands for
+#[derive(Debug, PartialEq, Clone, Serialize)]
+#[serde(untagged)]
+pub enum ClientLabel {
+    Raw(ClientId),
+    Hashed(String),
+    External(u64),
+}
+
+impl fmt::Display for ClientLabel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientLabel::Raw(client) => write!(f, "{}", client),
+            ClientLabel::Hashed(hash) => write!(f, "{}", hash),
+            ClientLabel::External(client) => write!(f, "{}", client),
+        }
+    }
+}
+
+/// SipHash-2-4 of the client id under a key derived from the salt. The same salt always gives the same hashes, so files of
+/// different runs can still be joined on the client column. There are only 65536 client ids: the salt is what keeps the hashes
+/// from being reversed by hashing all of them, so it must be long, random and kept secret
+#[derive(Clone, PartialEq)]
+pub struct Pseudonymizer {
+    k0: u64,
+    k1: u64,
+}
+
+impl Pseudonymizer {
+    pub fn new(salt: &str) -> Pseudonymizer {
+        let k0 = siphash(0, 0, salt.as_bytes());
+        Pseudonymizer {
+            k0,
+            k1: siphash(k0, 0, salt.as_bytes()),
+        }
+    }
+
+    /// 16 hexadecimal digits
+    pub fn hash(&self, client: ClientId) -> String {
+        format!("{:016x}", siphash(self.k0, self.k1, &client.to_le_bytes()))
+    }
+}
+
+impl fmt::Debug for Pseudonymizer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Pseudonymizer(..)")
+    }
+}
+
+fn siphash(k0: u64, k1: u64, data: &[u8]) -> u64 {
+    SipHasher24::new_with_keys(k0, k1).hash(data)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn it_should_keep_the_hashes_of_earlier_runs() {
+        let pseudonymizer = Pseudonymizer::new("s4lt");
+        assert_eq!("ae35cb24a44f4173", pseudonymizer.hash(1));
+        assert_eq!("6ab016f700e01ede", pseudonymizer.hash(4242));
+    }
+
+    #[test]
+    fn it_should_hash_client_ids_with_the_salt() {
+        let ids = ClientIds::hashed("s4lt");
+        assert_eq!(ids.label(1), ClientIds::hashed("s4lt").label(1));
+        assert_ne!(ids.label(1), ClientIds::hashed("another").label(1));
+        assert_ne!(ids.label(1), ids.label(2));
+        assert_eq!(16, ids.label(1).to_string().len());
+        assert_eq!("7", ClientIds::Raw.label(7).to_string());
+        assert_eq!(
+            "Pseudonymizer(..)",
+            format!("{:?}", Pseudonymizer::new("s4lt"))
+        );
+    }
+
+    #[test]
+    fn it_should_render_the_clients_a_reason_names_as_their_labels() {
+        let ids = ClientIds::hashed("s4lt");
+        let reason = Reason::from(format!(
+            "Cannot merge client {} into {}: transaction 1 exists in both accounts",
+            Mention(1),
+            Mention(4242)
+        ));
+        assert_eq!(
+            format!(
+                "Cannot merge client {} into {}: transaction 1 exists in both accounts",
+                ids.label(1),
+                ids.label(4242)
+            ),
+            ids.render(&reason)
+        );
+        assert_eq!(
+            "Cannot merge client 1 into 4242: transaction 1 exists in both accounts",
+            reason.to_string()
+        );
+        assert_eq!(
+            "Cannot merge client 1 into 4242: transaction 1 exists in both accounts",
+            ClientIds::Raw.render(&reason)
+        );
+        assert_eq!("no client", ids.render(&Reason::from("no client")));
+
+        let limit: Box<dyn Error> = Box::new(LimitExceeded(Reason::from(format!(
+            "Client limit of 1 reached by transaction {}",
+            Mention(4242)
+        ))));
+        assert_eq!(
+            format!(
+                "Client limit of 1 reached by transaction {}",
+                ids.label(4242)
+            ),
+            ids.describe(limit.as_ref())
+        );
+        assert_eq!(
+            "Client limit of 1 reached by transaction 4242",
+            limit.to_string()
+        );
+    }
+}
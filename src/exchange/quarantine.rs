@@ -0,0 +1,105 @@
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::Path;
+
+/// Records that could not even be read as CSV (invalid UTF-8, wrong number of fields), kept byte for byte so the originating
+/// system can be handed the exact payload. Every entry is a line `offset=<byte offset in the input> length=<n> error=<message>`
+/// followed by the n raw bytes of the record and a newline
+pub struct Quarantine {
+    input: File,
+    writer: io::BufWriter<File>,
+    records: u64,
+}
+
+impl Quarantine {
+    /// Quarantine records of the input file into the quarantine file, which is created or truncated
+    pub fn create<I: AsRef<Path>, P: AsRef<Path>>(input: I, path: P) -> io::Result<Quarantine> {
+        Ok(Quarantine {
+            input: File::open(input)?,
+            writer: io::BufWriter::new(File::create(path)?),
+            records: 0,
+        })
+    }
+
+    /// Copy the input bytes in [start, end) into the quarantine
+    pub fn record(
+        &mut self,
+        start: u64,
+        end: u64,
+        error: &dyn std::fmt::Display,
+    ) -> io::Result<()> {
+        let mut raw = vec![0; end.saturating_sub(start) as usize];
+        self.input.seek(SeekFrom::Start(start))?;
+        self.input.read_exact(&mut raw)?;
+
+        writeln!(
+            self.writer,
+            "offset={} length={} error={}",
+            start,
+            raw.len(),
+            error
+        )?;
+        self.writer.write_all(&raw)?;
+        writeln!(self.writer)?;
+        self.records += 1;
+        Ok(())
+    }
+
+    /// Number of records quarantined so far
+    pub fn records(&self) -> u64 {
+        self.records
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use std::fs;
+
+    #[test]
+    fn it_should_copy_the_raw_bytes_of_each_record_after_its_header_line() {
+        let dir = std::env::temp_dir().join("payment_engine_quarantine");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let (input, path) = (dir.join("input.csv"), dir.join("quarantine.txt"));
+        let mut raw = b"type,client,tx,amount\ndeposit,1,1,1.0\n".to_vec();
+        let start = raw.len() as u64;
+        raw.extend_from_slice(b"deposit,\xff\xfe,2,1.0\n");
+        let end = raw.len() as u64 - 1;
+        fs::write(&input, &raw).unwrap();
+        //a leftover of an earlier run is truncated
+        fs::write(&path, "stale").unwrap();
+
+        let mut quarantine = Quarantine::create(&input, &path).unwrap();
+        quarantine.record(start, end, &"invalid UTF-8").unwrap();
+        quarantine.record(end, start, &"empty").unwrap();
+        quarantine.flush().unwrap();
+        assert_eq!(2, quarantine.records());
+
+        let mut expected = format!("offset={} length=16 error=invalid UTF-8\n", start).into_bytes();
+        expected.extend_from_slice(b"deposit,\xff\xfe,2,1.0\n");
+        expected.extend_from_slice(format!("offset={} length=0 error=empty\n\n", end).as_bytes());
+        assert_eq!(expected, fs::read(&path).unwrap());
+
+        //past the end of the input
+        assert_eq!(
+            io::ErrorKind::UnexpectedEof,
+            quarantine
+                .record(end, end + 10, &"gone")
+                .unwrap_err()
+                .kind()
+        );
+        assert_eq!(2, quarantine.records());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
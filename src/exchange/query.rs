@@ -0,0 +1,148 @@
+use std::cmp::Ordering;
+
+use crate::exchange::client_profile::AccountBalance;
+use crate::exchange::client_profile::ClientProfile;
+use crate::exchange::transaction::Currency;
+use crate::exchange::transaction::Money;
+
+/// Read-only selection over the account summaries. Every criterion left as None matches all accounts
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct AccountFilter {
+    pub locked: Option<bool>,
+    pub negative_available: Option<bool>,
+    pub with_held_funds: Option<bool>,
+    pub min_total: Option<Currency>,
+}
+
+impl AccountFilter {
+    pub fn matches(&self, client: &ClientProfile) -> bool {
+        self.locked.is_none_or(|locked| client.locked() == locked)
+            && self
+                .negative_available
+                .is_none_or(|negative| (client.available() < Currency::zero()) == negative)
+            && self
+                .with_held_funds
+                .is_none_or(|held| (client.held() != Currency::zero()) == held)
+            && self.min_total.is_none_or(|min| client.total() >= min)
+    }
+}
+
+/// Order of the accounts in a page. Ties are broken by client id, so the same state always yields the same pages
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub enum AccountSort {
+    #[default]
+    Client,
+    /// Largest total first
+    Total,
+    /// Largest held funds first
+    Held,
+}
+
+impl AccountSort {
+    pub fn parse(name: &str) -> Option<AccountSort> {
+        match name {
+            "client" => Some(AccountSort::Client),
+            "total" => Some(AccountSort::Total),
+            "held" => Some(AccountSort::Held),
+            _ => None,
+        }
+    }
+
+    pub fn compare(&self, a: &ClientProfile, b: &ClientProfile) -> Ordering {
+        let by_key = match self {
+            AccountSort::Client => Ordering::Equal,
+            AccountSort::Total => b.total().cmp(&a.total()),
+            AccountSort::Held => b.held().cmp(&a.held()),
+        };
+        by_key.then_with(|| a.id().cmp(&b.id()))
+    }
+}
+
+/// Slice of the account summaries returned by Exchange::accounts_page()
+#[derive(Debug, PartialEq, Clone)]
+pub struct AccountPage {
+    pub offset: usize,
+    pub accounts: Vec<AccountBalance>,
+    /// Number of accounts across all pages
+    pub total: usize,
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn account(id: u16, available: &str, held: &str, locked: bool) -> ClientProfile {
+        let (available, held) = (Currency::str(available), Currency::str(held));
+        ClientProfile::new(
+            id,
+            available,
+            held,
+            available + held,
+            locked,
+            std::iter::empty(),
+        )
+    }
+
+    #[test]
+    fn it_should_match_the_accounts_meeting_every_criterion_given() {
+        let accounts = [
+            account(1, "10.0", "0.0", false),
+            account(2, "-5.0", "5.0", false),
+            account(3, "0.0", "2.0", true),
+        ];
+        let matching = |filter: &AccountFilter| -> Vec<u16> {
+            accounts
+                .iter()
+                .filter(|client| filter.matches(client))
+                .map(|client| client.id())
+                .collect()
+        };
+
+        assert_eq!(vec![1, 2, 3], matching(&AccountFilter::default()));
+        let locked = AccountFilter {
+            locked: Some(true),
+            ..AccountFilter::default()
+        };
+        assert_eq!(vec![3], matching(&locked));
+        let negative = AccountFilter {
+            negative_available: Some(true),
+            ..AccountFilter::default()
+        };
+        assert_eq!(vec![2], matching(&negative));
+        let held_unlocked = AccountFilter {
+            locked: Some(false),
+            with_held_funds: Some(true),
+            ..AccountFilter::default()
+        };
+        assert_eq!(vec![2], matching(&held_unlocked));
+        let min_total = AccountFilter {
+            min_total: Some(Currency::str("2.0")),
+            ..AccountFilter::default()
+        };
+        assert_eq!(vec![1, 3], matching(&min_total));
+    }
+
+    #[test]
+    fn it_should_sort_by_the_key_and_break_ties_by_client() {
+        assert_eq!(Some(AccountSort::Total), AccountSort::parse("total"));
+        assert_eq!(Some(AccountSort::Held), AccountSort::parse("held"));
+        assert_eq!(Some(AccountSort::Client), AccountSort::parse("client"));
+        assert_eq!(None, AccountSort::parse("available"));
+
+        let mut accounts = [
+            account(3, "1.0", "4.0", false),
+            account(1, "5.0", "0.0", false),
+            account(2, "9.0", "1.0", false),
+        ];
+        let order = |accounts: &[ClientProfile]| -> Vec<u16> {
+            accounts.iter().map(|client| client.id()).collect()
+        };
+        accounts.sort_by(|a, b| AccountSort::Client.compare(a, b));
+        assert_eq!(vec![1, 2, 3], order(&accounts));
+        accounts.sort_by(|a, b| AccountSort::Total.compare(a, b));
+        assert_eq!(vec![2, 1, 3], order(&accounts));
+        accounts.sort_by(|a, b| AccountSort::Held.compare(a, b));
+        assert_eq!(vec![3, 2, 1], order(&accounts));
+    }
+}
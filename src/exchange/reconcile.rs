@@ -0,0 +1,327 @@
+use std::collections::BTreeMap;
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use crate::exchange::pseudonym::ClientIds;
+use crate::exchange::transaction::ClientId;
+use crate::exchange::transaction::Currency;
+use crate::exchange::transaction::TransactionId;
+use crate::exchange::transaction::Type;
+use crate::exchange::Exchange;
+use crate::exchange::IngestOptions;
+use crate::exchange::RecordReader;
+
+pub const HEADER: &str = "tx,kind,client,type,amount,feed_client,feed_type,feed_amount";
+
+/// How a transaction of the verified feed disagrees with the stored ones
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum DiscrepancyKind {
+    /// Stored, but not in the feed
+    Missing,
+    /// In the feed, but not stored, e.g. rejected by the engine or never received from the first feed
+    Extra,
+    /// In the feed more than once, every row after the first one
+    Duplicate,
+    /// Stored with another client, type or amount than the feed has
+    Mismatch,
+}
+
+impl fmt::Display for DiscrepancyKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            DiscrepancyKind::Missing => "missing",
+            DiscrepancyKind::Extra => "extra",
+            DiscrepancyKind::Duplicate => "duplicate",
+            DiscrepancyKind::Mismatch => "mismatch",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// The fields of a transaction that are compared
+#[derive(Debug, PartialEq, Clone)]
+pub struct Record {
+    pub client: ClientId,
+    pub tx_type: Type,
+    pub amount: Option<Currency>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Discrepancy {
+    pub tx: TransactionId,
+    pub kind: DiscrepancyKind,
+    /// None for extra and duplicate rows
+    pub stored: Option<Record>,
+    /// None for missing transactions
+    pub feed: Option<Record>,
+}
+
+/// Outcome of reconcile_transactions_from_csv(), the discrepancies ordered by tx id
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct Reconciliation {
+    /// Deposits and withdrawals read from the feed
+    pub records: u64,
+    pub matched: u64,
+    /// Rows of the feed that are not compared: disputes, resolves, chargebacks and custom types
+    pub skipped: u64,
+    pub discrepancies: Vec<Discrepancy>,
+}
+
+impl Reconciliation {
+    pub fn count(&self, kind: DiscrepancyKind) -> usize {
+        self.discrepancies
+            .iter()
+            .filter(|discrepancy| discrepancy.kind == kind)
+            .count()
+    }
+}
+
+impl fmt::Display for Reconciliation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Reconciled {} records: {} matched, {} missing, {} extra, {} duplicate, {} mismatching, {} skipped",
+            self.records,
+            self.matched,
+            self.count(DiscrepancyKind::Missing),
+            self.count(DiscrepancyKind::Extra),
+            self.count(DiscrepancyKind::Duplicate),
+            self.count(DiscrepancyKind::Mismatch),
+            self.skipped
+        )
+    }
+}
+
+/// Verify a second feed of the same transactions against the deposits and withdrawals bank stores, without applying it: the
+/// records are read as process_transactions_from_csv_with() reads them, enriched and rescaled like the submitted ones, and
+/// matched by tx id on the client, type and amount. Only the transactions still retained can be matched, so the bank must retain them
+/// (EngineConfig::retain_transactions) and have no archive, whose clients no longer keep theirs. Disputes, resolves and
+/// chargebacks change no stored transaction and are skipped; a record that can not be read fails the reconciliation
+pub fn reconcile_transactions_from_csv(
+    path: &str,
+    bank: &Exchange,
+    options: &IngestOptions,
+) -> Result<Reconciliation, Box<dyn Error>> {
+    if !bank.config.retain_transactions || bank.archive.is_some() {
+        return Err(
+            "Reconciling needs the transactions retained, without --no-retention or an archive"
+                .into(),
+        );
+    }
+    let mut stored: BTreeMap<TransactionId, Record> = bank
+        .clients
+        .values()
+        .flat_map(|client| {
+            client
+                .transaction_ids()
+                .filter_map(|tx| client.transaction(*tx))
+        })
+        .filter(|t| matches!(t.tx_type, Type::Deposit | Type::Withdrawal))
+        .map(|t| {
+            (
+                t.tx,
+                Record {
+                    client: t.client,
+                    tx_type: t.tx_type.clone(),
+                    amount: t.amount,
+                },
+            )
+        })
+        .collect();
+
+    let mut reader = csv::Reader::from_path(path)?;
+    let headers = reader.headers()?.clone();
+    let ingest = RecordReader::new(&headers, options);
+    let mut reconciliation = Reconciliation::default();
+    let mut discrepancies: BTreeMap<(TransactionId, usize), Discrepancy> = BTreeMap::new();
+    let mut seen = HashSet::new();
+    let mut record = csv::StringRecord::new();
+    while reader.read_record(&mut record)? {
+        let mut t = bank.enricher.enrich(ingest.parse(bank, &record)?);
+        if !matches!(t.tx_type, Type::Deposit | Type::Withdrawal) {
+            reconciliation.skipped += 1;
+            continue;
+        }
+        reconciliation.records += 1;
+        if let Some(amount) = &mut t.amount {
+            amount.rescale(bank.config.amount_scale);
+        }
+        let feed = Record {
+            client: t.client,
+            tx_type: t.tx_type,
+            amount: t.amount,
+        };
+        let (kind, stored) = if !seen.insert(t.tx) {
+            (DiscrepancyKind::Duplicate, None)
+        } else {
+            match stored.remove(&t.tx) {
+                Some(stored) if stored == feed => {
+                    reconciliation.matched += 1;
+                    continue;
+                }
+                Some(stored) => (DiscrepancyKind::Mismatch, Some(stored)),
+                None => (DiscrepancyKind::Extra, None),
+            }
+        };
+        discrepancies.insert(
+            (t.tx, discrepancies.len()),
+            Discrepancy {
+                tx: t.tx,
+                kind,
+                stored,
+                feed: Some(feed),
+            },
+        );
+    }
+    for (tx, stored) in stored {
+        discrepancies.insert(
+            (tx, discrepancies.len()),
+            Discrepancy {
+                tx,
+                kind: DiscrepancyKind::Missing,
+                stored: Some(stored),
+                feed: None,
+            },
+        );
+    }
+    reconciliation.discrepancies = discrepancies.into_values().collect();
+    Ok(reconciliation)
+}
+
+pub fn write_reconciliation<W: io::Write>(
+    mut writer: W,
+    discrepancies: &[Discrepancy],
+    ids: &ClientIds,
+) -> io::Result<()> {
+    let record = |record: &Option<Record>| match record {
+        Some(record) => format!(
+            "{},{},{}",
+            ids.label(record.client),
+            record.tx_type,
+            record
+                .amount
+                .map(|amount| format!("{:.4}", amount))
+                .unwrap_or_default()
+        ),
+        None => ",,".to_string(),
+    };
+    writeln!(writer, "{}", HEADER)?;
+    for discrepancy in discrepancies {
+        writeln!(
+            writer,
+            "{},{},{},{}",
+            discrepancy.tx,
+            discrepancy.kind,
+            record(&discrepancy.stored),
+            record(&discrepancy.feed)
+        )?;
+    }
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use crate::exchange::enrichment::MappingEnricher;
+
+    #[test]
+    fn it_should_report_the_transactions_the_feeds_disagree_on() {
+        let dir = std::env::temp_dir().join("payment_engine_reconcile");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let (first, second) = (dir.join("first.csv"), dir.join("second.csv"));
+        std::fs::write(
+            &first,
+            "type,client,tx,amount\n\
+             deposit,1,1,10.0\n\
+             deposit,2,2,5.0\n\
+             withdrawal,1,3,4.0\n\
+             deposit,2,4,1.0\n\
+             dispute,2,2,\n",
+        )
+        .unwrap();
+        std::fs::write(
+            &second,
+            "type,client,tx,amount\n\
+             deposit,1,1,10.00\n\
+             deposit,2,2,5.5\n\
+             dispute,2,2,\n\
+             deposit,3,5,2.0\n\
+             deposit,1,1,10.0\n\
+             withdrawal,1,3,4.0\n",
+        )
+        .unwrap();
+        let mut bank = Exchange::new();
+        crate::exchange::process_transactions_from_csv(first.to_str().unwrap(), &mut bank).unwrap();
+        let before: Vec<_> = [1, 2].map(|id| bank.client(id).cloned()).to_vec();
+
+        let options = IngestOptions {
+            quiet: true,
+            ..IngestOptions::default()
+        };
+        let reconciliation =
+            reconcile_transactions_from_csv(second.to_str().unwrap(), &bank, &options).unwrap();
+        assert_eq!(before, [1, 2].map(|id| bank.client(id).cloned()).to_vec());
+        assert_eq!(
+            "Reconciled 5 records: 2 matched, 1 missing, 1 extra, 1 duplicate, 1 mismatching, 1 skipped",
+            reconciliation.to_string()
+        );
+
+        let mut written = Vec::new();
+        write_reconciliation(&mut written, &reconciliation.discrepancies, &ClientIds::Raw).unwrap();
+        assert_eq!(
+            "tx,kind,client,type,amount,feed_client,feed_type,feed_amount\n\
+             1,duplicate,,,,1,deposit,10.0000\n\
+             2,mismatch,2,deposit,5.0000,2,deposit,5.5000\n\
+             4,missing,2,deposit,1.0000,,,\n\
+             5,extra,,,,3,deposit,2.0000\n",
+            String::from_utf8(written).unwrap()
+        );
+
+        bank.config.retain_transactions = false;
+        assert_eq!(
+            true,
+            reconcile_transactions_from_csv(second.to_str().unwrap(), &bank, &options).is_err()
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn it_should_enrich_the_feed_before_matching_it() {
+        let dir = std::env::temp_dir().join("payment_engine_reconcile_enriched");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let feed = dir.join("feed.csv");
+        //external client and tx ids, which the enricher maps to the ones the engine uses
+        std::fs::write(
+            &feed,
+            "type,client,tx,amount\n\
+             deposit,100,500,10.0\n\
+             withdrawal,100,501,4.0\n",
+        )
+        .unwrap();
+        let enricher = MappingEnricher::from_reader(
+            "kind,from,to\nclient,100,1\ntx,500,1\ntx,501,2\n".as_bytes(),
+        )
+        .unwrap();
+        let options = IngestOptions {
+            quiet: true,
+            ..IngestOptions::default()
+        };
+        let mut bank = Exchange::new().with_enricher(enricher);
+        crate::exchange::process_transactions_from_csv(feed.to_str().unwrap(), &mut bank).unwrap();
+        assert_eq!(true, bank.transaction(1, 2).is_some());
+
+        let reconciliation =
+            reconcile_transactions_from_csv(feed.to_str().unwrap(), &bank, &options).unwrap();
+        assert_eq!(
+            "Reconciled 2 records: 2 matched, 0 missing, 0 extra, 0 duplicate, 0 mismatching, 0 skipped",
+            reconciliation.to_string()
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,222 @@
+use std::error::Error;
+use std::fs::File;
+use std::io;
+use std::io::BufRead;
+
+use crate::exchange::quarantine::Quarantine;
+use crate::exchange::Exchange;
+use crate::exchange::IngestOptions;
+use crate::exchange::RecordReader;
+
+/// Bytes [start, end) of the input skipped by the recovery, spanning lines in a row that could not be read, with the reason
+/// the first of them could not
+#[derive(Debug, PartialEq, Clone)]
+pub struct SkippedRange {
+    pub start: u64,
+    pub end: u64,
+    pub lines: u64,
+    pub error: String,
+}
+
+/// The record of a single line of the input, which must hold as many fields as the header
+fn record(line: &[u8], fields: usize) -> Result<Option<csv::StringRecord>, String> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .from_reader(line);
+    let mut record = csv::ByteRecord::new();
+    //a blank line, skipped as the reader of the input would
+    if !reader
+        .read_byte_record(&mut record)
+        .map_err(|e| e.to_string())?
+    {
+        return Ok(None);
+    }
+    if record.len() != fields {
+        return Err(format!(
+            "found record with {} fields, but the header has {} fields",
+            record.len(),
+            fields
+        ));
+    }
+    csv::StringRecord::from_byte_record(record)
+        .map(Some)
+        .map_err(|e| e.to_string())
+}
+
+/// process_transactions_from_csv_with() for inputs with corrupted regions (truncated lines, binary garbage): the input is read
+/// line by line, and a line that is not a record of the header's schema (invalid UTF-8, wrong number of fields, fields that do
+/// not parse) is skipped instead of failing the input, the reading carrying on at the next line. The lines skipped in a row
+/// make up one SkippedRange, logged unless the options are quiet and returned, and with a quarantine their bytes are
+/// copied there. Each line skipped is counted in Stats::malformed. Lines are record boundaries here: a quoted field spanning
+/// several lines is corruption too. Records that are read fail the input as they would otherwise, e.g. on a limit exceeded
+/// under LimitPolicy::Abort, and the header must be readable
+pub fn recover_transactions_from_csv(
+    path: &str,
+    bank: &mut Exchange,
+    options: &IngestOptions,
+) -> Result<Vec<SkippedRange>, Box<dyn Error>> {
+    let mut input = io::BufReader::new(File::open(path)?);
+    let mut line = Vec::new();
+    let mut offset = input.read_until(b'\n', &mut line)? as u64;
+    let headers = csv::Reader::from_reader(line.as_slice()).headers()?.clone();
+    let ingest = RecordReader::new(&headers, options);
+
+    let mut skipped: Vec<SkippedRange> = Vec::new();
+    let mut skipping = false;
+    loop {
+        line.clear();
+        let read = input.read_until(b'\n', &mut line)? as u64;
+        if read == 0 {
+            break;
+        }
+        let parsed = record(&line, headers.len()).and_then(|record| match record {
+            Some(record) => ingest
+                .parse(bank, &record)
+                .map(|_| Some(record))
+                .map_err(|e| e.to_string()),
+            None => Ok(None),
+        });
+        match parsed {
+            Ok(record) => {
+                skipping = false;
+                if let Some(record) = record {
+                    ingest.ingest(bank, &record)?;
+                }
+            }
+            Err(error) => {
+                bank.stats.malformed += 1;
+                match skipped.last_mut() {
+                    Some(range) if skipping => {
+                        range.end += read;
+                        range.lines += 1;
+                    }
+                    _ => skipped.push(SkippedRange {
+                        start: offset,
+                        end: offset + read,
+                        lines: 1,
+                        error,
+                    }),
+                }
+                skipping = true;
+            }
+        }
+        offset += read;
+    }
+
+    if let Some(quarantine_path) = &options.quarantine {
+        let mut quarantine = Quarantine::create(path, quarantine_path)?;
+        for range in &skipped {
+            quarantine.record(range.start, range.end, &range.error)?;
+        }
+        quarantine.flush()?;
+    }
+    if !options.quiet {
+        for range in &skipped {
+            eprintln!(
+                "Skipped bytes {}..{} ({} lines) of {}: {}",
+                range.start, range.end, range.lines, path, range.error
+            );
+        }
+    }
+    Ok(skipped)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use crate::exchange::transaction::Currency;
+    use crate::exchange::transaction::Money;
+
+    #[test]
+    fn it_should_skip_the_corrupted_regions_and_carry_on() {
+        let input = std::env::temp_dir().join("payment_engine_recovery.csv");
+        let mut rows = b"type,client,tx,amount\n\
+                         deposit,1,1,10.0\n\
+                         deposit,1,2"
+            .to_vec();
+        //truncated line followed by binary garbage
+        rows.extend_from_slice(b"\n\xff\xfe\x00garbage,\"unbalanced\n");
+        rows.extend_from_slice(b"withdrawal,1,3,4.0\n\ndeposit,x,4,1.0\ndeposit,2,5,1.0\n");
+        std::fs::write(&input, &rows).unwrap();
+
+        let mut exchange = Exchange::new();
+        let options = IngestOptions {
+            quiet: true,
+            ..IngestOptions::default()
+        };
+        assert_eq!(
+            true,
+            crate::exchange::process_transactions_from_csv_with(
+                input.to_str().unwrap(),
+                &mut Exchange::new(),
+                &options
+            )
+            .is_err()
+        );
+        let skipped =
+            recover_transactions_from_csv(input.to_str().unwrap(), &mut exchange, &options)
+                .unwrap();
+        let ranges: Vec<(u64, u64, u64)> = skipped
+            .iter()
+            .map(|range| (range.start, range.end, range.lines))
+            .collect();
+        assert_eq!(vec![(39, 74, 2), (94, 110, 1)], ranges);
+        assert_eq!(b"deposit,1,2\n", &rows[39..51]);
+        assert_eq!(b"deposit,x,4,1.0\n", &rows[94..110]);
+        assert_eq!(
+            Currency::str("6.0"),
+            exchange.client(1).unwrap().available()
+        );
+        assert_eq!(
+            Currency::str("1.0"),
+            exchange.client(2).unwrap().available()
+        );
+        assert_eq!(3, exchange.stats().malformed());
+        std::fs::remove_file(&input).unwrap();
+    }
+
+    #[test]
+    fn it_should_quarantine_the_skipped_ranges_of_crlf_inputs() {
+        let dir = std::env::temp_dir().join("payment_engine_recovery_quarantine");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let (input, quarantine) = (dir.join("input.csv"), dir.join("quarantine.txt"));
+        //the last record has no line break
+        let rows = b"type,client,tx,amount\r\n\
+                     deposit,1,1,10.0\r\n\
+                     deposit,1\r\n\
+                     deposit,1,2,2.5";
+        std::fs::write(&input, rows).unwrap();
+
+        let mut exchange = Exchange::new();
+        let options = IngestOptions {
+            quiet: true,
+            quarantine: Some(quarantine.clone()),
+            ..IngestOptions::default()
+        };
+        let skipped =
+            recover_transactions_from_csv(input.to_str().unwrap(), &mut exchange, &options)
+                .unwrap();
+        assert_eq!(
+            vec![SkippedRange {
+                start: 41,
+                end: 52,
+                lines: 1,
+                error: "found record with 2 fields, but the header has 4 fields".to_string(),
+            }],
+            skipped
+        );
+        assert_eq!(
+            Currency::str("12.5"),
+            exchange.client(1).unwrap().available()
+        );
+        assert_eq!(
+            "offset=41 length=11 error=found record with 2 fields, but the header has 4 fields\n\
+             deposit,1\r\n\n",
+            std::fs::read_to_string(&quarantine).unwrap()
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,292 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use crate::exchange::transaction::ClientId;
+
+/// A client id as partner feeds send it, wider than ClientId
+pub type ExternalId = u64;
+
+/// Internal ids there are to hand out
+pub const CAPACITY: usize = ClientId::MAX as usize + 1;
+
+/// Internal ids assigned to the external client ids of the input, in order of appearance, so feeds with ids beyond ClientId
+/// are processed without widening it. The mapping is persisted in a CSV file (external,internal) with one row appended per
+/// assignment, so checkpoints, ledgers and later runs given the same file keep meaning the same clients
+#[derive(Debug)]
+pub struct ClientMapping {
+    internal: HashMap<ExternalId, ClientId>,
+    /// External id of every internal id, by internal id
+    external: Vec<ExternalId>,
+    file: Option<File>,
+}
+
+impl ClientMapping {
+    /// A mapping kept in memory only
+    pub fn new() -> ClientMapping {
+        ClientMapping {
+            internal: HashMap::new(),
+            external: Vec::new(),
+            file: None,
+        }
+    }
+
+    /// Load the mapping file, empty when it does not exist yet. Its rows must assign the internal ids in order
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<ClientMapping> {
+        let mut mapping = ClientMapping::new();
+        match File::open(&path) {
+            Ok(file) => {
+                for (row, record) in csv::Reader::from_reader(file).records().enumerate() {
+                    let record = record?;
+                    if row >= CAPACITY {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!(
+                                "Client mapping row {} is beyond the {} internal ids",
+                                row + 1,
+                                CAPACITY
+                            ),
+                        ));
+                    }
+                    let field = |index| record.get(index).map(str::trim).unwrap_or_default();
+                    match (field(0).parse(), field(1).parse::<usize>()) {
+                        (Ok(external), Ok(internal))
+                            if internal == row && !mapping.internal.contains_key(&external) =>
+                        {
+                            mapping.insert(external)?
+                        }
+                        _ => {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                format!("Invalid client mapping row {}: {:?}", row + 1, record),
+                            ))
+                        }
+                    };
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+        if file.metadata()?.len() == 0 {
+            writeln!(file, "external,internal")?;
+        }
+        mapping.file = Some(file);
+        Ok(mapping)
+    }
+
+    /// The internal id the external one would be given next
+    fn next(&self, external: ExternalId) -> io::Result<ClientId> {
+        ClientId::try_from(self.external.len()).map_err(|_| {
+            io::Error::other(format!(
+                "No internal id left for client {}, all {} are assigned",
+                external, CAPACITY
+            ))
+        })
+    }
+
+    fn insert(&mut self, external: ExternalId) -> io::Result<ClientId> {
+        let client = self.next(external)?;
+        self.internal.insert(external, client);
+        self.external.push(external);
+        Ok(client)
+    }
+
+    /// The internal id of the external one, assigned and persisted on its first appearance. Fails once all the internal ids
+    /// are taken
+    pub fn internal(&mut self, external: ExternalId) -> io::Result<ClientId> {
+        if let Some(client) = self.internal.get(&external) {
+            return Ok(*client);
+        }
+        let client = self.next(external)?;
+        if let Some(file) = &mut self.file {
+            writeln!(file, "{},{}", external, client)?;
+        }
+        self.insert(external)
+    }
+
+    pub fn external(&self, client: ClientId) -> Option<ExternalId> {
+        self.external.get(client as usize).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.external.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.external.is_empty()
+    }
+}
+
+impl Default for ClientMapping {
+    fn default() -> Self {
+        ClientMapping::new()
+    }
+}
+
+/// The mapping shared by the readers of the inputs, which assign ids, and the writers of the outputs, which write the external
+/// ones. Clones are the same mapping
+#[derive(Clone, Default)]
+pub struct SharedMapping(Arc<Mutex<ClientMapping>>);
+
+impl SharedMapping {
+    pub fn new(mapping: ClientMapping) -> SharedMapping {
+        SharedMapping(Arc::new(Mutex::new(mapping)))
+    }
+
+    pub fn internal(&self, external: ExternalId) -> io::Result<ClientId> {
+        self.0.lock().unwrap().internal(external)
+    }
+
+    pub fn external(&self, client: ClientId) -> Option<ExternalId> {
+        self.0.lock().unwrap().external(client)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl PartialEq for SharedMapping {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl fmt::Debug for SharedMapping {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SharedMapping({} clients)", self.len())
+    }
+}
+
+/// The record with its client column (at index) replaced by the internal id of the external one
+pub(crate) fn remap_record(
+    mapping: &SharedMapping,
+    record: &csv::StringRecord,
+    index: usize,
+) -> Result<csv::StringRecord, Box<dyn std::error::Error>> {
+    let mut remapped = csv::StringRecord::with_capacity(record.as_slice().len(), record.len());
+    for (position, field) in record.iter().enumerate() {
+        if position != index {
+            remapped.push_field(field);
+            continue;
+        }
+        let external: ExternalId = field
+            .trim()
+            .parse()
+            .map_err(|_| format!("Invalid client id '{}' in record {:?}", field, record))?;
+        remapped.push_field(&mapping.internal(external)?.to_string());
+    }
+    remapped.set_position(record.position().cloned());
+    Ok(remapped)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use std::fs;
+
+    use crate::exchange::config::EngineConfig;
+    use crate::exchange::output::AmountFormat;
+    use crate::exchange::pseudonym::ClientIds;
+    use crate::exchange::Exchange;
+
+    #[test]
+    fn it_should_process_client_ids_beyond_the_internal_width_and_write_them_back() {
+        let dir = std::env::temp_dir().join("payment_engine_remap");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("input.csv");
+        fs::write(
+            &input,
+            "type,client,tx,amount\ndeposit,4000000000,1,10.0\ndeposit,70000,2,5.0\nwithdrawal,4000000000,3,2.5\n",
+        )
+        .unwrap();
+        let path = dir.join("mapping.csv");
+        let run = || {
+            let mapping = SharedMapping::new(ClientMapping::open(&path).unwrap());
+            let mut exchange = Exchange::new().with_config(EngineConfig {
+                client_ids: ClientIds::Mapped(mapping.clone()),
+                ..Default::default()
+            });
+            crate::exchange::process_transactions_from_csv(input.to_str().unwrap(), &mut exchange)
+                .unwrap();
+            let mut output = Vec::new();
+            exchange
+                .to_writer(&mut output, &AmountFormat::Plain)
+                .unwrap();
+            let mut rows: Vec<String> = String::from_utf8(output)
+                .unwrap()
+                .lines()
+                .map(str::to_string)
+                .collect();
+            rows.sort();
+            (exchange, mapping, rows)
+        };
+
+        let (exchange, mapping, rows) = run();
+        assert_eq!(true, exchange.client(0).is_some());
+        assert_eq!(Some(70000), mapping.external(1));
+        assert_eq!(
+            vec![
+                "4000000000,7.5000,0.0000,7.5000,false",
+                "70000,5.0000,0.0000,5.0000,false",
+                "client,available,held,total,locked"
+            ],
+            rows
+        );
+        assert_eq!(
+            "external,internal\n4000000000,0\n70000,1\n",
+            fs::read_to_string(&path).unwrap()
+        );
+
+        //a later run keeps the ids it was given
+        let (_, mapping, again) = run();
+        assert_eq!(2, mapping.len());
+        assert_eq!(rows, again);
+        assert_eq!(
+            "external,internal\n4000000000,0\n70000,1\n",
+            fs::read_to_string(&path).unwrap()
+        );
+
+        let mut full = ClientMapping::new();
+        for external in 0..CAPACITY as ExternalId {
+            full.internal(external + 1_000_000).unwrap();
+        }
+        assert_eq!(true, full.internal(1).is_err());
+        assert_eq!(Some(1_000_000), full.external(0));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn it_should_refuse_mapping_files_beyond_the_internal_ids() {
+        let dir = std::env::temp_dir().join("payment_engine_remap_capacity");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("mapping.csv");
+        let mut rows = String::from("external,internal\n");
+        for internal in 0..CAPACITY {
+            rows.push_str(&format!("{},{}\n", internal + 1_000_000, internal));
+        }
+        fs::write(&path, &rows).unwrap();
+        assert_eq!(CAPACITY, ClientMapping::open(&path).unwrap().len());
+
+        rows.push_str(&format!("{},{}\n", 1, CAPACITY));
+        fs::write(&path, &rows).unwrap();
+        let error = ClientMapping::open(&path).err().unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
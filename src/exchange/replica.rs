@@ -0,0 +1,202 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::error::Error;
+
+use crate::exchange::checkpoint::InputPosition;
+use crate::exchange::follow::tail_csv;
+use crate::exchange::follow::FollowOptions;
+use crate::exchange::transaction::ClientId;
+use crate::exchange::transaction::Currency;
+use crate::exchange::transaction::Transaction;
+use crate::exchange::transaction::TransactionId;
+use crate::exchange::transaction::Type;
+use crate::exchange::Exchange;
+
+/// A row of a ledger stream, see ledger::LedgerStream
+#[derive(Debug, Deserialize)]
+struct StreamedEntry {
+    seq: u64,
+    #[serde(rename = "type")]
+    tx_type: Type,
+    client: ClientId,
+    tx: TransactionId,
+    amount: Option<Currency>,
+    status: String,
+    metadata: String,
+}
+
+impl StreamedEntry {
+    fn transaction(self) -> Transaction {
+        Transaction {
+            tx_type: self.tx_type,
+            client: self.client,
+            tx: self.tx,
+            amount: self.amount,
+            metadata: self
+                .metadata
+                .split(';')
+                .filter_map(|pair| pair.split_once('='))
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect::<HashMap<_, _>>(),
+            under_dispute: false,
+            dispute_metadata: None,
+        }
+    }
+}
+
+/// Keep bank a read-only copy of the exchange writing the ledger stream at path, for queries and reports to be served without
+/// touching the ingesting process: every entry appended to the stream is applied again, like `tail -f` (see
+/// follow::follow_transactions_from_csv), with on_snapshot given the exchange, the transactions submitted at the previous
+/// snapshot and the position in the stream every FollowOptions::snapshot_every, following stops when it returns false.
+/// The stream holds the transactions as the primary settled them, already enriched, de-duplicated and undeferred, so the
+/// de-duplication window and the deferral of bank are turned off; its other settings must be the primary's for the outcomes to
+/// agree. An entry applied on one side and rejected on the other is logged as a divergence. The stream does not carry the
+/// dispute metadata, nor run the custom handlers of the primary
+pub fn follow_ledger(
+    path: &str,
+    bank: &mut Exchange,
+    follow: &FollowOptions,
+    on_snapshot: impl FnMut(&Exchange, u64, InputPosition) -> bool,
+) -> Result<(), Box<dyn Error>> {
+    bank.config.dedup_window = None;
+    bank.config.defer_disputes = None;
+    let mut headers: Option<csv::StringRecord> = None;
+    tail_csv(path, bank, follow, on_snapshot, |bank, record| {
+        let Some(headers) = &headers else {
+            headers = Some(record);
+            return Ok(());
+        };
+        let entry: StreamedEntry = record.deserialize(Some(headers))?;
+        let (seq, applied) = (entry.seq, entry.status == "applied");
+        if bank.process_new_transaction(entry.transaction()).is_ok() != applied {
+            eprintln!(
+                "Replica diverged from the primary at entry {}: {} there",
+                seq,
+                if applied { "applied" } else { "rejected" }
+            );
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use std::time::Duration;
+
+    use crate::exchange::config::EngineConfig;
+    use crate::exchange::dedup::DedupWindow;
+    use crate::exchange::deferral::DeferralWindow;
+    use crate::exchange::ledger::LedgerStream;
+    use crate::exchange::transaction::Money;
+
+    #[test]
+    fn it_should_replay_the_ledger_stream_of_the_primary() {
+        let dir = std::env::temp_dir().join("payment_engine_replica");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let (input, stream) = (dir.join("input.csv"), dir.join("ledger.csv"));
+        std::fs::write(
+            &input,
+            "type,client,tx,amount\n\
+             deposit,1,1,10.0\n\
+             deposit,2,2,5.0\n\
+             dispute,1,1,\n\
+             withdrawal,2,3,8.0\n\
+             chargeback,1,1,\n",
+        )
+        .unwrap();
+        let mut primary = Exchange::new();
+        let events = primary.subscribe();
+        let writer = LedgerStream::create(&stream).unwrap();
+        let written = std::thread::spawn(move || writer.write_all(events).unwrap());
+        crate::exchange::process_transactions_from_csv(input.to_str().unwrap(), &mut primary)
+            .unwrap();
+        let accounts: Vec<_> = [1, 2].map(|id| primary.client(id).cloned()).to_vec();
+        drop(primary);
+        assert_eq!(5, written.join().unwrap());
+
+        let mut replica = Exchange::new();
+        follow_ledger(
+            stream.to_str().unwrap(),
+            &mut replica,
+            &FollowOptions {
+                poll: Duration::from_millis(1),
+                snapshot_every: Duration::from_millis(50),
+            },
+            |replica, _, position| {
+                assert_eq!(6, position.records);
+                assert_eq!(5, replica.submitted());
+                false
+            },
+        )
+        .unwrap();
+        assert_eq!(accounts, [1, 2].map(|id| replica.client(id).cloned()).to_vec());
+        assert_eq!(true, replica.client(1).unwrap().locked());
+        assert_eq!(Currency::str("5.0"), replica.client(2).unwrap().available());
+        assert_eq!(1, replica.stats().rejected());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn it_should_carry_on_past_the_entries_it_diverges_on() {
+        let stream = std::env::temp_dir().join("payment_engine_replica_divergence.csv");
+        std::fs::write(
+            &stream,
+            "seq,type,client,tx,amount,status,reason,metadata\n\
+             1,deposit,1,1,10.0000,applied,,channel=web;region=eu\n\
+             2,withdrawal,1,2,50.0000,applied,,\n\
+             3,deposit,1,3,1.0000,rejected,Client limit of 1 reached,\n\
+             4,deposit,1,4,1.0000,applied,,\n",
+        )
+        .unwrap();
+        let mut replica = Exchange::new().with_config(EngineConfig {
+            dedup_window: DedupWindow::parse("10"),
+            defer_disputes: DeferralWindow::parse("10"),
+            ..EngineConfig::default()
+        });
+        follow_ledger(
+            stream.to_str().unwrap(),
+            &mut replica,
+            &FollowOptions {
+                poll: Duration::from_millis(1),
+                snapshot_every: Duration::from_millis(50),
+            },
+            |_, _, _| false,
+        )
+        .unwrap();
+
+        //the withdrawal rejected here, the deposit of entry 3 applied
+        assert_eq!(
+            Currency::str("12.0"),
+            replica.client(1).unwrap().available()
+        );
+        assert_eq!(4, replica.submitted());
+        assert_eq!(1, replica.stats().rejected());
+        assert_eq!(None, replica.config.dedup_window);
+        assert_eq!(None, replica.config.defer_disputes);
+        std::fs::remove_file(&stream).unwrap();
+    }
+
+    #[test]
+    fn it_should_read_back_the_metadata_of_the_streamed_entries() {
+        let entry = StreamedEntry {
+            seq: 1,
+            tx_type: Type::Deposit,
+            client: 1,
+            tx: 1,
+            amount: Some(Currency::str("1.0")),
+            status: "applied".to_string(),
+            metadata: "channel=web;note=a=b;broken".to_string(),
+        };
+        assert_eq!(
+            HashMap::from([
+                ("channel".to_string(), "web".to_string()),
+                ("note".to_string(), "a=b".to_string())
+            ]),
+            entry.transaction().metadata
+        );
+    }
+}
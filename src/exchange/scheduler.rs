@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+
+use crate::exchange::client_profile::ProcessingError;
+use crate::exchange::transaction::ClientId;
+use crate::exchange::transaction::Currency;
+use crate::exchange::transaction::Money;
+use crate::exchange::transaction::Transaction;
+use crate::exchange::transaction::TransactionId;
+use crate::exchange::transaction::Type;
+use crate::exchange::Exchange;
+
+/// The scheduler has no notion of wall clock: timestamps are whatever unit the caller advances it with (seconds by convention)
+pub type Timestamp = u64;
+
+pub const DAY: Timestamp = 24 * 60 * 60;
+pub const WEEK: Timestamp = 7 * DAY;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Instruction {
+    /// Withdraw every available fund above the threshold
+    Sweep {
+        threshold: Currency,
+    },
+    Deposit(Currency),
+    Withdrawal(Currency),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct RecurringInstruction {
+    client: ClientId,
+    instruction: Instruction,
+    next_run: Timestamp,
+    every: Timestamp,
+}
+
+/// Standing orders registered per client and materialized into real transactions when the clock moves forward.
+/// Materialized transactions get ids from a dedicated range starting at first_tx, which must not overlap with the input's tx ids
+pub struct Scheduler {
+    now: Timestamp,
+    next_tx: TransactionId,
+    instructions: Vec<RecurringInstruction>,
+}
+
+impl Scheduler {
+    pub fn new(now: Timestamp, first_tx: TransactionId) -> Scheduler {
+        Scheduler {
+            now,
+            next_tx: first_tx,
+            instructions: Vec::new(),
+        }
+    }
+
+    pub fn now(&self) -> Timestamp {
+        self.now
+    }
+
+    /// Run the instruction at first_run and then every `every` time units. An instruction with every == 0 only runs once
+    pub fn register(
+        &mut self,
+        client: ClientId,
+        instruction: Instruction,
+        first_run: Timestamp,
+        every: Timestamp,
+    ) {
+        self.instructions.push(RecurringInstruction {
+            client,
+            instruction,
+            next_run: first_run,
+            every,
+        });
+    }
+
+    pub fn instructions(&self) -> &[RecurringInstruction] {
+        &self.instructions
+    }
+
+    /// Advance the clock by one time unit
+    pub fn tick(&mut self, exchange: &mut Exchange) -> Vec<Result<Transaction, ProcessingError>> {
+        self.advance_to(self.now + 1, exchange)
+    }
+
+    /// Materialize every run due up to (and including) timestamp, in chronological order, and apply them to the exchange.
+    /// An instruction that was due several times since the last advance runs once per missed period.
+    /// Returns the outcome of every materialized transaction; sweeps with nothing above the threshold produce no transaction
+    pub fn advance_to(
+        &mut self,
+        timestamp: Timestamp,
+        exchange: &mut Exchange,
+    ) -> Vec<Result<Transaction, ProcessingError>> {
+        let mut outcomes = Vec::new();
+        if timestamp < self.now {
+            return outcomes;
+        }
+
+        //min_by_key returns the first minimum, so instructions due at the same time run in registration order
+        while let Some(index) = self
+            .instructions
+            .iter()
+            .enumerate()
+            .filter(|(_, i)| i.next_run <= timestamp)
+            .min_by_key(|(_, i)| i.next_run)
+            .map(|(index, _)| index)
+        {
+            if let Some(transaction) = self.materialize(index, exchange) {
+                outcomes.push(
+                    exchange
+                        .process_new_transaction(transaction.clone())
+                        .map(|_| transaction),
+                );
+            }
+
+            let instruction = &mut self.instructions[index];
+            if instruction.every == 0 {
+                self.instructions.remove(index);
+            } else {
+                instruction.next_run += instruction.every;
+            }
+        }
+
+        self.now = timestamp;
+        outcomes
+    }
+
+    fn materialize(&mut self, index: usize, exchange: &Exchange) -> Option<Transaction> {
+        let instruction = &self.instructions[index];
+        let (tx_type, amount) = match &instruction.instruction {
+            Instruction::Deposit(amount) => (Type::Deposit, *amount),
+            Instruction::Withdrawal(amount) => (Type::Withdrawal, *amount),
+            Instruction::Sweep { threshold } => {
+                let available = exchange.client(instruction.client)?.available();
+                let above_threshold = available - *threshold;
+                if above_threshold <= Currency::zero() {
+                    return None;
+                }
+                (Type::Withdrawal, above_threshold)
+            }
+        };
+
+        let transaction = Transaction {
+            tx_type,
+            client: instruction.client,
+            tx: self.next_tx,
+            amount: Some(amount),
+            metadata: HashMap::new(),
+            under_dispute: false,
+            dispute_metadata: None,
+        };
+        self.next_tx += 1;
+        Some(transaction)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn exchange_with_deposit(client: ClientId, amount: &str) -> Exchange {
+        let mut exchange = Exchange::new();
+        exchange
+            .process_new_transaction(Transaction {
+                tx_type: Type::Deposit,
+                client,
+                tx: 1,
+                amount: Some(Currency::str(amount)),
+                metadata: HashMap::new(),
+                under_dispute: false,
+                dispute_metadata: None,
+            })
+            .unwrap_or_default();
+        exchange
+    }
+
+    #[test]
+    fn it_should_sweep_available_funds_above_threshold_every_week() {
+        let mut exchange = exchange_with_deposit(1, "150.0");
+        let mut scheduler = Scheduler::new(0, 1_000_000);
+        scheduler.register(
+            1,
+            Instruction::Sweep {
+                threshold: Currency::str("100.0"),
+            },
+            WEEK,
+            WEEK,
+        );
+
+        assert_eq!(0, scheduler.advance_to(WEEK - 1, &mut exchange).len());
+
+        let outcomes = scheduler.advance_to(WEEK, &mut exchange);
+        assert_eq!(1, outcomes.len());
+        let sweep = outcomes[0].as_ref().unwrap();
+        assert_eq!(Type::Withdrawal, sweep.tx_type);
+        assert_eq!(1_000_000, sweep.tx);
+        assert_eq!(Some(Currency::str("50.0")), sweep.amount);
+        assert_eq!(
+            Currency::str("100.0"),
+            exchange.client(1).unwrap().available()
+        );
+
+        //nothing above the threshold on the second week
+        assert_eq!(0, scheduler.advance_to(2 * WEEK, &mut exchange).len());
+    }
+
+    #[test]
+    fn it_should_run_an_instruction_once_per_missed_period() {
+        let mut exchange = Exchange::new();
+        let mut scheduler = Scheduler::new(0, 500);
+        scheduler.register(2, Instruction::Deposit(Currency::str("10.0")), DAY, DAY);
+
+        let outcomes = scheduler.advance_to(3 * DAY, &mut exchange);
+
+        assert_eq!(3, outcomes.len());
+        assert_eq!(
+            vec![500, 501, 502],
+            outcomes
+                .iter()
+                .map(|o| o.as_ref().unwrap().tx)
+                .collect::<Vec<TransactionId>>()
+        );
+        assert_eq!(
+            Currency::str("30.0"),
+            exchange.client(2).unwrap().available()
+        );
+        assert_eq!(3 * DAY, scheduler.now());
+    }
+
+    #[test]
+    fn it_should_report_rejected_materialized_transactions() {
+        let mut exchange = exchange_with_deposit(1, "5.0");
+        let mut scheduler = Scheduler::new(0, 500);
+        scheduler.register(1, Instruction::Withdrawal(Currency::str("10.0")), 1, 0);
+
+        let outcomes = scheduler.tick(&mut exchange);
+
+        assert_eq!(1, outcomes.len());
+        assert_eq!(true, outcomes[0].is_err());
+        assert_eq!(0, scheduler.instructions().len());
+        assert_eq!(
+            Currency::str("5.0"),
+            exchange.client(1).unwrap().available()
+        );
+    }
+}
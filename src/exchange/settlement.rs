@@ -0,0 +1,253 @@
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+pub use crate::exchange::client_profile::AccountBalance;
+use crate::exchange::pseudonym::ClientIds;
+use crate::exchange::transaction::ClientId;
+use crate::exchange::transaction::Currency;
+use crate::exchange::transaction::Money;
+use crate::exchange::Exchange;
+
+pub const HEADER: &str = "client,opening_total,closing_total,net_movement,to_sweep,held";
+
+/// Balances of the last snapshot, keyed by client. An empty baseline means every client opened with nothing
+pub type Baseline = HashMap<ClientId, AccountBalance>;
+
+pub fn read_baseline<R: io::Read>(reader: R) -> Result<Baseline, Box<dyn Error>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(reader);
+    let mut baseline = Baseline::new();
+    for balance in reader.deserialize() {
+        let balance: AccountBalance = balance?;
+        baseline.insert(balance.client, balance);
+    }
+    Ok(baseline)
+}
+
+/// Settlement instruction for one client, or the aggregate of all of them when client is None
+#[derive(Debug, PartialEq, Clone)]
+pub struct SettlementLine {
+    pub client: Option<ClientId>,
+    pub opening_total: Currency,
+    pub closing_total: Currency,
+    pub net_movement: Currency,
+    /// Available funds that can be moved out: nothing is swept from locked accounts or accounts in deficit
+    pub to_sweep: Currency,
+    /// Funds held pending disputes
+    pub held: Currency,
+}
+
+impl SettlementLine {
+    fn amounts(&self) -> String {
+        format!(
+            ",{:.4},{:.4},{:.4},{:.4},{:.4}",
+            self.opening_total, self.closing_total, self.net_movement, self.to_sweep, self.held
+        )
+    }
+
+    fn add(&mut self, other: &SettlementLine) {
+        self.opening_total += other.opening_total;
+        self.closing_total += other.closing_total;
+        self.net_movement += other.net_movement;
+        self.to_sweep += other.to_sweep;
+        self.held += other.held;
+    }
+}
+
+impl fmt::Display for SettlementLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.client {
+            Some(client) => write!(f, "{}", client)?,
+            None => write!(f, "TOTAL")?,
+        }
+        write!(f, "{}", self.amounts())
+    }
+}
+
+/// Per-client settlement lines ordered by client id, followed by the aggregate line.
+/// Clients only present in the baseline (e.g. merged away since) close with nothing
+pub fn settle(exchange: &Exchange, baseline: &Baseline) -> Vec<SettlementLine> {
+    let mut lines: BTreeMap<ClientId, SettlementLine> = BTreeMap::new();
+
+    for (client, opening) in baseline {
+        lines.insert(
+            *client,
+            SettlementLine {
+                client: Some(*client),
+                opening_total: opening.total,
+                closing_total: Currency::zero(),
+                net_movement: -opening.total,
+                to_sweep: Currency::zero(),
+                held: Currency::zero(),
+            },
+        );
+    }
+
+    for client in exchange.accounts(&Default::default()) {
+        let opening_total = baseline
+            .get(&client.id())
+            .map_or(Currency::zero(), |opening| opening.total);
+        let to_sweep = if client.locked() || client.available() < Currency::zero() {
+            Currency::zero()
+        } else {
+            client.available()
+        };
+        lines.insert(
+            client.id(),
+            SettlementLine {
+                client: Some(client.id()),
+                opening_total,
+                closing_total: client.total(),
+                net_movement: client.total() - opening_total,
+                to_sweep,
+                held: client.held(),
+            },
+        );
+    }
+
+    let mut aggregate = SettlementLine {
+        client: None,
+        opening_total: Currency::zero(),
+        closing_total: Currency::zero(),
+        net_movement: Currency::zero(),
+        to_sweep: Currency::zero(),
+        held: Currency::zero(),
+    };
+    lines.values().for_each(|line| aggregate.add(line));
+
+    let mut lines: Vec<SettlementLine> = lines.into_values().collect();
+    lines.push(aggregate);
+    lines
+}
+
+pub fn write_settlement<W: io::Write>(
+    mut writer: W,
+    lines: &[SettlementLine],
+    ids: &ClientIds,
+) -> io::Result<()> {
+    writeln!(writer, "{}", HEADER)?;
+    for line in lines {
+        match line.client {
+            Some(client) => writeln!(writer, "{}{}", ids.label(client), line.amounts())?,
+            None => writeln!(writer, "{}", line)?,
+        }
+    }
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use crate::exchange::transaction::Transaction;
+    use crate::exchange::transaction::Type;
+
+    #[test]
+    fn it_should_settle_net_movement_since_the_baseline() {
+        let baseline = read_baseline(
+            "client,available,held,total,locked\n1,10.0000,0.0000,10.0000,false\n3,4.0000,0.0000,4.0000,false\n"
+                .as_bytes(),
+        )
+        .unwrap();
+
+        let mut exchange = Exchange::new();
+        for (client, tx, amount) in [(1, 1, "15.0"), (2, 2, "5.0")] {
+            exchange
+                .process_new_transaction(Transaction {
+                    tx_type: Type::Deposit,
+                    client,
+                    tx,
+                    amount: Some(Currency::str(amount)),
+                    metadata: HashMap::new(),
+                    under_dispute: false,
+                    dispute_metadata: None,
+                })
+                .unwrap();
+        }
+        exchange
+            .process_new_transaction(Transaction {
+                tx_type: Type::Dispute,
+                client: 2,
+                tx: 2,
+                amount: None,
+                metadata: HashMap::new(),
+                under_dispute: false,
+                dispute_metadata: None,
+            })
+            .unwrap();
+
+        let mut output = Vec::new();
+        write_settlement(&mut output, &settle(&exchange, &baseline), &ClientIds::Raw).unwrap();
+
+        assert_eq!(
+            "client,opening_total,closing_total,net_movement,to_sweep,held\n\
+             1,10.0000,15.0000,5.0000,15.0000,0.0000\n\
+             2,0.0000,5.0000,5.0000,0.0000,5.0000\n\
+             3,4.0000,0.0000,-4.0000,0.0000,0.0000\n\
+             TOTAL,14.0000,20.0000,6.0000,15.0000,5.0000\n",
+            String::from_utf8(output).unwrap()
+        );
+    }
+
+    #[test]
+    fn it_should_not_sweep_locked_accounts_nor_accounts_in_deficit() {
+        let mut exchange = Exchange::new();
+        let rows = [
+            (Type::Deposit, 1, 1, Some("10.0")),
+            (Type::Deposit, 1, 2, Some("3.0")),
+            (Type::Dispute, 1, 1, None),
+            (Type::Chargeback, 1, 1, None),
+            (Type::Deposit, 2, 3, Some("10.0")),
+            (Type::Withdrawal, 2, 4, Some("8.0")),
+            (Type::Dispute, 2, 3, None),
+        ];
+        for (tx_type, client, tx, amount) in rows {
+            exchange
+                .process_new_transaction(Transaction {
+                    tx_type,
+                    client,
+                    tx,
+                    amount: amount.map(Currency::str),
+                    metadata: HashMap::new(),
+                    under_dispute: false,
+                    dispute_metadata: None,
+                })
+                .unwrap();
+        }
+        //padded like a hand-edited snapshot
+        let baseline = read_baseline(
+            "client, available, held, total, locked\n 2 , 1.0 , 0.0 , 1.0 , false\n".as_bytes(),
+        )
+        .unwrap();
+
+        let lines = settle(&exchange, &baseline);
+        assert_eq!(
+            vec![
+                "1,0.0000,3.0000,3.0000,0.0000,0.0000",
+                "2,1.0000,2.0000,1.0000,0.0000,10.0000",
+                "TOTAL,1.0000,5.0000,4.0000,0.0000,10.0000"
+            ],
+            lines
+                .iter()
+                .map(SettlementLine::to_string)
+                .collect::<Vec<String>>()
+        );
+        assert_eq!(true, exchange.client(1).unwrap().locked());
+        assert_eq!(
+            Currency::str("-8.0"),
+            exchange.client(2).unwrap().available()
+        );
+        assert_eq!(
+            vec![None],
+            settle(&Exchange::new(), &Baseline::new())
+                .iter()
+                .map(|line| line.client)
+                .collect::<Vec<Option<ClientId>>>()
+        );
+    }
+}
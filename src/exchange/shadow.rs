@@ -0,0 +1,350 @@
+#[cfg(not(feature = "minor-units"))]
+use rust_decimal::prelude::ToPrimitive;
+use std::collections::HashMap;
+
+use crate::exchange::client_profile::ProcessingError;
+use crate::exchange::transaction::ChargebackReason;
+use crate::exchange::transaction::ClientId;
+use crate::exchange::transaction::Currency;
+use crate::exchange::transaction::Transaction;
+use crate::exchange::transaction::TransactionId;
+use crate::exchange::transaction::Type;
+use crate::exchange::Exchange;
+
+/// Amounts are tracked as integer 1/10000 units
+pub const MINOR_UNITS: i128 = 10_000;
+
+/// A disagreement between the engine and the shadow after applying the transaction with the given sequence number
+#[derive(Debug, PartialEq, Clone)]
+pub struct Divergence {
+    pub seq: u64,
+    pub client: ClientId,
+    pub tx: TransactionId,
+    /// available, held, total, locked, accepted, or precision/overflow when the shadow could not represent the amounts
+    pub field: &'static str,
+    pub engine: String,
+    pub shadow: String,
+}
+
+#[derive(Debug, Default, Clone)]
+struct ShadowTransaction {
+    amount: i128,
+    under_dispute: bool,
+    reason: Option<String>,
+    closed_by: Option<Type>,
+}
+
+#[derive(Debug, Default, Clone)]
+struct ShadowAccount {
+    available: i128,
+    held: i128,
+    total: i128,
+    locked: bool,
+    transactions: HashMap<TransactionId, ShadowTransaction>,
+}
+
+/// Exchange driven alongside an independent implementation of the balance rules on i128 minor units using checked arithmetic.
+/// Every transaction goes through both and any disagreement on the outcome or the resulting balances is recorded, which is used
+/// to validate the rust_decimal handling at scale. The shadow implements the default EngineConfig rules only
+pub struct DifferentialExchange {
+    exchange: Exchange,
+    accounts: HashMap<ClientId, ShadowAccount>,
+    seq: u64,
+    divergences: Vec<Divergence>,
+}
+
+impl DifferentialExchange {
+    pub fn new(exchange: Exchange) -> DifferentialExchange {
+        DifferentialExchange {
+            exchange,
+            accounts: HashMap::new(),
+            seq: 0,
+            divergences: Vec::new(),
+        }
+    }
+
+    pub fn exchange(&self) -> &Exchange {
+        &self.exchange
+    }
+
+    pub fn divergences(&self) -> &[Divergence] {
+        &self.divergences
+    }
+
+    pub fn process_new_transaction(
+        &mut self,
+        transaction: Transaction,
+    ) -> Result<(), ProcessingError> {
+        self.seq += 1;
+        let (client, tx) = (transaction.client, transaction.tx);
+
+        let shadow = self.apply_to_shadow(&transaction);
+        let result = self.exchange.process_new_transaction(transaction);
+
+        match shadow {
+            Err(field) => self.diverged(client, tx, field, "-".to_string(), "-".to_string()),
+            Ok(accepted) if accepted != result.is_ok() => self.diverged(
+                client,
+                tx,
+                "accepted",
+                result.is_ok().to_string(),
+                accepted.to_string(),
+            ),
+            Ok(_) => self.compare(client, tx),
+        }
+        result
+    }
+
+    fn diverged(
+        &mut self,
+        client: ClientId,
+        tx: TransactionId,
+        field: &'static str,
+        engine: String,
+        shadow: String,
+    ) {
+        self.divergences.push(Divergence {
+            seq: self.seq,
+            client,
+            tx,
+            field,
+            engine,
+            shadow,
+        });
+    }
+
+    fn compare(&mut self, client: ClientId, tx: TransactionId) {
+        let (profile, account) = match (self.exchange.client(client), self.accounts.get(&client)) {
+            (Some(profile), Some(account)) => (profile, account),
+            _ => return,
+        };
+
+        let mut diverged = Vec::new();
+        for (field, engine, shadow) in [
+            ("available", profile.available(), account.available),
+            ("held", profile.held(), account.held),
+            ("total", profile.total(), account.total),
+        ] {
+            if to_minor_units(engine) != Some(shadow) {
+                diverged.push((field, engine.to_string(), shadow.to_string()));
+            }
+        }
+        if profile.locked() != account.locked {
+            diverged.push((
+                "locked",
+                profile.locked().to_string(),
+                account.locked.to_string(),
+            ));
+        }
+
+        for (field, engine, shadow) in diverged {
+            self.diverged(client, tx, field, engine, shadow);
+        }
+    }
+
+    /// Ok(accepted) mirrors whether the engine should accept the transaction, Err names why the shadow could not follow
+    fn apply_to_shadow(&mut self, transaction: &Transaction) -> Result<bool, &'static str> {
+        let amount = match transaction.amount {
+            Some(amount) => Some(to_minor_units(amount).ok_or("precision")?),
+            None => None,
+        };
+        let reason = transaction
+            .dispute_metadata()
+            .and_then(|metadata| metadata.reason())
+            .map(str::to_string);
+        let account = self.accounts.entry(transaction.client).or_default();
+        if account.locked {
+            return Ok(false);
+        }
+
+        match (&transaction.tx_type, amount) {
+            (Type::Deposit, Some(amount)) => {
+                account.available = account.available.checked_add(amount).ok_or("overflow")?;
+                account.total = account.total.checked_add(amount).ok_or("overflow")?;
+                account
+                    .transactions
+                    .entry(transaction.tx)
+                    .or_insert(ShadowTransaction {
+                        amount,
+                        ..ShadowTransaction::default()
+                    });
+                Ok(true)
+            }
+            (Type::Withdrawal, Some(amount)) => {
+                if account.available < amount {
+                    return Ok(false);
+                }
+                account.available -= amount;
+                account.total -= amount;
+                account
+                    .transactions
+                    .entry(transaction.tx)
+                    .or_insert(ShadowTransaction {
+                        amount,
+                        ..ShadowTransaction::default()
+                    });
+                Ok(true)
+            }
+            (Type::Deposit, None) | (Type::Withdrawal, None) => Ok(false),
+            //custom types are rejected by an exchange without handlers, the shadow does not model the handlers
+            (Type::Custom(_), _) => Ok(false),
+            (Type::Dispute, _) => {
+                if let Some(disputed) = account.transactions.get_mut(&transaction.tx) {
+                    if disputed.under_dispute {
+                        return Ok(false);
+                    }
+                    account.held = account
+                        .held
+                        .checked_add(disputed.amount)
+                        .ok_or("overflow")?;
+                    account.available = account
+                        .available
+                        .checked_sub(disputed.amount)
+                        .ok_or("overflow")?;
+                    disputed.under_dispute = true;
+                    disputed.reason = reason.or(disputed.reason.take());
+                }
+                Ok(true)
+            }
+            (Type::Resolve, _) => {
+                if let Some(disputed) = account.transactions.get_mut(&transaction.tx) {
+                    if !disputed.under_dispute && disputed.closed_by == Some(Type::Resolve) {
+                        return Ok(false);
+                    }
+                    if disputed.under_dispute {
+                        disputed.closed_by = Some(Type::Resolve);
+                        account.held -= disputed.amount;
+                        account.available += disputed.amount;
+                        disputed.under_dispute = false;
+                        disputed.reason = reason.or(disputed.reason.take());
+                    }
+                }
+                Ok(true)
+            }
+            (Type::Chargeback, _) => {
+                if let Some(disputed) = account.transactions.get_mut(&transaction.tx) {
+                    if !disputed.under_dispute && disputed.closed_by == Some(Type::Chargeback) {
+                        return Ok(false);
+                    }
+                    if disputed.under_dispute {
+                        disputed.closed_by = Some(Type::Chargeback);
+                        account.held -= disputed.amount;
+                        account.total -= disputed.amount;
+                        disputed.under_dispute = false;
+                        disputed.reason = reason.or(disputed.reason.take());
+                        if ChargebackReason::from_code(disputed.reason.as_deref()).locks_account() {
+                            account.locked = true;
+                        }
+                    }
+                }
+                Ok(true)
+            }
+        }
+    }
+}
+
+/// None when the amount has more than 4 decimal places or does not fit
+#[cfg(not(feature = "minor-units"))]
+pub fn to_minor_units(amount: Currency) -> Option<i128> {
+    let scaled = amount.decimal().checked_mul(MINOR_UNITS.into())?;
+    if scaled.fract().is_zero() {
+        scaled.to_i128()
+    } else {
+        None
+    }
+}
+
+/// Currency already is minor units with the minor-units feature
+#[cfg(feature = "minor-units")]
+pub fn to_minor_units(amount: Currency) -> Option<i128> {
+    Some(amount.minor_units() as i128)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use crate::exchange::transaction::DisputeMetadata;
+
+    /// Deterministic xorshift so failures are reproducible without pulling a rand dependency
+    struct Generator(u64);
+
+    impl Generator {
+        fn next(&mut self, bound: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % bound
+        }
+    }
+
+    #[test]
+    fn it_should_not_diverge_on_a_large_random_workload() {
+        let mut generator = Generator(0x5eed);
+        let mut exchange = DifferentialExchange::new(Exchange::new());
+        let reasons = ["fraud", "duplicate", "goods-not-received", ""];
+
+        for tx in 1..=100_000u32 {
+            let client = generator.next(200) as ClientId;
+            let (tx_type, referenced) = match generator.next(10) {
+                0..=4 => (Type::Deposit, tx),
+                5..=6 => (Type::Withdrawal, tx),
+                7 => (Type::Dispute, tx.saturating_sub(generator.next(50) as u32)),
+                8 => (Type::Resolve, tx.saturating_sub(generator.next(50) as u32)),
+                _ => (
+                    Type::Chargeback,
+                    tx.saturating_sub(generator.next(50) as u32),
+                ),
+            };
+            let amount = matches!(tx_type, Type::Deposit | Type::Withdrawal).then(|| {
+                Currency::new(generator.next(10_000_000) as i64, generator.next(5) as u32)
+            });
+            let reason = reasons[generator.next(4) as usize];
+
+            exchange
+                .process_new_transaction(Transaction {
+                    tx_type,
+                    client,
+                    tx: referenced,
+                    amount,
+                    metadata: HashMap::new(),
+                    under_dispute: false,
+                    dispute_metadata: Some(DisputeMetadata::new(
+                        Some(reason.to_string()),
+                        None,
+                        None,
+                    )),
+                })
+                .unwrap_or_default();
+        }
+
+        assert_eq!(Vec::<Divergence>::new(), exchange.divergences());
+    }
+
+    //minor-units amounts never have more than 4 decimals
+    #[cfg(not(feature = "minor-units"))]
+    #[test]
+    fn it_should_report_amounts_the_minor_units_can_not_represent() {
+        use crate::exchange::transaction::Money;
+
+        let mut exchange = DifferentialExchange::new(Exchange::new());
+
+        exchange
+            .process_new_transaction(Transaction {
+                tx_type: Type::Deposit,
+                client: 1,
+                tx: 1,
+                amount: Some(Currency::str("0.00001")),
+                metadata: HashMap::new(),
+                under_dispute: false,
+                dispute_metadata: None,
+            })
+            .unwrap_or_default();
+
+        assert_eq!(1, exchange.divergences().len());
+        assert_eq!("precision", exchange.divergences()[0].field);
+        assert_eq!(None, to_minor_units(Currency::str("0.00001")));
+        assert_eq!(Some(-12345), to_minor_units(Currency::str("-1.2345")));
+    }
+}
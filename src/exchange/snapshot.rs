@@ -0,0 +1,281 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::exchange::output::AmountFormat;
+use crate::exchange::Exchange;
+
+/// When intermediate account snapshots are written during a run, see Snapshots
+#[derive(Debug, PartialEq, Clone)]
+pub struct SnapshotOptions {
+    pub dir: PathBuf,
+    /// Every that many transactions submitted
+    pub every_records: Option<u64>,
+    /// At most that often, whether or not every_records is set
+    pub every: Option<Duration>,
+    /// Snapshots kept in dir, the oldest ones are removed
+    pub keep: usize,
+    pub format: AmountFormat,
+}
+
+impl Default for SnapshotOptions {
+    fn default() -> Self {
+        SnapshotOptions {
+            dir: PathBuf::from("snapshots"),
+            every_records: Some(1_000_000),
+            every: None,
+            keep: 10,
+            format: AmountFormat::Plain,
+        }
+    }
+}
+
+/// Intermediate account summaries of a long run, written to a rotating directory as accounts-<sequence>.csv once every_records
+/// transactions were submitted since the previous one or every has elapsed, whichever comes first. A snapshot is written to a
+/// .partial file renamed once complete, so consumers picking up the newest accounts-*.csv never read half of one. The sequence
+/// carries on from the snapshots already in the directory, e.g. after resuming from a checkpoint
+#[derive(Debug, Clone)]
+pub struct Snapshots {
+    options: SnapshotOptions,
+    sequence: u64,
+    /// Transactions submitted at the previous snapshot
+    last_submitted: u64,
+    last_at: Instant,
+    written: VecDeque<PathBuf>,
+}
+
+/// The sequence number of a snapshot file name
+fn sequence(path: &Path) -> Option<u64> {
+    path.file_name()?
+        .to_str()?
+        .strip_prefix("accounts-")?
+        .strip_suffix(".csv")?
+        .parse()
+        .ok()
+}
+
+/// Files of a snapshot directory with their sequence number
+type Sequenced = Vec<(u64, PathBuf)>;
+
+/// The snapshots of the directory ordered by sequence, and the .partial files left behind by runs that stopped mid-write
+fn list(dir: &Path) -> io::Result<(Sequenced, Sequenced)> {
+    let (mut written, mut partial) = (Vec::new(), Vec::new());
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if let Some(sequence) = sequence(&path) {
+            written.push((sequence, path));
+        } else if let Some(sequence) = path
+            .file_stem()
+            .filter(|_| {
+                path.extension()
+                    .is_some_and(|extension| extension == "partial")
+            })
+            .and_then(|stem| sequence(Path::new(stem)))
+        {
+            partial.push((sequence, path));
+        }
+    }
+    written.sort_unstable();
+    Ok((written, partial))
+}
+
+/// Apply the retention of a snapshot directory: remove every snapshot but the newest keep (at least one), and the .partial
+/// files of snapshots older than the newest one, which no run is still writing. Returns the removed files. Runs writing
+/// snapshots apply it when they start and rotate the snapshots as they write them, so this is for directories filled by
+/// runs with a larger keep, see the compact subcommand
+pub fn compact(dir: &Path, keep: usize) -> io::Result<Vec<PathBuf>> {
+    let (written, partial) = list(dir)?;
+    let newest = written.last().map_or(0, |(sequence, _)| *sequence);
+    let stale = written.len().saturating_sub(keep.max(1));
+    let removed: Vec<PathBuf> = written
+        .into_iter()
+        .take(stale)
+        .chain(
+            partial
+                .into_iter()
+                .filter(|(sequence, _)| *sequence < newest),
+        )
+        .map(|(_, path)| path)
+        .collect();
+    for path in &removed {
+        fs::remove_file(path)?;
+    }
+    Ok(removed)
+}
+
+impl Snapshots {
+    pub fn create(options: SnapshotOptions) -> io::Result<Snapshots> {
+        fs::create_dir_all(&options.dir)?;
+        compact(&options.dir, options.keep)?;
+        let (written, _) = list(&options.dir)?;
+        Ok(Snapshots {
+            sequence: written.last().map_or(0, |(sequence, _)| *sequence),
+            options,
+            last_submitted: 0,
+            last_at: Instant::now(),
+            written: written.into_iter().map(|(_, path)| path).collect(),
+        })
+    }
+
+    /// Count the records of the next snapshot from the transactions submitted so far, see Exchange::with_snapshots()
+    pub(crate) fn start(&mut self, submitted: u64) {
+        self.last_submitted = submitted;
+    }
+
+    pub fn options(&self) -> &SnapshotOptions {
+        &self.options
+    }
+
+    /// The snapshots in the directory, oldest first
+    pub fn written(&self) -> impl Iterator<Item = &PathBuf> {
+        self.written.iter()
+    }
+
+    fn due(&self, submitted: u64) -> bool {
+        let records = self
+            .options
+            .every_records
+            .is_some_and(|every| submitted - self.last_submitted >= every);
+        let elapsed = self.options.every.is_some_and(|every| {
+            submitted > self.last_submitted && self.last_at.elapsed() >= every
+        });
+        records || elapsed
+    }
+
+    /// Write a snapshot of the exchange when one is due, see Snapshots. Returns its path
+    pub fn write_if_due(&mut self, exchange: &Exchange) -> io::Result<Option<PathBuf>> {
+        if !self.due(exchange.submitted()) {
+            return Ok(None);
+        }
+        self.write(exchange).map(Some)
+    }
+
+    /// Write a snapshot of the exchange now and remove the ones beyond keep
+    pub fn write(&mut self, exchange: &Exchange) -> io::Result<PathBuf> {
+        self.sequence += 1;
+        let path = self
+            .options
+            .dir
+            .join(format!("accounts-{:06}.csv", self.sequence));
+        let partial = path.with_extension("csv.partial");
+        let mut output = BufWriter::new(File::create(&partial)?);
+        exchange.to_writer(&mut output, &self.options.format)?;
+        output.into_inner()?.sync_all()?;
+        fs::rename(&partial, &path)?;
+
+        self.last_submitted = exchange.submitted();
+        self.last_at = Instant::now();
+        self.written.push_back(path.clone());
+        while self.written.len() > self.options.keep.max(1) {
+            if let Some(oldest) = self.written.pop_front() {
+                fs::remove_file(oldest)?;
+            }
+        }
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn it_should_rotate_snapshots_taken_every_few_records() {
+        let dir = std::env::temp_dir().join("payment_engine_snapshots");
+        let _ = fs::remove_dir_all(&dir);
+        let input = dir.with_extension("csv");
+        let mut rows = String::from("type,client,tx,amount\n");
+        for tx in 1..=25 {
+            rows.push_str(&format!("deposit,{},{},1.0\n", tx % 3, tx));
+        }
+        fs::write(&input, rows).unwrap();
+
+        let options = SnapshotOptions {
+            dir: dir.clone(),
+            every_records: Some(10),
+            keep: 2,
+            ..Default::default()
+        };
+        let mut exchange =
+            Exchange::new().with_snapshots(Snapshots::create(options.clone()).unwrap());
+        crate::exchange::process_transactions_from_csv(input.to_str().unwrap(), &mut exchange)
+            .unwrap();
+        let names = |dir: &Path| {
+            let mut names: Vec<String> = fs::read_dir(dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+                .collect();
+            names.sort();
+            names
+        };
+        assert_eq!(
+            vec!["accounts-000001.csv", "accounts-000002.csv"],
+            names(&dir)
+        );
+        //the second one was taken after 20 transactions
+        let second = fs::read_to_string(dir.join("accounts-000002.csv")).unwrap();
+        assert_eq!(true, second.contains("\n0,6.0000,0.0000,6.0000,false"));
+
+        //a new run carries on the sequence and rotates the old ones out
+        let mut snapshots = Snapshots::create(options).unwrap();
+        snapshots.write(&exchange).unwrap();
+        assert_eq!(
+            vec!["accounts-000002.csv", "accounts-000003.csv"],
+            names(&dir)
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_file(&input).unwrap();
+    }
+
+    #[test]
+    fn it_should_compact_a_snapshot_directory_to_the_newest_snapshots() {
+        let dir = std::env::temp_dir().join("payment_engine_snapshots_compact");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for name in [
+            "accounts-000001.csv",
+            "accounts-000002.csv",
+            "accounts-000003.csv",
+            "accounts-000002.csv.partial",
+            "accounts-000004.csv.partial",
+            "notes.txt",
+        ] {
+            fs::write(dir.join(name), "").unwrap();
+        }
+
+        let mut removed = compact(&dir, 2).unwrap();
+        removed.sort();
+        assert_eq!(
+            vec![
+                dir.join("accounts-000001.csv"),
+                dir.join("accounts-000002.csv.partial")
+            ],
+            removed
+        );
+        let mut left: Vec<String> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        left.sort();
+        //the newest partial may still be being written
+        assert_eq!(
+            vec![
+                "accounts-000002.csv",
+                "accounts-000003.csv",
+                "accounts-000004.csv.partial",
+                "notes.txt"
+            ],
+            left
+        );
+        assert_eq!(true, compact(&dir, 2).unwrap().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
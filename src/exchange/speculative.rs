@@ -0,0 +1,413 @@
+use std::error::Error;
+use std::sync::Arc;
+use std::thread;
+
+use crate::exchange::client_profile::ProcessingError;
+use crate::exchange::config::LimitPolicy;
+use crate::exchange::enrichment::NoEnrichment;
+use crate::exchange::process_transactions_from_csv_with;
+use crate::exchange::transaction::ClientId;
+use crate::exchange::transaction::Transaction;
+use crate::exchange::transaction::Type;
+use crate::exchange::Exchange;
+use crate::exchange::IngestOptions;
+use crate::exchange::RecordReader;
+
+/// Runs of deposits and withdrawals shorter than this (per thread) are applied serially, as spawning the threads and setting
+/// up their exchanges would cost more than it saves
+pub const MIN_PARALLEL_RUN: usize = 1024;
+
+/// Transactions read from the input before they are handed to Exchange::process_speculatively()
+pub const BATCH: usize = 1 << 16;
+
+/// How a batch was applied by Exchange::process_speculatively()
+#[derive(Debug, Default)]
+pub struct SpeculativeRun {
+    /// Transactions applied on the client shards
+    pub parallel: usize,
+    /// Transactions applied in input order on the exchange itself: dispute steps and other types, short runs, and whole
+    /// batches when the exchange can not speculate
+    pub serialized: usize,
+    /// Runs whose speculative result conflicted with the limits and were applied again serially
+    pub retried: usize,
+    /// The client of every transaction rejected with the reason, in input order
+    pub rejections: Vec<(ClientId, ProcessingError)>,
+}
+
+impl Exchange {
+    /// Whether the result of applying transactions out of input order could differ from the serial one, for reasons no
+    /// conflict check can undo: the ledger, the subscribers, the archive and the anomaly detector all see the transactions in
+    /// input order, and traced transactions are numbered by submission
+    fn speculation_blocked(&self) -> bool {
+        self.ledger.is_some()
+            || self.config.trace.is_some()
+            || self.events.is_observed()
+            || self.archive.is_some()
+            || self.config.archive_idle.is_some()
+            || self.config.limits.max_memory.is_some()
+            || self.config.anomaly_detection.is_some()
+            || self.config.dedup_window.is_some()
+            || !self.config.error_budget.is_empty()
+            || self.config.track_ordering
+            || self.config.defer_disputes.is_some()
+    }
+
+    /// Apply a batch of transactions as process_new_transaction() would one at a time, but optimistically in parallel: the
+    /// deposits and withdrawals between two transactions of another type are sharded by client over the given number of
+    /// threads, each applying the transactions of its clients in input order on its own exchange. A transaction referencing a
+    /// prior tx id (dispute, resolve, chargeback) or of a custom type is serialized, after the run before it is folded back.
+    /// Only the client and retained transaction limits are shared by the shards: a run whose result exceeds them is a
+    /// conflict, it is discarded and applied again serially. For workloads where disputes are rare most transactions end up
+    /// on the shards; the accounts and stats are the same as the serial engine's either way
+    pub fn process_speculatively(
+        &mut self,
+        batch: Vec<Transaction>,
+        threads: usize,
+    ) -> SpeculativeRun {
+        let mut run = SpeculativeRun::default();
+        if threads < 2 || self.speculation_blocked() {
+            for transaction in batch {
+                self.apply_serially(transaction, &mut run);
+            }
+            return run;
+        }
+
+        //transactions are enriched once up front, as the shard they go to depends on their enriched client
+        let enricher = std::mem::replace(&mut self.enricher, Arc::new(NoEnrichment));
+        let mut deposits = Vec::new();
+        for transaction in batch {
+            let transaction = enricher.enrich(transaction);
+            if matches!(transaction.tx_type, Type::Deposit | Type::Withdrawal) {
+                deposits.push(transaction);
+                continue;
+            }
+            self.apply_run(std::mem::take(&mut deposits), threads, &mut run);
+            self.apply_serially(transaction, &mut run);
+        }
+        self.apply_run(deposits, threads, &mut run);
+        self.enricher = enricher;
+        run
+    }
+
+    fn apply_serially(&mut self, transaction: Transaction, run: &mut SpeculativeRun) {
+        run.serialized += 1;
+        let client = transaction.client;
+        if let Err(e) = self.process_new_transaction(transaction) {
+            run.rejections.push((client, e));
+        }
+    }
+
+    /// Apply deposits and withdrawals on client shards, see process_speculatively()
+    fn apply_run(&mut self, deposits: Vec<Transaction>, threads: usize, run: &mut SpeculativeRun) {
+        if deposits.len() < MIN_PARALLEL_RUN * threads {
+            for transaction in deposits {
+                self.apply_serially(transaction, run);
+            }
+            return;
+        }
+
+        //with limits the shards work on copies of their clients, so a conflicting run can be discarded
+        let limits = &self.config.limits;
+        let limited = limits.max_clients.is_some() || limits.max_transactions.is_some();
+        let retry = limited.then(|| deposits.clone());
+        let clients: Vec<_> = deposits.iter().map(Transaction::client).collect();
+
+        let mut shards: Vec<(Exchange, Vec<(usize, Transaction)>)> = (0..threads)
+            .map(|_| (self.empty_like(), Vec::new()))
+            .collect();
+        for (i, transaction) in deposits.into_iter().enumerate() {
+            let (shard, transactions) = &mut shards[transaction.client as usize % threads];
+            if !shard.clients.contains_key(&transaction.client) {
+                let client = if limited {
+                    self.clients.get(&transaction.client).cloned()
+                } else {
+                    self.clients.remove(&transaction.client)
+                };
+                if let Some(client) = client {
+                    shard.retained_transactions += client.transaction_count();
+                    shard.clients.insert(transaction.client, client);
+                }
+            }
+            transactions.push((i, transaction));
+        }
+        let moved: usize = shards
+            .iter()
+            .map(|(shard, _)| shard.retained_transactions)
+            .sum();
+
+        let applied: Vec<_> = thread::scope(|scope| {
+            let threads: Vec<_> = shards
+                .into_iter()
+                .map(|(mut shard, transactions)| {
+                    scope.spawn(move || {
+                        let mut rejections = Vec::new();
+                        for (i, transaction) in transactions {
+                            let client = transaction.client;
+                            if let Err(e) = shard.process_new_transaction(transaction) {
+                                rejections.push((i, client, e));
+                            }
+                        }
+                        (shard, rejections)
+                    })
+                })
+                .collect();
+            threads
+                .into_iter()
+                //the clients of a shard that panicked are lost, so the panic can only be passed on
+                .map(|thread| {
+                    thread
+                        .join()
+                        .unwrap_or_else(|e| std::panic::resume_unwind(e))
+                })
+                .collect()
+        });
+
+        let retained = self.retained_transactions - moved
+            + applied
+                .iter()
+                .map(|(shard, _)| shard.retained_transactions)
+                .sum::<usize>();
+        if let Some(retry) = retry {
+            let new_clients = applied
+                .iter()
+                .flat_map(|(shard, _)| shard.clients.keys())
+                .filter(|id| !self.clients.contains_key(id))
+                .count();
+            let limits = &self.config.limits;
+            let conflict = limits
+                .max_clients
+                .is_some_and(|max_clients| self.clients.len() + new_clients > max_clients)
+                || limits.max_transactions.is_some_and(|max_transactions| {
+                    self.config.retain_transactions && retained > max_transactions
+                });
+            if conflict {
+                run.retried += 1;
+                for transaction in retry {
+                    self.apply_serially(transaction, run);
+                }
+                return;
+            }
+        }
+
+        let mut rejections = Vec::new();
+        for (shard, shard_rejections) in applied {
+            self.clients.extend(shard.clients);
+            self.stats.merge(shard.stats);
+            rejections.extend(shard_rejections);
+        }
+        rejections.sort_unstable_by_key(|(i, _, _)| *i);
+        run.rejections
+            .extend(rejections.into_iter().map(|(_, client, e)| (client, e)));
+        self.retained_transactions = retained;
+        run.parallel += clients.len();
+        for client in clients {
+            self.submitted += 1;
+            self.last_active.insert(client, self.submitted);
+        }
+    }
+}
+
+/// process_transactions_from_csv_with() applying the input a batch at a time with Exchange::process_speculatively(). Limits
+/// under LimitPolicy::Abort are checked before each transaction against the state it would be applied to, which a batch
+/// does not know yet, and a quarantine or the recovery need the offsets of the records, so they fall back to the serial
+/// ingestion
+pub fn process_transactions_from_csv_speculatively(
+    path: &str,
+    bank: &mut Exchange,
+    options: &IngestOptions,
+    threads: usize,
+) -> Result<(), Box<dyn Error>> {
+    let limits = &bank.config.limits;
+    let aborts = limits.on_exceeded == LimitPolicy::Abort
+        && (limits.max_clients.is_some()
+            || limits.max_transactions.is_some()
+            || limits.max_memory.is_some());
+    if aborts || options.quarantine.is_some() || options.recover {
+        return process_transactions_from_csv_with(path, bank, options);
+    }
+    let mut reader = csv::Reader::from_path(path)?;
+
+    let headers = reader.headers()?.clone();
+    let ingest = RecordReader::new(&headers, options);
+
+    let mut raw_record = csv::StringRecord::new();
+    let mut batch = Vec::with_capacity(BATCH);
+    loop {
+        let more = reader.read_record(&mut raw_record)?;
+        if more {
+            batch.push(ingest.read(bank, &raw_record)?);
+        }
+        if batch.len() == BATCH || (!more && !batch.is_empty()) {
+            let run = bank.process_speculatively(std::mem::take(&mut batch), threads);
+            for (_, error) in run.rejections {
+                ingest.log_rejection(bank, &error);
+            }
+            bank.snapshot_if_due();
+        }
+        if !more {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use std::collections::HashMap;
+
+    use crate::exchange::config::EngineConfig;
+    use crate::exchange::config::LimitPolicy;
+    use crate::exchange::config::Limits;
+    use crate::exchange::trace::TraceSampling;
+    use crate::exchange::trace::TRACE_ID;
+    use crate::exchange::transaction::Currency;
+    use crate::exchange::transaction::Money;
+    use crate::exchange::transaction::TransactionId;
+
+    fn workload() -> Vec<Transaction> {
+        let mut transactions = Vec::new();
+        let row =
+            |tx_type, client: ClientId, tx: TransactionId, amount: Option<String>| Transaction {
+                tx_type,
+                client,
+                tx,
+                amount: amount.map(|amount| Currency::str(&amount)),
+                metadata: HashMap::new(),
+                under_dispute: false,
+                dispute_metadata: None,
+            };
+        for tx in 1..=40_000u32 {
+            let client = |tx: TransactionId| (tx % 97) as ClientId;
+            //every 10000th transaction disputes the deposit 98 transactions back, which is charged back 5000 later
+            let disputed = (tx / 10_000 * 10_000).saturating_sub(98);
+            transactions.push(match tx % 10_000 {
+                0 => row(Type::Dispute, client(disputed), disputed, None),
+                5000 => row(Type::Chargeback, client(disputed), disputed, None),
+                n if n % 3 == 0 => row(
+                    Type::Withdrawal,
+                    client(tx),
+                    tx,
+                    Some(format!("{}.5", n % 7)),
+                ),
+                n => row(
+                    Type::Deposit,
+                    client(tx),
+                    tx,
+                    Some(format!("{}.25", n % 11)),
+                ),
+            });
+        }
+        transactions
+    }
+
+    fn summaries(exchange: &Exchange) -> Vec<String> {
+        let mut summaries: Vec<String> = exchange
+            .accounts(&Default::default())
+            .map(|client| {
+                format!(
+                    "{},{},{},{},{}",
+                    client.id(),
+                    client.available(),
+                    client.held(),
+                    client.total(),
+                    client.locked()
+                )
+            })
+            .collect();
+        summaries.sort();
+        summaries
+    }
+
+    #[test]
+    fn it_should_end_up_with_the_accounts_of_the_serial_engine() {
+        let mut serial = Exchange::new();
+        for transaction in workload() {
+            serial
+                .process_new_transaction(transaction)
+                .unwrap_or_default();
+        }
+
+        let mut speculative = Exchange::new();
+        let run = speculative.process_speculatively(workload(), 4);
+        assert_eq!(summaries(&serial), summaries(&speculative));
+        assert_eq!(serial.stats().processed(), speculative.stats().processed());
+        assert_eq!(serial.stats().rejected(), speculative.stats().rejected());
+        assert_eq!(serial.submitted(), speculative.submitted());
+        assert_eq!(
+            serial.retained_transactions,
+            speculative.retained_transactions
+        );
+        assert_eq!(true, run.parallel > run.serialized);
+        assert_eq!(0, run.retried);
+        assert_eq!(serial.stats().rejected(), run.rejections.len() as u64);
+        //the dispute steps referenced transactions applied on the shards
+        assert_eq!(
+            true,
+            speculative
+                .accounts(&Default::default())
+                .any(|c| c.locked())
+        );
+    }
+
+    #[test]
+    fn it_should_retry_runs_conflicting_with_the_client_limit_serially() {
+        let config = EngineConfig {
+            limits: Limits {
+                max_clients: Some(50),
+                on_exceeded: LimitPolicy::Degrade,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut serial = Exchange::new().with_config(config.clone());
+        for transaction in workload() {
+            serial
+                .process_new_transaction(transaction)
+                .unwrap_or_default();
+        }
+
+        let mut speculative = Exchange::new().with_config(config);
+        let run = speculative.process_speculatively(workload(), 4);
+        assert_eq!(true, run.retried > 0);
+        assert_eq!(50, speculative.accounts(&Default::default()).count());
+        assert_eq!(summaries(&serial), summaries(&speculative));
+        assert_eq!(serial.stats().processed(), speculative.stats().processed());
+        assert_eq!(serial.stats().rejected(), speculative.stats().rejected());
+    }
+
+    #[test]
+    fn it_should_apply_traced_transactions_serially_to_number_them_in_input_order() {
+        let config = EngineConfig {
+            trace: Some(TraceSampling::parse("0").unwrap()),
+            ..Default::default()
+        };
+        let mut serial = Exchange::new().with_config(config.clone());
+        for transaction in workload() {
+            serial
+                .process_new_transaction(transaction)
+                .unwrap_or_default();
+        }
+
+        let mut speculative = Exchange::new().with_config(config);
+        let run = speculative.process_speculatively(workload(), 4);
+        assert_eq!(0, run.parallel);
+        assert_eq!(workload().len(), run.serialized);
+        let trace_ids = |exchange: &Exchange| -> Vec<(TransactionId, String)> {
+            let mut ids: Vec<_> = exchange
+                .accounts(&Default::default())
+                .flat_map(|client| {
+                    client
+                        .transaction_ids()
+                        .filter_map(|tx| client.transaction(*tx))
+                })
+                .map(|t| (t.tx(), t.metadata()[TRACE_ID].clone()))
+                .collect();
+            ids.sort();
+            ids
+        };
+        assert_eq!(false, trace_ids(&serial).is_empty());
+        assert_eq!(trace_ids(&serial), trace_ids(&speculative));
+    }
+}
@@ -0,0 +1,229 @@
+use std::fmt;
+use std::io;
+
+use crate::exchange::client_profile::ClientProfile;
+use crate::exchange::pseudonym::ClientIds;
+use crate::exchange::transaction::Transaction;
+
+/// Rows per INSERT statement, so a large export is not one statement psql has to hold at once
+const ROWS_PER_INSERT: usize = 1000;
+
+const CREATE_TABLES: &str = "\
+CREATE TABLE IF NOT EXISTS accounts (
+    client TEXT PRIMARY KEY,
+    available NUMERIC NOT NULL,
+    held NUMERIC NOT NULL,
+    total NUMERIC NOT NULL,
+    locked BOOLEAN NOT NULL
+);
+CREATE TABLE IF NOT EXISTS transactions (
+    client TEXT NOT NULL,
+    tx BIGINT NOT NULL,
+    type TEXT NOT NULL,
+    amount NUMERIC,
+    under_dispute BOOLEAN NOT NULL,
+    PRIMARY KEY (client, tx)
+);
+";
+
+/// A string literal, with its single quotes doubled
+struct Literal<T>(T);
+
+impl<T: fmt::Display> fmt::Display for Literal<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "'{}'", self.0.to_string().replace('\'', "''"))
+    }
+}
+
+/// Write rows as INSERT statements of at most ROWS_PER_INSERT rows each
+fn write_inserts<W: io::Write>(
+    writer: &mut W,
+    into: &str,
+    rows: impl Iterator<Item = String>,
+) -> io::Result<()> {
+    let mut count = 0;
+    for row in rows {
+        if count % ROWS_PER_INSERT == 0 {
+            if count > 0 {
+                writeln!(writer, ";")?;
+            }
+            write!(writer, "INSERT INTO {} VALUES\n{}", into, row)?;
+        } else {
+            write!(writer, ",\n{}", row)?;
+        }
+        count += 1;
+    }
+    if count > 0 {
+        writeln!(writer, ";")?;
+    }
+    Ok(())
+}
+
+/// The accounts and their retained transactions as a Postgres script, to be loaded with psql: the accounts and transactions
+/// tables are created unless they exist, and the rows inserted in a single transaction, ordered by client and tx id. Client
+/// ids are written as their labels, so they are text; amounts are exact NUMERIC values. Only the transactions still
+/// retained are exported, see EngineConfig::retain_transactions
+pub fn write_sql<'a, W: io::Write>(
+    mut writer: W,
+    clients: impl Iterator<Item = &'a ClientProfile>,
+    ids: &ClientIds,
+) -> io::Result<()> {
+    let mut clients: Vec<&ClientProfile> = clients.collect();
+    clients.sort_unstable_by_key(|client| client.id());
+
+    writeln!(writer, "BEGIN;")?;
+    write!(writer, "{}", CREATE_TABLES)?;
+    write_inserts(
+        &mut writer,
+        "accounts (client, available, held, total, locked)",
+        clients.iter().map(|client| {
+            format!(
+                "({}, {:.4}, {:.4}, {:.4}, {})",
+                Literal(ids.label(client.id())),
+                client.available(),
+                client.held(),
+                client.total(),
+                client.locked()
+            )
+        }),
+    )?;
+    write_inserts(
+        &mut writer,
+        "transactions (client, tx, type, amount, under_dispute)",
+        clients.iter().flat_map(|client| {
+            let mut transactions: Vec<&Transaction> = client
+                .transaction_ids()
+                .filter_map(|tx| client.transaction(*tx))
+                .collect();
+            transactions.sort_unstable_by_key(|t| t.tx);
+            transactions.into_iter().map(move |t| {
+                format!(
+                    "({}, {}, {}, {}, {})",
+                    Literal(ids.label(client.id())),
+                    t.tx,
+                    Literal(&t.tx_type),
+                    t.amount
+                        .map_or("NULL".to_string(), |amount| format!("{:.4}", amount)),
+                    t.under_dispute
+                )
+            })
+        }),
+    )?;
+    writeln!(writer, "COMMIT;")?;
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use std::collections::HashMap;
+
+    use crate::exchange::config::EngineConfig;
+    use crate::exchange::transaction::Currency;
+    use crate::exchange::transaction::Money;
+    use crate::exchange::transaction::Type;
+    use crate::exchange::Exchange;
+
+    #[test]
+    fn it_should_export_the_accounts_and_transactions_as_postgres_inserts() {
+        let mut exchange = Exchange::new();
+        for (tx_type, client, tx, amount) in [
+            (Type::Deposit, 2, 3, Some("5.0")),
+            (Type::Deposit, 1, 1, Some("10.0")),
+            (Type::Withdrawal, 1, 2, Some("1.5")),
+            (Type::Dispute, 1, 1, None),
+        ] {
+            exchange
+                .process_new_transaction(Transaction {
+                    tx_type,
+                    client,
+                    tx,
+                    amount: amount.map(Currency::str),
+                    metadata: HashMap::new(),
+                    under_dispute: false,
+                    dispute_metadata: None,
+                })
+                .unwrap();
+        }
+
+        let mut written = Vec::new();
+        exchange.to_sql_writer(&mut written).unwrap();
+        let written = String::from_utf8(written).unwrap();
+        assert_eq!(
+            true,
+            written.starts_with("BEGIN;\nCREATE TABLE IF NOT EXISTS accounts")
+        );
+        assert_eq!(
+            true,
+            written.ends_with(
+                "INSERT INTO accounts (client, available, held, total, locked) VALUES\n\
+                 ('1', -1.5000, 10.0000, 8.5000, false),\n\
+                 ('2', 5.0000, 0.0000, 5.0000, false);\n\
+                 INSERT INTO transactions (client, tx, type, amount, under_dispute) VALUES\n\
+                 ('1', 1, 'deposit', 10.0000, true),\n\
+                 ('1', 2, 'withdrawal', 1.5000, false),\n\
+                 ('2', 3, 'deposit', 5.0000, false);\n\
+                 COMMIT;\n"
+            )
+        );
+
+        let mut batched = Vec::new();
+        write_inserts(
+            &mut batched,
+            "t",
+            (0..ROWS_PER_INSERT + 1).map(|i| format!("({})", i)),
+        )
+        .unwrap();
+        assert_eq!(
+            2,
+            String::from_utf8(batched)
+                .unwrap()
+                .matches("INSERT INTO t VALUES")
+                .count()
+        );
+        assert_eq!("'O''Brien'", Literal("O'Brien").to_string());
+    }
+
+    #[test]
+    fn it_should_write_no_insert_for_an_empty_table_and_label_the_clients() {
+        let mut written = Vec::new();
+        write_sql(&mut written, std::iter::empty(), &ClientIds::Raw).unwrap();
+        assert_eq!(
+            format!("BEGIN;\n{}COMMIT;\n", CREATE_TABLES),
+            String::from_utf8(written).unwrap()
+        );
+
+        let mut exchange = Exchange::new().with_config(EngineConfig {
+            retain_transactions: false,
+            ..EngineConfig::default()
+        });
+        exchange
+            .process_new_transaction(Transaction {
+                tx_type: Type::Deposit,
+                client: 7,
+                tx: 1,
+                amount: Some(Currency::str("2.0")),
+                metadata: HashMap::new(),
+                under_dispute: false,
+                dispute_metadata: None,
+            })
+            .unwrap();
+        let ids = ClientIds::hashed("salt");
+        let mut written = Vec::new();
+        write_sql(&mut written, exchange.clients.values(), &ids).unwrap();
+        let written = String::from_utf8(written).unwrap();
+        //the transactions are not retained, the account is exported alone
+        assert_eq!(
+            true,
+            written.ends_with(&format!(
+                "INSERT INTO accounts (client, available, held, total, locked) VALUES\n\
+                 ('{}', 2.0000, 0.0000, 2.0000, false);\n\
+                 COMMIT;\n",
+                ids.label(7)
+            ))
+        );
+        assert_eq!(false, written.contains("'7'"));
+    }
+}
@@ -0,0 +1,230 @@
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::exchange::disputes::AgingReport;
+use crate::exchange::events::DomainEvent;
+#[cfg(feature = "latency-histograms")]
+use crate::exchange::latency::Latencies;
+use crate::exchange::memory::MemoryUsage;
+use crate::exchange::ordering::OrderingAnomalyKind;
+use crate::exchange::ordering::OrderingReport;
+use crate::exchange::transaction::ChargebackReason;
+use crate::exchange::transaction::Type;
+
+/// Run-level counters reported at the end of processing. Chargeback reason counts are aggregated from the client profiles when the report is built
+#[derive(Debug, Default, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Stats {
+    pub(crate) processed: u64,
+    pub(crate) rejected: u64,
+    pub(crate) chargeback_reasons: BTreeMap<ChargebackReason, u64>,
+    /// Replayed dispute, resolve and chargeback rows (see ClientProfile::is_duplicate), also counted as rejected
+    pub(crate) duplicate_disputes: u64,
+    pub(crate) duplicate_resolves: u64,
+    pub(crate) duplicate_chargebacks: u64,
+    /// Transactions of a type unknown to the engine (see config::UnknownTypePolicy) per type string
+    #[serde(default)]
+    pub(crate) unknown_types: BTreeMap<String, u64>,
+    /// Deposits flagged by the anomaly detector per metric
+    #[serde(default)]
+    pub(crate) anomalies: BTreeMap<String, u64>,
+    /// Redeliveries absorbed by the de-duplication window, neither processed nor rejected
+    #[serde(default)]
+    pub(crate) deduplicated: u64,
+    /// Dispute steps parked until their transaction came, see EngineConfig::defer_disputes, and the ones among them given up on
+    #[serde(default)]
+    pub(crate) deferred: u64,
+    #[serde(default)]
+    pub(crate) deferred_expired: u64,
+    /// With an archive, the transactions of clients found in memory and of clients read back from the archive
+    #[serde(default)]
+    pub(crate) resident: u64,
+    #[serde(default)]
+    pub(crate) rehydrated: u64,
+    /// Records that could not be read and were quarantined, see IngestOptions::quarantine, or skipped, see
+    /// IngestOptions::recover
+    #[serde(default)]
+    pub(crate) malformed: u64,
+    /// Rows out of order, when EngineConfig::track_ordering is set
+    #[serde(default)]
+    pub(crate) ordering: OrderingReport,
+    /// Filled in when the report is built and EngineConfig::dispute_aging is set, see Exchange::dispute_aging
+    #[serde(skip)]
+    pub(crate) aging: Option<AgingReport>,
+    /// Filled in when the report is built, see Exchange::memory_usage
+    #[serde(skip)]
+    pub(crate) memory: MemoryUsage,
+    #[cfg(feature = "latency-histograms")]
+    #[serde(skip)]
+    pub(crate) latencies: Latencies,
+}
+
+impl Stats {
+    /// Subscriber of the exchange's events (see Exchange::subscribe), counting the transactions processed and rejected
+    pub(crate) fn observe(&mut self, event: &DomainEvent) {
+        match event {
+            DomainEvent::TransactionRejected { .. } => self.rejected += 1,
+            DomainEvent::AccountLocked { .. }
+            | DomainEvent::AccountFrozen { .. }
+            | DomainEvent::BalanceWrittenOff { .. }
+            | DomainEvent::BudgetExceeded { .. } => {}
+            DomainEvent::AnomalyDetected { anomaly, .. } => {
+                *self
+                    .anomalies
+                    .entry(anomaly.metric.to_string())
+                    .or_insert(0) += 1
+            }
+            _ => self.processed += 1,
+        }
+    }
+
+    pub fn processed(&self) -> u64 {
+        self.processed
+    }
+
+    pub fn rejected(&self) -> u64 {
+        self.rejected
+    }
+
+    pub fn deduplicated(&self) -> u64 {
+        self.deduplicated
+    }
+
+    pub fn deferred(&self) -> u64 {
+        self.deferred
+    }
+
+    pub fn deferred_expired(&self) -> u64 {
+        self.deferred_expired
+    }
+
+    pub fn resident(&self) -> u64 {
+        self.resident
+    }
+
+    pub fn rehydrated(&self) -> u64 {
+        self.rehydrated
+    }
+
+    pub fn malformed(&self) -> u64 {
+        self.malformed
+    }
+
+    pub fn ordering(&self) -> &OrderingReport {
+        &self.ordering
+    }
+
+    pub fn chargebacks(&self, reason: ChargebackReason) -> u64 {
+        self.chargeback_reasons.get(&reason).copied().unwrap_or(0)
+    }
+
+    pub fn duplicates(&self, tx_type: &Type) -> u64 {
+        match tx_type {
+            Type::Dispute => self.duplicate_disputes,
+            Type::Resolve => self.duplicate_resolves,
+            Type::Chargeback => self.duplicate_chargebacks,
+            _ => 0,
+        }
+    }
+
+    /// Transactions seen with the given unknown type string
+    pub fn unknown(&self, tx_type: &str) -> u64 {
+        self.unknown_types.get(tx_type).copied().unwrap_or(0)
+    }
+
+    pub fn aging(&self) -> Option<&AgingReport> {
+        self.aging.as_ref()
+    }
+
+    pub fn memory(&self) -> &MemoryUsage {
+        &self.memory
+    }
+
+    #[cfg(feature = "latency-histograms")]
+    pub fn latencies(&self) -> &Latencies {
+        &self.latencies
+    }
+
+    /// Add up the counters of another run, see Exchange::merge()
+    pub(crate) fn merge(&mut self, other: Stats) {
+        self.processed += other.processed;
+        self.rejected += other.rejected;
+        for (reason, count) in other.chargeback_reasons {
+            *self.chargeback_reasons.entry(reason).or_insert(0) += count;
+        }
+        self.duplicate_disputes += other.duplicate_disputes;
+        self.duplicate_resolves += other.duplicate_resolves;
+        self.duplicate_chargebacks += other.duplicate_chargebacks;
+        self.deduplicated += other.deduplicated;
+        self.deferred += other.deferred;
+        self.deferred_expired += other.deferred_expired;
+        self.resident += other.resident;
+        self.rehydrated += other.rehydrated;
+        self.malformed += other.malformed;
+        self.ordering.merge(other.ordering);
+        for (tx_type, count) in other.unknown_types {
+            *self.unknown_types.entry(tx_type).or_insert(0) += count;
+        }
+        for (metric, count) in other.anomalies {
+            *self.anomalies.entry(metric).or_insert(0) += count;
+        }
+        #[cfg(feature = "latency-histograms")]
+        self.latencies.merge(other.latencies);
+    }
+
+    pub(crate) fn record_duplicate(&mut self, tx_type: &Type) {
+        match tx_type {
+            Type::Dispute => self.duplicate_disputes += 1,
+            Type::Resolve => self.duplicate_resolves += 1,
+            Type::Chargeback => self.duplicate_chargebacks += 1,
+            _ => {}
+        }
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "processed: {}", self.processed)?;
+        write!(f, "rejected: {}", self.rejected)?;
+        for reason in ChargebackReason::ALL {
+            write!(f, "\nchargebacks.{}: {}", reason, self.chargebacks(reason))?;
+        }
+        for tx_type in [Type::Dispute, Type::Resolve, Type::Chargeback] {
+            write!(f, "\nduplicates.{}: {}", tx_type, self.duplicates(&tx_type))?;
+        }
+        write!(f, "\ndeduplicated: {}", self.deduplicated)?;
+        write!(f, "\ndeferred: {}", self.deferred)?;
+        write!(f, "\ndeferred.expired: {}", self.deferred_expired)?;
+        if self.resident + self.rehydrated > 0 {
+            write!(f, "\narchive.resident: {}", self.resident)?;
+            write!(f, "\narchive.rehydrated: {}", self.rehydrated)?;
+            write!(
+                f,
+                "\narchive.hit_rate: {:.2}%",
+                self.resident as f64 * 100.0 / (self.resident + self.rehydrated) as f64
+            )?;
+        }
+        write!(f, "\nmalformed: {}", self.malformed)?;
+        for (tx_type, count) in &self.unknown_types {
+            write!(f, "\nunknown.{}: {}", tx_type, count)?;
+        }
+        for (metric, count) in &self.anomalies {
+            write!(f, "\nanomalies.{}: {}", metric, count)?;
+        }
+        if self.ordering.total() > 0 {
+            for kind in OrderingAnomalyKind::ALL {
+                write!(f, "\nordering.{}: {}", kind, self.ordering.count(kind))?;
+            }
+        }
+        if let Some(aging) = &self.aging {
+            write!(f, "\n{}", aging)?;
+        }
+        write!(f, "\n{}", self.memory)?;
+        #[cfg(feature = "latency-histograms")]
+        if !self.latencies.is_empty() {
+            write!(f, "\n{}", self.latencies)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,191 @@
+use std::error::Error;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::task::Context;
+use std::task::Poll;
+
+use futures::stream;
+use futures::Sink;
+use futures::Stream;
+
+use crate::exchange::client_profile::AccountBalance;
+use crate::exchange::client_profile::ProcessingError;
+use crate::exchange::transaction::Transaction;
+use crate::exchange::Exchange;
+
+/// Error of the futures pipelines an ExchangeHandle is part of, so upstream parse errors and LimitExceeded can share a stream
+pub type PipelineError = Box<dyn Error + Send + Sync>;
+
+/// Cloneable handle on an exchange shared by async tasks, which is a Sink of transactions: `transactions.forward(handle)` processes
+/// every transaction of a stream of Result<Transaction, PipelineError>. As with the CSV ingestion, rejected transactions are logged
+/// and counted in the stats without failing the pipeline: only a limit exceeded under LimitPolicy::Abort (see
+/// Exchange::check_limits) or an unknown type under UnknownTypePolicy::Fail does. Transactions are processed synchronously when sent, the sink is always ready.
+/// For at-least-once sources, EngineConfig::dedup_window absorbs the redeliveries
+#[derive(Clone)]
+pub struct ExchangeHandle {
+    exchange: Arc<Mutex<Exchange>>,
+    quiet: bool,
+}
+
+impl ExchangeHandle {
+    pub fn new(exchange: Exchange) -> ExchangeHandle {
+        ExchangeHandle {
+            exchange: Arc::new(Mutex::new(exchange)),
+            quiet: false,
+        }
+    }
+
+    /// Do not log rejected transactions
+    pub fn quiet(mut self) -> ExchangeHandle {
+        self.quiet = true;
+        self
+    }
+
+    /// The exchange, for reads in between transactions. Do not hold on to it across an await point
+    pub fn lock(&self) -> MutexGuard<'_, Exchange> {
+        self.exchange.lock().expect("exchange lock poisoned")
+    }
+
+    /// The exchange back once this is its last handle, the handle itself otherwise
+    pub fn into_inner(self) -> Result<Exchange, ExchangeHandle> {
+        match Arc::try_unwrap(self.exchange) {
+            Ok(exchange) => Ok(exchange.into_inner().expect("exchange lock poisoned")),
+            Err(exchange) => Err(ExchangeHandle {
+                exchange,
+                quiet: self.quiet,
+            }),
+        }
+    }
+}
+
+impl Sink<Transaction> for ExchangeHandle {
+    type Error = PipelineError;
+
+    fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, transaction: Transaction) -> Result<(), Self::Error> {
+        let mut bank = self.lock();
+        bank.check_limits(&transaction)?;
+        bank.check_type(&transaction)?;
+        if let Err(ProcessingError(reason)) = bank.process_new_transaction(transaction) {
+            if !self.quiet {
+                eprintln!("{}", bank.config().client_ids.render(&reason));
+            }
+        }
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Exchange {
+    /// The account summaries as a stream, in the order of to_writer(), for pipelines publishing them downstream
+    pub fn summaries_stream(&self) -> impl Stream<Item = AccountBalance> + '_ {
+        stream::iter(self.clients.values().map(AccountBalance::from))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use std::collections::HashMap;
+
+    use futures::executor::block_on;
+    use futures::StreamExt;
+
+    use crate::exchange::config::EngineConfig;
+    use crate::exchange::config::Limits;
+    use crate::exchange::transaction::Currency;
+    use crate::exchange::transaction::Money;
+    use crate::exchange::transaction::Type;
+
+    fn deposit(client: u16, tx: u32, amount: &str) -> Transaction {
+        Transaction {
+            tx_type: Type::Deposit,
+            client,
+            tx,
+            amount: Some(Currency::str(amount)),
+            metadata: HashMap::new(),
+            under_dispute: false,
+            dispute_metadata: None,
+        }
+    }
+
+    #[test]
+    fn it_should_process_a_stream_forwarded_to_the_handle() {
+        let handle = ExchangeHandle::new(Exchange::new()).quiet();
+        let withdrawal = Transaction {
+            tx_type: Type::Withdrawal,
+            ..deposit(1, 3, "50.0")
+        };
+        let transactions = stream::iter(vec![
+            Ok(deposit(1, 1, "10.0")),
+            Ok(deposit(2, 2, "5.0")),
+            Ok(withdrawal),
+        ]);
+
+        block_on(transactions.forward(handle.clone())).unwrap();
+
+        let exchange = handle.into_inner().ok().unwrap();
+        //the withdrawal is rejected without stopping the pipeline
+        assert_eq!(1, exchange.stats().rejected());
+        let mut summaries: Vec<AccountBalance> = block_on(exchange.summaries_stream().collect());
+        summaries.sort_by_key(|summary| summary.client);
+        assert_eq!(
+            vec![
+                AccountBalance {
+                    client: 1,
+                    available: Currency::str("10.0"),
+                    held: Currency::zero(),
+                    total: Currency::str("10.0"),
+                    locked: false,
+                },
+                AccountBalance {
+                    client: 2,
+                    available: Currency::str("5.0"),
+                    held: Currency::zero(),
+                    total: Currency::str("5.0"),
+                    locked: false,
+                },
+            ],
+            summaries
+        );
+    }
+
+    #[test]
+    fn it_should_fail_the_pipeline_on_upstream_errors_and_exceeded_limits() {
+        let handle = ExchangeHandle::new(Exchange::new().with_config(EngineConfig {
+            limits: Limits {
+                max_clients: Some(1),
+                ..Limits::default()
+            },
+            ..EngineConfig::default()
+        }));
+
+        let transactions = stream::iter(vec![
+            Ok(deposit(1, 1, "10.0")),
+            Err("unparsable row".into()),
+        ]);
+        let e = block_on(transactions.forward(handle.clone())).unwrap_err();
+        assert_eq!("unparsable row", e.to_string());
+
+        let transactions = stream::iter(vec![Ok(deposit(2, 2, "10.0"))]);
+        assert_eq!(
+            true,
+            block_on(transactions.forward(handle.clone())).is_err()
+        );
+        assert_eq!(None, handle.lock().client(2));
+    }
+}
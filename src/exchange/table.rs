@@ -0,0 +1,127 @@
+use std::io;
+
+/// ANSI colors table cells can be highlighted with
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Color {
+    Red,
+    Yellow,
+}
+
+impl Color {
+    fn code(&self) -> &'static str {
+        match self {
+            Color::Red => "\x1b[31m",
+            Color::Yellow => "\x1b[33m",
+        }
+    }
+}
+
+const RESET: &str = "\x1b[0m";
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Cell {
+    text: String,
+    color: Option<Color>,
+}
+
+impl Cell {
+    pub fn new(text: impl Into<String>) -> Cell {
+        Cell {
+            text: text.into(),
+            color: None,
+        }
+    }
+
+    pub fn colored(self, color: Option<Color>) -> Cell {
+        Cell { color, ..self }
+    }
+}
+
+/// Text table with every column right aligned to its widest cell, for people reading the output in a terminal. Rows are
+/// kept in memory until written, as the widths are only known once every row is in. Colors are left out unless asked for, so
+/// the table can also be piped to a file
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct Table {
+    header: Vec<String>,
+    rows: Vec<Vec<Cell>>,
+}
+
+impl Table {
+    pub fn new<'a>(header: impl IntoIterator<Item = &'a str>) -> Table {
+        Table {
+            header: header.into_iter().map(str::to_string).collect(),
+            rows: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, row: Vec<Cell>) {
+        self.rows.push(row);
+    }
+
+    pub fn write<W: io::Write>(&self, mut writer: W, colored: bool) -> io::Result<()> {
+        let width = |text: &str| text.chars().count();
+        let mut widths: Vec<usize> = self.header.iter().map(|title| width(title)).collect();
+        for row in &self.rows {
+            for (column, cell) in row.iter().enumerate() {
+                if column == widths.len() {
+                    widths.push(0);
+                }
+                widths[column] = widths[column].max(width(&cell.text));
+            }
+        }
+
+        let header: Vec<Cell> = self.header.iter().map(Cell::new).collect();
+        for row in std::iter::once(&header).chain(&self.rows) {
+            let mut line = String::new();
+            for (column, cell) in row.iter().enumerate() {
+                if column > 0 {
+                    line.push_str("  ");
+                }
+                let padding = widths[column] - width(&cell.text);
+                line.push_str(&" ".repeat(padding));
+                match cell.color.filter(|_| colored) {
+                    Some(color) => {
+                        line.push_str(color.code());
+                        line.push_str(&cell.text);
+                        line.push_str(RESET);
+                    }
+                    None => line.push_str(&cell.text),
+                }
+            }
+            writeln!(writer, "{}", line)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn it_should_align_columns_and_only_color_when_asked() {
+        let mut table = Table::new(["client", "total"]);
+        table.push(vec![Cell::new("1"), Cell::new("1\u{a0}000.5000")]);
+        table.push(vec![
+            Cell::new("12").colored(Some(Color::Red)),
+            Cell::new("-2.0000"),
+        ]);
+
+        let mut plain = Vec::new();
+        table.write(&mut plain, false).unwrap();
+        assert_eq!(
+            "client       total\n     1  1\u{a0}000.5000\n    12     -2.0000\n",
+            String::from_utf8(plain).unwrap()
+        );
+
+        let mut colored = Vec::new();
+        table.write(&mut colored, true).unwrap();
+        assert_eq!(
+            true,
+            String::from_utf8(colored)
+                .unwrap()
+                .contains("    \x1b[31m12\x1b[0m     -2.0000\n")
+        );
+    }
+}
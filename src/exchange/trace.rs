@@ -0,0 +1,203 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::exchange::client_profile::AccountBalance;
+use crate::exchange::transaction::Currency;
+use crate::exchange::transaction::Money;
+
+/// Metadata key of the trace id of a transaction, read from the input column of the same name or generated on submission
+pub const TRACE_ID: &str = "trace_id";
+
+/// Which transactions are traced, see EngineConfig::trace: a share of them picked by their trace id, so the same ids are
+/// traced on every run and every engine of a cluster, and the ids asked for by name whatever the rate
+#[derive(Debug, PartialEq, Clone)]
+pub struct TraceSampling {
+    /// Share of the trace ids sampled, between 0 and 1
+    pub rate: f64,
+    pub ids: HashSet<String>,
+}
+
+impl TraceSampling {
+    /// <rate>[:<trace id>,<trace id>...] e.g. 0.0001 or 0:abc-1, the rate between 0 and 1
+    pub fn parse(sampling: &str) -> Option<TraceSampling> {
+        let (rate, ids) = match sampling.split_once(':') {
+            Some((rate, ids)) => (rate, ids.split(',').map(str::to_string).collect()),
+            None => (sampling, HashSet::new()),
+        };
+        let rate: f64 = rate.parse().ok()?;
+        (0.0..=1.0)
+            .contains(&rate)
+            .then_some(TraceSampling { rate, ids })
+    }
+
+    pub fn sampled(&self, trace_id: &str) -> bool {
+        self.rate >= 1.0
+            || self.ids.contains(trace_id)
+            || (hash(trace_id) as f64 / u64::MAX as f64) < self.rate
+    }
+}
+
+/// FNV-1a with the finalizer of MurmurHash3 so the high bits of short ids are mixed too, stable across runs and platforms
+/// unlike the hasher of the std maps
+fn hash(value: &str) -> u64 {
+    let mut hash = value.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^ (hash >> 33)
+}
+
+/// Stage of the processing a trace line was written at
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TraceStage {
+    /// The transaction as submitted, enriched and rescaled
+    Submit,
+    /// Absorbed as a redelivery, see EngineConfig::dedup_window
+    Dedup,
+    /// Parked until the transaction it refers to comes, see EngineConfig::defer_disputes
+    Defer,
+    /// Accepted or rejected by the checks of the exchange and of the account
+    Validate,
+    /// Change of the account balance
+    Balance,
+    /// Change of the transactions retained for later disputes
+    Store,
+}
+
+impl fmt::Display for TraceStage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let stage = match self {
+            TraceStage::Submit => "submit",
+            TraceStage::Dedup => "dedup",
+            TraceStage::Defer => "defer",
+            TraceStage::Validate => "validate",
+            TraceStage::Balance => "balance",
+            TraceStage::Store => "store",
+        };
+        write!(f, "{}", stage)
+    }
+}
+
+/// trace <trace id> <stage>: <detail>
+pub fn line(trace_id: &str, stage: TraceStage, detail: &str) -> String {
+    format!("trace {} {}: {}", trace_id, stage, detail)
+}
+
+/// Write the trace line to stderr, where the rest of the diagnostics of a run go
+pub fn emit(trace_id: &str, stage: TraceStage, detail: &str) {
+    eprintln!("{}", line(trace_id, stage, detail));
+}
+
+/// available <before> -> <after>, held <before> -> <after>, total <before> -> <after>, with a client unknown before starting
+/// from nothing
+pub fn balance_delta(before: Option<&AccountBalance>, after: &AccountBalance) -> String {
+    let (available, held, total) = before.map_or_else(
+        || (Currency::zero(), Currency::zero(), Currency::zero()),
+        |before| (before.available, before.held, before.total),
+    );
+    let mut delta = format!(
+        "available {:.4} -> {:.4}, held {:.4} -> {:.4}, total {:.4} -> {:.4}",
+        available, after.available, held, after.held, total, after.total
+    );
+    if before.is_some_and(|before| before.locked) != after.locked {
+        delta.push_str(if after.locked {
+            ", locked"
+        } else {
+            ", unlocked"
+        });
+    }
+    delta
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use std::collections::HashMap;
+
+    use crate::exchange::config::EngineConfig;
+    use crate::exchange::transaction::Transaction;
+    use crate::exchange::transaction::Type;
+    use crate::exchange::Exchange;
+
+    #[test]
+    fn it_should_sample_the_same_trace_ids_on_every_run() {
+        let sampling = TraceSampling::parse("0.25:abc-1").unwrap();
+        let sampled: Vec<bool> = (0..1000)
+            .map(|i| sampling.sampled(&i.to_string()))
+            .collect();
+        assert_eq!(
+            sampled,
+            (0..1000)
+                .map(|i| sampling.sampled(&i.to_string()))
+                .collect::<Vec<_>>()
+        );
+        let count = sampled.iter().filter(|sampled| **sampled).count();
+        assert_eq!(true, (150..350).contains(&count));
+        assert_eq!(
+            true,
+            TraceSampling::parse("0:abc-1").unwrap().sampled("abc-1")
+        );
+        assert_eq!(false, TraceSampling::parse("0").unwrap().sampled("abc-1"));
+        assert_eq!(true, TraceSampling::parse("1").unwrap().sampled("abc-1"));
+        assert_eq!(None, TraceSampling::parse("1.5"));
+        assert_eq!(None, TraceSampling::parse("x:abc-1"));
+
+        assert_eq!(
+            "trace abc-1 balance: available 0.0000 -> 1.5000, held 0.0000 -> 0.0000, total 0.0000 -> 1.5000",
+            line(
+                "abc-1",
+                TraceStage::Balance,
+                &balance_delta(
+                    None,
+                    &AccountBalance {
+                        client: 1,
+                        available: Currency::str("1.5"),
+                        held: Currency::zero(),
+                        total: Currency::str("1.5"),
+                        locked: false,
+                    }
+                )
+            )
+        );
+    }
+
+    #[test]
+    fn it_should_give_every_transaction_a_trace_id_when_tracing() {
+        let mut exchange = Exchange::new().with_ledger().with_config(EngineConfig {
+            trace: TraceSampling::parse("0:abc-1"),
+            ..EngineConfig::default()
+        });
+        for (tx, trace_id) in [(1, Some("abc-1")), (2, None)] {
+            exchange
+                .process_new_transaction(Transaction {
+                    tx_type: Type::Deposit,
+                    client: 1,
+                    tx,
+                    amount: Some(Currency::str("1.0")),
+                    metadata: trace_id
+                        .map(|id| HashMap::from([(TRACE_ID.to_string(), id.to_string())]))
+                        .unwrap_or_default(),
+                    under_dispute: false,
+                    dispute_metadata: None,
+                })
+                .unwrap();
+        }
+        let trace_ids: Vec<Option<&String>> = exchange
+            .ledger()
+            .unwrap()
+            .entries()
+            .iter()
+            .map(|entry| entry.transaction().metadata.get(TRACE_ID))
+            .collect();
+        //the generated ids are the submission numbers
+        assert_eq!(
+            vec![Some(&"abc-1".to_string()), Some(&"2".to_string())],
+            trace_ids
+        );
+    }
+}
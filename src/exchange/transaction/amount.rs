@@ -0,0 +1,299 @@
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::Decimal;
+use serde::de;
+use serde::Deserialize;
+use serde::Deserializer;
+use serde::Serialize;
+use serde::Serializer;
+use std::fmt;
+use std::iter::Sum;
+use std::ops::Add;
+use std::ops::AddAssign;
+use std::ops::Div;
+use std::ops::Mul;
+use std::ops::Neg;
+use std::ops::Sub;
+use std::ops::SubAssign;
+use std::str::FromStr;
+
+use crate::exchange::transaction::signed_decimal;
+use crate::exchange::transaction::NegativeAmount;
+
+/// Amount backed by a rust_decimal Decimal, the Currency of the default build. Balances used to be bare Decimals, which any
+/// Decimal could be mixed with and which exposed the whole Decimal API: the newtype only offers what the engine uses, the
+/// same as minor_units::MinorUnits, so both Currencies stay interchangeable. Arithmetic operators panic on overflow like
+/// rust_decimal's, the checked_ variants return None instead. Decimal is still reachable through From and decimal() for the
+/// code converting to and from other representations
+#[derive(Default, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
+pub struct Amount(Decimal);
+
+impl Amount {
+    pub const ZERO: Amount = Amount(Decimal::ZERO);
+    pub const MAX: Amount = Amount(Decimal::MAX);
+
+    /// num * 10^-scale, like Decimal::new
+    pub fn new(num: i64, scale: u32) -> Amount {
+        Amount(Decimal::new(num, scale))
+    }
+
+    /// The amount unless it is negative
+    pub fn non_negative(self) -> Result<Amount, NegativeAmount> {
+        if self.is_sign_negative() && !self.is_zero() {
+            Err(NegativeAmount(self.to_string()))
+        } else {
+            Ok(self)
+        }
+    }
+
+    pub fn decimal(&self) -> Decimal {
+        self.0
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.0.is_zero()
+    }
+
+    pub fn is_sign_negative(&self) -> bool {
+        self.0.is_sign_negative()
+    }
+
+    pub fn abs(&self) -> Amount {
+        Amount(self.0.abs())
+    }
+
+    /// Round to dp decimals, half to even like Decimal::round_dp
+    pub fn round_dp(&self, dp: u32) -> Amount {
+        Amount(self.0.round_dp(dp))
+    }
+
+    /// Fix the number of decimals to scale, rounding like Decimal::rescale
+    pub fn rescale(&mut self, scale: u32) {
+        self.0.rescale(scale);
+    }
+
+    /// Drop the decimals
+    pub fn trunc(&self) -> Amount {
+        Amount(self.0.trunc())
+    }
+
+    pub fn checked_add(self, other: Amount) -> Option<Amount> {
+        self.0.checked_add(other.0).map(Amount)
+    }
+
+    pub fn checked_sub(self, other: Amount) -> Option<Amount> {
+        self.0.checked_sub(other.0).map(Amount)
+    }
+
+    pub fn checked_mul(self, other: Amount) -> Option<Amount> {
+        self.0.checked_mul(other.0).map(Amount)
+    }
+
+    pub fn checked_div(self, other: Amount) -> Option<Amount> {
+        self.0.checked_div(other.0).map(Amount)
+    }
+
+    /// The nearest float, for statistics only
+    pub fn to_f64(&self) -> f64 {
+        self.0.to_f64().unwrap_or(0.0)
+    }
+}
+
+impl From<Decimal> for Amount {
+    fn from(decimal: Decimal) -> Self {
+        Amount(decimal)
+    }
+}
+
+impl From<Amount> for Decimal {
+    fn from(amount: Amount) -> Self {
+        amount.0
+    }
+}
+
+macro_rules! from_integer {
+    ($($integer:ty),*) => {
+        $(
+            impl From<$integer> for Amount {
+                fn from(units: $integer) -> Self {
+                    Amount(Decimal::from(units))
+                }
+            }
+        )*
+    };
+}
+
+from_integer!(i32, i64, u16, u32, u64);
+
+impl FromStr for Amount {
+    type Err = rust_decimal::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Decimal::from_str(s.trim()).map(Amount)
+    }
+}
+
+/// The Decimal's own rendering, so messages quoting amounts read as they did with bare Decimals
+impl fmt::Debug for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&self.0, f)
+    }
+}
+
+/// `{}` writes the decimals the amount has, `{:.N}` exactly N
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+/// Amounts are read from strings in plain decimal notation, signed or not ("-1.5", see signed_decimal()), which keep every
+/// decimal, or from JSON numbers
+impl<'de> Deserialize<'de> for Amount {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl de::Visitor<'_> for Visitor {
+            type Value = Amount;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a decimal amount")
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<Amount, E> {
+                signed_decimal(value)
+                    .map_err(E::custom)?
+                    .parse()
+                    .map_err(E::custom)
+            }
+
+            fn visit_i64<E: de::Error>(self, value: i64) -> Result<Amount, E> {
+                Ok(Amount::from(value))
+            }
+
+            fn visit_u64<E: de::Error>(self, value: u64) -> Result<Amount, E> {
+                Ok(Amount::from(value))
+            }
+
+            fn visit_f64<E: de::Error>(self, value: f64) -> Result<Amount, E> {
+                self.visit_str(&value.to_string())
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
+/// Always as a string, so no decimal is lost on the way through JSON
+impl Serialize for Amount {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl Add for Amount {
+    type Output = Amount;
+
+    fn add(self, other: Amount) -> Amount {
+        Amount(self.0 + other.0)
+    }
+}
+
+impl Sub for Amount {
+    type Output = Amount;
+
+    fn sub(self, other: Amount) -> Amount {
+        Amount(self.0 - other.0)
+    }
+}
+
+impl AddAssign for Amount {
+    fn add_assign(&mut self, other: Amount) {
+        self.0 += other.0;
+    }
+}
+
+impl SubAssign for Amount {
+    fn sub_assign(&mut self, other: Amount) {
+        self.0 -= other.0;
+    }
+}
+
+impl Neg for Amount {
+    type Output = Amount;
+
+    fn neg(self) -> Amount {
+        Amount(-self.0)
+    }
+}
+
+impl Mul for Amount {
+    type Output = Amount;
+
+    fn mul(self, other: Amount) -> Amount {
+        Amount(self.0 * other.0)
+    }
+}
+
+impl Div for Amount {
+    type Output = Amount;
+
+    fn div(self, other: Amount) -> Amount {
+        Amount(self.0 / other.0)
+    }
+}
+
+impl Sum for Amount {
+    fn sum<I: Iterator<Item = Amount>>(iter: I) -> Amount {
+        iter.fold(Amount::ZERO, Add::add)
+    }
+}
+
+impl<'a> Sum<&'a Amount> for Amount {
+    fn sum<I: Iterator<Item = &'a Amount>>(iter: I) -> Amount {
+        iter.copied().sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn it_should_keep_the_decimal_behavior_behind_the_narrower_api() {
+        let amount: Amount = "1.50".parse().unwrap();
+        assert_eq!("1.50", amount.to_string());
+        assert_eq!("1.5000", format!("{:.4}", amount));
+        assert_eq!("Some(1.50)", format!("{:?}", Some(amount)));
+        assert_eq!(Amount::new(3, 0), amount * Amount::from(2));
+        assert_eq!(
+            None,
+            Amount::from(Decimal::MAX).checked_add(Amount::from(1))
+        );
+        assert_eq!(Some(Amount::new(5, 1)), amount.checked_sub(Amount::from(1)));
+
+        assert_eq!(Ok(amount), amount.non_negative());
+        assert_eq!(Ok(Amount::ZERO), Amount::ZERO.non_negative());
+        assert_eq!(
+            "Negative amount -1.50",
+            (-amount).non_negative().unwrap_err().to_string()
+        );
+
+        assert_eq!("\"1.50\"", serde_json::to_string(&amount).unwrap());
+        assert_eq!(amount, serde_json::from_str::<Amount>("\"1.50\"").unwrap());
+        assert_eq!(
+            Amount::from(2),
+            serde_json::from_str::<Amount>("2").unwrap()
+        );
+        assert_eq!(
+            Amount::new(25, 1),
+            serde_json::from_str::<Amount>("2.5").unwrap()
+        );
+        assert_eq!(true, serde_json::from_str::<Amount>("\"abc\"").is_err());
+        //negative balances are read back from checkpoints, unlike negative input amounts
+        assert_eq!(
+            -amount,
+            serde_json::from_str::<Amount>("\"-1.50\"").unwrap()
+        );
+    }
+}
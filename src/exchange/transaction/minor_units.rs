@@ -0,0 +1,444 @@
+use serde::de;
+use serde::Deserialize;
+use serde::Deserializer;
+use serde::Serialize;
+use serde::Serializer;
+use std::fmt;
+use std::iter::Sum;
+use std::ops::Add;
+use std::ops::AddAssign;
+use std::ops::Div;
+use std::ops::Mul;
+use std::ops::Neg;
+use std::ops::Sub;
+use std::ops::SubAssign;
+use std::str::FromStr;
+
+use crate::exchange::transaction::signed_decimal;
+use crate::exchange::transaction::NegativeAmount;
+
+/// Number of implied decimals, the precision the engine works at
+pub const SCALE: u32 = 4;
+const UNIT: i64 = 10_i64.pow(SCALE);
+
+/// Amount stored as an i64 count of 1/10000 units, the Currency of the minor-units feature. It only offers the part of the
+/// rust_decimal API the engine uses, with integer arithmetic throughout: parsing rounds to 4 decimals (half away from zero),
+/// and the operators, like rust_decimal's, panic on overflow while the checked_ variants return None. The range is about
+/// ±922 trillion, so balances, unlike with rust_decimal, can realistically overflow: the engine rejects those transactions
+#[derive(Default, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
+pub struct MinorUnits(i64);
+
+impl MinorUnits {
+    pub const ZERO: MinorUnits = MinorUnits(0);
+    pub const MAX: MinorUnits = MinorUnits(i64::MAX);
+
+    /// num * 10^-scale, like rust_decimal's Decimal::new, rounded to 4 decimals
+    pub fn new(num: i64, scale: u32) -> MinorUnits {
+        if scale <= SCALE {
+            MinorUnits(
+                num.checked_mul(10_i64.pow(SCALE - scale))
+                    .expect("Multiplication overflowed"),
+            )
+        } else {
+            MinorUnits(round_div(num, 10_i64.pow(scale - SCALE)))
+        }
+    }
+
+    pub fn from_minor_units(units: i64) -> MinorUnits {
+        MinorUnits(units)
+    }
+
+    pub fn minor_units(&self) -> i64 {
+        self.0
+    }
+
+    /// The amount unless it is negative
+    pub fn non_negative(self) -> Result<MinorUnits, NegativeAmount> {
+        if self.0 < 0 {
+            Err(NegativeAmount(self.to_string()))
+        } else {
+            Ok(self)
+        }
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn is_sign_negative(&self) -> bool {
+        self.0 < 0
+    }
+
+    pub fn abs(&self) -> MinorUnits {
+        MinorUnits(self.0.abs())
+    }
+
+    /// Round to dp decimals, half away from zero. Amounts never have more than 4
+    pub fn round_dp(&self, dp: u32) -> MinorUnits {
+        if dp >= SCALE {
+            return *self;
+        }
+        let step = 10_i64.pow(SCALE - dp);
+        MinorUnits(round_div(self.0, step) * step)
+    }
+
+    /// Round to scale decimals like rust_decimal's Decimal::rescale. There is no scale above 4 to extend to
+    pub fn rescale(&mut self, scale: u32) {
+        *self = self.round_dp(scale);
+    }
+
+    /// Drop the decimals
+    pub fn trunc(&self) -> MinorUnits {
+        MinorUnits(self.0 / UNIT * UNIT)
+    }
+
+    pub fn checked_add(self, other: MinorUnits) -> Option<MinorUnits> {
+        self.0.checked_add(other.0).map(MinorUnits)
+    }
+
+    pub fn checked_sub(self, other: MinorUnits) -> Option<MinorUnits> {
+        self.0.checked_sub(other.0).map(MinorUnits)
+    }
+
+    /// Rounded back to 4 decimals, see Mul
+    pub fn checked_mul(self, other: MinorUnits) -> Option<MinorUnits> {
+        let product = self.0 as i128 * other.0 as i128;
+        i64::try_from(round_div_wide(product, UNIT as i128))
+            .ok()
+            .map(MinorUnits)
+    }
+
+    /// Rounded back to 4 decimals, see Div. None when dividing by zero
+    pub fn checked_div(self, other: MinorUnits) -> Option<MinorUnits> {
+        if other.0 == 0 {
+            return None;
+        }
+        let (dividend, divisor) = (self.0 as i128 * UNIT as i128, other.0 as i128);
+        i64::try_from(round_div_wide(dividend * divisor.signum(), divisor.abs()))
+            .ok()
+            .map(MinorUnits)
+    }
+
+    /// The nearest float, for statistics only
+    pub fn to_f64(&self) -> f64 {
+        self.0 as f64 / UNIT as f64
+    }
+}
+
+/// a / b rounded half away from zero, b > 0
+fn round_div(a: i64, b: i64) -> i64 {
+    let (quotient, remainder) = (a / b, a % b);
+    if remainder.abs() * 2 >= b {
+        quotient + a.signum()
+    } else {
+        quotient
+    }
+}
+
+impl From<i64> for MinorUnits {
+    fn from(units: i64) -> Self {
+        MinorUnits(units.checked_mul(UNIT).expect("Multiplication overflowed"))
+    }
+}
+
+impl From<i32> for MinorUnits {
+    fn from(units: i32) -> Self {
+        MinorUnits::from(units as i64)
+    }
+}
+
+impl From<u32> for MinorUnits {
+    fn from(units: u32) -> Self {
+        MinorUnits::from(units as i64)
+    }
+}
+
+impl From<u64> for MinorUnits {
+    fn from(units: u64) -> Self {
+        MinorUnits::from(i64::try_from(units).expect("Conversion overflowed"))
+    }
+}
+
+impl From<u16> for MinorUnits {
+    fn from(units: u16) -> Self {
+        MinorUnits::from(units as i64)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ParseMinorUnitsError(String);
+
+impl fmt::Display for ParseMinorUnitsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid amount '{}'", self.0)
+    }
+}
+
+impl std::error::Error for ParseMinorUnitsError {}
+
+impl FromStr for MinorUnits {
+    type Err = ParseMinorUnitsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ParseMinorUnitsError(s.to_string());
+        let (negative, digits) = match s.trim().strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, s.trim().strip_prefix('+').unwrap_or(s.trim())),
+        };
+        let (whole, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+        let is_digits = |part: &str| part.bytes().all(|b| b.is_ascii_digit());
+        if (whole.is_empty() && fraction.is_empty()) || !is_digits(whole) || !is_digits(fraction) {
+            return Err(invalid());
+        }
+
+        let whole: i64 = if whole.is_empty() {
+            0
+        } else {
+            whole.parse().map_err(|_| invalid())?
+        };
+        //the first 4 decimals, padded, and the 5th to round with
+        let mut decimals = fraction.bytes().map(|b| (b - b'0') as i64);
+        let mut units = 0;
+        for _ in 0..SCALE {
+            units = units * 10 + decimals.next().unwrap_or(0);
+        }
+        if decimals.next().is_some_and(|digit| digit >= 5) {
+            units += 1;
+        }
+        let units = whole
+            .checked_mul(UNIT)
+            .and_then(|whole| whole.checked_add(units))
+            .ok_or_else(invalid)?;
+        Ok(MinorUnits(if negative { -units } else { units }))
+    }
+}
+
+/// The amount as Display writes it, like rust_decimal's Debug
+impl fmt::Debug for MinorUnits {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+/// Like rust_decimal, `{}` writes as few decimals as needed and `{:.N}` exactly N (rounded half away from zero when N < 4)
+impl fmt::Display for MinorUnits {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (value, decimals) = match f.precision() {
+            Some(precision) => (self.round_dp(precision as u32), precision),
+            None => (*self, 0),
+        };
+        let sign = if value.0 < 0 { "-" } else { "" };
+        let (whole, fraction) = (
+            value.0.unsigned_abs() / UNIT as u64,
+            value.0.unsigned_abs() % UNIT as u64,
+        );
+        let mut fraction = format!("{:04}", fraction);
+        match f.precision() {
+            Some(_) => {
+                fraction.truncate(decimals.min(SCALE as usize));
+                while fraction.len() < decimals {
+                    fraction.push('0');
+                }
+            }
+            None => {
+                while fraction.ends_with('0') {
+                    fraction.pop();
+                }
+            }
+        }
+        if fraction.is_empty() {
+            write!(f, "{}{}", sign, whole)
+        } else {
+            write!(f, "{}{}.{}", sign, whole, fraction)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for MinorUnits {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl de::Visitor<'_> for Visitor {
+            type Value = MinorUnits;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "an amount with up to 4 decimals")
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<MinorUnits, E> {
+                signed_decimal(value)
+                    .map_err(E::custom)?
+                    .parse()
+                    .map_err(E::custom)
+            }
+
+            fn visit_i64<E: de::Error>(self, value: i64) -> Result<MinorUnits, E> {
+                value
+                    .checked_mul(UNIT)
+                    .map(MinorUnits)
+                    .ok_or_else(|| E::custom("amount out of range"))
+            }
+
+            fn visit_u64<E: de::Error>(self, value: u64) -> Result<MinorUnits, E> {
+                i64::try_from(value)
+                    .map_err(|_| E::custom("amount out of range"))
+                    .and_then(|value| self.visit_i64(value))
+            }
+
+            fn visit_f64<E: de::Error>(self, value: f64) -> Result<MinorUnits, E> {
+                self.visit_str(&value.to_string())
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
+/// As a string, like rust_decimal
+impl Serialize for MinorUnits {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl Add for MinorUnits {
+    type Output = MinorUnits;
+
+    fn add(self, other: MinorUnits) -> MinorUnits {
+        MinorUnits(self.0.checked_add(other.0).expect("Addition overflowed"))
+    }
+}
+
+impl Sub for MinorUnits {
+    type Output = MinorUnits;
+
+    fn sub(self, other: MinorUnits) -> MinorUnits {
+        MinorUnits(self.0.checked_sub(other.0).expect("Subtraction overflowed"))
+    }
+}
+
+impl AddAssign for MinorUnits {
+    fn add_assign(&mut self, other: MinorUnits) {
+        *self = *self + other;
+    }
+}
+
+impl SubAssign for MinorUnits {
+    fn sub_assign(&mut self, other: MinorUnits) {
+        *self = *self - other;
+    }
+}
+
+impl Neg for MinorUnits {
+    type Output = MinorUnits;
+
+    fn neg(self) -> MinorUnits {
+        MinorUnits(-self.0)
+    }
+}
+
+/// Products and quotients are rounded back to 4 decimals, half away from zero
+impl Mul for MinorUnits {
+    type Output = MinorUnits;
+
+    fn mul(self, other: MinorUnits) -> MinorUnits {
+        self.checked_mul(other).expect("Multiplication overflowed")
+    }
+}
+
+impl Div for MinorUnits {
+    type Output = MinorUnits;
+
+    fn div(self, other: MinorUnits) -> MinorUnits {
+        assert!(other.0 != 0, "Division by zero");
+        self.checked_div(other).expect("Division overflowed")
+    }
+}
+
+fn round_div_wide(a: i128, b: i128) -> i128 {
+    let (quotient, remainder) = (a / b, a % b);
+    if remainder.abs() * 2 >= b {
+        quotient + a.signum()
+    } else {
+        quotient
+    }
+}
+
+impl Sum for MinorUnits {
+    fn sum<I: Iterator<Item = MinorUnits>>(iter: I) -> MinorUnits {
+        iter.fold(MinorUnits::ZERO, Add::add)
+    }
+}
+
+impl<'a> Sum<&'a MinorUnits> for MinorUnits {
+    fn sum<I: Iterator<Item = &'a MinorUnits>>(iter: I) -> MinorUnits {
+        iter.copied().sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn it_should_parse_amounts_rounding_to_4_decimals() {
+        assert_eq!(Ok(MinorUnits(15_000)), "1.5".parse());
+        assert_eq!(Ok(MinorUnits(-2)), "-0.0002".parse());
+        assert_eq!(Ok(MinorUnits(10_000)), "1".parse());
+        assert_eq!(Ok(MinorUnits(5_000)), ".5".parse());
+        assert_eq!(Ok(MinorUnits(1)), "0.00005".parse());
+        assert_eq!(Ok(MinorUnits(0)), "0.00004".parse());
+        assert_eq!(true, "".parse::<MinorUnits>().is_err());
+        assert_eq!(true, "1.2.3".parse::<MinorUnits>().is_err());
+        assert_eq!(true, "abc".parse::<MinorUnits>().is_err());
+        assert_eq!(true, "99999999999999999".parse::<MinorUnits>().is_err());
+    }
+
+    #[test]
+    fn it_should_display_like_rust_decimal() {
+        assert_eq!("1.5", MinorUnits(15_000).to_string());
+        assert_eq!("10", MinorUnits(100_000).to_string());
+        assert_eq!("1.5000", format!("{:.4}", MinorUnits(15_000)));
+        assert_eq!("-0.0002", format!("{:.4}", MinorUnits(-2)));
+        assert_eq!("1.57", format!("{:.2}", MinorUnits(15_650)));
+        assert_eq!("2.000000", format!("{:.6}", MinorUnits(20_000)));
+        assert_eq!("Some(1.5)", format!("{:?}", Some(MinorUnits(15_000))));
+    }
+
+    #[test]
+    fn it_should_round_products_and_quotients_to_4_decimals() {
+        let amount: MinorUnits = "10.0".parse().unwrap();
+        assert_eq!(MinorUnits(10_000), amount / MinorUnits::from(10));
+        assert_eq!(MinorUnits(3_333), MinorUnits::from(1) / MinorUnits::from(3));
+        assert_eq!(
+            MinorUnits(-6_667),
+            MinorUnits::from(-2) / MinorUnits::from(3)
+        );
+        assert_eq!(MinorUnits(200_000), amount * MinorUnits::from(2));
+        assert_eq!(MinorUnits(1), MinorUnits(1) * MinorUnits(5_000));
+        assert_eq!(MinorUnits(20_000), MinorUnits(15_000).round_dp(0));
+        assert_eq!(MinorUnits(10_000), MinorUnits(15_000).trunc());
+        assert_eq!(MinorUnits(12_300), MinorUnits::new(123, 2));
+        assert_eq!(MinorUnits(2), MinorUnits::new(15, 5));
+    }
+
+    #[test]
+    fn it_should_return_none_from_checked_operations_instead_of_panicking() {
+        let max = MinorUnits(i64::MAX);
+        assert_eq!(None, max.checked_add(MinorUnits(1)));
+        assert_eq!(None, MinorUnits(i64::MIN).checked_sub(MinorUnits(1)));
+        assert_eq!(None, max.checked_mul(MinorUnits::from(2)));
+        assert_eq!(None, MinorUnits(1).checked_div(MinorUnits(0)));
+        assert_eq!(
+            Some(MinorUnits(3_333)),
+            MinorUnits::from(1).checked_div(MinorUnits::from(3))
+        );
+        assert_eq!(1.5, MinorUnits(15_000).to_f64());
+        assert_eq!(Ok(MinorUnits(0)), MinorUnits(0).non_negative());
+        assert_eq!(
+            "Negative amount -0.0002",
+            MinorUnits(-2).non_negative().unwrap_err().to_string()
+        );
+    }
+}
@@ -1,26 +1,209 @@
 use serde::Deserialize;
+use serde::Deserializer;
+use serde::Serialize;
+use serde::Serializer;
+use std::collections::HashMap;
 use std::fmt;
+use std::iter::Sum;
+use std::ops::Add;
+use std::ops::AddAssign;
+use std::ops::Div;
+use std::ops::Mul;
+use std::ops::Neg;
+use std::ops::Sub;
+use std::ops::SubAssign;
 use std::str::FromStr;
 
-/// Using rust_decimal to handle fixed precision decimals with no round-off errors. rust decimal was wrapped around a small library so it can be changed easily if needed
-pub type Currency = rust_decimal::Decimal;
+use crate::exchange::pseudonym::Mention;
+
+#[cfg(not(feature = "minor-units"))]
+pub mod amount;
+#[cfg(feature = "minor-units")]
+pub mod minor_units;
+pub mod schema;
+
+/// Using rust_decimal to handle fixed precision decimals with no round-off errors. rust decimal was wrapped in a newtype (amount::Amount) so it can be changed easily if needed, and no bare Decimal is mixed with amounts
+#[cfg(not(feature = "minor-units"))]
+pub type Currency = amount::Amount;
+/// With the minor-units feature amounts are i64 counts of 1/10000 units instead, much cheaper to add up than rust_decimal
+#[cfg(feature = "minor-units")]
+pub type Currency = minor_units::MinorUnits;
 
 pub type ClientId = u16;
 
+/// A negative amount where only non-negative ones are accepted, e.g. a reserve or a cap, see Currency::non_negative()
+#[derive(Debug, PartialEq, Clone)]
+pub struct NegativeAmount(pub String);
+
+impl fmt::Display for NegativeAmount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Negative amount {}", self.0)
+    }
+}
+
+impl std::error::Error for NegativeAmount {}
+
+/// An input amount that is not in plain decimal notation (see plain_decimal()) or is out of the AmountBounds
+#[derive(Debug, PartialEq, Clone)]
+pub struct InvalidAmount(pub String);
+
+impl fmt::Display for InvalidAmount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidAmount {}
+
+/// The amount, trimmed, if it is written in plain decimal notation: digits and at most one decimal point, e.g. 12.50 or .5.
+/// rust_decimal also reads exponents (1e3) and digit separators (1_000), which only ever come from an upstream bug, and signs,
+/// which no input amount has: the amounts of the input records go through this before parsing, so a negative deposit fails its
+/// record instead of moving the funds the other way
+pub fn plain_decimal(amount: &str) -> Result<&str, InvalidAmount> {
+    let decimal = signed_decimal(amount)?;
+    let reason = match decimal.chars().next() {
+        Some('-') => "negative amounts are not accepted",
+        Some('+') => "signed amounts are not accepted",
+        _ => return Ok(decimal),
+    };
+    Err(InvalidAmount(format!(
+        "Invalid amount '{}': {}",
+        amount, reason
+    )))
+}
+
+/// plain_decimal() with an optional sign, e.g. -12.50: what the deserializers of both Currencies accept, as the balances they
+/// read back from checkpoints and archives can be negative
+pub fn signed_decimal(amount: &str) -> Result<&str, InvalidAmount> {
+    let trimmed = amount.trim();
+    let digits = trimmed.strip_prefix(['-', '+']).unwrap_or(trimmed);
+    let invalid = |reason: &str| {
+        Err(InvalidAmount(format!(
+            "Invalid amount '{}': {}",
+            amount, reason
+        )))
+    };
+    if digits.contains(['e', 'E']) && digits.starts_with(|c: char| c.is_ascii_digit() || c == '.') {
+        return invalid("exponent notation is not accepted");
+    }
+    if digits.contains('_') {
+        return invalid("digit separators are not accepted");
+    }
+    let (whole, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+    let is_digits = |part: &str| part.bytes().all(|b| b.is_ascii_digit());
+    if (whole.is_empty() && fraction.is_empty()) || !is_digits(whole) || !is_digits(fraction) {
+        return invalid("not a plain decimal number");
+    }
+    Ok(trimmed)
+}
+
+/// Bounds every input amount must be within, both included, checked as records are read (IngestOptions::amount_bounds): an
+/// amount out of them fails the record like an amount that can not be read, instead of being processed. None is unbounded
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct AmountBounds {
+    pub min: Option<Currency>,
+    pub max: Option<Currency>,
+}
+
+impl AmountBounds {
+    /// <min>:<max>, either side left empty when unbounded e.g. 0.0001:1000000 or :1000000, min not above max
+    pub fn parse(bounds: &str) -> Option<AmountBounds> {
+        let (min, max) = bounds.split_once(':')?;
+        let bound = |amount: &str| -> Option<Option<Currency>> {
+            match amount {
+                "" => Some(None),
+                amount => Some(Some(plain_decimal(amount).ok()?.parse().ok()?)),
+            }
+        };
+        let bounds = AmountBounds {
+            min: bound(min)?,
+            max: bound(max)?,
+        };
+        match (bounds.min, bounds.max) {
+            (Some(min), Some(max)) if min > max => None,
+            _ => Some(bounds),
+        }
+    }
+
+    pub fn check(&self, tx: TransactionId, amount: Currency) -> Result<(), InvalidAmount> {
+        match (self.min, self.max) {
+            (Some(min), _) if amount < min => Err(InvalidAmount(format!(
+                "Amount {} of tx {} is below the minimum of {}",
+                amount, tx, min
+            ))),
+            (_, Some(max)) if amount > max => Err(InvalidAmount(format!(
+                "Amount {} of tx {} is above the maximum of {}",
+                amount, tx, max
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
 pub type TransactionId = u32;
 
-#[derive(Debug, Deserialize, PartialEq, Clone)]
-#[serde(rename_all = "lowercase")]
+/// Hasher of the hot maps keyed by ClientId/TransactionId. The default SipHash protects against crafted keys, which integer ids
+/// from our own upstream systems do not need: with the fast-hash feature the much cheaper FxHash is used instead
+#[cfg(feature = "fast-hash")]
+pub type IdHasher = rustc_hash::FxBuildHasher;
+#[cfg(not(feature = "fast-hash"))]
+pub type IdHasher = std::collections::hash_map::RandomState;
+
+pub type IdMap<K, V> = HashMap<K, V, IdHasher>;
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub enum Type {
     Deposit,
     Withdrawal,
     Dispute,
     Resolve,
     Chargeback,
+    /// Any other type string, handled by the CustomTransactionHandler registered for it (see Exchange::with_handler)
+    Custom(String),
+}
+
+impl Type {
+    pub fn from_name(name: &str) -> Type {
+        match name {
+            "deposit" => Type::Deposit,
+            "withdrawal" => Type::Withdrawal,
+            "dispute" => Type::Dispute,
+            "resolve" => Type::Resolve,
+            "chargeback" => Type::Chargeback,
+            custom => Type::Custom(custom.to_string()),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Type {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(Type::from_name(&String::deserialize(deserializer)?))
+    }
+}
+
+impl Serialize for Type {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Type::Deposit => "deposit",
+            Type::Withdrawal => "withdrawal",
+            Type::Dispute => "dispute",
+            Type::Resolve => "resolve",
+            Type::Chargeback => "chargeback",
+            Type::Custom(name) => name,
+        };
+        write!(f, "{}", name)
+    }
 }
+
 ///Instead of a general Transaction struct with an Enum specifying its type, a possible alternative could have been top level Transaction enum
 ///where each value of the enum would be a different type of transaction:
-/// ```
+/// ```ignore
 /// struct BaseTransaction {
 ///     client: ClientId
 ///     id: TransactionId
@@ -39,18 +222,64 @@ pub enum Type {
 /// }
 /// ```
 /// BaseTransaction would have the common fields for all types of transactions (client, tx id) and MoneyTransaction would be composed by BaseTransaction and a amount field
-#[derive(Debug, Deserialize, PartialEq, Clone)]
+///
+/// This is the canonical representation the engine works with. Input files are deserialized into the struct of their schema
+/// version (see schema::SchemaVersion) and converted into it, so the wire format can evolve without touching the engine
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct Transaction {
-    #[serde(rename(deserialize = "type"))]
     pub(crate) tx_type: Type,
     pub(crate) client: ClientId,
     pub(crate) tx: TransactionId,
     pub(crate) amount: Option<Currency>,
-    #[serde(skip)]
+    /// Extra input columns the engine does not know about, only captured when asked for (IngestOptions::capture_metadata)
+    /// and passed through untouched to the ledger, so upstream correlation ids survive the engine
+    pub(crate) metadata: HashMap<String, String>,
     pub(crate) under_dispute: bool,
+    /// On a stored transaction it holds the metadata gathered across the dispute lifecycle, kept after the dispute is resolved or charged back
+    pub(crate) dispute_metadata: Option<DisputeMetadata>,
 }
 
 impl Transaction {
+    pub fn tx_type(&self) -> &Type {
+        &self.tx_type
+    }
+
+    pub fn client(&self) -> ClientId {
+        self.client
+    }
+
+    pub fn tx(&self) -> TransactionId {
+        self.tx
+    }
+
+    pub fn amount(&self) -> Option<Currency> {
+        self.amount
+    }
+
+    pub fn metadata(&self) -> &HashMap<String, String> {
+        &self.metadata
+    }
+
+    pub fn metadata_mut(&mut self) -> &mut HashMap<String, String> {
+        &mut self.metadata
+    }
+
+    pub fn set_client(&mut self, client: ClientId) {
+        self.client = client;
+    }
+
+    pub fn set_tx(&mut self, tx: TransactionId) {
+        self.tx = tx;
+    }
+
+    pub fn under_dispute(&self) -> bool {
+        self.under_dispute
+    }
+
+    pub fn dispute_metadata(&self) -> Option<&DisputeMetadata> {
+        self.dispute_metadata.as_ref()
+    }
+
     pub fn start_dispute(&mut self) {
         self.under_dispute = true;
     }
@@ -58,6 +287,119 @@ impl Transaction {
     pub fn stop_dispute(&mut self) {
         self.under_dispute = false;
     }
+
+    /// Fields present on a dispute, resolve or chargeback row take precedence over the ones collected so far
+    pub fn annotate_dispute(&mut self, metadata: Option<DisputeMetadata>) {
+        if let Some(metadata) = metadata {
+            match &mut self.dispute_metadata {
+                Some(existing) => existing.merge(metadata),
+                None => self.dispute_metadata = Some(metadata),
+            }
+        }
+    }
+}
+
+/// Reason code of a chargeback, read from the reason column of the chargeback row or, when absent, of the dispute it closes.
+/// Rows without a reason or with an unrecognised code are Unspecified and keep the default behaviour of locking the account
+#[derive(Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Clone, Copy, Serialize, Deserialize)]
+pub enum ChargebackReason {
+    Fraud,
+    GoodsNotReceived,
+    Duplicate,
+    Unspecified,
+}
+
+impl ChargebackReason {
+    pub const ALL: [ChargebackReason; 4] = [
+        ChargebackReason::Fraud,
+        ChargebackReason::GoodsNotReceived,
+        ChargebackReason::Duplicate,
+        ChargebackReason::Unspecified,
+    ];
+
+    pub fn from_code(code: Option<&str>) -> ChargebackReason {
+        match code.map(|c| c.trim().to_lowercase()).as_deref() {
+            Some("fraud") => ChargebackReason::Fraud,
+            Some("goods-not-received") => ChargebackReason::GoodsNotReceived,
+            Some("duplicate") => ChargebackReason::Duplicate,
+            _ => ChargebackReason::Unspecified,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            ChargebackReason::Fraud => "fraud",
+            ChargebackReason::GoodsNotReceived => "goods-not-received",
+            ChargebackReason::Duplicate => "duplicate",
+            ChargebackReason::Unspecified => "unspecified",
+        }
+    }
+
+    /// Only fraud (and chargebacks without a usable reason) lock the account, the other reasons just reverse the funds
+    pub fn locks_account(&self) -> bool {
+        matches!(
+            self,
+            ChargebackReason::Fraud | ChargebackReason::Unspecified
+        )
+    }
+}
+
+impl fmt::Display for ChargebackReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.code())
+    }
+}
+
+/// Optional evidence attached to dispute, resolve and chargeback rows through the reason, case_id and note columns
+#[derive(Debug, PartialEq, Clone, Default, Serialize, Deserialize)]
+pub struct DisputeMetadata {
+    pub(crate) reason: Option<String>,
+    pub(crate) case_id: Option<String>,
+    pub(crate) note: Option<String>,
+}
+
+impl DisputeMetadata {
+    pub const COLUMNS: [&'static str; 3] = ["reason", "case_id", "note"];
+
+    pub fn new(
+        reason: Option<String>,
+        case_id: Option<String>,
+        note: Option<String>,
+    ) -> DisputeMetadata {
+        DisputeMetadata {
+            reason,
+            case_id,
+            note,
+        }
+    }
+
+    pub fn reason(&self) -> Option<&str> {
+        self.reason.as_deref()
+    }
+
+    pub fn case_id(&self) -> Option<&str> {
+        self.case_id.as_deref()
+    }
+
+    pub fn note(&self) -> Option<&str> {
+        self.note.as_deref()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.reason.is_none() && self.case_id.is_none() && self.note.is_none()
+    }
+
+    fn merge(&mut self, other: DisputeMetadata) {
+        if other.reason.is_some() {
+            self.reason = other.reason;
+        }
+        if other.case_id.is_some() {
+            self.case_id = other.case_id;
+        }
+        if other.note.is_some() {
+            self.note = other.note;
+        }
+    }
 }
 
 impl fmt::Display for Transaction {
@@ -71,18 +413,111 @@ impl fmt::Display for Transaction {
     }
 }
 
-//assume that all transactions are in the same currency
-pub trait Money {
-    fn zero() -> Currency;
-    fn str(m: &str) -> Currency;
+impl Transaction {
+    /// The transaction as Display writes it, with the client written through Mention, for the reasons of the engine's errors
+    pub fn mention(&self) -> String {
+        format!(
+            "{:?},{},{},{:?},{}",
+            self.tx_type,
+            Mention(self.client),
+            self.tx,
+            self.amount,
+            self.under_dispute
+        )
+    }
+}
+
+/// What the engine needs of an amount, implemented by both backends: amount::Amount (rust_decimal) and, with the
+/// minor-units feature, minor_units::MinorUnits. The engine is generic over it through Currency, the backend the build
+/// selects, rather than through a type parameter on every account, event and report: only one backend is compiled at a time.
+/// The operators panic on overflow, so balances are only changed through the checked_ operations, and a transaction that
+/// would overflow them is rejected. All transactions are assumed to be in the same currency
+pub trait Money:
+    Copy
+    + Default
+    + Ord
+    + fmt::Debug
+    + fmt::Display
+    + FromStr
+    + Serialize
+    + for<'de> Deserialize<'de>
+    + Add<Output = Self>
+    + Sub<Output = Self>
+    + Mul<Output = Self>
+    + Div<Output = Self>
+    + Neg<Output = Self>
+    + AddAssign
+    + SubAssign
+    + Sum
+    + From<i64>
+{
+    /// The largest amount the backend holds
+    const MAX: Self;
+
+    fn zero() -> Self;
+    /// Parse an amount, panicking on an invalid one
+    fn str(m: &str) -> Self;
+    fn checked_add(self, other: Self) -> Option<Self>;
+    fn checked_sub(self, other: Self) -> Option<Self>;
+    fn checked_mul(self, other: Self) -> Option<Self>;
+    /// None on a division by zero as well
+    fn checked_div(self, other: Self) -> Option<Self>;
 }
 
-impl Money for Currency {
-    fn zero() -> Currency {
-        Currency::new(0, 4)
+#[cfg(not(feature = "minor-units"))]
+impl Money for amount::Amount {
+    const MAX: Self = amount::Amount::MAX;
+
+    fn zero() -> Self {
+        amount::Amount::new(0, 4)
+    }
+
+    fn str(m: &str) -> Self {
+        amount::Amount::from_str(m).unwrap()
+    }
+
+    fn checked_add(self, other: Self) -> Option<Self> {
+        amount::Amount::checked_add(self, other)
+    }
+
+    fn checked_sub(self, other: Self) -> Option<Self> {
+        amount::Amount::checked_sub(self, other)
+    }
+
+    fn checked_mul(self, other: Self) -> Option<Self> {
+        amount::Amount::checked_mul(self, other)
+    }
+
+    fn checked_div(self, other: Self) -> Option<Self> {
+        amount::Amount::checked_div(self, other)
+    }
+}
+
+#[cfg(feature = "minor-units")]
+impl Money for minor_units::MinorUnits {
+    const MAX: Self = minor_units::MinorUnits::MAX;
+
+    fn zero() -> Self {
+        minor_units::MinorUnits::ZERO
+    }
+
+    fn str(m: &str) -> Self {
+        minor_units::MinorUnits::from_str(m).unwrap()
+    }
+
+    fn checked_add(self, other: Self) -> Option<Self> {
+        minor_units::MinorUnits::checked_add(self, other)
+    }
+
+    fn checked_sub(self, other: Self) -> Option<Self> {
+        minor_units::MinorUnits::checked_sub(self, other)
+    }
+
+    fn checked_mul(self, other: Self) -> Option<Self> {
+        minor_units::MinorUnits::checked_mul(self, other)
     }
 
-    fn str(m: &str) -> Currency {
-        Currency::from_str(m).unwrap()
+    fn checked_div(self, other: Self) -> Option<Self> {
+        minor_units::MinorUnits::checked_div(self, other)
     }
 }
@@ -0,0 +1,294 @@
+use serde::de;
+use serde::Deserialize;
+use serde::Deserializer;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::exchange::transaction::plain_decimal;
+use crate::exchange::transaction::ClientId;
+use crate::exchange::transaction::Currency;
+use crate::exchange::transaction::DisputeMetadata;
+use crate::exchange::transaction::Transaction;
+use crate::exchange::transaction::TransactionId;
+use crate::exchange::transaction::Type;
+
+/// Versions of the input CSV layout. Each version deserializes into its own wire struct which is then converted into the
+/// canonical Transaction, so files written for an older version keep working as columns are added
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum SchemaVersion {
+    /// type,client,tx,amount
+    V1,
+    /// V1 plus the optional dispute metadata columns reason,case_id,note
+    V2,
+}
+
+impl SchemaVersion {
+    pub const LATEST: SchemaVersion = SchemaVersion::V2;
+
+    /// The newest version whose distinguishing columns show up in the headers; files with only the original columns are V1
+    pub fn detect(headers: &csv::StringRecord) -> SchemaVersion {
+        if headers
+            .iter()
+            .any(|header| DisputeMetadata::COLUMNS.contains(&header.trim()))
+        {
+            SchemaVersion::V2
+        } else {
+            SchemaVersion::V1
+        }
+    }
+
+    pub fn columns(&self) -> &'static [&'static str] {
+        match self {
+            SchemaVersion::V1 => &["type", "client", "tx", "amount"],
+            SchemaVersion::V2 => &[
+                "type", "client", "tx", "amount", "reason", "case_id", "note",
+            ],
+        }
+    }
+
+    /// Position and name of the header columns this version does not know about
+    pub fn extra_columns(&self, headers: &csv::StringRecord) -> Vec<(usize, String)> {
+        headers
+            .iter()
+            .enumerate()
+            .filter(|(_, header)| !self.columns().contains(&header.trim()))
+            .map(|(index, header)| (index, header.trim().to_string()))
+            .collect()
+    }
+
+    pub fn read(
+        &self,
+        record: &csv::StringRecord,
+        headers: &csv::StringRecord,
+    ) -> Result<Transaction, csv::Error> {
+        match self {
+            SchemaVersion::V1 => record
+                .deserialize::<TransactionV1>(Some(headers))
+                .map(Transaction::from),
+            SchemaVersion::V2 => record
+                .deserialize::<TransactionV2>(Some(headers))
+                .map(Transaction::from),
+        }
+    }
+}
+
+impl FromStr for SchemaVersion {
+    type Err = String;
+
+    fn from_str(version: &str) -> Result<Self, Self::Err> {
+        match version.trim_start_matches(['v', 'V']) {
+            "1" => Ok(SchemaVersion::V1),
+            "2" => Ok(SchemaVersion::V2),
+            _ => Err(format!("Unknown input schema version '{}'", version)),
+        }
+    }
+}
+
+impl fmt::Display for SchemaVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SchemaVersion::V1 => write!(f, "v1"),
+            SchemaVersion::V2 => write!(f, "v2"),
+        }
+    }
+}
+
+/// The amount column as it is written, which must be in plain decimal notation (see transaction::plain_decimal). It is read as
+/// a string: the csv deserializer hands Currency's own deserializer the numbers it recognizes already parsed, 1e3 as the float
+/// 1000
+fn amount<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Currency>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|amount| {
+            plain_decimal(&amount)
+                .map_err(de::Error::custom)?
+                .parse()
+                .map_err(de::Error::custom)
+        })
+        .transpose()
+}
+
+#[derive(Debug, Deserialize)]
+struct TransactionV1 {
+    #[serde(rename = "type")]
+    tx_type: Type,
+    client: ClientId,
+    tx: TransactionId,
+    #[serde(default, deserialize_with = "amount")]
+    amount: Option<Currency>,
+}
+
+impl From<TransactionV1> for Transaction {
+    fn from(row: TransactionV1) -> Self {
+        Transaction {
+            tx_type: row.tx_type,
+            client: row.client,
+            tx: row.tx,
+            amount: row.amount,
+            metadata: HashMap::new(),
+            under_dispute: false,
+            dispute_metadata: None,
+        }
+    }
+}
+
+/// Missing columns and empty fields are both read as None, and a row without any of them has no metadata
+#[derive(Debug, Deserialize)]
+struct TransactionV2 {
+    #[serde(rename = "type")]
+    tx_type: Type,
+    client: ClientId,
+    tx: TransactionId,
+    #[serde(default, deserialize_with = "amount")]
+    amount: Option<Currency>,
+    reason: Option<String>,
+    case_id: Option<String>,
+    note: Option<String>,
+}
+
+impl From<TransactionV2> for Transaction {
+    fn from(row: TransactionV2) -> Self {
+        let metadata = DisputeMetadata::new(row.reason, row.case_id, row.note);
+        Transaction {
+            tx_type: row.tx_type,
+            client: row.client,
+            tx: row.tx,
+            amount: row.amount,
+            metadata: HashMap::new(),
+            under_dispute: false,
+            dispute_metadata: Some(metadata).filter(|m| !m.is_empty()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use crate::exchange::transaction::AmountBounds;
+    use crate::exchange::transaction::Money;
+
+    #[test]
+    fn it_should_detect_the_schema_version_from_headers() {
+        assert_eq!(
+            SchemaVersion::V1,
+            SchemaVersion::detect(&csv::StringRecord::from(vec![
+                "type", "client", "tx", "amount"
+            ]))
+        );
+        assert_eq!(
+            SchemaVersion::V2,
+            SchemaVersion::detect(&csv::StringRecord::from(vec![
+                "type", "client", "tx", "amount", "note"
+            ]))
+        );
+    }
+
+    #[test]
+    fn it_should_convert_every_version_into_the_canonical_transaction() {
+        let headers =
+            csv::StringRecord::from(vec!["type", "client", "tx", "amount", "reason", "note"]);
+        let record = csv::StringRecord::from(vec!["dispute", "3", "7", "", "fraud", ""]);
+
+        let v1 = SchemaVersion::V1.read(&record, &headers).unwrap();
+        let v2 = SchemaVersion::V2.read(&record, &headers).unwrap();
+
+        assert_eq!(None, v1.dispute_metadata);
+        assert_eq!(
+            Some(DisputeMetadata::new(Some("fraud".to_string()), None, None)),
+            v2.dispute_metadata
+        );
+        assert_eq!(
+            Transaction {
+                dispute_metadata: None,
+                ..v2
+            },
+            v1
+        );
+
+        let deposit = SchemaVersion::V2
+            .read(
+                &csv::StringRecord::from(vec!["deposit", "3", "8", "1.5"]),
+                &csv::StringRecord::from(vec!["type", "client", "tx", "amount"]),
+            )
+            .unwrap();
+        assert_eq!(Some(Currency::str("1.5")), deposit.amount);
+        assert_eq!(None, deposit.dispute_metadata);
+    }
+
+    #[test]
+    fn it_should_list_columns_unknown_to_the_version() {
+        let headers =
+            csv::StringRecord::from(vec!["type", "client", "tx", "amount", "note", "trace"]);
+
+        assert_eq!(
+            vec![(4, "note".to_string()), (5, "trace".to_string())],
+            SchemaVersion::V1.extra_columns(&headers)
+        );
+        assert_eq!(
+            vec![(5, "trace".to_string())],
+            SchemaVersion::V2.extra_columns(&headers)
+        );
+    }
+
+    #[test]
+    fn it_should_only_read_amounts_in_plain_decimal_notation() {
+        let headers = csv::StringRecord::from(vec!["type", "client", "tx", "amount"]);
+        let read = |amount: &str| {
+            SchemaVersion::V1
+                .read(
+                    &csv::StringRecord::from(vec!["deposit", "1", "1", amount]),
+                    &headers,
+                )
+                .map(|t| t.amount)
+                .map_err(|e| e.to_string())
+        };
+        assert_eq!(Ok(Some(Currency::str("12.50"))), read("12.50"));
+        assert_eq!(Ok(Some(Currency::str("0.5"))), read(".5"));
+        for (amount, reason) in [
+            ("1e3", "exponent notation is not accepted"),
+            ("1.5E-2", "exponent notation is not accepted"),
+            ("1_000", "digit separators are not accepted"),
+            ("NaN", "not a plain decimal number"),
+            ("-", "not a plain decimal number"),
+            ("0x10", "not a plain decimal number"),
+            ("-1.0", "negative amounts are not accepted"),
+            ("-12.50", "negative amounts are not accepted"),
+            ("+1.0", "signed amounts are not accepted"),
+            ("-1e3", "exponent notation is not accepted"),
+        ] {
+            let error = read(amount).unwrap_err();
+            assert_eq!(
+                true,
+                error.contains(&format!("Invalid amount '{}': {}", amount, reason)),
+                "{}",
+                error
+            );
+        }
+
+        let bounds = AmountBounds::parse("0.0001:1000").unwrap();
+        assert_eq!(Ok(()), bounds.check(1, Currency::str("1000")));
+        assert_eq!(
+            "Amount 1000.5 of tx 7 is above the maximum of 1000",
+            bounds
+                .check(7, Currency::str("1000.5"))
+                .unwrap_err()
+                .to_string()
+        );
+        assert_eq!(
+            "Amount 0 of tx 7 is below the minimum of 0.0001",
+            bounds.check(7, Currency::str("0")).unwrap_err().to_string()
+        );
+        assert_eq!(Some(AmountBounds::default()), AmountBounds::parse(":"));
+        assert_eq!(None, AmountBounds::parse("10:1"));
+        assert_eq!(None, AmountBounds::parse("1e3:"));
+    }
+
+    #[test]
+    fn it_should_parse_declared_versions() {
+        assert_eq!(Ok(SchemaVersion::V1), "1".parse());
+        assert_eq!(Ok(SchemaVersion::V2), "v2".parse());
+        assert_eq!(true, "3".parse::<SchemaVersion>().is_err());
+    }
+}
@@ -0,0 +1,172 @@
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::time::Duration;
+use std::time::Instant;
+
+use notify::event::AccessKind;
+use notify::event::AccessMode;
+use notify::event::ModifyKind;
+use notify::event::RenameMode;
+use notify::EventKind;
+use notify::RecursiveMode;
+use notify::Watcher;
+
+use crate::exchange::process_transactions_from_csv_with;
+use crate::exchange::Exchange;
+use crate::exchange::IngestOptions;
+
+pub const PROCESSED: &str = "processed";
+pub const FAILED: &str = "failed";
+
+/// Process every CSV file dropped into dir with the same long-lived exchange, then move it to dir/processed, or to dir/failed
+/// if it could not be read. A failed file leaves the exchange as it was: it is processed on a fork that is only committed on success.
+/// Files already in dir are processed first, in name order. New files are picked up once they are closed after writing or moved
+/// into dir, so a file is never read while it is still being written. Every snapshot_every, on_snapshot is given the exchange and
+/// the number of transactions submitted at the previous snapshot; watching stops when it returns false
+pub fn watch_directory(
+    dir: &Path,
+    bank: &mut Exchange,
+    options: &IngestOptions,
+    snapshot_every: Duration,
+    mut on_snapshot: impl FnMut(&Exchange, u64) -> bool,
+) -> Result<(), Box<dyn Error>> {
+    fs::create_dir_all(dir.join(PROCESSED))?;
+    fs::create_dir_all(dir.join(FAILED))?;
+
+    let (events, dropped) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(events)?;
+    watcher.watch(dir, RecursiveMode::NonRecursive)?;
+
+    let mut existing: Vec<PathBuf> = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<_, _>>()?;
+    existing.sort();
+    for path in existing.iter().filter(|path| is_csv(path)) {
+        process_file(path, bank, options)?;
+    }
+
+    let mut since = bank.submitted();
+    let mut last_snapshot = Instant::now();
+    loop {
+        let timeout = snapshot_every.saturating_sub(last_snapshot.elapsed());
+        match dropped.recv_timeout(timeout) {
+            Ok(event) => {
+                let event = event?;
+                let written = matches!(
+                    event.kind,
+                    EventKind::Access(AccessKind::Close(AccessMode::Write))
+                        | EventKind::Modify(ModifyKind::Name(RenameMode::To))
+                );
+                if written {
+                    for path in event.paths.iter().filter(|path| is_csv(path)) {
+                        process_file(path, bank, options)?;
+                    }
+                }
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                return Err("directory watcher stopped".into())
+            }
+        }
+
+        if last_snapshot.elapsed() >= snapshot_every {
+            if !on_snapshot(bank, since) {
+                return Ok(());
+            }
+            since = bank.submitted();
+            last_snapshot = Instant::now();
+        }
+    }
+}
+
+fn is_csv(path: &Path) -> bool {
+    path.is_file() && path.extension().is_some_and(|extension| extension == "csv")
+}
+
+/// Only failing to move the file is an error, failing to read it moves it to failed/
+fn process_file(
+    path: &Path,
+    bank: &mut Exchange,
+    options: &IngestOptions,
+) -> Result<(), Box<dyn Error>> {
+    let mut fork = bank.fork();
+    let destination =
+        match process_transactions_from_csv_with(&path.to_string_lossy(), &mut fork, options) {
+            Ok(_) => {
+                bank.commit(fork);
+                PROCESSED
+            }
+            Err(e) => {
+                eprintln!("Failed to read {} with exception: {}", path.display(), e);
+                FAILED
+            }
+        };
+
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let file_name = path.file_name().ok_or("watched path without a file name")?;
+    fs::rename(path, dir.join(destination).join(file_name))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use crate::exchange::transaction::Currency;
+    use crate::exchange::transaction::Money;
+
+    #[test]
+    fn it_should_process_dropped_files_and_move_them_out_of_the_way() {
+        let dir = std::env::temp_dir().join("payment_engine_watch");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("a.csv"),
+            "type,client,tx,amount\ndeposit,1,1,1.0\n",
+        )
+        .unwrap();
+
+        let mut snapshots = 0;
+        let mut exchange = Exchange::new();
+        watch_directory(
+            &dir,
+            &mut exchange,
+            &IngestOptions::default(),
+            Duration::from_millis(100),
+            |exchange, _| {
+                snapshots += 1;
+                match snapshots {
+                    1 => {
+                        assert_eq!(true, dir.join(PROCESSED).join("a.csv").exists());
+                        fs::write(
+                            dir.join("b.csv"),
+                            "type,client,tx,amount\ndeposit,1,2,2.0\n",
+                        )
+                        .unwrap();
+                        fs::write(
+                            dir.join("c.csv"),
+                            "type,client,tx,amount\ndeposit,2,3,1.0\ndeposit,x,4,1.0\n",
+                        )
+                        .unwrap();
+                        true
+                    }
+                    _ => {
+                        assert_eq!(Currency::str("3.0"), exchange.client(1).unwrap().total());
+                        //c.csv failed halfway through, none of it is applied
+                        assert_eq!(None, exchange.client(2));
+                        assert_eq!(true, dir.join(FAILED).join("c.csv").exists());
+                        false
+                    }
+                }
+            },
+        )
+        .unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(2, snapshots);
+    }
+}
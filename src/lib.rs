@@ -0,0 +1,4 @@
+// tests compare flags with assert_eq!(true/false, ..) so failures print both sides
+#![cfg_attr(test, allow(clippy::bool_assert_comparison))]
+
+pub mod exchange;
@@ -1,22 +1,1746 @@
+use payment_engine::exchange;
+#[cfg(feature = "alert-webhook")]
+use payment_engine::exchange::alerts::Webhook;
+use payment_engine::exchange::anomaly::AnomalyDetection;
+use payment_engine::exchange::anonymize::Anonymizer;
+use payment_engine::exchange::archive::Archive;
+use payment_engine::exchange::cluster::ClientRange;
+use payment_engine::exchange::config::AccountTier;
+use payment_engine::exchange::config::CapPolicy;
+use payment_engine::exchange::config::EngineConfig;
+use payment_engine::exchange::config::LimitExceeded;
+use payment_engine::exchange::config::LimitPolicy;
+use payment_engine::exchange::config::RollingReserve;
+use payment_engine::exchange::config::TierCaps;
+use payment_engine::exchange::config::UnknownTransactionType;
+use payment_engine::exchange::config::UnknownTypePolicy;
+use payment_engine::exchange::config::WriteOffRule;
+use payment_engine::exchange::dedup::DedupWindow;
+use payment_engine::exchange::deferral::DeferralWindow;
+use payment_engine::exchange::delta;
+use payment_engine::exchange::disputes::DisputeAging;
+#[cfg(feature = "encryption")]
+use payment_engine::exchange::encryption::Encryption;
+#[cfg(feature = "encryption")]
+use payment_engine::exchange::encryption::EnvKey;
+use payment_engine::exchange::enrichment::MappingEnricher;
+use payment_engine::exchange::float;
+use payment_engine::exchange::follow::FollowOptions;
+#[cfg(feature = "latency-histograms")]
+use payment_engine::exchange::latency::Stage;
+use payment_engine::exchange::ledger::LedgerStream;
+use payment_engine::exchange::manifest::AlreadyProcessed;
+use payment_engine::exchange::manifest::Manifest;
+use payment_engine::exchange::manifest::ManifestEntry;
+#[cfg(feature = "object-store")]
+use payment_engine::exchange::object_store::MountedStore;
+#[cfg(feature = "object-store")]
+use payment_engine::exchange::object_store::ObjectStore;
+#[cfg(feature = "object-store")]
+use payment_engine::exchange::object_store::ObjectUrl;
+use payment_engine::exchange::output;
+use payment_engine::exchange::output::AmountFormat;
+use payment_engine::exchange::output::OutputSchema;
+use payment_engine::exchange::output::TrailerPlacement;
+use payment_engine::exchange::output::TrailerWriter;
+use payment_engine::exchange::pseudonym::ClientIds;
+use payment_engine::exchange::query::AccountFilter;
+use payment_engine::exchange::reconcile;
+use payment_engine::exchange::remap::ClientMapping;
+use payment_engine::exchange::remap::SharedMapping;
+use payment_engine::exchange::settlement;
+use payment_engine::exchange::snapshot;
+use payment_engine::exchange::snapshot::SnapshotOptions;
+use payment_engine::exchange::snapshot::Snapshots;
+use payment_engine::exchange::trace::TraceSampling;
+use payment_engine::exchange::transaction::AmountBounds;
+use payment_engine::exchange::transaction::ClientId;
+use payment_engine::exchange::transaction::Currency;
+use payment_engine::exchange::transaction::TransactionId;
+use payment_engine::exchange::transaction::Type;
 use std::env;
+use std::error::Error;
+use std::fs::File;
+use std::io;
+use std::io::IsTerminal;
+use std::io::Write;
+use std::net::Shutdown;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::path::Path;
+use std::time::Duration;
+#[cfg(feature = "latency-histograms")]
+use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
 use tokio::task;
-mod exchange;
+
+#[derive(Default)]
+struct Options {
+    file: Option<String>,
+    /// Further input files, processed concurrently with file as disjoint partitions of the input
+    partitions: Vec<String>,
+    print_stats: bool,
+    amount_format: Option<AmountFormat>,
+    settlement: Option<String>,
+    settlement_baseline: Option<String>,
+    /// Held funds weighed by how long disputes held them, see float::float
+    float_report: Option<String>,
+    /// Account summaries of a previous run, compared to the new ones in the delta report
+    baseline: Option<String>,
+    delta: Option<String>,
+    /// Another build of the engine run on the same input files, whose account summaries are compared to the new ones
+    shadow: Option<String>,
+    /// Where the accounts the shadow disagrees on are written, stderr when not given
+    shadow_report: Option<String>,
+    /// Second feed of the same transactions, checked against the stored ones, see reconcile::reconcile_transactions_from_csv
+    verify_feed: Option<String>,
+    /// Where the discrepancies of the verified feed are written, stderr when not given
+    verify_report: Option<String>,
+    receivables: Option<String>,
+    /// Postgres script loading the accounts and their transactions, see sql::write_sql
+    sql: Option<String>,
+    ingest: exchange::IngestOptions,
+    ledger: Option<String>,
+    /// Ledger appended to as the transactions are processed, for a replica to follow
+    ledger_stream: Option<String>,
+    rejects: Option<String>,
+    /// Mapping file the transactions are enriched with before processing
+    enrich: Option<String>,
+    output_shards: Option<usize>,
+    output_prefix: Option<String>,
+    /// Row count and checksum written after the account summaries or next to them
+    trailer: Option<TrailerPlacement>,
+    output_threads: Option<usize>,
+    /// The account summaries are written and flushed this many clients at a time, see output::write_accounts_in_ranges
+    flush_every: Option<usize>,
+    /// Apply deposits and withdrawals on this many threads, see Exchange::process_speculatively
+    speculative_threads: Option<usize>,
+    /// One engine per range of client ids, see cluster::process_client_ranges_from_csv
+    client_ranges: Option<Vec<ClientRange>>,
+    /// Only the final account summaries, see aggregate::aggregate_transactions_from_csv
+    aggregate_only: bool,
+    /// Where route sends the records of each range of client ids
+    routes: Vec<(ClientRange, String)>,
+    json: bool,
+    /// Account summaries as an aligned table for people to read
+    table: bool,
+    /// Color the table, None when only coloring a terminal
+    color: Option<bool>,
+    extended: bool,
+    output_schema: OutputSchema,
+    follow: bool,
+    watch: bool,
+    snapshot_every: Option<Duration>,
+    json_holds: bool,
+    json_annotations: bool,
+    config: EngineConfig,
+    pending_chargebacks: Option<String>,
+    /// Small balances of inactive accounts written off once the input is processed
+    write_off: Option<WriteOffRule>,
+    /// JSON report of the rows out of order
+    ordering_report: Option<String>,
+    archive: Option<String>,
+    checkpoint: Option<String>,
+    checkpoint_every: Option<u64>,
+    /// Where the breaches of the error budget are posted
+    #[cfg(feature = "alert-webhook")]
+    alert_webhook: Option<Webhook>,
+    /// Intermediate account snapshots written while the input is processed
+    snapshots: Option<SnapshotOptions>,
+    resume: bool,
+    /// Write the open disputes instead of the account summaries
+    disputes: bool,
+    /// Selection of the accounts of a checkpoint to print, with query
+    query: AccountFilter,
+    query_client: Option<ClientId>,
+    query_tx: Option<TransactionId>,
+    manifest: Option<String>,
+    force: bool,
+    /// Where the account summaries are written instead of stdout, a file or an object url
+    output: Option<String>,
+    /// Directory the object storage buckets are mounted in
+    #[cfg(feature = "object-store")]
+    object_store_root: Option<String>,
+    /// Seals checkpoints, ledgers and rejects
+    #[cfg(feature = "encryption")]
+    encryption: Option<Encryption>,
+}
+
+fn parse_count(option: &str, value: Option<String>) -> Result<usize, String> {
+    value
+        .and_then(|value| value.parse().ok())
+        .ok_or_else(|| format!("{} expects a number", option))
+}
+
+fn parse_percent(option: &str, value: Option<String>) -> Result<f64, String> {
+    value
+        .and_then(|value| value.parse().ok())
+        .filter(|percent| (0.0..=100.0).contains(percent))
+        .ok_or_else(|| format!("{} expects a percentage", option))
+}
+
+fn parse_amount(option: &str, value: Option<String>) -> Result<Currency, String> {
+    value
+        .and_then(|value| value.parse::<Currency>().ok()?.non_negative().ok())
+        .ok_or_else(|| format!("{} expects an amount that is not negative", option))
+}
+
+/// Exit code of a run that exceeded a threshold of its error budget, once every output is written
+const EXIT_BUDGET_EXCEEDED: i32 = 3;
+
+/// Exit code of a run stopped by --on-limit abort or --unknown-types fail. Nothing is written to stdout: the accounts would
+/// only hold the transactions before the one that stopped it
+const EXIT_ABORTED: i32 = 4;
+
+/// Exit code of a run refused by --manifest because an input file was already processed. Nothing is processed or written
+const EXIT_ALREADY_PROCESSED: i32 = 5;
+
+const MAPPED_AND_HASHED: &str = "--client-mapping can not be combined with --hash-client-ids-env";
+
+fn parse_options(args: Vec<String>) -> Result<Options, String> {
+    let mut options = Options::default();
+    let (mut snapshot_dir, mut snapshot_records, mut snapshot_minutes, mut snapshot_keep) =
+        (None, None, None, None);
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--stats" => options.print_stats = true,
+            "--amount-format" => {
+                let format = args.next().unwrap_or_default();
+                options.amount_format = Some(
+                    AmountFormat::parse(&format)
+                        .ok_or_else(|| format!("Unknown amount format '{}'", format))?,
+                );
+            }
+            "--settlement" => options.settlement = args.next(),
+            "--settlement-baseline" => options.settlement_baseline = args.next(),
+            "--float-report" => options.float_report = args.next(),
+            "--baseline" => options.baseline = args.next(),
+            "--delta" => options.delta = args.next(),
+            "--shadow" => options.shadow = args.next(),
+            "--shadow-report" => options.shadow_report = args.next(),
+            "--verify-feed" => options.verify_feed = args.next(),
+            "--verify-report" => options.verify_report = args.next(),
+            "--schema-version" => {
+                options.ingest.schema = Some(args.next().unwrap_or_default().parse()?)
+            }
+            "--capture-metadata" => options.ingest.capture_metadata = true,
+            "--quiet" => options.ingest.quiet = true,
+            "--quarantine" => options.ingest.quarantine = args.next().map(Into::into),
+            "--recover" => options.ingest.recover = true,
+            "--amount-bounds" => {
+                options.ingest.amount_bounds = Some(
+                    args.next()
+                        .as_deref()
+                        .and_then(AmountBounds::parse)
+                        .ok_or("--amount-bounds expects <min>:<max>")?,
+                )
+            }
+            "--ledger" => options.ledger = args.next(),
+            "--ledger-stream" => options.ledger_stream = args.next(),
+            "--enrich" => options.enrich = args.next(),
+            "--rejects" => options.rejects = args.next(),
+            "--output-shards" => {
+                options.output_shards = Some(
+                    args.next()
+                        .and_then(|shards| shards.parse().ok())
+                        .filter(|shards| *shards > 0)
+                        .ok_or("--output-shards expects a positive number of shards")?,
+                )
+            }
+            "--output-prefix" => options.output_prefix = args.next(),
+            "--trailer" => {
+                options.trailer = Some(
+                    args.next()
+                        .as_deref()
+                        .and_then(TrailerPlacement::parse)
+                        .ok_or("--trailer expects footer or sidecar")?,
+                )
+            }
+            "--follow" => options.follow = true,
+            "--snapshot-interval" => {
+                options.snapshot_every =
+                    Some(Duration::from_secs(parse_count(&arg, args.next())? as u64))
+            }
+            "--snapshot-dir" => snapshot_dir = args.next(),
+            "--snapshot-records" => snapshot_records = Some(parse_count(&arg, args.next())? as u64),
+            "--snapshot-minutes" => snapshot_minutes = Some(parse_count(&arg, args.next())? as u64),
+            "--snapshot-keep" => snapshot_keep = Some(parse_count(&arg, args.next())?.max(1)),
+            "--json" => options.json = true,
+            "--format" => match args.next().as_deref() {
+                Some("csv") => {}
+                Some("json") => options.json = true,
+                Some("table") => options.table = true,
+                _ => return Err("--format expects csv, json or table".to_string()),
+            },
+            "--color" => {
+                options.color = match args.next().as_deref() {
+                    Some("auto") => None,
+                    Some("always") => Some(true),
+                    Some("never") => Some(false),
+                    _ => return Err("--color expects auto, always or never".to_string()),
+                }
+            }
+            "--extended" => options.extended = true,
+            "--output-schema" => {
+                let schema = args.next().unwrap_or_default();
+                options.output_schema = OutputSchema::parse(&schema)
+                    .ok_or_else(|| format!("Unknown output schema '{}'", schema))?;
+            }
+            "--amount-scale" => {
+                options.config.amount_scale = parse_count(&arg, args.next())? as u32
+            }
+            "--reserve" => {
+                options.config.reserve = parse_amount(&arg, args.next())?
+            }
+            "--client-reserve" => {
+                let (client, reserve) = args
+                    .next()
+                    .as_deref()
+                    .and_then(|value| value.split_once('='))
+                    .and_then(|(client, amount)| {
+                        Some((
+                            client.parse().ok()?,
+                            amount.parse::<Currency>().ok()?.non_negative().ok()?,
+                        ))
+                    })
+                    .ok_or("--client-reserve expects <client>=<amount>, the amount not negative")?;
+                options.config.client_reserves.insert(client, reserve);
+            }
+            "--cooling-off" => options.config.cooling_off = parse_count(&arg, args.next())? as u64,
+            "--client-cooling-off" => {
+                let (client, transactions) = args
+                    .next()
+                    .as_deref()
+                    .and_then(|value| value.split_once('='))
+                    .and_then(|(client, transactions)| {
+                        Some((client.parse().ok()?, transactions.parse().ok()?))
+                    })
+                    .ok_or("--client-cooling-off expects <client>=<transactions>")?;
+                options
+                    .config
+                    .client_cooling_off
+                    .insert(client, transactions);
+            }
+            "--client" => {
+                options.query_client = Some(
+                    parse_count(&arg, args.next())?
+                        .try_into()
+                        .map_err(|_| "--client expects a client id")?,
+                )
+            }
+            "--tx" => {
+                options.query_tx = Some(
+                    parse_count(&arg, args.next())?
+                        .try_into()
+                        .map_err(|_| "--tx expects a transaction id")?,
+                )
+            }
+            "--locked" => options.query.locked = Some(true),
+            "--negative" => options.query.negative_available = Some(true),
+            "--no-retention" => options.config.retain_transactions = false,
+            "--aggregate-only" => options.aggregate_only = true,
+            "--deny" => {
+                //the binary registers no handler, so a name other than the built-in types can only be a typo denying nothing
+                for name in args
+                    .next()
+                    .ok_or("--deny expects a comma separated list of transaction types")?
+                    .split(',')
+                {
+                    match Type::from_name(name.trim()) {
+                        Type::Custom(name) => {
+                            return Err(format!("Unknown transaction type {} in --deny", name))
+                        }
+                        denied => options.config.denied_types.push(denied),
+                    }
+                }
+            }
+            "--rolling-reserve" => {
+                options.config.rolling_reserve = Some(
+                    args.next()
+                        .as_deref()
+                        .and_then(RollingReserve::parse)
+                        .ok_or("--rolling-reserve expects <percent>:<transactions>")?,
+                )
+            }
+            "--max-reject-rate" => {
+                options.config.error_budget.max_reject_rate =
+                    Some(parse_percent(&arg, args.next())?)
+            }
+            "--max-parse-failure-rate" => {
+                options.config.error_budget.max_parse_failure_rate =
+                    Some(parse_percent(&arg, args.next())?)
+            }
+            "--max-negative-balances" => {
+                options.config.error_budget.max_negative_balances =
+                    Some(parse_count(&arg, args.next())?)
+            }
+            #[cfg(feature = "alert-webhook")]
+            "--alert-webhook" => {
+                options.alert_webhook = Some(
+                    args.next()
+                        .as_deref()
+                        .and_then(Webhook::parse)
+                        .ok_or("--alert-webhook expects an http[s]://host[:port][/path] url")?,
+                )
+            }
+            "--trace" => {
+                options.config.trace = Some(
+                    args.next()
+                        .as_deref()
+                        .and_then(TraceSampling::parse)
+                        .ok_or("--trace expects <rate>[:<trace-id>,<trace-id>...]")?,
+                )
+            }
+            "--dedup-window" => {
+                options.config.dedup_window = Some(
+                    args.next()
+                        .as_deref()
+                        .and_then(DedupWindow::parse)
+                        .ok_or("--dedup-window expects <size>[:<ttl-seconds>]")?,
+                )
+            }
+            "--defer-disputes" => {
+                options.config.defer_disputes = Some(
+                    args.next()
+                        .as_deref()
+                        .and_then(DeferralWindow::parse)
+                        .ok_or("--defer-disputes expects <capacity>[:<max-age>]")?,
+                )
+            }
+            "--anomaly-detection" => {
+                options.config.anomaly_detection = Some(
+                    args.next()
+                        .as_deref()
+                        .and_then(AnomalyDetection::parse)
+                        .ok_or("--anomaly-detection expects <z-score>:<window>")?,
+                )
+            }
+            "--freeze-on-anomaly" => options.config.freeze_on_anomaly = true,
+            "--write-off" => {
+                options.write_off = Some(
+                    args.next()
+                        .as_deref()
+                        .and_then(WriteOffRule::parse)
+                        .ok_or("--write-off expects <below>:<idle transactions>")?,
+                )
+            }
+            "--high-risk" => {
+                let client = parse_count(&arg, args.next())?
+                    .try_into()
+                    .map_err(|_| "--high-risk expects a client id")?;
+                options.config.high_risk_clients.insert(client);
+            }
+            "--holds" => options.json_holds = true,
+            "--annotations" => options.json_annotations = true,
+            "--flush-every" => options.flush_every = Some(parse_count(&arg, args.next())?.max(1)),
+            "--output-threads" => {
+                options.output_threads = Some(parse_count(&arg, args.next())?.max(1))
+            }
+            "--speculative-threads" => {
+                options.speculative_threads = Some(parse_count(&arg, args.next())?.max(1))
+            }
+            "--client-ranges" => {
+                options.client_ranges = Some(
+                    args.next()
+                        .as_deref()
+                        .and_then(ClientRange::parse_list)
+                        .ok_or("--client-ranges expects a number of ranges or <first>-<last>,... ranges that do not overlap")?,
+                )
+            }
+            "--to" => {
+                options.routes = args
+                    .next()
+                    .ok_or("--to expects <first>-<last>=<host:port>,...")?
+                    .split(',')
+                    .map(|route| {
+                        route
+                            .split_once('=')
+                            .and_then(|(range, address)| {
+                                Some((ClientRange::parse(range)?, address.to_string()))
+                            })
+                            .ok_or("--to expects <first>-<last>=<host:port>,...")
+                    })
+                    .collect::<Result<_, _>>()?
+            }
+            "--max-clients" => {
+                options.config.limits.max_clients = Some(parse_count(&arg, args.next())?)
+            }
+            "--max-transactions" => {
+                options.config.limits.max_transactions = Some(parse_count(&arg, args.next())?)
+            }
+            "--max-memory" => {
+                options.config.limits.max_memory = Some(parse_count(&arg, args.next())?)
+            }
+            "--chargeback-approval-threshold" => {
+                options.config.chargeback_approval_threshold =
+                    Some(parse_amount(&arg, args.next())?)
+            }
+            "--pending-chargebacks" => options.pending_chargebacks = args.next(),
+            "--dispute-aging" => {
+                options.config.dispute_aging = Some(
+                    args.next()
+                        .as_deref()
+                        .and_then(DisputeAging::parse)
+                        .ok_or("--dispute-aging expects increasing positive ages in transactions, e.g. 10000,50000")?,
+                )
+            }
+            "--ordering-report" => {
+                options.ordering_report = args.next();
+                options.config.track_ordering = true;
+            }
+            "--on-limit" => {
+                options.config.limits.on_exceeded = match args.next().as_deref() {
+                    Some("abort") => LimitPolicy::Abort,
+                    Some("degrade") => LimitPolicy::Degrade,
+                    _ => return Err("--on-limit expects abort or degrade".to_string()),
+                }
+            }
+            "--unknown-types" => {
+                options.config.unknown_types = match args.next().as_deref() {
+                    Some("dispatch") => UnknownTypePolicy::Dispatch,
+                    Some("reject") => UnknownTypePolicy::Reject,
+                    Some("fail") => UnknownTypePolicy::Fail,
+                    _ => return Err("--unknown-types expects dispatch, reject or fail".to_string()),
+                }
+            }
+            "--tier-caps" => {
+                let caps = args
+                    .next()
+                    .as_deref()
+                    .and_then(TierCaps::parse)
+                    .ok_or("--tier-caps expects <max deposited>:<max transactions>")?;
+                options.config.client_caps.tiers.push(caps);
+            }
+            "--on-cap" => {
+                options.config.client_caps.on_exceeded = match args.next().as_deref() {
+                    Some("reject") => CapPolicy::Reject,
+                    Some("flag") => CapPolicy::Flag,
+                    _ => return Err("--on-cap expects reject or flag".to_string()),
+                }
+            }
+            "--account-tier" => {
+                let (name, tier) = args
+                    .next()
+                    .as_deref()
+                    .and_then(AccountTier::parse)
+                    .ok_or("--account-tier expects <name>:<setting>=<value>,...")?;
+                options.config.account_tiers.insert(name, tier);
+            }
+            "--unlock-on-resolve" => options.config.unlock_on_resolve = true,
+            "--hold-locked-deposits" => options.config.hold_deposits_when_locked = true,
+            "--receivables" => options.receivables = args.next(),
+            "--sql" => options.sql = args.next(),
+            "--archive" => options.archive = args.next(),
+            "--checkpoint" => options.checkpoint = args.next(),
+            "--checkpoint-every" => {
+                options.checkpoint_every = Some(parse_count(&arg, args.next())?.max(1) as u64)
+            }
+            "--resume" => options.resume = true,
+            "--manifest" => options.manifest = args.next(),
+            "--force" => options.force = true,
+            "--output" => options.output = args.next(),
+            #[cfg(feature = "object-store")]
+            "--object-store-root" => options.object_store_root = args.next(),
+            #[cfg(feature = "encryption")]
+            "--encryption-key-env" => {
+                let variable = args
+                    .next()
+                    .ok_or("--encryption-key-env expects a variable")?;
+                options.encryption =
+                    Some(Encryption::from_provider(&EnvKey(variable)).map_err(|e| e.to_string())?);
+            }
+            "--hash-client-ids-env" => {
+                let variable = args
+                    .next()
+                    .ok_or("--hash-client-ids-env expects a variable")?;
+                let salt = std::env::var(&variable)
+                    .map_err(|_| format!("{} does not hold a salt", variable))?;
+                if options.config.client_ids != ClientIds::Raw {
+                    return Err(MAPPED_AND_HASHED.to_string());
+                }
+                options.config.client_ids = ClientIds::hashed(&salt);
+            }
+            "--client-mapping" => {
+                let path = args.next().ok_or("--client-mapping expects a file")?;
+                let mapping = ClientMapping::open(&path)
+                    .map_err(|e| format!("Failed to open client mapping {}: {}", path, e))?;
+                if options.config.client_ids != ClientIds::Raw {
+                    return Err(MAPPED_AND_HASHED.to_string());
+                }
+                options.config.client_ids = ClientIds::Mapped(SharedMapping::new(mapping));
+            }
+            "--archive-resident" => {
+                options.config.archive_resident = Some(parse_count(&arg, args.next())?.max(1))
+            }
+            "--archive-idle" => {
+                options.config.archive_idle = Some(parse_count(&arg, args.next())? as u64)
+            }
+            _ if arg.starts_with("--") => return Err(format!("Unknown option {}", arg)),
+            _ if options.file.is_some() => options.partitions.push(arg),
+            _ => options.file = Some(arg),
+        }
+    }
+    if (options.json_holds || options.json_annotations) && !options.json {
+        return Err("--holds and --annotations are only available with --json".to_string());
+    }
+    if !options.partitions.is_empty() && (options.follow || options.archive.is_some()) {
+        return Err("Several input files can not be followed or archived".to_string());
+    }
+    if (options.resume || options.checkpoint_every.is_some()) && options.checkpoint.is_none() {
+        return Err("--resume and --checkpoint-every require --checkpoint".to_string());
+    }
+    //the checkpoint only holds the exchange state, resuming any of these would lose or duplicate part of their output
+    if options.checkpoint.is_some()
+        && (options.follow
+            || !options.partitions.is_empty()
+            || options.archive.is_some()
+            || options.ledger.is_some()
+            || options.ledger_stream.is_some()
+            || options.rejects.is_some()
+            || options.float_report.is_some()
+            || options.ingest.quarantine.is_some()
+            || options.ingest.recover)
+    {
+        return Err("--checkpoint can not be combined with --follow, several input files, --archive, --ledger, --ledger-stream, --rejects, --float-report, --quarantine or --recover".to_string());
+    }
+    //the deferred rows are not part of the checkpoint, a resumed run would never settle them
+    if options.checkpoint.is_some() && options.config.defer_disputes.is_some() {
+        return Err("--defer-disputes can not be combined with --checkpoint".to_string());
+    }
+    //nothing can be disputed without the transactions, the dispute steps are rejected rather than ignored
+    if !options.config.retain_transactions {
+        if options.config.defer_disputes.is_some() {
+            return Err("--no-retention can not be combined with --defer-disputes".to_string());
+        }
+        options
+            .config
+            .denied_types
+            .extend([Type::Dispute, Type::Resolve, Type::Chargeback]);
+    }
+    if options.output_schema != OutputSchema::Classic && (options.json || options.extended) {
+        return Err("--output-schema can not be combined with --json or --extended".to_string());
+    }
+    #[cfg(feature = "encryption")]
+    if options.encryption.is_some() && options.ledger_stream.is_some() {
+        return Err(
+            "--ledger-stream is written in the clear, it can not be combined with encryption"
+                .to_string(),
+        );
+    }
+    if options.force && options.manifest.is_none() {
+        return Err("--force requires --manifest".to_string());
+    }
+    if options.ingest.recover && options.follow {
+        return Err("--recover can not be combined with --follow".to_string());
+    }
+    if options.manifest.is_some() && options.follow {
+        return Err("--manifest can not be combined with --follow".to_string());
+    }
+    #[cfg(feature = "object-store")]
+    if options.file.as_deref().and_then(ObjectUrl::parse).is_some()
+        && (options.follow
+            || options.checkpoint.is_some()
+            || !options.partitions.is_empty()
+            || options.manifest.is_some()
+            || options.ingest.quarantine.is_some()
+            || options.ingest.recover)
+    {
+        return Err("An object storage input can not be combined with --follow, --checkpoint, several input files, --manifest, --quarantine or --recover".to_string());
+    }
+    if options.config.freeze_on_anomaly && options.config.anomaly_detection.is_none() {
+        return Err("--freeze-on-anomaly requires --anomaly-detection".to_string());
+    }
+    if !options.config.high_risk_clients.is_empty() && options.config.rolling_reserve.is_none() {
+        return Err("--high-risk requires --rolling-reserve".to_string());
+    }
+    if options.baseline.is_some() != options.delta.is_some() {
+        return Err("--baseline and --delta must be given together".to_string());
+    }
+    //the previous account summaries hold external ids, the baselines are read as internal ones
+    if matches!(options.config.client_ids, ClientIds::Mapped(_))
+        && (options.baseline.is_some()
+            || options.settlement_baseline.is_some()
+            || options.shadow.is_some())
+    {
+        return Err("--client-mapping can not be combined with --baseline, --settlement-baseline or --shadow".to_string());
+    }
+    #[cfg(feature = "alert-webhook")]
+    if options.alert_webhook.is_some() && options.config.error_budget.is_empty() {
+        return Err("--alert-webhook requires --max-reject-rate, --max-parse-failure-rate or --max-negative-balances".to_string());
+    }
+    if options.shadow_report.is_some() && options.shadow.is_none() {
+        return Err("--shadow-report requires --shadow".to_string());
+    }
+    if options.shadow.is_some() && (options.follow || options.watch) {
+        return Err("--shadow can not be combined with --follow or watch".to_string());
+    }
+    if options.verify_report.is_some() && options.verify_feed.is_none() {
+        return Err("--verify-report requires --verify-feed".to_string());
+    }
+    if options.verify_feed.is_some()
+        && (options.follow
+            || options.watch
+            || options.aggregate_only
+            || options.archive.is_some()
+            || !options.config.retain_transactions)
+    {
+        return Err("--verify-feed can not be combined with --follow, watch, --aggregate-only, --archive or --no-retention".to_string());
+    }
+    if options.speculative_threads.is_some()
+        && (options.follow
+            || options.watch
+            || !options.partitions.is_empty()
+            || options.checkpoint.is_some())
+    {
+        return Err("--speculative-threads can not be combined with --follow, watch, several input files or --checkpoint".to_string());
+    }
+    if options.flush_every.is_some()
+        && (options.table
+            || options.json
+            || options.extended
+            || options.output_schema != OutputSchema::Classic
+            || options.output_shards.is_some())
+    {
+        return Err("--flush-every can not be combined with --table, --json, --extended, --output-schema or --output-shards".to_string());
+    }
+    if options.trailer.is_some() && options.output_shards.is_some() {
+        return Err("--trailer can not be combined with --output-shards".to_string());
+    }
+    if options.trailer == Some(TrailerPlacement::Sidecar) && options.output.is_none() {
+        return Err("--trailer sidecar expects --output".to_string());
+    }
+    if options.aggregate_only
+        && (options.follow
+            || options.watch
+            || !options.partitions.is_empty()
+            || options.checkpoint.is_some()
+            || options.client_ranges.is_some()
+            || options.speculative_threads.is_some()
+            || options.archive.is_some()
+            || options.ledger.is_some()
+            || options.ledger_stream.is_some()
+            || options.rejects.is_some()
+            || options.float_report.is_some()
+            || options.ingest.quarantine.is_some()
+            || options.ingest.recover)
+    {
+        return Err("--aggregate-only can not be combined with --follow, watch, several input files, --checkpoint, --client-ranges, --speculative-threads, --archive, --ledger, --ledger-stream, --rejects, --float-report, --quarantine or --recover".to_string());
+    }
+    if options.client_ranges.is_some()
+        && (options.follow
+            || options.watch
+            || !options.partitions.is_empty()
+            || options.checkpoint.is_some()
+            || options.speculative_threads.is_some()
+            || snapshot_dir.is_some()
+            || matches!(options.config.client_ids, ClientIds::Mapped(_)))
+    {
+        return Err("--client-ranges can not be combined with --follow, watch, several input files, --checkpoint, --speculative-threads, --snapshot-dir or --client-mapping".to_string());
+    }
+    match snapshot_dir {
+        Some(dir) => {
+            options.snapshots = Some(SnapshotOptions {
+                dir: dir.into(),
+                every_records: snapshot_records.or(snapshot_minutes.is_none().then_some(1_000_000)),
+                every: snapshot_minutes.map(|minutes| Duration::from_secs(minutes * 60)),
+                keep: snapshot_keep.unwrap_or(10),
+                format: options.amount_format.clone().unwrap_or(AmountFormat::Plain),
+            })
+        }
+        None if snapshot_records.is_some()
+            || snapshot_minutes.is_some()
+            || snapshot_keep.is_some() =>
+        {
+            return Err(
+                "--snapshot-records, --snapshot-minutes and --snapshot-keep require --snapshot-dir"
+                    .to_string(),
+            )
+        }
+        None => (),
+    }
+    if options.snapshots.is_some() && !options.partitions.is_empty() {
+        return Err("--snapshot-dir can not be combined with several input files".to_string());
+    }
+    let archiving =
+        options.config.archive_idle.is_some() || options.config.archive_resident.is_some();
+    let limits = &options.config.limits;
+    //limits under the degrade policy spill clients to the archive before rejecting or no longer retaining anything
+    let spilling = limits.on_exceeded == LimitPolicy::Degrade
+        && (limits.max_clients.is_some()
+            || limits.max_transactions.is_some()
+            || limits.max_memory.is_some());
+    if options.archive.is_some() != archiving && !(spilling && options.archive.is_some()) {
+        return Err("--archive must be given with --archive-idle, --archive-resident or limits under --on-limit degrade, and --archive-idle and --archive-resident with --archive".to_string());
+    }
+    Ok(options)
+}
+
+#[cfg(feature = "object-store")]
+fn object_store(options: &Options) -> io::Result<MountedStore> {
+    options
+        .object_store_root
+        .as_ref()
+        .map(MountedStore::new)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Object urls are read and written through the buckets mounted under --object-store-root, which is missing",
+            )
+        })
+}
+
+/// A local file, or with the object-store feature an object written through the store
+fn create_output(options: &Options, path: &str) -> io::Result<Box<dyn io::Write + Send>> {
+    #[cfg(feature = "object-store")]
+    if let Some(url) = ObjectUrl::parse(path) {
+        return object_store(options)?.put(&url);
+    }
+    #[cfg(not(feature = "object-store"))]
+    let _ = options;
+    Ok(Box::new(io::BufWriter::new(File::create(path)?)))
+}
+
+/// create_output() for the files holding balances and transactions, sealed when an encryption key is given
+fn write_sealed_output(
+    options: &Options,
+    path: &str,
+    write: impl FnOnce(&mut dyn io::Write) -> Result<(), csv::Error>,
+) -> Result<(), Box<dyn Error>> {
+    let mut output = create_output(options, path)?;
+    #[cfg(feature = "encryption")]
+    if let Some(encryption) = &options.encryption {
+        let mut sealed = encryption.writer(&mut output)?;
+        write(&mut sealed)?;
+        sealed.finish()?;
+        return Ok(());
+    }
+    write(&mut output)?;
+    output.flush()?;
+    Ok(())
+}
+
+/// decrypt <file> --encryption-key-env <variable>: the plaintext of a sealed file to stdout, once its integrity checked out
+#[cfg(feature = "encryption")]
+fn decrypt(options: &Options) -> Result<(), Box<dyn Error>> {
+    let (Some(file), Some(encryption)) = (&options.file, &options.encryption) else {
+        return Err("decrypt expects a file and --encryption-key-env".into());
+    };
+    let mut plain = Vec::new();
+    io::Read::read_to_end(
+        &mut encryption.reader(io::BufReader::new(File::open(file)?))?,
+        &mut plain,
+    )?;
+    io::stdout().lock().write_all(&plain)?;
+    Ok(())
+}
+
+fn write_settlement(
+    exchange: &exchange::Exchange,
+    output: impl io::Write,
+    baseline: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    let baseline = match baseline {
+        Some(baseline) => settlement::read_baseline(File::open(baseline)?)?,
+        None => settlement::Baseline::new(),
+    };
+    settlement::write_settlement(
+        output,
+        &settlement::settle(exchange, &baseline),
+        &exchange.config().client_ids,
+    )?;
+    Ok(())
+}
+
+fn write_delta(
+    exchange: &exchange::Exchange,
+    output: impl io::Write,
+    baseline: &str,
+) -> Result<(), Box<dyn Error>> {
+    let baseline = settlement::read_baseline(File::open(baseline)?)?;
+    delta::write_delta(
+        output,
+        &delta::diff(exchange, &baseline),
+        &exchange.config().client_ids,
+    )?;
+    Ok(())
+}
+
+/// Run the shadow binary on the input files alone, as it may be a build that predates the other options, and compare its
+/// account summaries to the exchange's. The shadow's balances are the previous ones of the deltas
+fn shadow_deltas(
+    exchange: &exchange::Exchange,
+    binary: &str,
+    files: &[String],
+) -> Result<Vec<delta::AccountDelta>, Box<dyn Error>> {
+    let output = std::process::Command::new(binary).args(files).output()?;
+    if !output.status.success() {
+        return Err(format!(
+            "{} exited with {}: {}",
+            binary,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+    let baseline = settlement::read_baseline(&output.stdout[..])?;
+    Ok(delta::diff(exchange, &baseline))
+}
+
+/// The manifest with the fingerprints of the input files, to be recorded once they are processed and the outputs written.
+/// Fails with AlreadyProcessed when one of them was processed before, unless forced
+fn previously_processed(
+    path: &str,
+    files: &[String],
+    force: bool,
+) -> Result<(Manifest, Vec<ManifestEntry>), Box<dyn Error>> {
+    let manifest = Manifest::open(path)?;
+    let mut entries = Vec::new();
+    for file in files {
+        let entry = ManifestEntry::fingerprint(file)?;
+        if let Some(known) = manifest.find(&entry).filter(|_| !force) {
+            return Err(AlreadyProcessed {
+                file: file.clone(),
+                known: known.clone(),
+            }
+            .into());
+        }
+        entries.push(entry);
+    }
+    Ok((manifest, entries))
+}
+
+/// Whether the table is colored: by default only on a terminal, and unless NO_COLOR (https://no-color.org) is set
+fn colored(options: &Options) -> bool {
+    options.color.unwrap_or(
+        options.output.is_none() && io::stdout().is_terminal() && env::var_os("NO_COLOR").is_none(),
+    )
+}
+
+/// query <checkpoint> [--client <id>] [--locked] [--negative] [--tx <id>]: the matching accounts of a checkpoint, or the
+/// transaction with the given id, read without processing anything
+fn query(options: &Options, mut exchange: exchange::Exchange) -> Result<(), Box<dyn Error>> {
+    let file = options
+        .file
+        .as_ref()
+        .ok_or("query expects a checkpoint file")?;
+    exchange.restore_checkpoint(Path::new(file))?;
+    let format = options.amount_format.clone().unwrap_or(AmountFormat::Plain);
+    let ids = &exchange.config().client_ids;
+    let stdout = io::stdout().lock();
+    if let Some(tx) = options.query_tx {
+        let transaction = exchange
+            .find_transaction(tx)
+            .ok_or_else(|| format!("No transaction {} in {}", tx, file))?;
+        output::write_transactions(stdout, std::iter::once(transaction), &format, ids)?;
+        return Ok(());
+    }
+    if let Some(client) = options.query_client {
+        exchange
+            .client(client)
+            .ok_or_else(|| format!("No client {} in {}", client, file))?;
+    }
+    let accounts = exchange
+        .accounts(&options.query)
+        .filter(|client| options.query_client.is_none_or(|id| client.id() == id));
+    if options.table {
+        output::write_accounts_table(stdout, accounts, &format, ids, colored(options))?;
+    } else if options.json {
+        output::write_accounts_json(
+            stdout,
+            accounts,
+            &format,
+            ids,
+            options.json_holds,
+            options.json_annotations,
+        )?;
+    } else {
+        output::write_accounts(stdout, accounts, &format, ids)?;
+    }
+    Ok(())
+}
+
+/// import-disputes <file> --checkpoint <checkpoint>: the disputes of a dispute export applied to the checkpointed state, which
+/// is written back in place, and what became of each row reported to --output or stdout
+fn import_disputes(
+    options: &Options,
+    mut exchange: exchange::Exchange,
+) -> Result<(), Box<dyn Error>> {
+    let (Some(file), Some(checkpoint)) = (&options.file, &options.checkpoint) else {
+        return Err("import-disputes expects a dispute file and --checkpoint".into());
+    };
+    let checkpoint = Path::new(checkpoint);
+    let position = exchange.restore_checkpoint(checkpoint)?;
+    let import = exchange.import_disputes(File::open(file)?)?;
+    exchange.write_checkpoint(checkpoint, position)?;
+    let unmatched = import.unmatched().count();
+    let mut report: Box<dyn io::Write> = match &options.output {
+        Some(path) => create_output(options, path)?,
+        None => Box::new(io::stdout().lock()),
+    };
+    import.write_report(&mut report, &exchange.config().client_ids)?;
+    report.flush()?;
+    if unmatched > 0 {
+        eprintln!("{} disputes did not match any known transaction", unmatched);
+    }
+    Ok(())
+}
+
+/// replica <ledger stream> [--checkpoint <file>]: a read-only copy of the exchange writing the ledger stream, kept up to
+/// date until interrupted. Like --follow, the accounts changed are written to stdout every --snapshot-interval, and the state
+/// to the checkpoint if given, for query to read
+fn replica(options: &Options, mut exchange: exchange::Exchange) -> Result<(), Box<dyn Error>> {
+    let file = options
+        .file
+        .as_ref()
+        .ok_or("replica expects a ledger stream")?;
+    if options.resume {
+        return Err(
+            "A replica can not be resumed, it follows the ledger stream from the start".into(),
+        );
+    }
+    let follow = FollowOptions {
+        snapshot_every: options
+            .snapshot_every
+            .unwrap_or(FollowOptions::default().snapshot_every),
+        ..FollowOptions::default()
+    };
+    let format = options.amount_format.clone().unwrap_or(AmountFormat::Plain);
+    exchange::replica::follow_ledger(file, &mut exchange, &follow, |exchange, since, position| {
+        if let Err(e) = output::write_accounts(
+            io::stdout().lock(),
+            exchange.accounts_changed_since(since),
+            &format,
+            &exchange.config().client_ids,
+        ) {
+            eprintln!("Failed to write snapshot with exception: {}", e)
+        }
+        if let Some(path) = &options.checkpoint {
+            if let Err(e) = exchange.write_checkpoint(Path::new(path), position) {
+                eprintln!("Failed to write checkpoint with exception: {}", e)
+            }
+        }
+        true
+    })
+}
+
+/// route <input> --to <first>-<last>=<host:port>,...: the records of the input sent to the engines listening for their range
+/// of client ids, see cluster::route_csv
+fn route(options: &Options) -> Result<(), Box<dyn Error>> {
+    let file = options.file.as_ref().ok_or("route expects an input file")?;
+    if options.routes.is_empty() {
+        return Err("route expects --to".into());
+    }
+    let mut routes = options
+        .routes
+        .iter()
+        .map(|(range, address)| Ok((*range, io::BufWriter::new(TcpStream::connect(address)?))))
+        .collect::<io::Result<Vec<_>>>()?;
+    let unrouted = exchange::cluster::route_csv(File::open(file)?, &mut routes)?;
+    for (_, route) in routes {
+        route.into_inner()?.shutdown(Shutdown::Write)?;
+    }
+    if unrouted > 0 {
+        eprintln!("{} records of clients no range owns were skipped", unrouted);
+    }
+    Ok(())
+}
+
+/// listen <host:port>: process the transactions of the first connection, e.g. a range of client ids sent by route, then write
+/// the account summaries to --output or stdout, for merge-shards to put together
+fn listen(options: &Options, mut exchange: exchange::Exchange) -> Result<(), Box<dyn Error>> {
+    let address = options.file.as_ref().ok_or("listen expects an address")?;
+    let (input, _) = TcpListener::bind(address)?.accept()?;
+    exchange::process_transactions_from_reader_with(input, &mut exchange, &options.ingest)?;
+    let format = options.amount_format.clone().unwrap_or(AmountFormat::Plain);
+    match &options.output {
+        Some(path) => exchange.to_writer(create_output(options, path)?, &format)?,
+        None => exchange.to_writer(io::stdout().lock(), &format)?,
+    }
+    if options.print_stats {
+        eprintln!("{}", exchange.stats());
+    }
+    Ok(())
+}
+
+/// merge-shards <output> <shard>...
+fn merge_shards(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let (output, shards) = args
+        .split_first()
+        .ok_or("merge-shards expects an output file followed by the shard files")?;
+    output::merge_account_files(shards, io::BufWriter::new(File::create(output)?))?;
+    Ok(())
+}
+
+/// anonymize <input> <output> [--seed <n>]: a copy of the input that can be shared as a fixture, see anonymize::Anonymizer.
+/// Without a seed one is picked and reported, to regenerate the same fixture later
+fn anonymize(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let usage = "anonymize expects an input file, an output file and optionally --seed <n>";
+    let seed = match args {
+        [_, _] => SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos() as u64,
+        [_, _, flag, seed] if flag == "--seed" => seed.parse().map_err(|_| usage)?,
+        _ => return Err(usage.into()),
+    };
+    let anonymized = Anonymizer::new(seed).anonymize_csv(
+        File::open(&args[0])?,
+        io::BufWriter::new(File::create(&args[1])?),
+    )?;
+    eprintln!(
+        "{} rows anonymized with seed {}, {} rows skipped",
+        anonymized.rows, seed, anonymized.skipped
+    );
+    Ok(())
+}
+
+/// compact <snapshot dir> [--snapshot-keep <n>]: the snapshot directory trimmed to the newest snapshots, see snapshot::compact
+fn compact(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let usage = "compact expects a snapshot directory and optionally --snapshot-keep <n>";
+    let keep = match args {
+        [_] => SnapshotOptions::default().keep,
+        [_, flag, keep] if flag == "--snapshot-keep" => keep.parse().map_err(|_| usage)?,
+        _ => return Err(usage.into()),
+    };
+    let removed = snapshot::compact(Path::new(&args[0]), keep)?;
+    eprintln!("{} files removed from {}", removed.len(), args[0]);
+    Ok(())
+}
 
 #[tokio::main]
 async fn main() {
-    let mut exchange = exchange::Exchange::new();
-    if let Some(file) = env::args().nth(1) {
-        let exchange = task::spawn_blocking(move || {
-            if let Err(e) = exchange::process_transactions_from_csv(&file, &mut exchange) {
-                eprintln!("Failed to read CSV with exception: {}", e)
-            }
-            exchange
-        }).await.unwrap(); 
-        
-        exchange.to_csv()
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("merge-shards") {
+        if let Err(e) = merge_shards(&args[1..]) {
+            eprintln!("Failed to merge shards with exception: {}", e)
+        }
+        return;
+    }
+    if args.first().map(String::as_str) == Some("compact") {
+        if let Err(e) = compact(&args[1..]) {
+            eprintln!("Failed to compact with exception: {}", e)
+        }
+        return;
+    }
+    if args.first().map(String::as_str) == Some("anonymize") {
+        if let Err(e) = anonymize(&args[1..]) {
+            eprintln!("Failed to anonymize with exception: {}", e)
+        }
+        return;
+    }
+
+    //watch <dir> [options]: the directory takes the place of the input file. disputes <file> [options]: the dispute queue is
+    //written out instead of the account summaries
+    let watch = args.first().map(String::as_str) == Some("watch");
+    let disputes = args.first().map(String::as_str) == Some("disputes");
+    let decrypting = args.first().map(String::as_str) == Some("decrypt");
+    let querying = args.first().map(String::as_str) == Some("query");
+    let importing = args.first().map(String::as_str) == Some("import-disputes");
+    let replicating = args.first().map(String::as_str) == Some("replica");
+    let routing = args.first().map(String::as_str) == Some("route");
+    let listening = args.first().map(String::as_str) == Some("listen");
+    let args = if watch
+        || disputes
+        || decrypting
+        || querying
+        || importing
+        || replicating
+        || routing
+        || listening
+    {
+        args[1..].to_vec()
+    } else {
+        args
+    };
+    let options = match parse_options(args).map(|options| Options {
+        watch,
+        disputes,
+        ..options
+    }) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+    if decrypting {
+        #[cfg(feature = "encryption")]
+        if let Err(e) = decrypt(&options) {
+            eprintln!("Failed to decrypt with exception: {}", e)
+        }
+        #[cfg(not(feature = "encryption"))]
+        eprintln!("decrypt needs the encryption feature");
+        return;
+    }
+    if !querying
+        && (options.query != AccountFilter::default()
+            || options.query_client.is_some()
+            || options.query_tx.is_some())
+    {
+        eprintln!("--client, --tx, --locked and --negative are only available with query");
+        return;
+    }
+    if watch && !options.partitions.is_empty() {
+        eprintln!("Only one directory can be watched");
+        return;
+    }
+    if disputes && options.follow {
+        eprintln!("disputes can not be combined with --follow");
+        return;
+    }
+    if watch && options.manifest.is_some() {
+        eprintln!("--manifest can not be combined with watch");
+        return;
+    }
+    #[cfg(feature = "object-store")]
+    if watch && options.file.as_deref().and_then(ObjectUrl::parse).is_some() {
+        eprintln!("Object storage can not be watched");
+        return;
+    }
+
+    let mut exchange = exchange::Exchange::new().with_config(options.config.clone());
+    if options.ledger.is_some() || options.rejects.is_some() || options.float_report.is_some() {
+        exchange = exchange.with_ledger();
+    }
+    #[cfg(feature = "encryption")]
+    if let Some(encryption) = &options.encryption {
+        exchange = exchange.with_encryption(encryption.clone());
+    }
+    if querying {
+        if let Err(e) = query(&options, exchange) {
+            eprintln!("Failed to query with exception: {}", e)
+        }
+        return;
+    }
+    if importing {
+        if let Err(e) = import_disputes(&options, exchange) {
+            eprintln!("Failed to import disputes with exception: {}", e)
+        }
+        return;
+    }
+    if replicating {
+        if let Err(e) = replica(&options, exchange) {
+            eprintln!("Failed to replicate with exception: {}", e)
+        }
+        return;
+    }
+    if routing {
+        if let Err(e) = route(&options) {
+            eprintln!("Failed to route with exception: {}", e)
+        }
+        return;
+    }
+    if listening {
+        if let Err(e) = listen(&options, exchange) {
+            eprintln!("Failed to listen with exception: {}", e)
+        }
+        return;
+    }
+    if let Some(path) = &options.enrich {
+        match MappingEnricher::from_path(path) {
+            Ok(enricher) => exchange = exchange.with_enricher(enricher),
+            Err(e) => {
+                eprintln!("Failed to read {} with exception: {}", path, e);
+                return;
+            }
+        }
+    }
+    if let Some(path) = &options.archive {
+        match Archive::create(path) {
+            Ok(archive) => exchange = exchange.with_archive(archive),
+            Err(e) => {
+                eprintln!("Failed to create archive with exception: {}", e);
+                return;
+            }
+        }
+    }
+    #[cfg(feature = "alert-webhook")]
+    if let Some(webhook) = &options.alert_webhook {
+        exchange = exchange.with_alert_webhook(webhook.clone());
+    }
+    if let Some(snapshots) = &options.snapshots {
+        match Snapshots::create(snapshots.clone()) {
+            Ok(snapshots) => exchange = exchange.with_snapshots(snapshots),
+            Err(e) => {
+                eprintln!("Failed to create snapshot directory with exception: {}", e);
+                return;
+            }
+        }
+    }
+    //the stream is written from the events on a thread of its own, which ends once the exchange is dropped
+    let ledger_stream = match &options.ledger_stream {
+        Some(path) => match LedgerStream::create(path) {
+            Ok(stream) => {
+                let events = exchange.subscribe();
+                Some(std::thread::spawn(move || stream.write_all(events)))
+            }
+            Err(e) => {
+                eprintln!("Failed to create ledger stream with exception: {}", e);
+                return;
+            }
+        },
+        None => None,
+    };
+    let mut budget_exceeded = false;
+    if let Some(file) = options.file.clone() {
+        let ingest = options.ingest.clone();
+        let follow = FollowOptions {
+            snapshot_every: options
+                .snapshot_every
+                .unwrap_or(FollowOptions::default().snapshot_every),
+            ..FollowOptions::default()
+        };
+        let (follow_input, watch_input) = (options.follow, options.watch);
+        let checkpoint =
+            options
+                .checkpoint
+                .as_ref()
+                .map(|path| exchange::checkpoint::CheckpointOptions {
+                    path: path.into(),
+                    every: options.checkpoint_every.unwrap_or(100_000),
+                });
+        let resume = options.resume;
+        let speculative = options.speculative_threads;
+        let client_ranges = options.client_ranges.clone();
+        let aggregate_only = options.aggregate_only;
+        let partitions: Vec<String> = std::iter::once(file.clone())
+            .chain(options.partitions.iter().cloned())
+            .collect();
+        let mut manifest = match &options.manifest {
+            Some(path) => match previously_processed(path, &partitions, options.force) {
+                Ok(manifest) => Some(manifest),
+                Err(e) if e.is::<AlreadyProcessed>() => {
+                    eprintln!("{}", e);
+                    std::process::exit(EXIT_ALREADY_PROCESSED);
+                }
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return;
+                }
+            },
+            None => None,
+        };
+        #[cfg(feature = "object-store")]
+        let object_input = match ObjectUrl::parse(&file)
+            .map(|url| object_store(&options).map(|store| (url, store)))
+            .transpose()
+        {
+            Ok(input) => input,
+            Err(e) => {
+                eprintln!("{}", e);
+                return;
+            }
+        };
+        let snapshot_format = options.amount_format.clone().unwrap_or(AmountFormat::Plain);
+        let (mut exchange, aborted, processed) = task::spawn_blocking(move || {
+            let result = match (follow_input, watch_input) {
+                //both run until interrupted. Watch snapshots list every account, follow ones the accounts changed since the previous one
+                (_, true) => exchange::watch::watch_directory(
+                    std::path::Path::new(&file),
+                    &mut exchange,
+                    &ingest,
+                    follow.snapshot_every,
+                    |exchange, _| {
+                        if let Err(e) = exchange.to_writer(io::stdout().lock(), &snapshot_format) {
+                            eprintln!("Failed to write snapshot with exception: {}", e)
+                        }
+                        true
+                    },
+                ),
+                (true, false) => exchange::follow::follow_transactions_from_csv(
+                    &file,
+                    &mut exchange,
+                    &ingest,
+                    &follow,
+                    |exchange, since| {
+                        if let Err(e) = output::write_accounts(
+                            io::stdout().lock(),
+                            exchange.accounts_changed_since(since),
+                            &snapshot_format,
+                            &exchange.config().client_ids,
+                        ) {
+                            eprintln!("Failed to write snapshot with exception: {}", e)
+                        }
+                        true
+                    },
+                ),
+                (false, false) if checkpoint.is_some() => {
+                    exchange::checkpoint::process_transactions_from_csv_with_checkpoints(
+                        &file,
+                        &mut exchange,
+                        &ingest,
+                        checkpoint.as_ref().unwrap(),
+                        resume,
+                    )
+                }
+                (false, false) if partitions.len() > 1 => {
+                    exchange::partition::process_partitions_from_csv(
+                        &partitions,
+                        &mut exchange,
+                        &ingest,
+                    )
+                }
+                #[cfg(feature = "object-store")]
+                (false, false) if object_input.is_some() => {
+                    let (url, store) = object_input.as_ref().unwrap();
+                    exchange::object_store::process_transactions_from_object(
+                        url,
+                        store,
+                        &mut exchange,
+                        &ingest,
+                    )
+                }
+                (false, false) if aggregate_only => {
+                    exchange::aggregate::aggregate_transactions_from_csv(
+                        &file,
+                        &mut exchange,
+                        &ingest,
+                    )
+                }
+                (false, false) if client_ranges.is_some() => {
+                    exchange::cluster::process_client_ranges_from_csv(
+                        &file,
+                        &mut exchange,
+                        client_ranges.as_ref().unwrap(),
+                        &ingest,
+                    )
+                }
+                (false, false) if speculative.is_some() => {
+                    exchange::speculative::process_transactions_from_csv_speculatively(
+                        &file,
+                        &mut exchange,
+                        &ingest,
+                        speculative.unwrap(),
+                    )
+                }
+                (false, false) => {
+                    exchange::process_transactions_from_csv_with(&file, &mut exchange, &ingest)
+                }
+            };
+            let processed = result.is_ok();
+            let aborted = match result {
+                Err(e) => {
+                    eprintln!(
+                        "Failed to read CSV with exception: {}",
+                        exchange.config().client_ids.describe(e.as_ref())
+                    );
+                    e.is::<LimitExceeded>() || e.is::<UnknownTransactionType>()
+                }
+                Ok(_) => false,
+            };
+            (exchange, aborted, processed)
+        })
+        .await
+        .unwrap();
+        if aborted {
+            std::process::exit(EXIT_ABORTED);
+        }
+        exchange.expire_deferred();
+        if let Some(rule) = &options.write_off {
+            let written_off = exchange.write_off_small_balances(rule);
+            if !options.ingest.quiet && !written_off.is_empty() {
+                eprintln!(
+                    "Wrote off the balances of {} inactive accounts, {} in total",
+                    written_off.len(),
+                    written_off
+                        .iter()
+                        .map(|(_, amount)| *amount)
+                        .sum::<Currency>()
+                );
+            }
+        }
+        budget_exceeded = !exchange.check_error_budget().is_empty();
+
+        #[cfg(feature = "latency-histograms")]
+        let output_started = Instant::now();
+        let mut outputs_failed = false;
+        let mut summaries = TrailerWriter::new(match &options.output {
+            Some(path) => match create_output(&options, path) {
+                Ok(output) => output,
+                Err(e) => {
+                    eprintln!("Failed to create {} with exception: {}", path, e);
+                    return;
+                }
+            },
+            None => Box::new(io::stdout().lock()) as Box<dyn io::Write>,
+        });
+        match (&options.amount_format, options.output_shards) {
+            _ if options.disputes => {
+                if let Err(e) = output::write_disputes(
+                    &mut summaries,
+                    &exchange.open_disputes(),
+                    &exchange.config().client_ids,
+                ) {
+                    eprintln!("Failed to write disputes with exception: {}", e);
+                    outputs_failed = true;
+                }
+            }
+            (format, Some(shards)) => {
+                let prefix = options.output_prefix.as_deref().unwrap_or("accounts");
+                if let Err(e) = exchange.to_shard_files(
+                    &output::shard_paths(prefix, shards),
+                    format.as_ref().unwrap_or(&AmountFormat::Plain),
+                ) {
+                    eprintln!("Failed to write account shards with exception: {}", e);
+                    outputs_failed = true;
+                }
+            }
+            (format, None) if options.table => {
+                if let Err(e) = exchange.to_table_writer(
+                    &mut summaries,
+                    format.as_ref().unwrap_or(&AmountFormat::Plain),
+                    colored(&options),
+                ) {
+                    eprintln!("Failed to write accounts with exception: {}", e);
+                    outputs_failed = true;
+                }
+            }
+            (format, None) if options.json => {
+                if let Err(e) = exchange.to_json_writer(
+                    &mut summaries,
+                    format.as_ref().unwrap_or(&AmountFormat::Plain),
+                    options.json_holds,
+                    options.json_annotations,
+                ) {
+                    eprintln!("Failed to write accounts with exception: {}", e);
+                    outputs_failed = true;
+                }
+            }
+            (format, None) if options.output_schema == OutputSchema::Extended => {
+                if let Err(e) = exchange.to_composite_writer(
+                    &mut summaries,
+                    format.as_ref().unwrap_or(&AmountFormat::Plain),
+                ) {
+                    eprintln!("Failed to write accounts with exception: {}", e);
+                    outputs_failed = true;
+                }
+            }
+            (format, None) if options.extended => {
+                if let Err(e) = exchange.to_extended_writer(
+                    &mut summaries,
+                    format.as_ref().unwrap_or(&AmountFormat::Plain),
+                ) {
+                    eprintln!("Failed to write accounts with exception: {}", e);
+                    outputs_failed = true;
+                }
+            }
+            (format, None) if options.flush_every.is_some() => {
+                let quiet = options.ingest.quiet;
+                if let Err(e) = exchange.to_writer_in_ranges(
+                    &mut summaries,
+                    format.as_ref().unwrap_or(&AmountFormat::Plain),
+                    options.output_threads.unwrap_or(1),
+                    options.flush_every.unwrap_or(usize::MAX),
+                    |written, total| {
+                        if !quiet {
+                            eprintln!("Wrote {} of {} accounts", written, total)
+                        }
+                    },
+                ) {
+                    eprintln!("Failed to write accounts with exception: {}", e);
+                    outputs_failed = true;
+                }
+            }
+            (format, None) if options.output_threads.is_some() => {
+                if let Err(e) = exchange.to_writer_parallel(
+                    &mut summaries,
+                    format.as_ref().unwrap_or(&AmountFormat::Plain),
+                    options.output_threads.unwrap_or(1),
+                ) {
+                    eprintln!("Failed to write accounts with exception: {}", e);
+                    outputs_failed = true;
+                }
+            }
+            (Some(format), None) => {
+                if let Err(e) = exchange.to_writer(&mut summaries, format) {
+                    eprintln!("Failed to write accounts with exception: {}", e);
+                    outputs_failed = true;
+                }
+            }
+            (None, None) => {
+                if let Err(e) = exchange.to_writer(&mut summaries, &AmountFormat::Plain) {
+                    eprintln!("Failed to write accounts with exception: {}", e);
+                    outputs_failed = true;
+                }
+            }
+        }
+        let trailer = summaries.trailer();
+        let mut written = match options.trailer {
+            Some(TrailerPlacement::Footer) => summaries.write_trailer().map(drop),
+            _ => summaries.flush(),
+        };
+        drop(summaries);
+        if let (Some(TrailerPlacement::Sidecar), Some(path), Ok(())) =
+            (options.trailer, &options.output, &written)
+        {
+            written =
+                create_output(&options, &output::trailer_path(path)).and_then(|mut sidecar| {
+                    writeln!(sidecar, "{}", trailer)?;
+                    sidecar.flush()
+                });
+        }
+        if let Err(e) = written {
+            eprintln!("Failed to write accounts with exception: {}", e);
+            outputs_failed = true;
+        }
+        if let Some(path) = &options.settlement {
+            if let Err(e) = create_output(&options, path)
+                .map_err(Into::into)
+                .and_then(|output| {
+                    write_settlement(&exchange, output, options.settlement_baseline.as_deref())
+                })
+            {
+                eprintln!("Failed to write settlement report with exception: {}", e);
+                outputs_failed = true;
+            }
+        }
+        if let (Some(path), Some(ledger)) = (&options.float_report, exchange.ledger()) {
+            if let Err(e) = create_output(&options, path).and_then(|output| {
+                float::write_float(output, &float::float(ledger), &exchange.config().client_ids)
+            }) {
+                eprintln!("Failed to write float report with exception: {}", e);
+                outputs_failed = true;
+            }
+        }
+        if let (Some(baseline), Some(path)) = (&options.baseline, &options.delta) {
+            if let Err(e) = create_output(&options, path)
+                .map_err(Into::into)
+                .and_then(|output| write_delta(&exchange, output, baseline))
+            {
+                eprintln!("Failed to write delta report with exception: {}", e);
+                outputs_failed = true;
+            }
+        }
+        if let (Some(binary), Some(file)) = (&options.shadow, &options.file) {
+            let files: Vec<String> = std::iter::once(file.clone())
+                .chain(options.partitions.iter().cloned())
+                .collect();
+            let report = shadow_deltas(&exchange, binary, &files).and_then(|deltas| {
+                if !deltas.is_empty() {
+                    eprintln!("Shadow {} disagrees on {} accounts", binary, deltas.len());
+                }
+                let output = match &options.shadow_report {
+                    Some(path) => create_output(&options, path)?,
+                    None if deltas.is_empty() => return Ok(()),
+                    None => Box::new(io::stderr()),
+                };
+                delta::write_delta(output, &deltas, &exchange.config().client_ids)?;
+                Ok(())
+            });
+            if let Err(e) = report {
+                eprintln!("Failed to compare with shadow with exception: {}", e);
+                outputs_failed = true;
+            }
+        }
+        if let Some(feed) = &options.verify_feed {
+            let report =
+                reconcile::reconcile_transactions_from_csv(feed, &exchange, &options.ingest)
+                    .and_then(|reconciliation| {
+                        eprintln!("{}", reconciliation);
+                        let output = match &options.verify_report {
+                            Some(path) => create_output(&options, path)?,
+                            None if reconciliation.discrepancies.is_empty() => return Ok(()),
+                            None => Box::new(io::stderr()),
+                        };
+                        reconcile::write_reconciliation(
+                            output,
+                            &reconciliation.discrepancies,
+                            &exchange.config().client_ids,
+                        )?;
+                        Ok(())
+                    });
+            if let Err(e) = report {
+                eprintln!(
+                    "Failed to verify {} with exception: {}",
+                    feed,
+                    exchange.config().client_ids.describe(e.as_ref())
+                );
+                outputs_failed = true;
+            }
+        }
+        if let Some(path) = &options.receivables {
+            if let Err(e) = create_output(&options, path)
+                .and_then(|output| exchange.receivables_to_writer(output))
+            {
+                eprintln!("Failed to write receivables report with exception: {}", e);
+                outputs_failed = true;
+            }
+        }
+        if let Some(path) = &options.sql {
+            if let Err(e) =
+                create_output(&options, path).and_then(|output| exchange.to_sql_writer(output))
+            {
+                eprintln!("Failed to write SQL export with exception: {}", e);
+                outputs_failed = true;
+            }
+        }
+        if let (Some(path), Some(ledger)) = (&options.ledger, exchange.ledger()) {
+            if let Err(e) = write_sealed_output(&options, path, |output| {
+                ledger.write_csv(output, &exchange.config().client_ids)
+            }) {
+                eprintln!("Failed to write ledger with exception: {}", e);
+                outputs_failed = true;
+            }
+        }
+        if let (Some(path), Some(ledger)) = (&options.rejects, exchange.ledger()) {
+            if let Err(e) = write_sealed_output(&options, path, |output| {
+                ledger.write_rejects(output, &exchange.config().client_ids)
+            }) {
+                eprintln!("Failed to write rejects with exception: {}", e);
+                outputs_failed = true;
+            }
+        }
+        if let Some(path) = &options.pending_chargebacks {
+            if let Err(e) = create_output(&options, path).and_then(|output| {
+                output::write_pending_chargebacks(
+                    output,
+                    exchange.pending_chargebacks(),
+                    &exchange.config().client_ids,
+                )
+            }) {
+                eprintln!("Failed to write pending chargebacks with exception: {}", e);
+                outputs_failed = true;
+            }
+        }
+        if let Some(path) = &options.ordering_report {
+            if let Err(e) = create_output(&options, path).and_then(|output| {
+                exchange
+                    .stats()
+                    .ordering()
+                    .write_json(output, &exchange.config().client_ids)
+            }) {
+                eprintln!("Failed to write ordering report with exception: {}", e);
+                outputs_failed = true;
+            }
+        }
+        #[cfg(feature = "latency-histograms")]
+        exchange.record_stage(Stage::Output, output_started.elapsed());
+        if options.print_stats {
+            eprintln!("{}", exchange.stats());
+        }
+        drop(exchange);
+        if let Some(Ok(Err(e))) = ledger_stream.map(std::thread::JoinHandle::join) {
+            eprintln!("Failed to write ledger stream with exception: {}", e);
+            outputs_failed = true;
+        }
+        //recorded last, so a run whose outputs could not all be written can be retried without --force
+        if let Some((manifest, entries)) =
+            manifest.as_mut().filter(|_| processed && !outputs_failed)
+        {
+            for entry in entries.drain(..) {
+                if let Err(e) = manifest.record(entry) {
+                    eprintln!("Failed to update manifest with exception: {}", e)
+                }
+            }
+        }
     } else {
         eprintln!("You must provide a valid file path");
     }
 
-    println!("Processing done!")
-}
\ No newline at end of file
+    //stdout only carries the account summaries, so it can be redirected and parsed
+    if !options.ingest.quiet {
+        eprintln!("Processing done!")
+    }
+    if budget_exceeded {
+        std::process::exit(EXIT_BUDGET_EXCEEDED);
+    }
+}
@@ -0,0 +1,540 @@
+//! End-to-end runs of the payment_engine binary, so the argument parsing, the dispatch between the ingestion modes and the
+//! writers of main.rs are covered along with the engine. Every directory of tests/fixtures is a scenario:
+//!
+//! - input.csv, the input file
+//! - args, optional, the arguments after the input file, one per line. {out} stands for a scratch directory of the run
+//! - expected_stdout, the account summaries written to stdout
+//! - expected_<name>, optional, the content of {out}/<name> once the run is over (ledgers, reports)
+//!
+//! Outputs are normalized before being compared: account rows come out in hash map order, so CSV and table rows after the
+//! header are sorted and JSON arrays are sorted by client. Two generated scenarios check that a large input gives the same
+//! accounts through the binary as through the library, and through every way of applying it.
+//!
+//! cargo test --test end_to_end
+//!
+//! UPDATE_FIXTURES=1 writes the outputs of the runs as the expected ones instead of comparing them, to review with git diff
+use std::env;
+use std::fs;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+
+use payment_engine::exchange;
+use payment_engine::exchange::output::AmountFormat;
+use payment_engine::exchange::Exchange;
+
+/// xorshift, so every run generates the same input
+struct Generator(u64);
+
+impl Generator {
+    fn next(&mut self, bound: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % bound
+    }
+}
+
+fn scratch(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("payment_engine_end_to_end_{}", name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Run the binary with the arguments, failing on a non-zero exit. Returns stdout
+fn run<S: AsRef<std::ffi::OsStr>>(args: &[S]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_payment_engine"))
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "payment_engine failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// The output with its row order made deterministic, see the module documentation
+fn normalize(output: &str) -> String {
+    if let Ok(serde_json::Value::Array(mut accounts)) = serde_json::from_str(output) {
+        accounts.sort_by_key(|account| account["client"].as_u64());
+        return serde_json::to_string_pretty(&accounts).unwrap() + "\n";
+    }
+    let mut lines = output.lines().map(str::trim_end);
+    let header = lines.next().unwrap_or_default();
+    let mut rows: Vec<&str> = lines.filter(|line| !line.is_empty()).collect();
+    rows.sort_unstable();
+    std::iter::once(header)
+        .chain(rows)
+        .map(|line| format!("{}\n", line))
+        .collect()
+}
+
+/// Compare the actual output with the expected file, or write it there under UPDATE_FIXTURES
+fn check(scenario: &str, expected: &Path, actual: &str) -> Option<String> {
+    let actual = normalize(actual);
+    if env::var_os("UPDATE_FIXTURES").is_some() {
+        fs::write(expected, &actual).unwrap();
+        return None;
+    }
+    let expected_output = fs::read_to_string(expected).unwrap_or_default();
+    (normalize(&expected_output) != actual).then(|| {
+        format!(
+            "{}: {} differs\n--- expected\n{}--- actual\n{}",
+            scenario,
+            expected.file_name().unwrap().to_string_lossy(),
+            expected_output,
+            actual
+        )
+    })
+}
+
+#[test]
+fn it_should_produce_the_expected_outputs_of_every_fixture() {
+    let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let mut scenarios: Vec<PathBuf> = fs::read_dir(&fixtures)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.is_dir())
+        .collect();
+    scenarios.sort();
+    assert!(!scenarios.is_empty());
+
+    let mut failures = Vec::new();
+    for scenario in &scenarios {
+        let name = scenario.file_name().unwrap().to_string_lossy().to_string();
+        let out = scratch(&name);
+        let mut args = vec![scenario.join("input.csv").to_string_lossy().to_string()];
+        if let Ok(extra) = fs::read_to_string(scenario.join("args")) {
+            args.extend(
+                extra
+                    .lines()
+                    .filter(|line| !line.trim().is_empty())
+                    .map(|line| line.trim().replace("{out}", &out.to_string_lossy())),
+            );
+        }
+
+        let stdout = run(&args);
+        failures.extend(check(&name, &scenario.join("expected_stdout"), &stdout));
+        for entry in fs::read_dir(scenario).unwrap() {
+            let path = entry.unwrap().path();
+            let file = path.file_name().unwrap().to_string_lossy().to_string();
+            if let Some(output) = file
+                .strip_prefix("expected_")
+                .filter(|output| *output != "stdout")
+            {
+                let actual = fs::read_to_string(out.join(output)).unwrap_or_default();
+                failures.extend(check(&name, &path, &actual));
+            }
+        }
+        fs::remove_dir_all(&out).unwrap();
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
+
+/// Deposits and withdrawals over a few hundred clients, with disputes of earlier transactions of the same client followed
+/// by resolves and chargebacks. Written both as a single file and as one file per group of clients, so a client's
+/// transactions keep their order in either
+fn generate(dir: &Path, transactions: u64, partitions: u64) -> (PathBuf, Vec<PathBuf>) {
+    const CLIENTS: u64 = 500;
+    let header = "type,client,tx,amount";
+    let path = dir.join("input.csv");
+    let mut single = BufWriter::new(fs::File::create(&path).unwrap());
+    writeln!(single, "{}", header).unwrap();
+    let parts: Vec<PathBuf> = (0..partitions)
+        .map(|i| dir.join(format!("part-{}.csv", i)))
+        .collect();
+    let mut writers: Vec<BufWriter<fs::File>> = parts
+        .iter()
+        .map(|path| {
+            let mut writer = BufWriter::new(fs::File::create(path).unwrap());
+            writeln!(writer, "{}", header).unwrap();
+            writer
+        })
+        .collect();
+
+    let mut generator = Generator(0x5eed);
+    let mut history: Vec<Vec<u64>> = vec![Vec::new(); CLIENTS as usize];
+    for tx in 1..=transactions {
+        let client = generator.next(CLIENTS);
+        let past = &history[client as usize];
+        let row = match generator.next(100) {
+            0..=1 if !past.is_empty() => {
+                let step = ["dispute", "resolve", "chargeback"][generator.next(3) as usize];
+                let disputed = past[generator.next(past.len() as u64) as usize];
+                format!("{},{},{},", step, client, disputed)
+            }
+            0..=59 => {
+                history[client as usize].push(tx);
+                format!(
+                    "deposit,{},{},{}.{:04}",
+                    client,
+                    tx,
+                    generator.next(1000),
+                    generator.next(10_000)
+                )
+            }
+            _ => {
+                history[client as usize].push(tx);
+                format!(
+                    "withdrawal,{},{},{}.{:04}",
+                    client,
+                    tx,
+                    generator.next(200),
+                    generator.next(10_000)
+                )
+            }
+        };
+        writeln!(single, "{}", row).unwrap();
+        writeln!(writers[(client % partitions) as usize], "{}", row).unwrap();
+    }
+    single.flush().unwrap();
+    for mut writer in writers {
+        writer.flush().unwrap();
+    }
+    (path, parts)
+}
+
+#[test]
+fn it_should_match_the_library_on_a_large_generated_input() {
+    let dir = scratch("generated_library");
+    let (input, _) = generate(&dir, 20_000, 1);
+    let input = input.to_string_lossy().to_string();
+
+    let mut exchange = Exchange::new();
+    exchange::process_transactions_from_csv(&input, &mut exchange).unwrap();
+    let mut expected = Vec::new();
+    exchange
+        .to_writer(&mut expected, &AmountFormat::Plain)
+        .unwrap();
+
+    assert_eq!(
+        normalize(&String::from_utf8(expected).unwrap()),
+        normalize(&run(&[&input]))
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn it_should_give_the_same_accounts_through_every_ingestion_mode() {
+    let dir = scratch("generated_modes");
+    let (input, parts) = generate(&dir, 20_000, 4);
+    let input = input.to_string_lossy().to_string();
+    let serial = normalize(&run(&[&input]));
+    assert!(serial.lines().count() > 400);
+
+    let partitioned: Vec<String> = parts
+        .iter()
+        .map(|part| part.to_string_lossy().to_string())
+        .collect();
+    assert_eq!(serial, normalize(&run(&partitioned)));
+    assert_eq!(
+        serial,
+        normalize(&run(&[input.as_str(), "--speculative-threads", "4"]))
+    );
+    assert_eq!(
+        serial,
+        normalize(&run(&[input.as_str(), "--output-threads", "3"]))
+    );
+    let checkpoint = dir.join("checkpoint.json").to_string_lossy().to_string();
+    assert_eq!(
+        serial,
+        normalize(&run(&[
+            input.as_str(),
+            "--checkpoint",
+            &checkpoint,
+            "--checkpoint-every",
+            "1000"
+        ]))
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn it_should_report_the_accounts_a_shadow_build_disagrees_on() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = scratch("shadow");
+    let input = dir.join("input.csv");
+    fs::write(
+        &input,
+        "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,2,2,5.0\ndispute,2,2,\nchargeback,2,2,\n",
+    )
+    .unwrap();
+    let input = input.to_string_lossy().to_string();
+    let report = dir.join("shadow.csv").to_string_lossy().to_string();
+
+    //the engine as its own shadow agrees on every account
+    let binary = env!("CARGO_BIN_EXE_payment_engine");
+    run(&[
+        input.as_str(),
+        "--shadow",
+        binary,
+        "--shadow-report",
+        &report,
+    ]);
+    assert_eq!(
+        format!("{}\n", exchange::delta::HEADER),
+        fs::read_to_string(&report).unwrap()
+    );
+
+    //a build that would not charge back the disputed deposit
+    let older = dir.join("older.sh");
+    fs::write(
+        &older,
+        "#!/bin/sh\nprintf 'client,available,held,total,locked\\n1,10.0000,0.0000,10.0000,false\\n2,0.0000,5.0000,5.0000,false\\n'\n",
+    )
+    .unwrap();
+    fs::set_permissions(&older, fs::Permissions::from_mode(0o755)).unwrap();
+    run(&[
+        input.as_str(),
+        "--shadow",
+        &older.to_string_lossy(),
+        "--shadow-report",
+        &report,
+    ]);
+    assert_eq!(
+        format!(
+            "{}\n2,locked,0.0000,5.0000,5.0000,0.0000,0.0000,0.0000,true\n",
+            exchange::delta::HEADER
+        ),
+        fs::read_to_string(&report).unwrap()
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn it_should_refuse_to_deny_unknown_transaction_types() {
+    let dir = scratch("deny_typo");
+    let input = dir.join("input.csv");
+    fs::write(&input, "type,client,tx,amount\ndeposit,1,1,10.0\n").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_payment_engine"))
+        .arg(&input)
+        .args(["--deny", "deposit,withdrawl"])
+        .output()
+        .unwrap();
+    assert!(String::from_utf8_lossy(&output.stderr)
+        .contains("Unknown transaction type withdrawl in --deny"));
+    assert_eq!("", String::from_utf8_lossy(&output.stdout));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn it_should_refuse_negative_reserves_and_approval_thresholds() {
+    let dir = scratch("negative_reserve");
+    let input = dir.join("input.csv");
+    fs::write(
+        &input,
+        "type,client,tx,amount\ndeposit,1,1,10.0\nwithdrawal,1,2,110.0\n",
+    )
+    .unwrap();
+    for (args, error) in [
+        (
+            ["--chargeback-approval-threshold", "-1"],
+            "--chargeback-approval-threshold expects an amount that is not negative",
+        ),
+        (
+            ["--reserve", "-100"],
+            "--reserve expects an amount that is not negative",
+        ),
+        (
+            ["--client-reserve", "1=-100"],
+            "--client-reserve expects <client>=<amount>, the amount not negative",
+        ),
+    ] {
+        let output = Command::new(env!("CARGO_BIN_EXE_payment_engine"))
+            .arg(&input)
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(
+            String::from_utf8_lossy(&output.stderr).contains(error),
+            "{:?}",
+            args
+        );
+        assert_eq!("", String::from_utf8_lossy(&output.stdout));
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn it_should_exit_with_a_distinct_code_on_files_already_processed() {
+    let dir = scratch("manifest");
+    let (input, manifest) = (dir.join("input.csv"), dir.join("processed.csv"));
+    fs::write(&input, "type,client,tx,amount\ndeposit,1,1,10.0\n").unwrap();
+    let submit = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_payment_engine"))
+            .arg(&input)
+            .arg("--manifest")
+            .arg(&manifest)
+            .args(args)
+            .output()
+            .unwrap()
+    };
+
+    //the settlement report can not be written, so the file is not recorded as processed and the retry goes through
+    let unwritable = dir.join("missing").join("settlement.csv");
+    let failed = submit(&["--settlement", unwritable.to_str().unwrap()]);
+    assert!(String::from_utf8_lossy(&failed.stderr).contains("Failed to write settlement report"));
+    assert!(!manifest.exists());
+
+    assert_eq!(Some(0), submit(&[]).status.code());
+    let refused = submit(&[]);
+    assert_eq!(Some(5), refused.status.code());
+    assert!(String::from_utf8_lossy(&refused.stderr).contains("which was already processed"));
+    assert_eq!("", String::from_utf8_lossy(&refused.stdout));
+    assert_eq!(Some(0), submit(&["--force"]).status.code());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn it_should_exit_with_a_distinct_code_when_the_error_budget_is_exceeded() {
+    let dir = scratch("error_budget");
+    let input = dir.join("input.csv");
+    fs::write(
+        &input,
+        "type,client,tx,amount\ndeposit,1,1,10.0\nwithdrawal,1,2,50.0\nwithdrawal,2,3,1.0\ndeposit,2,4,1.0\n",
+    )
+    .unwrap();
+    let budget = |max: &str| {
+        Command::new(env!("CARGO_BIN_EXE_payment_engine"))
+            .arg(&input)
+            .args(["--max-reject-rate", max])
+            .output()
+            .unwrap()
+    };
+
+    let exceeded = budget("25");
+    assert_eq!(Some(3), exceeded.status.code());
+    assert!(String::from_utf8_lossy(&exceeded.stderr)
+        .contains("Error budget exceeded: reject rate 50.00% above 25%"));
+    //the account summaries are still written
+    assert_eq!(3, String::from_utf8_lossy(&exceeded.stdout).lines().count());
+    assert_eq!(Some(0), budget("50").status.code());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn it_should_not_log_the_raw_client_ids_when_they_are_hashed() {
+    let dir = scratch("hashed_logs");
+    let input = dir.join("input.csv");
+    fs::write(
+        &input,
+        "type,client,tx,amount\ndeposit,4242,1,10.0\nwithdrawal,4242,2,50.0\ndeposit,4343,3,1.0\n",
+    )
+    .unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_payment_engine"))
+        .arg(&input)
+        .args(["--hash-client-ids-env", "PAYMENT_ENGINE_TEST_SALT"])
+        .args(["--max-clients", "1", "--on-limit", "abort"])
+        .env("PAYMENT_ENGINE_TEST_SALT", "s4lt")
+        .output()
+        .unwrap();
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    //the rejected withdrawal, then the client limit stopping the run
+    assert!(stderr.contains("Igoring transaction Withdrawal,6ab016f700e01ede,2"));
+    assert!(stderr.contains("Client limit of 1 reached by transaction Deposit,"));
+    assert!(
+        !stderr.contains("4242") && !stderr.contains("4343"),
+        "{}",
+        stderr
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn it_should_not_trace_or_route_the_raw_client_ids_when_they_are_hashed() {
+    let dir = scratch("hashed_trace");
+    let input = dir.join("input.csv");
+    fs::write(
+        &input,
+        "type,client,tx,amount\ndeposit,4242,1,10.0\nwithdrawal,4242,2,50.0\ndeposit,4343,3,1.0\n",
+    )
+    .unwrap();
+    let hashed = |args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_payment_engine"))
+            .arg(&input)
+            .args(["--hash-client-ids-env", "PAYMENT_ENGINE_TEST_SALT"])
+            .args(args)
+            .env("PAYMENT_ENGINE_TEST_SALT", "s4lt")
+            .output()
+            .unwrap();
+        String::from_utf8_lossy(&output.stderr).to_string()
+    };
+
+    let traced = hashed(&["--trace", "1"]);
+    assert!(traced.contains("trace 1 submit: submission 1, Deposit,6ab016f700e01ede,1"));
+    assert!(traced.contains("trace 2 validate: rejected, "));
+    let routed = hashed(&["--client-ranges", "0-4300"]);
+    assert!(routed.contains("No client range owns client "));
+    for stderr in [traced, routed] {
+        assert!(
+            !stderr.contains("4242") && !stderr.contains("4343"),
+            "{}",
+            stderr
+        );
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn it_should_exit_with_a_distinct_code_and_no_summaries_when_a_run_is_aborted() {
+    let dir = scratch("aborted");
+    let input = dir.join("input.csv");
+    fs::write(
+        &input,
+        "type,client,tx,amount\ndeposit,1,1,10.0\nrebate,1,2,1.0\ndeposit,2,3,1.0\n",
+    )
+    .unwrap();
+    let run = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_payment_engine"))
+            .arg(&input)
+            .args(args)
+            .output()
+            .unwrap()
+    };
+
+    for args in [
+        &["--max-clients", "1", "--on-limit", "abort"][..],
+        &["--max-clients", "1", "--client-ranges", "2"][..],
+        &["--unknown-types", "fail"][..],
+        &["--unknown-types", "fail", "--client-ranges", "2"][..],
+    ] {
+        let aborted = run(args);
+        assert_eq!(Some(4), aborted.status.code(), "{:?}", args);
+        assert_eq!("", String::from_utf8_lossy(&aborted.stdout), "{:?}", args);
+    }
+    let degraded = run(&["--max-clients", "1", "--on-limit", "degrade"]);
+    assert_eq!(Some(0), degraded.status.code());
+    assert_eq!(2, String::from_utf8_lossy(&degraded.stdout).lines().count());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// tests/checkpoints/unversioned.checkpoint was written by a build from before checkpoints were versioned, after the first
+/// seven rows of tests/checkpoints/input.csv
+#[test]
+fn it_should_resume_from_a_checkpoint_written_before_they_were_versioned() {
+    let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/checkpoints");
+    let dir = scratch("unversioned_checkpoint");
+    let checkpoint = dir.join("run.checkpoint");
+    fs::copy(fixtures.join("unversioned.checkpoint"), &checkpoint).unwrap();
+    let input = fixtures.join("input.csv");
+
+    let resumed = run(&[
+        input.as_os_str(),
+        "--checkpoint".as_ref(),
+        checkpoint.as_os_str(),
+        "--resume".as_ref(),
+    ]);
+    assert_eq!(normalize(&run(&[&input])), normalize(&resumed));
+    let written: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(&checkpoint).unwrap()).unwrap();
+    assert_eq!(3, written["version"]);
+    fs::remove_dir_all(&dir).unwrap();
+}
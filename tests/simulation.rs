@@ -0,0 +1,279 @@
+//! Deterministic simulation of the async pipeline: sources parsing CSV rows, a bounded channel, the engine behind an
+//! ExchangeHandle and an output task reading the accounts while transactions are still flowing. Every task runs on a single
+//! threaded executor whose scheduler picks the next task to poll from a seeded xorshift, so each seed is one reproducible
+//! interleaving. The engine only promises order independence across clients, so every source keeps the order of the
+//! transactions of a client and the final state must match the one of a sequential run, whatever the seed.
+//!
+//! cargo test --test simulation
+//!
+//! SIMULATION_SEED replays a single seed instead of the whole range
+use std::env;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::Context;
+use std::task::Poll;
+
+use futures::channel::mpsc;
+use futures::executor::block_on;
+use futures::stream;
+use futures::task::noop_waker;
+use futures::SinkExt;
+use futures::StreamExt;
+
+use payment_engine::exchange::output::AmountFormat;
+use payment_engine::exchange::query::AccountSort;
+use payment_engine::exchange::stream::ExchangeHandle;
+use payment_engine::exchange::stream::PipelineError;
+use payment_engine::exchange::transaction::schema::SchemaVersion;
+use payment_engine::exchange::transaction::Transaction;
+use payment_engine::exchange::Exchange;
+
+const CLIENTS: u64 = 24;
+const TRANSACTIONS_PER_CLIENT: u64 = 40;
+const SOURCES: u64 = 4;
+const CHANNEL_CAPACITY: usize = 2;
+const SEEDS: u64 = 64;
+/// Polls after which a run that has not finished is considered deadlocked
+const MAX_STEPS: u64 = 1_000_000;
+
+/// xorshift, so every run of a seed makes the same choices
+struct Generator(u64);
+
+impl Generator {
+    fn new(seed: u64) -> Generator {
+        //xorshift never leaves zero
+        Generator(seed.max(1))
+    }
+
+    fn next(&mut self, bound: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % bound
+    }
+}
+
+/// The CSV rows of every client, in the order the client submitted them. Transaction ids are unique across clients
+fn workload() -> Vec<Vec<String>> {
+    let mut generator = Generator::new(0x5eed);
+    let mut tx = 0;
+    (1..=CLIENTS)
+        .map(|client| {
+            let mut deposits = Vec::new();
+            let mut disputed = Vec::new();
+            (0..TRANSACTIONS_PER_CLIENT)
+                .map(|_| match generator.next(10) {
+                    0..=4 => {
+                        tx += 1;
+                        deposits.push(tx);
+                        format!("deposit,{},{},{}.5", client, tx, generator.next(100))
+                    }
+                    5 | 6 => {
+                        tx += 1;
+                        format!("withdrawal,{},{},{}.25", client, tx, generator.next(60))
+                    }
+                    7 if !deposits.is_empty() => {
+                        let disputing =
+                            deposits.swap_remove(generator.next(deposits.len() as u64) as usize);
+                        disputed.push(disputing);
+                        format!("dispute,{},{},", client, disputing)
+                    }
+                    8 | 9 if !disputed.is_empty() => {
+                        let settling =
+                            disputed.swap_remove(generator.next(disputed.len() as u64) as usize);
+                        let step = if generator.next(4) == 0 {
+                            "chargeback"
+                        } else {
+                            "resolve"
+                        };
+                        format!("{},{},{},", step, client, settling)
+                    }
+                    //disputes of transactions the client never made, which the engine rejects
+                    _ => format!("dispute,{},{},", client, tx + 1_000_000),
+                })
+                .collect()
+        })
+        .collect()
+}
+
+fn parse(row: &str) -> Transaction {
+    let headers = csv::StringRecord::from(SchemaVersion::V1.columns().to_vec());
+    let record = csv::StringRecord::from(row.split(',').collect::<Vec<_>>());
+    SchemaVersion::V1.read(&record, &headers).unwrap()
+}
+
+/// Future pending once before completing, a point where the scheduler can switch to another task
+struct Yield(bool);
+
+impl Future for Yield {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<()> {
+        if self.0 {
+            Poll::Ready(())
+        } else {
+            self.0 = true;
+            Poll::Pending
+        }
+    }
+}
+
+/// Single threaded executor polling a task picked by its generator on every step. Tasks are never woken: a pending task is
+/// simply polled again when it is picked, so the interleaving only depends on the seed
+struct Scheduler {
+    generator: Generator,
+    tasks: Vec<Pin<Box<dyn Future<Output = ()>>>>,
+}
+
+impl Scheduler {
+    fn new(seed: u64) -> Scheduler {
+        Scheduler {
+            generator: Generator::new(seed),
+            tasks: Vec::new(),
+        }
+    }
+
+    fn spawn(&mut self, task: impl Future<Output = ()> + 'static) {
+        self.tasks.push(Box::pin(task));
+    }
+
+    fn run(mut self, seed: u64) {
+        let waker = noop_waker();
+        let mut context = Context::from_waker(&waker);
+        let mut steps = 0;
+        while !self.tasks.is_empty() {
+            steps += 1;
+            assert!(steps < MAX_STEPS, "seed {} deadlocked", seed);
+            let picked = self.generator.next(self.tasks.len() as u64) as usize;
+            if self.tasks[picked].as_mut().poll(&mut context).is_ready() {
+                drop(self.tasks.swap_remove(picked));
+            }
+        }
+    }
+}
+
+/// What the engine promises not to depend on the order of the clients: the account summaries with their activity columns
+/// (sorted, the order of the clients is not part of it), the counters and the open disputes
+#[derive(Debug, PartialEq)]
+struct Outcome {
+    accounts: Vec<String>,
+    processed: u64,
+    rejected: u64,
+    open_disputes: Vec<(u16, u32)>,
+}
+
+impl Outcome {
+    fn of(bank: &Exchange) -> Outcome {
+        let mut output = Vec::new();
+        bank.to_composite_writer(&mut output, &AmountFormat::Plain)
+            .unwrap();
+        let mut accounts: Vec<String> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(str::to_string)
+            .collect();
+        accounts.sort();
+        let mut open_disputes: Vec<(u16, u32)> = bank
+            .open_disputes()
+            .iter()
+            .map(|dispute| (dispute.client, dispute.tx))
+            .collect();
+        open_disputes.sort();
+        Outcome {
+            accounts,
+            processed: bank.stats().processed(),
+            rejected: bank.stats().rejected(),
+            open_disputes,
+        }
+    }
+}
+
+fn sequential(workload: &[Vec<String>]) -> Outcome {
+    let handle = ExchangeHandle::new(Exchange::new()).quiet();
+    let transactions = workload
+        .iter()
+        .flatten()
+        .map(|row| Ok::<_, PipelineError>(parse(row)));
+    block_on(stream::iter(transactions).forward(handle.clone())).unwrap();
+    Outcome::of(&handle.into_inner().ok().unwrap())
+}
+
+fn simulate(workload: &[Vec<String>], seed: u64) -> Outcome {
+    let mut scheduler = Scheduler::new(seed);
+    let handle = ExchangeHandle::new(Exchange::new()).quiet();
+    let (sender, receiver) = mpsc::channel::<Transaction>(CHANNEL_CAPACITY);
+
+    for source in 0..SOURCES {
+        let mut queues: Vec<Vec<String>> = workload
+            .iter()
+            .skip(source as usize)
+            .step_by(SOURCES as usize)
+            .map(|rows| rows.iter().rev().cloned().collect())
+            .collect();
+        let mut sender = sender.clone();
+        let mut generator = Generator::new(seed ^ (source + 1).wrapping_mul(0x9e3779b97f4a7c15));
+        scheduler.spawn(async move {
+            //interleaves the clients of the source, each one in the order of its own rows
+            while !queues.is_empty() {
+                let picked = generator.next(queues.len() as u64) as usize;
+                let row = queues[picked].pop().unwrap();
+                if queues[picked].is_empty() {
+                    queues.swap_remove(picked);
+                }
+                sender.send(parse(&row)).await.unwrap();
+                if generator.next(3) == 0 {
+                    Yield(false).await;
+                }
+            }
+        });
+    }
+    drop(sender);
+
+    let engine = handle.clone();
+    scheduler.spawn(async move {
+        receiver
+            .map(Ok::<_, PipelineError>)
+            .forward(engine)
+            .await
+            .unwrap();
+    });
+
+    let output = handle.clone();
+    scheduler.spawn(async move {
+        for _ in 0..50 {
+            let page = output
+                .lock()
+                .accounts_page(0, usize::MAX, AccountSort::Client);
+            for account in page.accounts {
+                assert_eq!(
+                    account.total,
+                    account.available + account.held,
+                    "seed {}, client {}",
+                    seed,
+                    account.client
+                );
+            }
+            Yield(false).await;
+        }
+    });
+
+    scheduler.run(seed);
+    Outcome::of(&handle.into_inner().ok().unwrap())
+}
+
+#[test]
+fn it_should_reach_the_sequential_state_whatever_the_interleaving_of_the_clients() {
+    let workload = workload();
+    let expected = sequential(&workload);
+    assert_eq!(CLIENTS as usize + 1, expected.accounts.len());
+    assert!(expected.rejected > 0);
+    assert!(expected.accounts.iter().any(|row| row.contains("true")));
+
+    let seeds = match env::var("SIMULATION_SEED") {
+        Ok(seed) => seed.parse().unwrap()..=seed.parse().unwrap(),
+        Err(_) => 1..=SEEDS,
+    };
+    for seed in seeds {
+        assert_eq!(expected, simulate(&workload, seed), "seed {}", seed);
+    }
+}